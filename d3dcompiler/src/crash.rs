@@ -0,0 +1,426 @@
+//! Crash reports for faults inside the DLL, enabled by the `crash-handler` feature
+//!
+//! Installs handlers for SIGSEGV, SIGBUS and SIGILL when the DLL is loaded.
+//! A fault whose RIP lies in the mapped image gets a report on stderr: the
+//! RVA and nearest symbol, the registers, and a stack walked with the image's
+//! `.pdata` unwind data. Every signal is then passed on to the handler that was
+//! installed before, so other handlers and the default core dump still run.
+//!
+//! The handlers run on an alternate signal stack, which `setup_tib` installs
+//! on every thread that calls into the DLL, so a stack overflow is reported
+//! too. Symbol tables are built when an image is loaded; the handler only
+//! reads them.
+
+use crate::imports::ntdll::{self, CONTEXT};
+use crate::imports::{Image, find_image};
+use std::ffi::c_void;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, OnceLock, RwLock};
+
+const SIGNALS: [i32; 3] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGILL];
+const MAX_FRAMES: usize = 64;
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+static PREVIOUS: OnceLock<Vec<(i32, libc::sigaction)>> = OnceLock::new();
+static REPORTED: AtomicBool = AtomicBool::new(false);
+// Symbols of each loaded image, by image id
+static SYMBOLS: RwLock<Vec<(usize, Symbols)>> = RwLock::new(Vec::new());
+
+/// Installs the handlers once per process.
+pub fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        let mut previous = Vec::new();
+        for sig in SIGNALS {
+            let mut sa: libc::sigaction = std::mem::zeroed();
+            sa.sa_sigaction = crash_handler as *const () as usize;
+            sa.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut sa.sa_mask);
+            let mut old: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(sig, &sa, &mut old);
+            previous.push((sig, old));
+        }
+        let _ = PREVIOUS.set(previous);
+    });
+}
+
+/// Builds the symbol table used to report faults in `image`.
+pub unsafe fn add_image(image: &Image) {
+    let symbols = Symbols::new(image);
+    SYMBOLS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((image.id, symbols));
+}
+
+/// Drops the symbol table of an image being unloaded.
+pub fn remove_image(id: usize) {
+    SYMBOLS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(image, _)| *image != id);
+}
+
+/// An alternate signal stack this module mapped for the thread, removed
+/// when the thread exits
+struct AltStack {
+    /// Start of the mapping, including its guard page, or null if the thread
+    /// already had an alternate stack
+    mapping: *mut c_void,
+    size: usize,
+}
+
+thread_local! {
+    static ALT_STACK: AltStack = unsafe { AltStack::install() };
+}
+
+/// Gives the calling thread an alternate signal stack, unless it has one.
+pub fn install_alt_stack() {
+    ALT_STACK.with(|_| {});
+}
+
+impl AltStack {
+    unsafe fn install() -> AltStack {
+        let none = AltStack {
+            mapping: std::ptr::null_mut(),
+            size: 0,
+        };
+        let mut current: libc::stack_t = std::mem::zeroed();
+        if libc::sigaltstack(std::ptr::null(), &mut current) != 0
+            || current.ss_flags & libc::SS_DISABLE == 0
+        {
+            return none;
+        }
+
+        // A guard page below the stack turns an overflow of the handler
+        // itself into a plain crash
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let size = ALT_STACK_SIZE.max(libc::SIGSTKSZ) + page;
+        let mapping = libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if mapping == libc::MAP_FAILED {
+            return none;
+        }
+        libc::mprotect(mapping, page, libc::PROT_NONE);
+        let stack = libc::stack_t {
+            ss_sp: mapping.byte_add(page),
+            ss_flags: 0,
+            ss_size: size - page,
+        };
+        if libc::sigaltstack(&stack, std::ptr::null_mut()) != 0 {
+            libc::munmap(mapping, size);
+            return none;
+        }
+        AltStack { mapping, size }
+    }
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        if self.mapping.is_null() {
+            return;
+        }
+        unsafe {
+            let disable = libc::stack_t {
+                ss_sp: std::ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: 0,
+            };
+            libc::sigaltstack(&disable, std::ptr::null_mut());
+            libc::munmap(self.mapping, self.size);
+        }
+    }
+}
+
+unsafe extern "C" fn crash_handler(sig: i32, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let uc = context as *mut libc::ucontext_t;
+    let rip = (*uc).uc_mcontext.gregs[libc::REG_RIP as usize] as usize;
+    if let Some(image) = find_image(rip)
+        && !REPORTED.swap(true, Ordering::SeqCst)
+    {
+        report(sig, info, uc, &image);
+    }
+    chain(sig, info, context);
+}
+
+/// Passes the signal to the handler that was installed before ours.
+unsafe fn chain(sig: i32, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let Some(&(_, previous)) = PREVIOUS
+        .get()
+        .and_then(|previous| previous.iter().find(|(s, _)| *s == sig))
+    else {
+        return;
+    };
+
+    match previous.sa_sigaction {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => {
+            // Returning re-executes the faulting instruction, which now
+            // gets the default action
+            libc::sigaction(sig, &previous, std::ptr::null_mut());
+        }
+        handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(i32, *mut libc::siginfo_t, *mut c_void) =
+                std::mem::transmute(handler);
+            handler(sig, info, context);
+        }
+        handler => {
+            let handler: extern "C" fn(i32) = std::mem::transmute(handler);
+            handler(sig);
+        }
+    }
+}
+
+unsafe fn report(sig: i32, info: *mut libc::siginfo_t, uc: *mut libc::ucontext_t, image: &Image) {
+    let gregs = &(*uc).uc_mcontext.gregs;
+    let reg = |r: i32| gregs[r as usize] as u64;
+
+    let mut context = CONTEXT::zeroed();
+    context.Rax = reg(libc::REG_RAX);
+    context.Rcx = reg(libc::REG_RCX);
+    context.Rdx = reg(libc::REG_RDX);
+    context.Rbx = reg(libc::REG_RBX);
+    context.Rsp = reg(libc::REG_RSP);
+    context.Rbp = reg(libc::REG_RBP);
+    context.Rsi = reg(libc::REG_RSI);
+    context.Rdi = reg(libc::REG_RDI);
+    context.R8 = reg(libc::REG_R8);
+    context.R9 = reg(libc::REG_R9);
+    context.R10 = reg(libc::REG_R10);
+    context.R11 = reg(libc::REG_R11);
+    context.R12 = reg(libc::REG_R12);
+    context.R13 = reg(libc::REG_R13);
+    context.R14 = reg(libc::REG_R14);
+    context.R15 = reg(libc::REG_R15);
+    context.Rip = reg(libc::REG_RIP);
+
+    let name = match sig {
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGBUS => "SIGBUS",
+        _ => "SIGILL",
+    };
+    let tables = SYMBOLS.read().unwrap_or_else(|e| e.into_inner());
+    let empty = Symbols {
+        map_base: image.map_base,
+        symbols: Vec::new(),
+    };
+    let symbols = tables
+        .iter()
+        .find(|(id, _)| *id == image.id)
+        .map_or(&empty, |(_, symbols)| symbols);
+    eprintln!(
+        "[d3dcompiler] {} in {} at {}, fault address 0x{:x}",
+        name,
+        image.name,
+        symbols.describe(context.Rip),
+        (*info).si_addr() as usize
+    );
+    eprintln!(
+        "[d3dcompiler] Image mapped at 0x{:x}, original image base 0x{:x}",
+        image.map_base, image.image_base
+    );
+
+    eprintln!("[d3dcompiler] Registers:");
+    let rows = [
+        ("RAX", context.Rax, "RBX", context.Rbx),
+        ("RCX", context.Rcx, "RDX", context.Rdx),
+        ("RSI", context.Rsi, "RDI", context.Rdi),
+        ("RBP", context.Rbp, "RSP", context.Rsp),
+        ("R8 ", context.R8, "R9 ", context.R9),
+        ("R10", context.R10, "R11", context.R11),
+        ("R12", context.R12, "R13", context.R13),
+        ("R14", context.R14, "R15", context.R15),
+    ];
+    for (a, a_value, b, b_value) in rows {
+        eprintln!("  {}=0x{:016x}  {}=0x{:016x}", a, a_value, b, b_value);
+    }
+
+    eprintln!("[d3dcompiler] Stack:");
+    for frame in 0..MAX_FRAMES {
+        let pc = context.Rip;
+        if !image.contains(pc as usize) {
+            eprintln!("  #{:<2} 0x{:016x} (outside {})", frame, pc, image.name);
+            break;
+        }
+        eprintln!("  #{:<2} {}", frame, symbols.describe(pc));
+
+        let sp = context.Rsp;
+        match ntdll::lookup_function_entry(pc) {
+            Some((entry, image_base)) => {
+                let mut handler_data = std::ptr::null_mut();
+                let mut establisher_frame = 0;
+                ntdll::virtual_unwind(
+                    0,
+                    image_base,
+                    pc,
+                    entry,
+                    &mut context,
+                    &mut handler_data,
+                    &mut establisher_frame,
+                );
+            }
+            None => {
+                // Leaf function: only the return address is on the stack
+                context.Rip = *(context.Rsp as *const u64);
+                context.Rsp += 8;
+            }
+        }
+        if context.Rsp <= sp {
+            eprintln!("  (stack walk stopped: frame did not unwind)");
+            break;
+        }
+    }
+}
+
+/// Names for addresses in the image: its exports, plus the `jmp [IAT]`
+/// thunks it uses to call imports
+struct Symbols {
+    map_base: usize,
+    symbols: Vec<(usize, String)>,
+}
+
+impl Symbols {
+    unsafe fn new(image: &Image) -> Self {
+        let mut symbols = image.exports.clone();
+        symbols.extend(import_thunks(image));
+        symbols.sort();
+        Symbols {
+            map_base: image.map_base,
+            symbols,
+        }
+    }
+
+    /// Formats `pc` as its RVA and the nearest symbol at or below it,
+    /// without allocating
+    fn describe(&self, pc: u64) -> Location<'_> {
+        Location { symbols: self, pc }
+    }
+}
+
+struct Location<'a> {
+    symbols: &'a Symbols,
+    pc: u64,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbols = &self.symbols.symbols;
+        let rva = self.pc as usize - self.symbols.map_base;
+        let index = symbols.partition_point(|(start, _)| *start <= rva);
+        match index.checked_sub(1).map(|i| &symbols[i]) {
+            Some((start, name)) => write!(f, "RVA 0x{:x} ({}+0x{:x})", rva, name, rva - start),
+            None => write!(f, "RVA 0x{:x}", rva),
+        }
+    }
+}
+
+/// Finds `jmp qword ptr [rip+disp32]` instructions in the image that jump
+/// through an IAT slot.
+unsafe fn import_thunks(image: &Image) -> Vec<(usize, String)> {
+    let slots = &image.import_slots;
+    let code = std::slice::from_raw_parts(image.map_base as *const u8, image.map_size);
+    let mut thunks = Vec::new();
+    for (rva, window) in code.windows(6).enumerate() {
+        if window[0] != 0xFF || window[1] != 0x25 {
+            continue;
+        }
+        let disp = i32::from_le_bytes([window[2], window[3], window[4], window[5]]);
+        let target = (rva + 6).wrapping_add_signed(disp as isize);
+        if let Ok(index) = slots.binary_search_by_key(&target, |(slot, _)| *slot) {
+            thunks.push((rva, format!("{} thunk", slots[index].1)));
+        }
+    }
+    thunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An image over `code` with two exports and one IAT slot at 0x80
+    fn test_image(code: &[u8]) -> Image {
+        Image {
+            id: 0,
+            name: "test.dll".to_string(),
+            map_base: code.as_ptr() as usize,
+            map_size: code.len(),
+            image_base: 0x1_8000_0000,
+            exception_dir: 0,
+            exception_dir_size: 0,
+            exports: vec![
+                (0x40, "D3DCompile".to_string()),
+                (0x60, "D3DReflect".to_string()),
+            ],
+            import_slots: vec![(0x80, "kernel32!HeapAlloc".to_string())],
+            entry_point: 0,
+            tls_callbacks: 0,
+            tls: None,
+            attached: AtomicBool::new(false),
+        }
+    }
+
+    #[test]
+    fn test_import_thunks() {
+        let mut code = vec![0xCCu8; 0x100];
+        // jmp [rip+0x6A] at 0x10 reads the slot at 0x16 + 0x6A = 0x80
+        code[0x10..0x16].copy_from_slice(&[0xFF, 0x25, 0x6A, 0, 0, 0]);
+        // jmp [rip-0x10] at 0x30 reads 0x26, which is not a slot
+        code[0x30..0x36].copy_from_slice(&[0xFF, 0x25, 0xF0, 0xFF, 0xFF, 0xFF]);
+        let image = test_image(&code);
+
+        let thunks = unsafe { import_thunks(&image) };
+        assert_eq!(thunks, vec![(0x10, "kernel32!HeapAlloc thunk".to_string())]);
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut code = vec![0xCCu8; 0x100];
+        code[0x10..0x16].copy_from_slice(&[0xFF, 0x25, 0x6A, 0, 0, 0]);
+        let image = test_image(&code);
+        let symbols = unsafe { Symbols::new(&image) };
+        let describe = |rva: usize| symbols.describe((image.map_base + rva) as u64).to_string();
+
+        assert_eq!(describe(0x8), "RVA 0x8");
+        assert_eq!(describe(0x10), "RVA 0x10 (kernel32!HeapAlloc thunk+0x0)");
+        assert_eq!(describe(0x40), "RVA 0x40 (D3DCompile+0x0)");
+        assert_eq!(describe(0x52), "RVA 0x52 (D3DCompile+0x12)");
+        assert_eq!(describe(0xF0), "RVA 0xf0 (D3DReflect+0x90)");
+    }
+
+    unsafe fn current_alt_stack() -> libc::stack_t {
+        let mut current: libc::stack_t = std::mem::zeroed();
+        assert_eq!(libc::sigaltstack(std::ptr::null(), &mut current), 0);
+        current
+    }
+
+    #[test]
+    fn test_install_alt_stack() {
+        std::thread::spawn(|| unsafe {
+            // Start like a thread the runtime did not create
+            let disable = libc::stack_t {
+                ss_sp: std::ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: 0,
+            };
+            assert_eq!(libc::sigaltstack(&disable, std::ptr::null_mut()), 0);
+
+            install_alt_stack();
+            let installed = current_alt_stack();
+            assert_eq!(installed.ss_flags & libc::SS_DISABLE, 0);
+            assert!(installed.ss_size >= ALT_STACK_SIZE);
+
+            // Installed once per thread
+            install_alt_stack();
+            assert_eq!(current_alt_stack().ss_sp, installed.ss_sp);
+        })
+        .join()
+        .unwrap();
+    }
+}
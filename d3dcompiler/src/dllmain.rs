@@ -0,0 +1,239 @@
+//! DllMain and TLS callback notifications
+//!
+//! Follows the Windows loader: an image gets `DLL_PROCESS_ATTACH` when it is
+//! loaded and `DLL_PROCESS_DETACH` when it is unloaded. Every other thread
+//! gets `DLL_THREAD_ATTACH` the first time it calls into the image, from
+//! `setup_tib`, and `DLL_THREAD_DETACH` from a destructor when it exits. The
+//! image's TLS callbacks run before its entry point for each notification,
+//! and each thread gets its own copy of the image's `__declspec(thread)` data.
+
+use crate::imports::{Image, images};
+use crate::{D3DCompilerError, Result};
+use std::cell::{RefCell, UnsafeCell};
+use std::ffi::c_void;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const DLL_PROCESS_DETACH: u32 = 0;
+pub const DLL_PROCESS_ATTACH: u32 = 1;
+pub const DLL_THREAD_ATTACH: u32 = 2;
+pub const DLL_THREAD_DETACH: u32 = 3;
+
+/// TLS indexes available to the images loaded at one time; unloading an
+/// image frees its index
+pub const MAX_TLS_INDEXES: usize = 64;
+
+/// An image's TLS template, at runtime addresses
+pub struct TlsData {
+    /// Index into the thread's `ThreadLocalStoragePointer` array
+    pub index: usize,
+    /// Initialized data copied into each thread's block
+    pub start: usize,
+    pub end: usize,
+    /// Zeroed bytes following the initialized data
+    pub zero_fill: usize,
+}
+
+type EntryPoint = unsafe extern "win64" fn(*mut c_void, u32, *mut c_void) -> i32;
+type TlsCallback = unsafe extern "win64" fn(*mut c_void, u32, *mut c_void);
+
+static NEXT_TLS_INDEX: AtomicUsize = AtomicUsize::new(0);
+// Indexes of unloaded images, reused before new ones
+static FREE_TLS_INDEXES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+// Bumped whenever an image finishes DLL_PROCESS_ATTACH
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+struct ThreadState {
+    /// Ids of the images this thread is attached to, in attach order
+    attached: Vec<usize>,
+    /// GENERATION when this thread last looked for new images
+    generation: usize,
+}
+
+thread_local! {
+    // The array the TEB's ThreadLocalStoragePointer points at. It has no
+    // destructor, so it stays valid while THREAD's destructor runs.
+    static TLS_ARRAY: UnsafeCell<[usize; MAX_TLS_INDEXES]> =
+        const { UnsafeCell::new([0; MAX_TLS_INDEXES]) };
+    static THREAD: RefCell<ThreadState> = const {
+        RefCell::new(ThreadState {
+            attached: Vec::new(),
+            generation: 0,
+        })
+    };
+}
+
+impl Drop for ThreadState {
+    fn drop(&mut self) {
+        let loaded = images();
+        for id in self.attached.iter().rev() {
+            if let Some(image) = loaded
+                .iter()
+                .find(|image| image.id == *id && image.attached.load(Ordering::SeqCst))
+            {
+                unsafe { notify(image, DLL_THREAD_DETACH) };
+            }
+        }
+        TLS_ARRAY.with(|array| unsafe {
+            for block in (*array.get()).iter_mut() {
+                libc::free(*block as *mut c_void);
+                *block = 0;
+            }
+        });
+    }
+}
+
+/// Returns this thread's `ThreadLocalStoragePointer` array.
+pub fn tls_array() -> *mut usize {
+    TLS_ARRAY.with(|array| array.get() as *mut usize)
+}
+
+pub fn alloc_tls_index() -> Result<usize> {
+    let free = FREE_TLS_INDEXES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .pop();
+    if let Some(index) = free {
+        return Ok(index);
+    }
+    let index = NEXT_TLS_INDEX.fetch_add(1, Ordering::Relaxed);
+    if index >= MAX_TLS_INDEXES {
+        NEXT_TLS_INDEX.fetch_sub(1, Ordering::Relaxed);
+        return Err(D3DCompilerError::LoadError(format!(
+            "no TLS index left, {} images with TLS data are loaded",
+            MAX_TLS_INDEXES
+        )));
+    }
+    Ok(index)
+}
+
+/// Returns the index of an image that is being unloaded or failed to load.
+pub fn free_tls_index(index: usize) {
+    FREE_TLS_INDEXES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(index);
+}
+
+/// Calls the image's TLS callbacks and then its entry point with `reason`.
+///
+/// Returns what the entry point returned, or TRUE if there is none.
+pub unsafe fn notify(image: &Image, reason: u32) -> i32 {
+    let module = image.map_base as *mut c_void;
+    if image.tls_callbacks != 0 {
+        // The array may change while callbacks run, so reread every entry
+        let mut callback = image.tls_callbacks as *const usize;
+        while *callback != 0 {
+            let f = std::mem::transmute::<usize, TlsCallback>(*callback);
+            f(module, reason, std::ptr::null_mut());
+            callback = callback.add(1);
+        }
+    }
+    if image.entry_point == 0 {
+        return 1;
+    }
+    let entry = std::mem::transmute::<usize, EntryPoint>(image.entry_point);
+    entry(module, reason, std::ptr::null_mut())
+}
+
+/// Attaches the calling thread, which is loading `image`.
+///
+/// Returns false if DllMain returned FALSE. Like Windows, the image then
+/// gets `DLL_PROCESS_DETACH` right away, and the caller must unload it.
+pub unsafe fn process_attach(image: &Image) -> bool {
+    THREAD.with(|thread| thread.borrow_mut().attached.push(image.id));
+    alloc_tls_block(image);
+    if notify(image, DLL_PROCESS_ATTACH) == 0 {
+        process_detach(image);
+        return false;
+    }
+    image.attached.store(true, Ordering::SeqCst);
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    true
+}
+
+/// Sends `DLL_PROCESS_DETACH` and runs the exit functions the image
+/// registered. Threads no longer attach to it or get its detach notification.
+pub unsafe fn process_detach(image: &Image) {
+    image.attached.store(false, Ordering::SeqCst);
+    notify(image, DLL_PROCESS_DETACH);
+    for func in crate::imports::msvcrt::take_onexit(image) {
+        let func = std::mem::transmute::<usize, unsafe extern "win64" fn() -> i32>(func);
+        func();
+    }
+    THREAD.with(|thread| thread.borrow_mut().attached.retain(|id| *id != image.id));
+    free_tls_block(image);
+}
+
+/// Sends `DLL_THREAD_ATTACH` to the images the calling thread has not
+/// attached to yet.
+pub unsafe fn attach_thread() {
+    // Also skipped once the thread has detached, while it exits
+    let generation = GENERATION.load(Ordering::SeqCst);
+    if THREAD
+        .try_with(|thread| thread.borrow().generation == generation)
+        .unwrap_or(true)
+    {
+        return;
+    }
+
+    for image in images() {
+        if !image.attached.load(Ordering::SeqCst) {
+            continue;
+        }
+        let attaching = THREAD.with(|thread| {
+            let mut thread = thread.borrow_mut();
+            let attaching = !thread.attached.contains(&image.id);
+            if attaching {
+                thread.attached.push(image.id);
+            }
+            attaching
+        });
+        // Not borrowed while the DLL runs
+        if attaching {
+            alloc_tls_block(&image);
+            notify(&image, DLL_THREAD_ATTACH);
+        }
+    }
+    THREAD.with(|thread| thread.borrow_mut().generation = generation);
+}
+
+unsafe fn alloc_tls_block(image: &Image) {
+    let Some(tls) = &image.tls else {
+        return;
+    };
+    let data = tls.end - tls.start;
+    let block = libc::calloc(1, (data + tls.zero_fill).max(1)) as *mut u8;
+    if !block.is_null() {
+        std::ptr::copy_nonoverlapping(tls.start as *const u8, block, data);
+    }
+    // A reused index may still hold the block of an image unloaded by
+    // another thread
+    let slot = tls_array().add(tls.index);
+    libc::free(*slot as *mut c_void);
+    *slot = block as usize;
+}
+
+unsafe fn free_tls_block(image: &Image) {
+    if let Some(tls) = &image.tls {
+        let slot = tls_array().add(tls.index);
+        libc::free(*slot as *mut c_void);
+        *slot = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_index_reuse() {
+        let first = alloc_tls_index().unwrap();
+        let second = alloc_tls_index().unwrap();
+        assert_ne!(first, second);
+        free_tls_index(first);
+        assert_eq!(alloc_tls_index().unwrap(), first);
+        free_tls_index(first);
+        free_tls_index(second);
+    }
+}
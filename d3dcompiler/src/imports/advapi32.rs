@@ -26,7 +26,11 @@ import_fn! {
         _samDesired: u32,
         _phkResult: *mut *mut c_void,
     ) -> i32 {
-        trace_call!("advapi32!RegOpenKeyExA");
+        trace_call!(
+            "advapi32!RegOpenKeyExA",
+            "key={:p}, sub_key={:p}, options=0x{:x}, sam_desired={}, result={:p}",
+            _hKey, _lpSubKey, _ulOptions, _samDesired, _phkResult
+        );
         ERROR_FILE_NOT_FOUND
     }
 
@@ -37,7 +41,11 @@ import_fn! {
         _samDesired: u32,
         _phkResult: *mut *mut c_void,
     ) -> i32 {
-        trace_call!("advapi32!RegOpenKeyExW");
+        trace_call!(
+            "advapi32!RegOpenKeyExW",
+            "key={:p}, sub_key={:p}, options=0x{:x}, sam_desired={}, result={:p}",
+            _hKey, _lpSubKey, _ulOptions, _samDesired, _phkResult
+        );
         ERROR_FILE_NOT_FOUND
     }
 
@@ -49,7 +57,11 @@ import_fn! {
         _lpData: *mut u8,
         _lpcbData: *mut u32,
     ) -> i32 {
-        trace_call!("advapi32!RegQueryValueExA");
+        trace_call!(
+            "advapi32!RegQueryValueExA",
+            "key={:p}, value_name={:p}, reserved={:p}, type={:p}, data={:p}, cb_data={:p}",
+            _hKey, _lpValueName, _lpReserved, _lpType, _lpData, _lpcbData
+        );
         ERROR_FILE_NOT_FOUND
     }
 
@@ -61,7 +73,11 @@ import_fn! {
         _lpData: *mut u8,
         _lpcbData: *mut u32,
     ) -> i32 {
-        trace_call!("advapi32!RegQueryValueExW");
+        trace_call!(
+            "advapi32!RegQueryValueExW",
+            "key={:p}, value_name={:p}, reserved={:p}, type={:p}, data={:p}, cb_data={:p}",
+            _hKey, _lpValueName, _lpReserved, _lpType, _lpData, _lpcbData
+        );
         ERROR_FILE_NOT_FOUND
     }
 
@@ -75,12 +91,23 @@ import_fn! {
         _lpcchClass: *mut u32,
         _lpftLastWriteTime: *mut u64,
     ) -> i32 {
-        trace_call!("advapi32!RegEnumKeyExA");
+        trace_call!(
+            "advapi32!RegEnumKeyExA",
+            "key={:p}, index={}, name={:p}, cch_name={:p}, reserved={:p}, class={:p}, cch_class={:p}, last_write_time={:p}",
+            _hKey,
+            _dwIndex,
+            _lpName,
+            _lpcchName,
+            _lpReserved,
+            _lpClass,
+            _lpcchClass,
+            _lpftLastWriteTime,
+        );
         259 // ERROR_NO_MORE_ITEMS
     }
 
     fn RegCloseKey(_hKey: *mut c_void) -> i32 {
-        trace_call!("advapi32!RegCloseKey");
+        trace_call!("advapi32!RegCloseKey", "key={:p}", _hKey);
         0
     }
 
@@ -93,13 +120,17 @@ import_fn! {
         _dwProvType: u32,
         _dwFlags: u32,
     ) -> i32 {
-        trace_call!("advapi32!CryptAcquireContextW");
+        trace_call!(
+            "advapi32!CryptAcquireContextW",
+            "prov={:p}, container={:p}, provider={:p}, prov_type={}, flags=0x{:x}",
+            phProv, _szContainer, _szProvider, _dwProvType, _dwFlags
+        );
         *phProv = 0x1000 as *mut c_void;
         1
     }
 
     fn CryptReleaseContext(_hProv: *mut c_void, _dwFlags: u32) -> i32 {
-        trace_call!("advapi32!CryptReleaseContext");
+        trace_call!("advapi32!CryptReleaseContext", "prov={:p}, flags=0x{:x}", _hProv, _dwFlags);
         1
     }
 
@@ -122,7 +153,7 @@ import_fn! {
     }
 
     fn CryptDestroyHash(hHash: *mut c_void) -> i32 {
-        trace_call!("advapi32!CryptDestroyHash");
+        trace_call!("advapi32!CryptDestroyHash", "hash={:p}", hHash);
         get_crypto_handles()
             .write()
             .unwrap()
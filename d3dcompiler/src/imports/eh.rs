@@ -0,0 +1,883 @@
+//! Exception dispatch for the loaded image
+//!
+//! Walks the image's frames with the `.pdata` unwinder in [`ntdll`], calling
+//! each frame's language handler the way `RtlDispatchException` and
+//! `RtlUnwindEx` do. Also implements the two language handlers the DLL
+//! imports: `__C_specific_handler` for `__try` blocks and `__CxxFrameHandler3`
+//! for C++ `try`/`catch` and destructors.
+//!
+//! Exceptions are raised by `_CxxThrowException`. Hardware faults still arrive
+//! as signals and are not dispatched.
+
+use super::ntdll::{self, CONTEXT, RUNTIME_FUNCTION, UNW_FLAG_EHANDLER, UNW_FLAG_UHANDLER};
+use super::*;
+use std::cell::{Cell, RefCell};
+
+// EXCEPTION_RECORD flags
+pub const EXCEPTION_NONCONTINUABLE: u32 = 0x01;
+pub const EXCEPTION_UNWINDING: u32 = 0x02;
+pub const EXCEPTION_EXIT_UNWIND: u32 = 0x04;
+pub const EXCEPTION_TARGET_UNWIND: u32 = 0x20;
+const EXCEPTION_UNWIND: u32 = EXCEPTION_UNWINDING | EXCEPTION_EXIT_UNWIND;
+
+// EXCEPTION_DISPOSITION
+const EXCEPTION_CONTINUE_EXECUTION: u32 = 0;
+const EXCEPTION_CONTINUE_SEARCH: u32 = 1;
+
+const STATUS_UNWIND: u32 = 0xC0000027;
+
+/// Exception code of C++ exceptions ('msc' | 0xE0000000)
+pub const CXX_EXCEPTION: u32 = 0xE06D7363;
+// Versions of the compiler's EH data, used as FuncInfo and exception magic
+const CXX_MAGIC_1: u32 = 0x19930520;
+const CXX_MAGIC_3: u32 = 0x19930522;
+
+// FuncInfo::eh_flags: compiled with /EHs, so catch(...) ignores SEH exceptions
+const FI_EHS_FLAG: i32 = 1;
+// HandlerType::adjectives
+const HT_IS_REFERENCE: u32 = 0x08;
+// CatchableType::properties
+const CT_IS_SIMPLE_TYPE: u32 = 0x01;
+const CT_BY_REFERENCE_ONLY: u32 = 0x02;
+const CT_HAS_VIRTUAL_BASE: u32 = 0x04;
+
+#[repr(C)]
+pub struct EXCEPTION_RECORD {
+    pub ExceptionCode: u32,
+    pub ExceptionFlags: u32,
+    pub ExceptionRecord: *mut EXCEPTION_RECORD,
+    pub ExceptionAddress: *mut c_void,
+    pub NumberParameters: u32,
+    pub ExceptionInformation: [u64; 15],
+}
+
+#[repr(C)]
+pub struct EXCEPTION_POINTERS {
+    pub ExceptionRecord: *mut EXCEPTION_RECORD,
+    pub ContextRecord: *mut CONTEXT,
+}
+
+#[repr(C)]
+pub struct DISPATCHER_CONTEXT {
+    pub ControlPc: u64,
+    pub ImageBase: u64,
+    pub FunctionEntry: *mut RUNTIME_FUNCTION,
+    pub EstablisherFrame: u64,
+    pub TargetIp: u64,
+    pub ContextRecord: *mut CONTEXT,
+    pub LanguageHandler: u64,
+    pub HandlerData: *mut c_void,
+    pub HistoryTable: *mut c_void,
+    pub ScopeIndex: u32,
+    pub Fill0: u32,
+}
+
+type LanguageHandler = unsafe extern "win64" fn(
+    *mut EXCEPTION_RECORD,
+    u64,
+    *mut CONTEXT,
+    *mut DISPATCHER_CONTEXT,
+) -> u32;
+
+// ============ Stack walking ============
+
+/// An exception being dispatched
+struct Dispatch {
+    record: *mut EXCEPTION_RECORD,
+    /// Registers where the exception was raised
+    context: CONTEXT,
+    previous: *const Dispatch,
+}
+
+/// A catch block running on this thread
+struct ActiveCatch {
+    /// Runtime address of the catch block
+    funclet: u64,
+    /// Establisher frame of the function that owns the catch block
+    parent_frame: u64,
+    /// Registers of that function at the point the exception left it
+    parent_context: CONTEXT,
+    /// Registers of its caller
+    parent_caller: CONTEXT,
+    /// The caught C++ object and its ThrowInfo, kept for rethrow
+    object: u64,
+    throw_info: u64,
+    throw_image_base: u64,
+}
+
+thread_local! {
+    static DISPATCH: Cell<*const Dispatch> = const { Cell::new(std::ptr::null()) };
+    // Innermost last
+    static ACTIVE_CATCHES: RefCell<Vec<ActiveCatch>> = const { RefCell::new(Vec::new()) };
+}
+
+/// One image frame found while walking the stack
+struct Frame {
+    /// The frame's registers, before unwinding it
+    context: CONTEXT,
+    control_pc: u64,
+    image_base: u64,
+    function_entry: *mut RUNTIME_FUNCTION,
+    establisher_frame: u64,
+    handler: Option<u64>,
+    handler_data: *mut c_void,
+}
+
+impl Frame {
+    /// Calls the frame's language handler, if it has one.
+    unsafe fn call_handler(
+        &mut self,
+        record: *mut EXCEPTION_RECORD,
+        context: *mut CONTEXT,
+        target_ip: u64,
+    ) -> u32 {
+        let Some(handler) = self.handler else {
+            return EXCEPTION_CONTINUE_SEARCH;
+        };
+        let mut dispatcher = DISPATCHER_CONTEXT {
+            ControlPc: self.control_pc,
+            ImageBase: self.image_base,
+            FunctionEntry: self.function_entry,
+            EstablisherFrame: self.establisher_frame,
+            TargetIp: target_ip,
+            ContextRecord: &mut self.context,
+            LanguageHandler: handler,
+            HandlerData: self.handler_data,
+            HistoryTable: std::ptr::null_mut(),
+            ScopeIndex: 0,
+            Fill0: 0,
+        };
+        let handler: LanguageHandler = std::mem::transmute(handler);
+        handler(record, self.establisher_frame, context, &mut dispatcher)
+    }
+}
+
+/// Returns true if `pc` is the return address inside [`call_catch_block`].
+fn is_catch_return(pc: u64) -> bool {
+    let start = call_catch_block as *const () as u64;
+    pc > start && pc < start + 16
+}
+
+/// Steps `context` out of the innermost image frame and returns that frame.
+///
+/// A catch block's frame continues into the caller of the function that owns
+/// it: the catch block shares that function's EH data, so its handler call
+/// already covered the owner. `catches` counts the catch blocks passed.
+/// Returns `None` once the walk leaves the image.
+unsafe fn next_frame(
+    context: &mut CONTEXT,
+    handler_type: u32,
+    catches: &mut usize,
+) -> Option<Frame> {
+    loop {
+        let pc = context.Rip;
+        if is_catch_return(pc) {
+            *context = ACTIVE_CATCHES.with(|active| {
+                let active = active.borrow();
+                active.iter().rev().nth(*catches).map(|c| c.parent_caller)
+            })?;
+            *catches += 1;
+            continue;
+        }
+
+        let Some((entry, image_base)) = ntdll::lookup_function_entry(pc) else {
+            if !ntdll::in_image(pc) {
+                return None;
+            }
+            // Leaf function: only the return address is on the stack
+            context.Rip = *(context.Rsp as *const u64);
+            context.Rsp += 8;
+            continue;
+        };
+
+        let frame_context = *context;
+        let mut handler_data = std::ptr::null_mut();
+        let mut establisher_frame = 0;
+        let handler = ntdll::virtual_unwind(
+            handler_type,
+            image_base,
+            pc,
+            entry,
+            context,
+            &mut handler_data,
+            &mut establisher_frame,
+        );
+        return Some(Frame {
+            context: frame_context,
+            control_pc: pc,
+            image_base,
+            function_entry: entry,
+            establisher_frame,
+            handler,
+            handler_data,
+        });
+    }
+}
+
+/// Dispatches an exception raised at `context` to the image's handlers.
+///
+/// Never returns: a handler either unwinds to where the exception is caught,
+/// or a filter continues execution, or the exception is unhandled and the
+/// process aborts.
+unsafe fn raise_exception(record: &mut EXCEPTION_RECORD, context: &CONTEXT) -> ! {
+    let dispatch = Dispatch {
+        record,
+        context: *context,
+        previous: DISPATCH.get(),
+    };
+    DISPATCH.set(&dispatch);
+
+    let mut walk = *context;
+    let mut exception_context = *context;
+    let mut catches = 0;
+    while let Some(mut frame) = next_frame(&mut walk, UNW_FLAG_EHANDLER, &mut catches) {
+        match frame.call_handler(record, &mut exception_context, 0) {
+            EXCEPTION_CONTINUE_SEARCH => {}
+            EXCEPTION_CONTINUE_EXECUTION
+                if record.ExceptionFlags & EXCEPTION_NONCONTINUABLE == 0 =>
+            {
+                DISPATCH.set(dispatch.previous);
+                ntdll::restore_context(&exception_context);
+            }
+            _ => break,
+        }
+    }
+
+    DISPATCH.set(dispatch.previous);
+    unhandled_exception(record)
+}
+
+/// Runs every frame's unwind handler from `start` up to `target_frame`.
+///
+/// Catch blocks left on the way are ended. Returns the registers of the
+/// target frame.
+unsafe fn unwind_to_frame(
+    target_frame: u64,
+    target_ip: u64,
+    record: &mut EXCEPTION_RECORD,
+    start: &CONTEXT,
+) -> CONTEXT {
+    record.ExceptionFlags |= EXCEPTION_UNWINDING;
+    if target_frame == 0 {
+        record.ExceptionFlags |= EXCEPTION_EXIT_UNWIND;
+    }
+
+    let mut walk = *start;
+    let mut catches = 0;
+    loop {
+        let Some(mut frame) = next_frame(&mut walk, UNW_FLAG_UHANDLER, &mut catches) else {
+            unhandled_exception(record);
+        };
+        let is_target = frame.establisher_frame == target_frame;
+        if is_target {
+            record.ExceptionFlags |= EXCEPTION_TARGET_UNWIND;
+        }
+        let mut context = frame.context;
+        frame.call_handler(record, &mut context, target_ip);
+        record.ExceptionFlags &= !EXCEPTION_TARGET_UNWIND;
+
+        if is_target {
+            end_catches(catches, record);
+            return frame.context;
+        }
+        if target_frame != 0 && frame.establisher_frame > target_frame {
+            eprintln!(
+                "[d3dcompiler] Unwind target frame 0x{:x} is not on the stack",
+                target_frame
+            );
+            std::process::abort();
+        }
+    }
+}
+
+/// Unwinds to `target_frame` and continues at `target_ip`, like `RtlUnwindEx`.
+///
+/// Starts from the exception being dispatched if there is one, otherwise from
+/// `context`, the caller's registers.
+pub(crate) unsafe extern "win64" fn unwind_ex(
+    target_frame: u64,
+    target_ip: u64,
+    record: *mut EXCEPTION_RECORD,
+    return_value: u64,
+    context: *const CONTEXT,
+) -> ! {
+    let dispatch = DISPATCH.get();
+    let start = if dispatch.is_null() {
+        *context
+    } else {
+        (*dispatch).context
+    };
+
+    let mut local_record: EXCEPTION_RECORD = std::mem::zeroed();
+    let record = if record.is_null() {
+        local_record.ExceptionCode = STATUS_UNWIND;
+        local_record.ExceptionAddress = start.Rip as *mut c_void;
+        &mut local_record
+    } else {
+        &mut *record
+    };
+
+    let mut resume = unwind_to_frame(target_frame, target_ip, record, &start);
+    if !dispatch.is_null() {
+        DISPATCH.set((*dispatch).previous);
+    }
+    resume.Rip = target_ip;
+    resume.Rax = return_value;
+    ntdll::restore_context(&resume)
+}
+
+unsafe fn unhandled_exception(record: &EXCEPTION_RECORD) -> ! {
+    let name = cxx_exception_info(record)
+        .and_then(|(_, throw_info, image_base)| {
+            let types = (image_base + throw_info.p_catchable_type_array as u64)
+                as *const CatchableTypeArray;
+            ((*types).count > 0).then(|| {
+                let first =
+                    &*((image_base + *(*types).types.as_ptr() as u64) as *const CatchableType);
+                type_name(image_base, first.p_type)
+            })
+        })
+        .map(|name| format!(" ({})", name.to_string_lossy()))
+        .unwrap_or_default();
+    let dll = find_image(record.ExceptionAddress as usize)
+        .map_or_else(|| DLL_NAME.to_string(), |image| image.name.clone());
+    eprintln!(
+        "[d3dcompiler] Unhandled exception 0x{:08x}{} in {}",
+        record.ExceptionCode, name, dll
+    );
+    std::process::abort()
+}
+
+// ============ Catch blocks ============
+
+/// Calls a catch block with its parent's frame and returns where to continue.
+#[unsafe(naked)]
+unsafe extern "win64" fn call_catch_block(_funclet: u64, _frame: u64) -> u64 {
+    std::arch::naked_asm!(
+        "sub rsp, 0x28",
+        "mov rax, rcx",
+        "mov rcx, rdx",
+        "call rax",
+        "add rsp, 0x28",
+        "ret",
+    )
+}
+
+/// Ends the `count` innermost catch blocks, which an exception has left.
+unsafe fn end_catches(count: usize, record: &EXCEPTION_RECORD) {
+    let in_flight = cxx_exception_info(record).map_or(0, |(object, _, _)| object);
+    for _ in 0..count {
+        if let Some(active) = ACTIVE_CATCHES.with(|active| active.borrow_mut().pop())
+            && active.object != in_flight
+        {
+            destroy_exception(&active);
+        }
+    }
+}
+
+/// Destroys a caught object unless an outer catch block still holds it.
+unsafe fn destroy_exception(active: &ActiveCatch) {
+    let shared = ACTIVE_CATCHES.with(|catches| {
+        catches
+            .borrow()
+            .iter()
+            .any(|other| other.object == active.object)
+    });
+    if active.throw_info == 0 || shared {
+        return;
+    }
+    let throw_info = &*(active.throw_info as *const ThrowInfo);
+    if throw_info.pmfn_unwind != 0 {
+        let destructor: unsafe extern "win64" fn(u64) =
+            std::mem::transmute(active.throw_image_base + throw_info.pmfn_unwind as u64);
+        destructor(active.object);
+    }
+}
+
+/// Raises a C++ exception for `_CxxThrowException`.
+///
+/// A null `object` and `throw_info` rethrow the exception of the innermost
+/// running catch block.
+pub(crate) unsafe extern "win64" fn cxx_throw(
+    object: u64,
+    throw_info: u64,
+    context: *const CONTEXT,
+) -> ! {
+    let (object, throw_info, image_base) = if object == 0 && throw_info == 0 {
+        let current = ACTIVE_CATCHES.with(|active| {
+            let active = active.borrow();
+            active
+                .last()
+                .map(|c| (c.object, c.throw_info, c.throw_image_base))
+        });
+        match current {
+            Some(current) if current.1 != 0 => current,
+            _ => {
+                eprintln!("[d3dcompiler] Rethrow outside of a catch block");
+                std::process::abort();
+            }
+        }
+    } else {
+        // Type info RVAs are relative to the image that threw
+        let image_base = find_image((*context).Rip as usize).map_or(0, |image| image.map_base);
+        (object, throw_info, image_base as u64)
+    };
+
+    let mut record: EXCEPTION_RECORD = std::mem::zeroed();
+    record.ExceptionCode = CXX_EXCEPTION;
+    record.ExceptionFlags = EXCEPTION_NONCONTINUABLE;
+    record.ExceptionAddress = (*context).Rip as *mut c_void;
+    record.NumberParameters = 4;
+    record.ExceptionInformation[..4].copy_from_slice(&[
+        CXX_MAGIC_1 as u64,
+        object,
+        throw_info,
+        image_base,
+    ]);
+    raise_exception(&mut record, &*context)
+}
+
+// ============ __C_specific_handler ============
+
+#[repr(C)]
+struct ScopeRecord {
+    begin_address: u32,
+    end_address: u32,
+    handler_address: u32,
+    jump_target: u32,
+}
+
+/// Language handler for functions with `__try` blocks.
+pub(crate) unsafe fn c_specific_handler(
+    record: *mut EXCEPTION_RECORD,
+    establisher_frame: u64,
+    context: *mut CONTEXT,
+    dispatcher: *mut DISPATCHER_CONTEXT,
+) -> u32 {
+    let dispatcher = &mut *dispatcher;
+    let base = dispatcher.ImageBase;
+    let pc = (dispatcher.ControlPc - base) as u32;
+    let count = *(dispatcher.HandlerData as *const u32);
+    let scopes = std::slice::from_raw_parts(
+        (dispatcher.HandlerData as *const u32).add(1) as *const ScopeRecord,
+        count as usize,
+    );
+    let in_scope = |scope: &ScopeRecord| pc >= scope.begin_address && pc < scope.end_address;
+
+    if (*record).ExceptionFlags & EXCEPTION_UNWIND == 0 {
+        for scope in &scopes[dispatcher.ScopeIndex as usize..] {
+            if !in_scope(scope) || scope.jump_target == 0 {
+                continue;
+            }
+            // A handler address of 1 is __except(EXCEPTION_EXECUTE_HANDLER)
+            let filter = if scope.handler_address == 1 {
+                1
+            } else {
+                let mut pointers = EXCEPTION_POINTERS {
+                    ExceptionRecord: record,
+                    ContextRecord: context,
+                };
+                let filter: unsafe extern "win64" fn(*mut EXCEPTION_POINTERS, u64) -> i32 =
+                    std::mem::transmute(base + scope.handler_address as u64);
+                filter(&mut pointers, establisher_frame)
+            };
+            if filter < 0 {
+                return EXCEPTION_CONTINUE_EXECUTION;
+            }
+            if filter > 0 {
+                unwind_ex(
+                    establisher_frame,
+                    base + scope.jump_target as u64,
+                    record,
+                    (*record).ExceptionCode as u64,
+                    context,
+                );
+            }
+        }
+    } else {
+        let target = dispatcher.TargetIp.wrapping_sub(base) as u32;
+        let is_target = (*record).ExceptionFlags & EXCEPTION_TARGET_UNWIND != 0;
+        for (i, scope) in scopes
+            .iter()
+            .enumerate()
+            .skip(dispatcher.ScopeIndex as usize)
+        {
+            if !in_scope(scope) {
+                continue;
+            }
+            if is_target && target >= scope.begin_address && target < scope.end_address {
+                break;
+            }
+            if scope.jump_target == 0 {
+                // __finally block: runs with AbnormalTermination() true
+                dispatcher.ScopeIndex = i as u32 + 1;
+                let finally: unsafe extern "win64" fn(u8, u64) =
+                    std::mem::transmute(base + scope.handler_address as u64);
+                finally(1, establisher_frame);
+            } else if is_target && scope.jump_target == target {
+                break;
+            }
+        }
+    }
+    EXCEPTION_CONTINUE_SEARCH
+}
+
+// ============ __CxxFrameHandler3 ============
+
+#[repr(C)]
+struct FuncInfo {
+    magic_and_bbt: u32,
+    max_state: i32,
+    disp_unwind_map: u32,
+    n_try_blocks: u32,
+    disp_try_block_map: u32,
+    n_ip_map_entries: u32,
+    disp_ip_to_state_map: u32,
+    disp_unwind_help: i32,
+    disp_es_type_list: u32,
+    eh_flags: i32,
+}
+
+#[repr(C)]
+struct UnwindMapEntry {
+    to_state: i32,
+    action: u32,
+}
+
+#[repr(C)]
+struct TryBlockMapEntry {
+    try_low: i32,
+    try_high: i32,
+    catch_high: i32,
+    n_catches: i32,
+    disp_handler_array: u32,
+}
+
+#[repr(C)]
+struct HandlerType {
+    adjectives: u32,
+    disp_type: u32,
+    disp_catch_obj: i32,
+    disp_of_handler: u32,
+    disp_frame: u32,
+}
+
+#[repr(C)]
+struct IpToStateMapEntry {
+    ip: u32,
+    state: i32,
+}
+
+#[repr(C)]
+struct ThrowInfo {
+    attributes: u32,
+    pmfn_unwind: u32,
+    p_forward_compat: u32,
+    p_catchable_type_array: u32,
+}
+
+#[repr(C)]
+struct CatchableTypeArray {
+    count: i32,
+    types: [u32; 0],
+}
+
+#[repr(C)]
+struct CatchableType {
+    properties: u32,
+    p_type: u32,
+    mdisp: i32,
+    pdisp: i32,
+    vdisp: i32,
+    size_or_offset: i32,
+    copy_function: u32,
+}
+
+/// Returns the object, ThrowInfo and the thrower's image base of a C++
+/// exception, or `None` for other exceptions.
+unsafe fn cxx_exception_info(record: &EXCEPTION_RECORD) -> Option<(u64, &'static ThrowInfo, u64)> {
+    let magic = record.ExceptionInformation[0] as u32;
+    let is_cxx = record.ExceptionCode == CXX_EXCEPTION
+        && record.NumberParameters >= 3
+        && (CXX_MAGIC_1..=CXX_MAGIC_3).contains(&magic)
+        && record.ExceptionInformation[2] != 0;
+    is_cxx.then(|| {
+        let image_base = if record.NumberParameters >= 4 {
+            record.ExceptionInformation[3]
+        } else {
+            0
+        };
+        (
+            record.ExceptionInformation[1],
+            &*(record.ExceptionInformation[2] as *const ThrowInfo),
+            image_base,
+        )
+    })
+}
+
+/// Returns the decorated name in a TypeDescriptor, empty for catch(...).
+unsafe fn type_name(image_base: u64, type_descriptor: u32) -> &'static std::ffi::CStr {
+    if type_descriptor == 0 {
+        return c"";
+    }
+    // Skip the vftable and spare pointers
+    std::ffi::CStr::from_ptr((image_base + type_descriptor as u64 + 16) as *const i8)
+}
+
+/// Returns the EH state of the function at `pc`.
+unsafe fn ip_to_state(image_base: u64, func_info: &FuncInfo, pc: u64) -> i32 {
+    let map = std::slice::from_raw_parts(
+        (image_base + func_info.disp_ip_to_state_map as u64) as *const IpToStateMapEntry,
+        func_info.n_ip_map_entries as usize,
+    );
+    let rva = (pc - image_base) as u32;
+    match map.partition_point(|entry| entry.ip <= rva) {
+        0 => -1,
+        i => map[i - 1].state,
+    }
+}
+
+/// Runs the unwind actions (destructors) from state `from` down to `to`.
+unsafe fn unwind_to_state(image_base: u64, func_info: &FuncInfo, frame: u64, from: i32, to: i32) {
+    let map = (image_base + func_info.disp_unwind_map as u64) as *const UnwindMapEntry;
+    let mut state = from;
+    while state > to && state < func_info.max_state {
+        let entry = &*map.add(state as usize);
+        if entry.action != 0 {
+            call_catch_block(image_base + entry.action as u64, frame);
+        }
+        state = entry.to_state;
+    }
+}
+
+/// Applies a CatchableType's base class displacement to `object`.
+unsafe fn adjust_pointer(object: u64, catchable: &CatchableType) -> u64 {
+    let mut adjusted = object.wrapping_add_signed(catchable.mdisp as i64);
+    if catchable.pdisp >= 0 {
+        let vbtable = *(object.wrapping_add_signed(catchable.pdisp as i64) as *const u64);
+        let offset = *(vbtable.wrapping_add_signed(catchable.vdisp as i64) as *const i32);
+        adjusted = adjusted.wrapping_add_signed(catchable.pdisp as i64 + offset as i64);
+    }
+    adjusted
+}
+
+/// Finds the type in the thrown object's CatchableTypeArray that `handler`
+/// catches. Returns `Some(None)` for catch(...).
+unsafe fn match_handler<'a>(
+    image_base: u64,
+    handler: &HandlerType,
+    exception: Option<(u64, &'a ThrowInfo, u64)>,
+    catch_seh: bool,
+) -> Option<Option<&'a CatchableType>> {
+    let name = type_name(image_base, handler.disp_type);
+    let Some((_, throw_info, throw_base)) = exception else {
+        return (name.is_empty() && catch_seh).then_some(None);
+    };
+    if name.is_empty() {
+        return Some(None);
+    }
+
+    let types =
+        &*((throw_base + throw_info.p_catchable_type_array as u64) as *const CatchableTypeArray);
+    (0..types.count as usize)
+        .map(|i| &*((throw_base + *types.types.as_ptr().add(i) as u64) as *const CatchableType))
+        .find(|catchable| {
+            type_name(throw_base, catchable.p_type) == name
+                && (catchable.properties & CT_BY_REFERENCE_ONLY == 0
+                    || handler.adjectives & HT_IS_REFERENCE != 0)
+        })
+        .map(Some)
+}
+
+/// Initializes a catch block's parameter from the thrown object.
+unsafe fn copy_exception(
+    object: u64,
+    throw_base: u64,
+    handler: &HandlerType,
+    catchable: &CatchableType,
+    frame: u64,
+) {
+    if handler.disp_catch_obj == 0 {
+        return;
+    }
+    let dest = frame.wrapping_add_signed(handler.disp_catch_obj as i64);
+    let size = catchable.size_or_offset as usize;
+
+    if handler.adjectives & HT_IS_REFERENCE != 0 {
+        *(dest as *mut u64) = adjust_pointer(object, catchable);
+    } else if catchable.properties & CT_IS_SIMPLE_TYPE != 0 {
+        libc::memcpy(dest as *mut c_void, object as *const c_void, size);
+        // Pointer-sized simple types are pointers, which may need adjusting
+        if size == 8 && *(dest as *const u64) != 0 {
+            *(dest as *mut u64) = adjust_pointer(*(dest as *const u64), catchable);
+        }
+    } else if catchable.copy_function == 0 {
+        let source = adjust_pointer(object, catchable);
+        libc::memcpy(dest as *mut c_void, source as *const c_void, size);
+    } else if catchable.properties & CT_HAS_VIRTUAL_BASE != 0 {
+        let copy: unsafe extern "win64" fn(u64, u64, i32) =
+            std::mem::transmute(throw_base + catchable.copy_function as u64);
+        copy(dest, adjust_pointer(object, catchable), 1);
+    } else {
+        let copy: unsafe extern "win64" fn(u64, u64) =
+            std::mem::transmute(throw_base + catchable.copy_function as u64);
+        copy(dest, adjust_pointer(object, catchable));
+    }
+}
+
+/// Language handler for functions with C++ `try` blocks or destructors.
+pub(crate) unsafe fn cxx_frame_handler(
+    record: *mut EXCEPTION_RECORD,
+    establisher_frame: u64,
+    _context: *mut CONTEXT,
+    dispatcher: *mut DISPATCHER_CONTEXT,
+) -> u32 {
+    let record = &mut *record;
+    let dispatcher = &*dispatcher;
+    let base = dispatcher.ImageBase;
+    let func_info = &*((base + *(dispatcher.HandlerData as *const u32) as u64) as *const FuncInfo);
+    let magic = func_info.magic_and_bbt & 0x1FFF_FFFF;
+    if !(CXX_MAGIC_1..=CXX_MAGIC_3).contains(&magic) {
+        return EXCEPTION_CONTINUE_SEARCH;
+    }
+
+    // Catch blocks share their owner's EH data and reach its locals through
+    // the owner's frame
+    let function = base + (*dispatcher.FunctionEntry).BeginAddress as u64;
+    let owner = ACTIVE_CATCHES.with(|active| {
+        let active = active.borrow();
+        active
+            .iter()
+            .rposition(|c| c.funclet == function)
+            .map(|i| (i, active[i].parent_frame))
+    });
+    let frame = owner.map_or(establisher_frame, |(_, frame)| frame);
+    let state = ip_to_state(base, func_info, dispatcher.ControlPc);
+
+    if record.ExceptionFlags & EXCEPTION_UNWIND != 0 {
+        // The frame that catches unwinds its own states once it knows the try block
+        if record.ExceptionFlags & EXCEPTION_TARGET_UNWIND == 0 {
+            unwind_to_state(base, func_info, frame, state, -1);
+        }
+        return EXCEPTION_CONTINUE_SEARCH;
+    }
+
+    let exception = cxx_exception_info(record);
+    let catch_seh = magic < CXX_MAGIC_3 || func_info.eh_flags & FI_EHS_FLAG == 0;
+    let try_blocks = std::slice::from_raw_parts(
+        (base + func_info.disp_try_block_map as u64) as *const TryBlockMapEntry,
+        func_info.n_try_blocks as usize,
+    );
+    for try_block in try_blocks {
+        if state < try_block.try_low || state > try_block.try_high {
+            continue;
+        }
+        let handlers = std::slice::from_raw_parts(
+            (base + try_block.disp_handler_array as u64) as *const HandlerType,
+            try_block.n_catches as usize,
+        );
+        for handler in handlers {
+            if let Some(catchable) = match_handler(base, handler, exception, catch_seh) {
+                catch_exception(
+                    record,
+                    dispatcher,
+                    func_info,
+                    frame,
+                    owner.map(|(i, _)| i),
+                    state,
+                    try_block,
+                    handler,
+                    catchable.zip(exception),
+                );
+            }
+        }
+    }
+    EXCEPTION_CONTINUE_SEARCH
+}
+
+/// Unwinds to the catching frame, runs the catch block and continues after it.
+#[allow(clippy::too_many_arguments)]
+unsafe fn catch_exception(
+    record: &mut EXCEPTION_RECORD,
+    dispatcher: &DISPATCHER_CONTEXT,
+    func_info: &FuncInfo,
+    frame: u64,
+    owner: Option<usize>,
+    state: i32,
+    try_block: &TryBlockMapEntry,
+    handler: &HandlerType,
+    exception: Option<(&CatchableType, (u64, &ThrowInfo, u64))>,
+) -> ! {
+    let base = dispatcher.ImageBase;
+    let dispatch = DISPATCH.get();
+    if dispatch.is_null() {
+        unhandled_exception(record);
+    }
+
+    // Run the destructors of every frame in between
+    let mut resume = unwind_to_frame(dispatcher.EstablisherFrame, 0, record, &(*dispatch).context);
+    DISPATCH.set((*dispatch).previous);
+
+    // Thrown from a catch block: that block is over, continue in its owner
+    if let Some(owner) = owner {
+        let active = ACTIVE_CATCHES.with(|active| {
+            let mut active = active.borrow_mut();
+            debug_assert_eq!(owner + 1, active.len());
+            active.pop()
+        });
+        if let Some(active) = active {
+            resume = active.parent_context;
+            let in_flight = exception.map_or(0, |(_, (object, _, _))| object);
+            if active.object != in_flight {
+                destroy_exception(&active);
+            }
+        }
+    }
+
+    unwind_to_state(base, func_info, frame, state, try_block.try_low);
+
+    if let Some((catchable, (object, _, throw_base))) = exception {
+        copy_exception(object, throw_base, handler, catchable, frame);
+    }
+
+    let mut parent_caller = resume;
+    match ntdll::lookup_function_entry(parent_caller.Rip) {
+        Some((entry, image_base)) => {
+            let (mut handler_data, mut establisher) = (std::ptr::null_mut(), 0);
+            ntdll::virtual_unwind(
+                0,
+                image_base,
+                parent_caller.Rip,
+                entry,
+                &mut parent_caller,
+                &mut handler_data,
+                &mut establisher,
+            );
+        }
+        None => parent_caller.Rip = 0,
+    }
+
+    let (object, throw_info, throw_image_base) = cxx_exception_info(record)
+        .map_or((0, 0, 0), |(object, info, image_base)| {
+            (object, info as *const ThrowInfo as u64, image_base)
+        });
+    let funclet = base + handler.disp_of_handler as u64;
+    ACTIVE_CATCHES.with(|active| {
+        active.borrow_mut().push(ActiveCatch {
+            funclet,
+            parent_frame: frame,
+            parent_context: resume,
+            parent_caller,
+            object,
+            throw_info,
+            throw_image_base,
+        })
+    });
+
+    resume.Rip = call_catch_block(funclet, frame);
+
+    if let Some(active) = ACTIVE_CATCHES.with(|active| active.borrow_mut().pop()) {
+        destroy_exception(&active);
+    }
+    ntdll::restore_context(&resume)
+}
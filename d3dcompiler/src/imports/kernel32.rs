@@ -1,15 +1,21 @@
 use super::*;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::CStr;
 use std::sync::RwLock;
 use std::sync::atomic::AtomicU64;
 
 static EXCEPTION_FILTER: AtomicU64 = AtomicU64::new(0);
-static HEAP_HANDLE: AtomicU32 = AtomicU32::new(0x12345678);
+const PROCESS_HEAP: usize = 0x12345678;
+static NEXT_HEAP: AtomicU32 = AtomicU32::new(PROCESS_HEAP as u32 + 1);
 static HANDLE_MAP: OnceLock<RwLock<HashMap<usize, i32>>> = OnceLock::new();
 static NEXT_HANDLE: AtomicU32 = AtomicU32::new(0x1000);
 static MMAP_MAP: OnceLock<RwLock<HashMap<usize, (usize, usize)>>> = OnceLock::new();
+// VirtualAlloc regions, base -> size
+static VIRTUAL_MAP: OnceLock<RwLock<BTreeMap<usize, usize>>> = OnceLock::new();
+// Heaps from HeapCreate, handle -> blocks allocated from them. Each heap has
+// its own lock, so allocating only takes the map's read lock.
+static HEAP_MAP: OnceLock<RwLock<HashMap<usize, Mutex<HashSet<usize>>>>> = OnceLock::new();
 static TLS_SLOTS: OnceLock<RwLock<HashMap<u32, libc::pthread_key_t>>> = OnceLock::new();
 static TLS_NEXT: AtomicU32 = AtomicU32::new(0);
 static LAST_ERROR: AtomicU32 = AtomicU32::new(0);
@@ -64,6 +70,42 @@ fn get_mmap_map() -> &'static RwLock<HashMap<usize, (usize, usize)>> {
     MMAP_MAP.get_or_init(|| RwLock::new(HashMap::new()))
 }
 
+fn get_virtual_map() -> &'static RwLock<BTreeMap<usize, usize>> {
+    VIRTUAL_MAP.get_or_init(|| RwLock::new(BTreeMap::new()))
+}
+
+/// Returns the base and size of the VirtualAlloc region containing `addr`.
+fn find_virtual_region(addr: usize) -> Option<(usize, usize)> {
+    let map = get_virtual_map().read().unwrap();
+    let (&base, &size) = map.range(..=addr).next_back()?;
+    (addr < base + size).then_some((base, size))
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn get_heap_map() -> &'static RwLock<HashMap<usize, Mutex<HashSet<usize>>>> {
+    HEAP_MAP.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Runs `f` on the blocks of a heap from HeapCreate. The process heap is
+/// never in the map, so its allocations skip the lock entirely.
+fn with_heap_blocks(heap: *mut c_void, f: impl FnOnce(&mut HashSet<usize>)) {
+    if heap as usize == PROCESS_HEAP {
+        return;
+    }
+    let heaps = get_heap_map().read().unwrap_or_else(|e| e.into_inner());
+    if let Some(blocks) = heaps.get(&(heap as usize)) {
+        f(&mut blocks.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+const MEM_COMMIT: u32 = 0x1000;
+const MEM_RESERVE: u32 = 0x2000;
+const MEM_DECOMMIT: u32 = 0x4000;
+const MEM_RELEASE: u32 = 0x8000;
+
 import_fn! {
     // ============ KERNEL32 - process ============
 
@@ -78,12 +120,12 @@ import_fn! {
     }
 
     fn UnhandledExceptionFilter(_exception_info: *mut c_void) -> i32 {
-        trace_call!("kernel32!UnhandledExceptionFilter");
+        trace_call!("kernel32!UnhandledExceptionFilter", "exception_info={:p}", _exception_info);
         panic!("kernel32!UnhandledExceptionFilter not implemented");
     }
 
     fn SetUnhandledExceptionFilter(filter: *mut c_void) -> *mut c_void {
-        trace_call!("kernel32!SetUnhandledExceptionFilter");
+        trace_call!("kernel32!SetUnhandledExceptionFilter", "filter={:p}", filter);
         let old = EXCEPTION_FILTER.swap(filter as u64, Ordering::SeqCst);
         old as *mut c_void
     }
@@ -182,13 +224,15 @@ import_fn! {
     fn VirtualAlloc(
         lpAddress: *mut c_void,
         dwSize: usize,
-        _flAllocationType: u32,
+        flAllocationType: u32,
         flProtect: u32,
     ) -> *mut c_void {
         trace_call!(
             "kernel32!VirtualAlloc",
-            "size={}, prot=0x{:x}",
+            "addr={:p}, size={}, type=0x{:x}, prot=0x{:x}",
+            lpAddress,
             dwSize,
+            flAllocationType,
             flProtect
         );
         let prot = match flProtect {
@@ -200,9 +244,31 @@ import_fn! {
             _ => libc::PROT_READ | libc::PROT_WRITE,
         };
 
+        // Committing pages of a region already reserved, which was charged
+        // when it was mapped
+        let page = page_size();
+        if !lpAddress.is_null() && find_virtual_region(lpAddress as usize).is_some() {
+            let start = lpAddress as usize & !(page - 1);
+            let end = (lpAddress as usize + dwSize).next_multiple_of(page);
+            return if libc::mprotect(start as *mut c_void, end - start, prot) == 0 {
+                start as *mut c_void
+            } else {
+                std::ptr::null_mut()
+            };
+        }
+
+        let size = dwSize.next_multiple_of(page);
+        let prot = if flAllocationType & (MEM_RESERVE | MEM_COMMIT) == MEM_RESERVE {
+            libc::PROT_NONE
+        } else {
+            prot
+        };
+        if !charge_allocation(size) {
+            return std::ptr::null_mut();
+        }
         let ptr = libc::mmap(
             lpAddress,
-            dwSize,
+            size,
             prot,
             libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
             -1,
@@ -210,8 +276,11 @@ import_fn! {
         );
 
         if ptr == libc::MAP_FAILED {
+            uncharge_allocation(size);
             std::ptr::null_mut()
         } else {
+            record_allocation(ptr as usize, size, size);
+            get_virtual_map().write().unwrap().insert(ptr as usize, size);
             ptr
         }
     }
@@ -219,16 +288,40 @@ import_fn! {
     fn VirtualFree(
         lpAddress: *mut c_void,
         dwSize: usize,
-        _dwFreeType: u32,
+        dwFreeType: u32,
     ) -> i32 {
         trace_call!(
             "kernel32!VirtualFree",
-            "addr={:p}, size={}",
+            "addr={:p}, size={}, type=0x{:x}",
             lpAddress,
-            dwSize
+            dwSize,
+            dwFreeType
         );
-        if dwSize == 0 || libc::munmap(lpAddress, dwSize) == 0 {
-            1
+        let addr = lpAddress as usize;
+        if dwFreeType & MEM_RELEASE != 0 {
+            // Releases the whole region, which must be passed with size 0
+            let region = get_virtual_map().write().unwrap().remove(&addr);
+            let Some(size) = region else {
+                return 0;
+            };
+            release_allocation(addr);
+            (libc::munmap(lpAddress, size) == 0) as i32
+        } else if dwFreeType & MEM_DECOMMIT != 0 {
+            let Some((base, size)) = find_virtual_region(addr) else {
+                return 0;
+            };
+            // Size 0 decommits from the address to the end of the region
+            let page = page_size();
+            let start = addr & !(page - 1);
+            let end = if dwSize == 0 {
+                base + size
+            } else {
+                (addr + dwSize).next_multiple_of(page).min(base + size)
+            };
+            let len = end - start;
+            (libc::madvise(start as *mut c_void, len, libc::MADV_DONTNEED) == 0
+                && libc::mprotect(start as *mut c_void, len, libc::PROT_NONE) == 0)
+                as i32
         } else {
             0
         }
@@ -236,7 +329,7 @@ import_fn! {
 
     fn GetProcessHeap() -> *mut c_void {
         trace_call!("kernel32!GetProcessHeap");
-        HEAP_HANDLE.load(Ordering::Relaxed) as *mut c_void
+        PROCESS_HEAP as *mut c_void
     }
 
     fn HeapCreate(
@@ -244,37 +337,64 @@ import_fn! {
         _dwInitialSize: usize,
         _dwMaximumSize: usize,
     ) -> *mut c_void {
-        trace_call!("kernel32!HeapCreate");
-        HEAP_HANDLE.fetch_add(1, Ordering::Relaxed) as *mut c_void
-    }
-
-    fn HeapDestroy(_hHeap: *mut c_void) -> i32 {
-        trace_call!("kernel32!HeapDestroy");
+        trace_call!(
+            "kernel32!HeapCreate",
+            "options=0x{:x}, initial_size={}, maximum_size={}",
+            _flOptions, _dwInitialSize, _dwMaximumSize
+        );
+        let heap = NEXT_HEAP.fetch_add(1, Ordering::Relaxed) as usize;
+        get_heap_map()
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(heap, Mutex::new(HashSet::new()));
+        heap as *mut c_void
+    }
+
+    fn HeapDestroy(hHeap: *mut c_void) -> i32 {
+        trace_call!("kernel32!HeapDestroy", "heap={:p}", hHeap);
+        let Some(blocks) = get_heap_map()
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(hHeap as usize))
+        else {
+            return 0;
+        };
+        for block in blocks.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            tracked_free(block as *mut c_void);
+        }
         1
     }
 
     fn HeapAlloc(
-        _hHeap: *mut c_void,
+        hHeap: *mut c_void,
         dwFlags: u32,
         dwBytes: usize,
     ) -> *mut c_void {
         trace_call!("kernel32!HeapAlloc", "size={}", dwBytes);
-        let ptr = libc::malloc(dwBytes);
+        let ptr = tracked_malloc(dwBytes);
         if dwFlags & 0x08 != 0 && !ptr.is_null() {
             libc::memset(ptr, 0, dwBytes);
         }
+        if !ptr.is_null() {
+            with_heap_blocks(hHeap, |blocks| {
+                blocks.insert(ptr as usize);
+            });
+        }
         ptr
     }
 
-    fn HeapFree(_hHeap: *mut c_void, _dwFlags: u32, lpMem: *mut c_void) -> i32 {
+    fn HeapFree(hHeap: *mut c_void, _dwFlags: u32, lpMem: *mut c_void) -> i32 {
         trace_call!("kernel32!HeapFree", "ptr={:p}", lpMem);
-        libc::free(lpMem);
+        with_heap_blocks(hHeap, |blocks| {
+            blocks.remove(&(lpMem as usize));
+        });
+        tracked_free(lpMem);
         1
     }
 
     fn LocalAlloc(uFlags: u32, uBytes: usize) -> *mut c_void {
         trace_call!("kernel32!LocalAlloc", "size={}", uBytes);
-        let ptr = libc::malloc(uBytes);
+        let ptr = tracked_malloc(uBytes);
         if uFlags & 0x40 != 0 && !ptr.is_null() {
             libc::memset(ptr, 0, uBytes);
         }
@@ -283,7 +403,7 @@ import_fn! {
 
     fn LocalFree(hMem: *mut c_void) -> *mut c_void {
         trace_call!("kernel32!LocalFree", "ptr={:p}", hMem);
-        libc::free(hMem);
+        tracked_free(hMem);
         std::ptr::null_mut()
     }
 
@@ -395,7 +515,11 @@ import_fn! {
     }
 
     fn GetFileSize(hFile: *mut c_void, lpFileSizeHigh: *mut u32) -> u32 {
-        trace_call!("kernel32!GetFileSize");
+        trace_call!(
+            "kernel32!GetFileSize",
+            "file={:p}, file_size_high={:p}",
+            hFile, lpFileSizeHigh
+        );
         let handle = hFile as usize;
         if let Some(fd) = get_fd(handle) {
             let mut stat: libc::stat = std::mem::zeroed();
@@ -413,7 +537,7 @@ import_fn! {
     }
 
     fn GetFileSizeEx(hFile: *mut c_void, lpFileSize: *mut i64) -> i32 {
-        trace_call!("kernel32!GetFileSizeEx");
+        trace_call!("kernel32!GetFileSizeEx", "file={:p}, file_size={:p}", hFile, lpFileSize);
         let handle = hFile as usize;
         if let Some(fd) = get_fd(handle) {
             let mut stat: libc::stat = std::mem::zeroed();
@@ -429,7 +553,7 @@ import_fn! {
     }
 
     fn GetFileType(_hFile: *mut c_void) -> u32 {
-        trace_call!("kernel32!GetFileType");
+        trace_call!("kernel32!GetFileType", "file={:p}", _hFile);
         1
     }
 
@@ -439,7 +563,11 @@ import_fn! {
         lpDistanceToMoveHigh: *mut i32,
         dwMoveMethod: u32,
     ) -> u32 {
-        trace_call!("kernel32!SetFilePointer");
+        trace_call!(
+            "kernel32!SetFilePointer",
+            "file={:p}, distance_to_move={}, distance_to_move_high={:p}, move_method={}",
+            hFile, lDistanceToMove, lpDistanceToMoveHigh, dwMoveMethod
+        );
         let handle = hFile as usize;
         if let Some(fd) = get_fd(handle) {
             let offset = if lpDistanceToMoveHigh.is_null() {
@@ -475,7 +603,11 @@ import_fn! {
         lpNewFilePointer: *mut i64,
         dwMoveMethod: u32,
     ) -> i32 {
-        trace_call!("kernel32!SetFilePointerEx");
+        trace_call!(
+            "kernel32!SetFilePointerEx",
+            "file={:p}, distance_to_move={}, new_file_pointer={:p}, move_method={}",
+            hFile, liDistanceToMove, lpNewFilePointer, dwMoveMethod
+        );
         let handle = hFile as usize;
         if let Some(fd) = get_fd(handle) {
             let whence = match dwMoveMethod {
@@ -500,7 +632,7 @@ import_fn! {
     }
 
     fn SetEndOfFile(hFile: *mut c_void) -> i32 {
-        trace_call!("kernel32!SetEndOfFile");
+        trace_call!("kernel32!SetEndOfFile", "file={:p}", hFile);
         let handle = hFile as usize;
         if let Some(fd) = get_fd(handle) {
             let pos = libc::lseek(fd, 0, libc::SEEK_CUR);
@@ -515,7 +647,7 @@ import_fn! {
     }
 
     fn DeleteFileW(lpFileName: *const u16) -> i32 {
-        trace_call!("kernel32!DeleteFileW");
+        trace_call!("kernel32!DeleteFileW", "file_name={:p}", lpFileName);
         let path = wstr_to_string(lpFileName);
         if libc::unlink(path.as_ptr() as *const i8) == 0 {
             1
@@ -525,7 +657,7 @@ import_fn! {
     }
 
     fn GetFileAttributesW(lpFileName: *const u16) -> u32 {
-        trace_call!("kernel32!GetFileAttributesW");
+        trace_call!("kernel32!GetFileAttributesW", "file_name={:p}", lpFileName);
         let path = wstr_to_string(lpFileName);
         let mut stat: libc::stat = std::mem::zeroed();
         if libc::stat(path.as_ptr() as *const i8, &mut stat) == 0 {
@@ -546,7 +678,11 @@ import_fn! {
         _lpFileName: *const u16,
         _dwFileAttributes: u32,
     ) -> i32 {
-        trace_call!("kernel32!SetFileAttributesW");
+        trace_call!(
+            "kernel32!SetFileAttributesW",
+            "file_name={:p}, file_attributes={}",
+            _lpFileName, _dwFileAttributes
+        );
         1
     }
 
@@ -556,7 +692,11 @@ import_fn! {
         lpBuffer: *mut u16,
         _lpFilePart: *mut *mut u16,
     ) -> u32 {
-        trace_call!("kernel32!GetFullPathNameW");
+        trace_call!(
+            "kernel32!GetFullPathNameW",
+            "file_name={:p}, buffer_length={}, buffer={:p}, file_part={:p}",
+            lpFileName, nBufferLength, lpBuffer, _lpFilePart
+        );
         let mut len = 0;
         while *lpFileName.add(len) != 0 {
             len += 1;
@@ -581,7 +721,16 @@ import_fn! {
         dwMaximumSizeLow: u32,
         _lpName: *const u16,
     ) -> *mut c_void {
-        trace_call!("kernel32!CreateFileMappingW");
+        trace_call!(
+            "kernel32!CreateFileMappingW",
+            "file={:p}, file_mapping_attributes={:p}, protect=0x{:x}, maximum_size_high={}, maximum_size_low={}, name={:p}",
+            hFile,
+            _lpFileMappingAttributes,
+            _flProtect,
+            dwMaximumSizeHigh,
+            dwMaximumSizeLow,
+            _lpName,
+        );
         let handle = hFile as usize;
         let size = ((dwMaximumSizeHigh as u64) << 32) | dwMaximumSizeLow as u64;
 
@@ -604,7 +753,15 @@ import_fn! {
         dwFileOffsetLow: u32,
         dwNumberOfBytesToMap: usize,
     ) -> *mut c_void {
-        trace_call!("kernel32!MapViewOfFile");
+        trace_call!(
+            "kernel32!MapViewOfFile",
+            "file_mapping_object={:p}, desired_access=0x{:x}, file_offset_high={}, file_offset_low={}, number_of_bytes_to_map={}",
+            hFileMappingObject,
+            dwDesiredAccess,
+            dwFileOffsetHigh,
+            dwFileOffsetLow,
+            dwNumberOfBytesToMap,
+        );
         MapViewOfFileEx(
             hFileMappingObject,
             dwDesiredAccess,
@@ -623,7 +780,16 @@ import_fn! {
         dwNumberOfBytesToMap: usize,
         lpBaseAddress: *mut c_void,
     ) -> *mut c_void {
-        trace_call!("kernel32!MapViewOfFileEx");
+        trace_call!(
+            "kernel32!MapViewOfFileEx",
+            "file_mapping_object={:p}, desired_access=0x{:x}, file_offset_high={}, file_offset_low={}, number_of_bytes_to_map={}, base_address={:p}",
+            hFileMappingObject,
+            dwDesiredAccess,
+            dwFileOffsetHigh,
+            dwFileOffsetLow,
+            dwNumberOfBytesToMap,
+            lpBaseAddress,
+        );
         let mapping_handle = hFileMappingObject as usize;
 
         if let Some((fd, size)) = get_mmap_map().read().unwrap().get(&mapping_handle).copied() {
@@ -660,7 +826,7 @@ import_fn! {
     }
 
     fn UnmapViewOfFile(_lpBaseAddress: *const c_void) -> i32 {
-        trace_call!("kernel32!UnmapViewOfFile");
+        trace_call!("kernel32!UnmapViewOfFile", "base_address={:p}", _lpBaseAddress);
         1
     }
 
@@ -668,7 +834,11 @@ import_fn! {
         lpBaseAddress: *const c_void,
         dwNumberOfBytesToFlush: usize,
     ) -> i32 {
-        trace_call!("kernel32!FlushViewOfFile");
+        trace_call!(
+            "kernel32!FlushViewOfFile",
+            "base_address={:p}, number_of_bytes_to_flush={}",
+            lpBaseAddress, dwNumberOfBytesToFlush
+        );
         if libc::msync(
             lpBaseAddress as *mut c_void,
             dwNumberOfBytesToFlush,
@@ -691,14 +861,29 @@ import_fn! {
         _lpBytesReturned: *mut u32,
         _lpOverlapped: *mut c_void,
     ) -> i32 {
-        trace_call!("kernel32!DeviceIoControl");
+        trace_call!(
+            "kernel32!DeviceIoControl",
+            "device={:p}, io_control_code={}, in_buffer={:p}, in_buffer_size={}, out_buffer={:p}, out_buffer_size={}, bytes_returned={:p}, overlapped={:p}",
+            _hDevice,
+            _dwIoControlCode,
+            _lpInBuffer,
+            _nInBufferSize,
+            _lpOutBuffer,
+            _nOutBufferSize,
+            _lpBytesReturned,
+            _lpOverlapped,
+        );
         panic!("kernel32!DeviceIoControl not implemented");
     }
 
     // ============ KERNEL32 - sync ============
 
     fn InitializeCriticalSection(lpCriticalSection: *mut c_void) {
-        trace_call!("kernel32!InitializeCriticalSection");
+        trace_call!(
+            "kernel32!InitializeCriticalSection",
+            "critical_section={:p}",
+            lpCriticalSection
+        );
         let cs = lpCriticalSection as *mut libc::pthread_mutex_t;
         libc::pthread_mutex_init(cs, std::ptr::null());
     }
@@ -707,25 +892,29 @@ import_fn! {
         lpCriticalSection: *mut c_void,
         _dwSpinCount: u32,
     ) -> i32 {
-        trace_call!("kernel32!InitializeCriticalSectionAndSpinCount");
+        trace_call!(
+            "kernel32!InitializeCriticalSectionAndSpinCount",
+            "critical_section={:p}, spin_count={}",
+            lpCriticalSection, _dwSpinCount
+        );
         InitializeCriticalSection(lpCriticalSection);
         1
     }
 
     fn DeleteCriticalSection(lpCriticalSection: *mut c_void) {
-        trace_call!("kernel32!DeleteCriticalSection");
+        trace_call!("kernel32!DeleteCriticalSection", "critical_section={:p}", lpCriticalSection);
         let cs = lpCriticalSection as *mut libc::pthread_mutex_t;
         libc::pthread_mutex_destroy(cs);
     }
 
     fn EnterCriticalSection(lpCriticalSection: *mut c_void) {
-        trace_call!("kernel32!EnterCriticalSection");
+        trace_call!("kernel32!EnterCriticalSection", "critical_section={:p}", lpCriticalSection);
         let cs = lpCriticalSection as *mut libc::pthread_mutex_t;
         libc::pthread_mutex_lock(cs);
     }
 
     fn LeaveCriticalSection(lpCriticalSection: *mut c_void) {
-        trace_call!("kernel32!LeaveCriticalSection");
+        trace_call!("kernel32!LeaveCriticalSection", "critical_section={:p}", lpCriticalSection);
         let cs = lpCriticalSection as *mut libc::pthread_mutex_t;
         libc::pthread_mutex_unlock(cs);
     }
@@ -754,7 +943,7 @@ import_fn! {
     }
 
     fn TlsFree(dwTlsIndex: u32) -> i32 {
-        trace_call!("kernel32!TlsFree");
+        trace_call!("kernel32!TlsFree", "tls_index={}", dwTlsIndex);
         if let Some(key) = get_tls_slots().write().unwrap().remove(&dwTlsIndex) {
             libc::pthread_key_delete(key);
             1
@@ -764,7 +953,7 @@ import_fn! {
     }
 
     fn TlsGetValue(dwTlsIndex: u32) -> *mut c_void {
-        trace_call!("kernel32!TlsGetValue");
+        trace_call!("kernel32!TlsGetValue", "tls_index={}", dwTlsIndex);
         if let Some(&key) = get_tls_slots().read().unwrap().get(&dwTlsIndex) {
             libc::pthread_getspecific(key)
         } else {
@@ -773,7 +962,7 @@ import_fn! {
     }
 
     fn TlsSetValue(dwTlsIndex: u32, lpTlsValue: *mut c_void) -> i32 {
-        trace_call!("kernel32!TlsSetValue");
+        trace_call!("kernel32!TlsSetValue", "tls_index={}, tls_value={:p}", dwTlsIndex, lpTlsValue);
         if let Some(&key) = get_tls_slots().read().unwrap().get(&dwTlsIndex) {
             if libc::pthread_setspecific(key, lpTlsValue) == 0 {
                 1
@@ -815,7 +1004,11 @@ import_fn! {
     }
 
     fn QueryPerformanceCounter(lpPerformanceCount: *mut i64) -> i32 {
-        trace_call!("kernel32!QueryPerformanceCounter");
+        trace_call!(
+            "kernel32!QueryPerformanceCounter",
+            "performance_count={:p}",
+            lpPerformanceCount
+        );
         let mut ts: libc::timespec = std::mem::zeroed();
         if libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) == 0 {
             *lpPerformanceCount = ts.tv_sec * 1_000_000_000 + ts.tv_nsec;
@@ -826,7 +1019,11 @@ import_fn! {
     }
 
     fn GetSystemTimeAsFileTime(lpSystemTimeAsFileTime: *mut u64) {
-        trace_call!("kernel32!GetSystemTimeAsFileTime");
+        trace_call!(
+            "kernel32!GetSystemTimeAsFileTime",
+            "system_time_as_file_time={:p}",
+            lpSystemTimeAsFileTime
+        );
         let mut tv: libc::timeval = std::mem::zeroed();
         libc::gettimeofday(&mut tv, std::ptr::null_mut());
         let epoch_diff = 116444736000000000u64;
@@ -835,7 +1032,7 @@ import_fn! {
     }
 
     fn GetSystemInfo(lpSystemInfo: *mut c_void) {
-        trace_call!("kernel32!GetSystemInfo");
+        trace_call!("kernel32!GetSystemInfo", "system_info={:p}", lpSystemInfo);
         let info = lpSystemInfo as *mut SYSTEM_INFO;
         (*info).processor_architecture = 9; // PROCESSOR_ARCHITECTURE_AMD64
         (*info).reserved = 0;
@@ -851,7 +1048,7 @@ import_fn! {
     }
 
     fn OutputDebugStringA(lpOutputString: *const i8) {
-        trace_call!("kernel32!OutputDebugStringA");
+        trace_call!("kernel32!OutputDebugStringA", "output_string={:p}", lpOutputString);
         if !lpOutputString.is_null() {
             eprintln!(
                 "[DEBUG] {}",
@@ -861,12 +1058,12 @@ import_fn! {
     }
 
     fn DisableThreadLibraryCalls(_hLibModule: *mut c_void) -> i32 {
-        trace_call!("kernel32!DisableThreadLibraryCalls");
+        trace_call!("kernel32!DisableThreadLibraryCalls", "lib_module={:p}", _hLibModule);
         1
     }
 
     fn FreeLibrary(_hLibModule: *mut c_void) -> i32 {
-        trace_call!("kernel32!FreeLibrary");
+        trace_call!("kernel32!FreeLibrary", "lib_module={:p}", _hLibModule);
         1
     }
 
@@ -875,7 +1072,11 @@ import_fn! {
         _hFile: *mut c_void,
         _dwFlags: u32,
     ) -> *mut c_void {
-        trace_call!("kernel32!LoadLibraryExW");
+        trace_call!(
+            "kernel32!LoadLibraryExW",
+            "lib_file_name={:p}, file={:p}, flags=0x{:x}",
+            _lpLibFileName, _hFile, _dwFlags
+        );
         panic!("kernel32!LoadLibraryExW not implemented");
     }
 
@@ -883,7 +1084,11 @@ import_fn! {
         _hModule: *mut c_void,
         _lpProcName: *const i8,
     ) -> *mut c_void {
-        trace_call!("kernel32!GetProcAddress");
+        trace_call!(
+            "kernel32!GetProcAddress",
+            "module={:p}, proc_name={:p}",
+            _hModule, _lpProcName
+        );
         panic!("kernel32!GetProcAddress not implemented");
     }
 
@@ -892,7 +1097,11 @@ import_fn! {
         _lpFilename: *mut i8,
         _nSize: u32,
     ) -> u32 {
-        trace_call!("kernel32!GetModuleFileNameA");
+        trace_call!(
+            "kernel32!GetModuleFileNameA",
+            "module={:p}, filename={:p}, size={}",
+            _hModule, _lpFilename, _nSize
+        );
         // Return 0: no module filename available
         0
     }
@@ -902,7 +1111,11 @@ import_fn! {
         lpBuffer: *mut i8,
         nSize: u32,
     ) -> u32 {
-        trace_call!("kernel32!GetEnvironmentVariableA");
+        trace_call!(
+            "kernel32!GetEnvironmentVariableA",
+            "name={:p}, buffer={:p}, size={}",
+            lpName, lpBuffer, nSize
+        );
         let val = libc::getenv(lpName);
         if val.is_null() {
             0
@@ -922,7 +1135,11 @@ import_fn! {
         lpDst: *mut u16,
         nSize: u32,
     ) -> u32 {
-        trace_call!("kernel32!ExpandEnvironmentStringsW");
+        trace_call!(
+            "kernel32!ExpandEnvironmentStringsW",
+            "src={:p}, dst={:p}, size={}",
+            lpSrc, lpDst, nSize
+        );
         let mut len = 0;
         while *lpSrc.add(len) != 0 {
             len += 1;
@@ -945,7 +1162,11 @@ import_fn! {
         lpWideCharStr: *mut u16,
         cchWideChar: i32,
     ) -> i32 {
-        trace_call!("kernel32!MultiByteToWideChar");
+        trace_call!(
+            "kernel32!MultiByteToWideChar",
+            "code_page={}, flags=0x{:x}, multi_byte_str={:p}, cb_multi_byte={}, wide_char_str={:p}, cch_wide_char={}",
+            _CodePage, _dwFlags, lpMultiByteStr, cbMultiByte, lpWideCharStr, cchWideChar
+        );
         let len = if cbMultiByte < 0 {
             libc::strlen(lpMultiByteStr) as i32 + 1
         } else {
@@ -973,7 +1194,18 @@ import_fn! {
         _lpDefaultChar: *const i8,
         _lpUsedDefaultChar: *mut i32,
     ) -> i32 {
-        trace_call!("kernel32!WideCharToMultiByte");
+        trace_call!(
+            "kernel32!WideCharToMultiByte",
+            "code_page={}, flags=0x{:x}, wide_char_str={:p}, cch_wide_char={}, multi_byte_str={:p}, cb_multi_byte={}, default_char={:p}, used_default_char={:p}",
+            _CodePage,
+            _dwFlags,
+            lpWideCharStr,
+            cchWideChar,
+            lpMultiByteStr,
+            cbMultiByte,
+            _lpDefaultChar,
+            _lpUsedDefaultChar,
+        );
         let len = if cchWideChar < 0 {
             let mut l = 0;
             while *lpWideCharStr.add(l) != 0 {
@@ -1004,7 +1236,11 @@ import_fn! {
         lpDestStr: *mut u16,
         cchDest: i32,
     ) -> i32 {
-        trace_call!("kernel32!LCMapStringW");
+        trace_call!(
+            "kernel32!LCMapStringW",
+            "locale={}, map_flags=0x{:x}, src_str={:p}, cch_src={}, dest_str={:p}, cch_dest={}",
+            _Locale, dwMapFlags, lpSrcStr, cchSrc, lpDestStr, cchDest
+        );
         let len = if cchSrc < 0 {
             let mut l = 0;
             while *lpSrcStr.add(l) != 0 {
@@ -1034,7 +1270,91 @@ import_fn! {
     }
 
     fn lstrcmpiA(lpString1: *const i8, lpString2: *const i8) -> i32 {
-        trace_call!("kernel32!lstrcmpiA");
+        trace_call!("kernel32!lstrcmpiA", "string1={:p}, string2={:p}", lpString1, lpString2);
         libc::strcasecmp(lpString1, lpString2)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Held by tests that allocate, since the allocation count is process-wide
+    static ALLOCATING: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_virtual_alloc_accounting() {
+        let _lock = ALLOCATING.lock().unwrap();
+        let page = page_size();
+        let allocated = || ALLOC_BYTES.load(Ordering::SeqCst);
+        unsafe {
+            // Freed after the limit is set, without driving the count negative
+            let early = VirtualAlloc(std::ptr::null_mut(), page, MEM_COMMIT, 0x04);
+            assert!(!early.is_null());
+            set_allocation_limit(Some(64 * page), None);
+
+            // Reserving charges the region once; committing it adds nothing
+            let base = VirtualAlloc(std::ptr::null_mut(), 4 * page, MEM_RESERVE, 0x04);
+            assert!(!base.is_null());
+            assert_eq!(allocated(), 4 * page as isize);
+            let committed = VirtualAlloc(base.byte_add(page + 1), page, MEM_COMMIT, 0x04);
+            assert_eq!(committed, base.byte_add(page));
+            *(committed as *mut u8) = 1;
+            assert_eq!(allocated(), 4 * page as isize);
+
+            assert_eq!(VirtualFree(committed, page, MEM_DECOMMIT), 1);
+            assert_eq!(allocated(), 4 * page as isize);
+
+            // Releasing with size 0 frees the whole region
+            assert_eq!(VirtualFree(base, 0, MEM_RELEASE), 1);
+            assert_eq!(allocated(), 0);
+            assert!(find_virtual_region(base as usize).is_none());
+            assert_eq!(VirtualFree(base, 0, MEM_RELEASE), 0);
+
+            assert_eq!(VirtualFree(early, 0, MEM_RELEASE), 1);
+            assert_eq!(allocated(), 0);
+
+            // Over the limit
+            let huge = VirtualAlloc(std::ptr::null_mut(), 65 * page, MEM_COMMIT, 0x04);
+            assert!(huge.is_null());
+            assert_eq!(allocated(), 0);
+            set_allocation_limit(None, None);
+        }
+    }
+
+    #[test]
+    fn test_heap_destroy_frees_blocks() {
+        let _lock = ALLOCATING.lock().unwrap();
+        unsafe {
+            set_allocation_limit(Some(1 << 20), None);
+            let heap = HeapCreate(0, 0, 0);
+            assert_ne!(heap, GetProcessHeap());
+            let freed = HeapAlloc(heap, 0, 32);
+            assert_eq!(HeapFree(heap, 0, freed), 1);
+            for _ in 0..4 {
+                assert!(!HeapAlloc(heap, 0x08, 64).is_null());
+            }
+            assert_eq!(
+                get_heap_map().read().unwrap()[&(heap as usize)]
+                    .lock()
+                    .unwrap()
+                    .len(),
+                4
+            );
+
+            assert!(ALLOC_BYTES.load(Ordering::SeqCst) >= 4 * 64);
+
+            assert_eq!(HeapDestroy(heap), 1);
+            assert_eq!(ALLOC_BYTES.load(Ordering::SeqCst), 0);
+            assert!(
+                !get_heap_map()
+                    .read()
+                    .unwrap()
+                    .contains_key(&(heap as usize))
+            );
+            assert_eq!(HeapDestroy(heap), 0);
+            assert_eq!(HeapDestroy(GetProcessHeap()), 0);
+            set_allocation_limit(None, None);
+        }
+    }
+}
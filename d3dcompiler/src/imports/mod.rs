@@ -1,52 +1,165 @@
 pub mod advapi32;
+pub mod eh;
 pub mod kernel32;
 pub mod msvcrt;
 pub mod ntdll;
 pub mod printf;
 pub mod rpcrt4;
+#[cfg(feature = "trace-imports")]
+pub mod trace;
+pub mod trap;
 
 use super::*;
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-// Caller address captured by thunks (set before calling impl)
-pub static CALLER_ADDR: AtomicUsize = AtomicUsize::new(0);
+/// A DLL image mapped by the loader
+pub struct Image {
+    /// Unique for the life of the process, unlike `map_base`
+    pub id: usize,
+    /// Module name from the export directory
+    pub name: String,
+    pub map_base: usize,
+    pub map_size: usize,
+    /// Image base the DLL was linked at
+    pub image_base: usize,
+    /// RVA and size of the `.pdata` table (used for unwinding)
+    pub exception_dir: usize,
+    pub exception_dir_size: usize,
+    /// Exports as `(rva, name)`, sorted by RVA
+    pub exports: Vec<(usize, String)>,
+    /// IAT slots as `(rva, "dll!name")`, sorted by RVA
+    pub import_slots: Vec<(usize, String)>,
+    /// Runtime address of the entry point (DllMain), or 0
+    pub entry_point: usize,
+    /// Runtime address of the null-terminated TLS callback array, or 0
+    pub tls_callbacks: usize,
+    /// The image's `__declspec(thread)` data, if it has a TLS directory
+    pub tls: Option<crate::dllmain::TlsData>,
+    /// Set once `DLL_PROCESS_ATTACH` has returned, cleared by unloading
+    pub attached: AtomicBool,
+}
+
+impl Image {
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.map_base && addr < self.map_base + self.map_size
+    }
+}
+
+// Every image currently mapped
+static IMAGES: RwLock<Vec<Arc<Image>>> = RwLock::new(Vec::new());
+static NEXT_IMAGE_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub fn next_image_id() -> usize {
+    NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed)
+}
 
-// DLL address space info (set by loader, used to convert runtime addresses to original VA)
-pub static DLL_MAP_BASE: AtomicUsize = AtomicUsize::new(0);
-pub static DLL_MAP_SIZE: AtomicUsize = AtomicUsize::new(0);
-pub static DLL_IMAGE_BASE: AtomicUsize = AtomicUsize::new(0);
+pub fn register_image(image: Image) -> Arc<Image> {
+    let image = Arc::new(image);
+    IMAGES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(image.clone());
+    image
+}
+
+/// Removes an image before it is unmapped
+pub fn unregister_image(id: usize) {
+    IMAGES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|image| image.id != id);
+}
+
+/// Returns every mapped image, in load order
+pub fn images() -> Vec<Arc<Image>> {
+    IMAGES.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Returns the image mapped at `addr`, if any
+pub fn find_image(addr: usize) -> Option<Arc<Image>> {
+    IMAGES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|image| image.contains(addr))
+        .cloned()
+}
 
 /// Convert a runtime address to the DLL's original (unrelocated) VA
 #[inline]
 pub fn to_original_va(addr: usize) -> usize {
-    let map_base = DLL_MAP_BASE.load(Ordering::Relaxed);
-    let map_size = DLL_MAP_SIZE.load(Ordering::Relaxed);
-    let image_base = DLL_IMAGE_BASE.load(Ordering::Relaxed);
-
-    if map_base != 0 && addr >= map_base && addr < map_base + map_size {
+    match find_image(addr) {
         // Address is within DLL, convert to original VA
-        addr - map_base + image_base
-    } else {
+        Some(image) => addr - image.map_base + image.image_base,
         // Address outside DLL, return as-is
-        addr
+        None => addr,
     }
 }
 
+#[cfg(feature = "trace-imports")]
 macro_rules! trace_call {
     ($name:expr) => {
-        // let ret_addr = $crate::imports::CALLER_ADDR.load(std::sync::atomic::Ordering::Relaxed);
-        // let original_va = $crate::imports::to_original_va(ret_addr);
-        // eprintln!("[IMPORT] {:#x} {}", original_va, $name);
+        $crate::imports::trace::record($name, String::new)
     };
     ($name:expr, $($arg:tt)*) => {
-        // let ret_addr = $crate::imports::CALLER_ADDR.load(std::sync::atomic::Ordering::Relaxed);
-        // let original_va = $crate::imports::to_original_va(ret_addr);
-        // eprintln!("[IMPORT] {:#x} {} - {}", original_va, $name, format!($($arg)*));
+        $crate::imports::trace::record($name, || format!($($arg)*))
     };
 }
 
+#[cfg(not(feature = "trace-imports"))]
+macro_rules! trace_call {
+    ($($arg:tt)*) => {};
+}
+
 pub(crate) use trace_call;
 
+// Assembles an import thunk. With trace-imports, the thunk first hands the
+// DLL's return address to trace::set_caller, saving the argument registers
+// around the call. It never touches GS, so threads without a TIB can call
+// shims too.
+#[cfg(feature = "trace-imports")]
+macro_rules! thunk_asm {
+    ($($body:tt)*) => {
+        std::arch::naked_asm!(
+            "push rcx",
+            "push rdx",
+            "push r8",
+            "push r9",
+            // XMM0-3 spill space, shadow space, and 16-byte alignment
+            "sub rsp, 0x68",
+            "movdqu [rsp+0x20], xmm0",
+            "movdqu [rsp+0x30], xmm1",
+            "movdqu [rsp+0x40], xmm2",
+            "movdqu [rsp+0x50], xmm3",
+            "mov rcx, [rsp+0x88]",
+            "call {set_caller}",
+            "movdqu xmm0, [rsp+0x20]",
+            "movdqu xmm1, [rsp+0x30]",
+            "movdqu xmm2, [rsp+0x40]",
+            "movdqu xmm3, [rsp+0x50]",
+            "add rsp, 0x68",
+            "pop r9",
+            "pop r8",
+            "pop rdx",
+            "pop rcx",
+            // The body ends with a comma, as every thunk writes it
+            $($body)*
+            set_caller = sym $crate::imports::trace::set_caller,
+        )
+    };
+}
+
+#[cfg(not(feature = "trace-imports"))]
+macro_rules! thunk_asm {
+    ($($body:tt)*) => {
+        std::arch::naked_asm!($($body)*)
+    };
+}
+
+pub(crate) use thunk_asm;
+
 macro_rules! import_fn {
     // Entry point - parse multiple functions
     ($($tt:tt)*) => {
@@ -60,11 +173,8 @@ macro_rules! import_fn_inner {
         ::paste::paste! {
             #[unsafe(naked)]
             pub unsafe extern "win64" fn $name( $(_: $argty),* ) -> $ret {
-                std::arch::naked_asm!(
-                    // "mov r11, [rsp]",
-                    // "mov [rip + {caller}], r11",
+                $crate::imports::thunk_asm!(
                     "jmp {impl_fn}",
-                    // caller = sym $crate::imports::CALLER_ADDR,
                     impl_fn = sym [<$name _impl>],
                 )
             }
@@ -80,11 +190,8 @@ macro_rules! import_fn_inner {
         ::paste::paste! {
             #[unsafe(naked)]
             pub unsafe extern "win64" fn $name( $(_: $argty),* ) {
-                std::arch::naked_asm!(
-                    // "mov r11, [rsp]",
-                    // "mov [rip + {caller}], r11",
+                $crate::imports::thunk_asm!(
                     "jmp {impl_fn}",
-                    // caller = sym $crate::imports::CALLER_ADDR,
                     impl_fn = sym [<$name _impl>],
                 )
             }
@@ -102,6 +209,108 @@ macro_rules! import_fn_inner {
 pub(crate) use import_fn;
 pub(crate) use import_fn_inner;
 
+// ============ Allocation limit ============
+
+// Bytes the DLL holds through its allocation imports, counted only while a limit is set
+static ALLOC_BYTES: AtomicIsize = AtomicIsize::new(0);
+// 0 means unlimited
+static ALLOC_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static ALLOC_EXCEEDED_HOOK: Mutex<Option<fn()>> = Mutex::new(None);
+// Allocations counted in ALLOC_BYTES, by address. Freeing anything else,
+// such as memory allocated before the limit was set, leaves the count alone.
+static ALLOC_CHARGED: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+pub fn set_allocation_limit(limit: Option<usize>, on_exceeded: Option<fn()>) {
+    *ALLOC_EXCEEDED_HOOK
+        .lock()
+        .unwrap_or_else(|e| e.into_inner()) = on_exceeded;
+    ALLOC_CHARGED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
+    ALLOC_BYTES.store(0, Ordering::SeqCst);
+    ALLOC_LIMIT.store(limit.unwrap_or(0), Ordering::SeqCst);
+}
+
+/// Counts `size` bytes against the limit. Returns false if that would exceed it.
+///
+/// Follow with [`record_allocation`] once the memory is allocated, or
+/// [`uncharge_allocation`] if allocating failed.
+fn charge_allocation(size: usize) -> bool {
+    let limit = ALLOC_LIMIT.load(Ordering::Relaxed);
+    if limit == 0 {
+        return true;
+    }
+    let total = ALLOC_BYTES.fetch_add(size as isize, Ordering::Relaxed) + size as isize;
+    if total > limit as isize {
+        ALLOC_BYTES.fetch_sub(size as isize, Ordering::Relaxed);
+        let hook = *ALLOC_EXCEEDED_HOOK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(hook) = hook {
+            hook();
+        }
+        return false;
+    }
+    true
+}
+
+fn uncharge_allocation(size: usize) {
+    if ALLOC_LIMIT.load(Ordering::Relaxed) != 0 {
+        ALLOC_BYTES.fetch_sub(size as isize, Ordering::Relaxed);
+    }
+}
+
+/// Records that `addr` holds the `size` bytes charged for it, corrected to
+/// `actual`, what freeing it will release.
+fn record_allocation(addr: usize, size: usize, actual: usize) {
+    if ALLOC_LIMIT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    ALLOC_BYTES.fetch_add(actual as isize - size as isize, Ordering::Relaxed);
+    ALLOC_CHARGED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(addr, actual);
+}
+
+/// Releases what was charged for `addr`, if anything.
+fn release_allocation(addr: usize) {
+    if ALLOC_LIMIT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let charged = ALLOC_CHARGED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&addr);
+    if let Some(size) = charged {
+        ALLOC_BYTES.fetch_sub(size as isize, Ordering::Relaxed);
+    }
+}
+
+/// `malloc` counted against the allocation limit
+unsafe fn tracked_malloc(size: usize) -> *mut c_void {
+    if !charge_allocation(size) {
+        return std::ptr::null_mut();
+    }
+    let ptr = libc::malloc(size);
+    if ptr.is_null() {
+        uncharge_allocation(size);
+    } else {
+        // Charge what free will release, which may be rounded up
+        record_allocation(ptr as usize, size, libc::malloc_usable_size(ptr));
+    }
+    ptr
+}
+
+/// `free` for memory from [`tracked_malloc`]
+unsafe fn tracked_free(ptr: *mut c_void) {
+    if !ptr.is_null() {
+        release_allocation(ptr as usize);
+    }
+    libc::free(ptr)
+}
+
 // ============ Helpers ============
 
 unsafe fn wstr_to_string(s: *const u16) -> Vec<u8> {
@@ -8,6 +8,25 @@ static ERRNO_VAL: AtomicU32 = AtomicU32::new(0);
 // Comparator wrapper for qsort/bsearch - translates C calling convention to win64
 type Win64Comparator = unsafe extern "win64" fn(*const c_void, *const c_void) -> i32;
 
+// Functions registered with _onexit and __dllonexit, run when their image unloads
+static ONEXIT: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Removes and returns the exit functions registered from inside `image`,
+/// most recent first.
+pub fn take_onexit(image: &Image) -> Vec<usize> {
+    let mut registered = ONEXIT.lock().unwrap_or_else(|e| e.into_inner());
+    let mut taken = Vec::new();
+    registered.retain(|&func| {
+        let inside = image.contains(func);
+        if inside {
+            taken.push(func);
+        }
+        !inside
+    });
+    taken.reverse();
+    taken
+}
+
 thread_local! {
     static QSORT_COMPARATOR: Cell<Option<Win64Comparator>> = const { Cell::new(None) };
 }
@@ -24,12 +43,12 @@ unsafe extern "C" fn qsort_wrapper(a: *const c_void, b: *const c_void) -> i32 {
 import_fn! {
     fn malloc(size: usize) -> *mut c_void {
         trace_call!("msvcrt!malloc", "size={}", size);
-        libc::malloc(size)
+        tracked_malloc(size)
     }
 
     fn free(ptr: *mut c_void) {
         trace_call!("msvcrt!free", "ptr={:p}", ptr);
-        libc::free(ptr)
+        tracked_free(ptr)
     }
 
     fn memcpy(dst: *mut c_void, src: *const c_void, n: usize) -> *mut c_void {
@@ -38,7 +57,11 @@ import_fn! {
     }
 
     fn memcpy_s(dst: *mut c_void, dst_size: usize, src: *const c_void, count: usize) -> i32 {
-        trace_call!("msvcrt!memcpy_s", "dst={:p}, dst_size={}, src={:p}, count={}", dst, dst_size, src, count);
+        trace_call!(
+            "msvcrt!memcpy_s",
+            "dst={:p}, dst_size={}, src={:p}, count={}",
+            dst, dst_size, src, count
+        );
         if dst.is_null() || src.is_null() || dst_size < count {
             return 22; // EINVAL
         }
@@ -78,17 +101,17 @@ import_fn! {
     // ============ msvcrt - string ============
 
     fn strcmp(s1: *const i8, s2: *const i8) -> i32 {
-        trace_call!("msvcrt!strcmp");
+        trace_call!("msvcrt!strcmp", "s1={:p}, s2={:p}", s1, s2);
         libc::strcmp(s1, s2)
     }
 
     fn strncmp(s1: *const i8, s2: *const i8, n: usize) -> i32 {
-        trace_call!("msvcrt!strncmp");
+        trace_call!("msvcrt!strncmp", "s1={:p}, s2={:p}, n={}", s1, s2, n);
         libc::strncmp(s1, s2, n)
     }
 
     fn strcpy_s(dst: *mut i8, dst_size: usize, src: *const i8) -> i32 {
-        trace_call!("msvcrt!strcpy_s");
+        trace_call!("msvcrt!strcpy_s", "dst={:p}, dst_size={}, src={:p}", dst, dst_size, src);
         if dst.is_null() || src.is_null() {
             return 22;
         }
@@ -106,7 +129,11 @@ import_fn! {
         src: *const i8,
         count: usize,
     ) -> i32 {
-        trace_call!("msvcrt!strncpy_s");
+        trace_call!(
+            "msvcrt!strncpy_s",
+            "dst={:p}, dst_size={}, src={:p}, count={}",
+            dst, dst_size, src, count
+        );
         if dst.is_null() || src.is_null() {
             return 22;
         }
@@ -120,7 +147,7 @@ import_fn! {
     }
 
     fn strcat_s(dst: *mut i8, dst_size: usize, src: *const i8) -> i32 {
-        trace_call!("msvcrt!strcat_s");
+        trace_call!("msvcrt!strcat_s", "dst={:p}, dst_size={}, src={:p}", dst, dst_size, src);
         if dst.is_null() || src.is_null() {
             return 22;
         }
@@ -134,52 +161,57 @@ import_fn! {
     }
 
     fn strchr(s: *const i8, c: i32) -> *mut i8 {
-        trace_call!("msvcrt!strchr");
+        trace_call!("msvcrt!strchr", "s={:p}, c={}", s, c);
         libc::strchr(s, c)
     }
 
     fn strrchr(s: *const i8, c: i32) -> *mut i8 {
-        trace_call!("msvcrt!strrchr");
+        trace_call!("msvcrt!strrchr", "s={:p}, c={}", s, c);
         libc::strrchr(s, c)
     }
 
     fn strstr(haystack: *const i8, needle: *const i8) -> *mut i8 {
-        trace_call!("msvcrt!strstr");
+        trace_call!("msvcrt!strstr", "haystack={:p}, needle={:p}", haystack, needle);
         libc::strstr(haystack, needle)
     }
 
     fn strnlen(s: *const i8, max_len: usize) -> usize {
-        trace_call!("msvcrt!strnlen");
+        trace_call!("msvcrt!strnlen", "s={:p}, max_len={}", s, max_len);
         libc::strnlen(s, max_len)
     }
 
     fn _strdup(s: *const i8) -> *mut i8 {
-        trace_call!("msvcrt!_strdup");
-        libc::strdup(s)
+        trace_call!("msvcrt!_strdup", "s={:p}", s);
+        let size = libc::strlen(s) + 1;
+        let dst = tracked_malloc(size) as *mut i8;
+        if !dst.is_null() {
+            libc::memcpy(dst as *mut c_void, s as *const c_void, size);
+        }
+        dst
     }
 
     fn _stricmp(s1: *const i8, s2: *const i8) -> i32 {
-        trace_call!("msvcrt!_stricmp");
+        trace_call!("msvcrt!_stricmp", "s1={:p}, s2={:p}", s1, s2);
         libc::strcasecmp(s1, s2)
     }
 
     fn _strnicmp(s1: *const i8, s2: *const i8, n: usize) -> i32 {
-        trace_call!("msvcrt!_strnicmp");
+        trace_call!("msvcrt!_strnicmp", "s1={:p}, s2={:p}, n={}", s1, s2, n);
         libc::strncasecmp(s1, s2, n)
     }
 
     fn tolower(c: i32) -> i32 {
-        trace_call!("msvcrt!tolower");
+        trace_call!("msvcrt!tolower", "c={}", c);
         libc::tolower(c)
     }
 
     fn toupper(c: i32) -> i32 {
-        trace_call!("msvcrt!toupper");
+        trace_call!("msvcrt!toupper", "c={}", c);
         libc::toupper(c)
     }
 
     fn towlower(c: u32) -> u32 {
-        trace_call!("msvcrt!towlower");
+        trace_call!("msvcrt!towlower", "c={}", c);
         // Simple ASCII-only lowercase
         if c >= 'A' as u32 && c <= 'Z' as u32 {
             c + 32
@@ -189,32 +221,32 @@ import_fn! {
     }
 
     fn isalnum(c: i32) -> i32 {
-        trace_call!("msvcrt!isalnum");
+        trace_call!("msvcrt!isalnum", "c={}", c);
         libc::isalnum(c)
     }
 
     fn isalpha(c: i32) -> i32 {
-        trace_call!("msvcrt!isalpha");
+        trace_call!("msvcrt!isalpha", "c={}", c);
         libc::isalpha(c)
     }
 
     fn isdigit(c: i32) -> i32 {
-        trace_call!("msvcrt!isdigit");
+        trace_call!("msvcrt!isdigit", "c={}", c);
         libc::isdigit(c)
     }
 
     fn isspace(c: i32) -> i32 {
-        trace_call!("msvcrt!isspace");
+        trace_call!("msvcrt!isspace", "c={}", c);
         libc::isspace(c)
     }
 
     fn isxdigit(c: i32) -> i32 {
-        trace_call!("msvcrt!isxdigit");
+        trace_call!("msvcrt!isxdigit", "c={}", c);
         libc::isxdigit(c)
     }
 
     fn __isascii(c: i32) -> i32 {
-        trace_call!("msvcrt!__isascii");
+        trace_call!("msvcrt!__isascii", "c={}", c);
         if (0..=127).contains(&c) {
             1
         } else {
@@ -225,7 +257,7 @@ import_fn! {
     // ============ msvcrt - wide string ============
 
     fn wcsncmp(s1: *const u16, s2: *const u16, n: usize) -> i32 {
-        trace_call!("msvcrt!wcsncmp");
+        trace_call!("msvcrt!wcsncmp", "s1={:p}, s2={:p}, n={}", s1, s2, n);
         for i in 0..n {
             let c1 = *s1.add(i);
             let c2 = *s2.add(i);
@@ -245,7 +277,11 @@ import_fn! {
         src: *const u16,
         count: usize,
     ) -> i32 {
-        trace_call!("msvcrt!wcsncpy_s");
+        trace_call!(
+            "msvcrt!wcsncpy_s",
+            "dst={:p}, dst_size={}, src={:p}, count={}",
+            dst, dst_size, src, count
+        );
         if dst.is_null() || src.is_null() {
             return 22;
         }
@@ -269,7 +305,11 @@ import_fn! {
         src: *const u16,
         count: usize,
     ) -> i32 {
-        trace_call!("msvcrt!wcsncat_s");
+        trace_call!(
+            "msvcrt!wcsncat_s",
+            "dst={:p}, dst_size={}, src={:p}, count={}",
+            dst, dst_size, src, count
+        );
         if dst.is_null() || src.is_null() {
             return 22;
         }
@@ -292,12 +332,12 @@ import_fn! {
     }
 
     fn wcscat_s(dst: *mut u16, dst_size: usize, src: *const u16) -> i32 {
-        trace_call!("msvcrt!wcscat_s");
+        trace_call!("msvcrt!wcscat_s", "dst={:p}, dst_size={}, src={:p}", dst, dst_size, src);
         wcsncat_s(dst, dst_size, src, usize::MAX)
     }
 
     fn wcscpy_s(dst: *mut u16, dst_size: usize, src: *const u16) -> i32 {
-        trace_call!("msvcrt!wcscpy_s");
+        trace_call!("msvcrt!wcscpy_s", "dst={:p}, dst_size={}, src={:p}", dst, dst_size, src);
         if dst.is_null() || src.is_null() {
             return 22;
         }
@@ -315,7 +355,7 @@ import_fn! {
     }
 
     fn wcsrchr(s: *const u16, c: u16) -> *mut u16 {
-        trace_call!("msvcrt!wcsrchr");
+        trace_call!("msvcrt!wcsrchr", "s={:p}, c={}", s, c);
         let mut last = std::ptr::null_mut();
         let mut p = s;
         while *p != 0 {
@@ -328,13 +368,13 @@ import_fn! {
     }
 
     fn _wcsdup(s: *const u16) -> *mut u16 {
-        trace_call!("msvcrt!_wcsdup");
+        trace_call!("msvcrt!_wcsdup", "s={:p}", s);
         let mut len = 0;
         while *s.add(len) != 0 {
             len += 1;
         }
         let size = (len + 1) * 2;
-        let dst = libc::malloc(size) as *mut u16;
+        let dst = tracked_malloc(size) as *mut u16;
         if !dst.is_null() {
             for i in 0..=len {
                 *dst.add(i) = *s.add(i);
@@ -344,7 +384,7 @@ import_fn! {
     }
 
     fn _wcsicmp(s1: *const u16, s2: *const u16) -> i32 {
-        trace_call!("msvcrt!_wcsicmp");
+        trace_call!("msvcrt!_wcsicmp", "s1={:p}, s2={:p}", s1, s2);
         let mut i = 0;
         loop {
             let c1 = ascii_lower(*s1.add(i) as u32);
@@ -360,7 +400,7 @@ import_fn! {
     }
 
     fn _wcsnicmp(s1: *const u16, s2: *const u16, n: usize) -> i32 {
-        trace_call!("msvcrt!_wcsnicmp");
+        trace_call!("msvcrt!_wcsnicmp", "s1={:p}, s2={:p}, n={}", s1, s2, n);
         for i in 0..n {
             let c1 = ascii_lower(*s1.add(i) as u32);
             let c2 = ascii_lower(*s2.add(i) as u32);
@@ -375,12 +415,12 @@ import_fn! {
     }
 
     fn _mbscmp(s1: *const u8, s2: *const u8) -> i32 {
-        trace_call!("msvcrt!_mbscmp");
+        trace_call!("msvcrt!_mbscmp", "s1={:p}, s2={:p}", s1, s2);
         libc::strcmp(s1 as *const i8, s2 as *const i8)
     }
 
     fn _mbstrlen(s: *const u8) -> usize {
-        trace_call!("msvcrt!_mbstrlen");
+        trace_call!("msvcrt!_mbstrlen", "s={:p}", s);
         libc::strlen(s as *const i8)
     }
 
@@ -394,7 +434,11 @@ import_fn! {
         _arg3: u64,
         _arg4: u64,
     ) -> i32 {
-        trace_call!("msvcrt!sscanf_s");
+        trace_call!(
+            "msvcrt!sscanf_s",
+            "buffer={:p}, format={:p}, arg1={}, arg2={}, arg3={}, arg4={}",
+            _buffer, _format, _arg1, _arg2, _arg3, _arg4
+        );
         panic!("msvcrt!sscanf_s not implemented");
     }
 
@@ -407,7 +451,11 @@ import_fn! {
         _arg3: u64,
         _arg4: u64,
     ) -> i32 {
-        trace_call!("msvcrt!swprintf_s");
+        trace_call!(
+            "msvcrt!swprintf_s",
+            "buffer={:p}, size={}, format={:p}, arg1={}, arg2={}, arg3={}, arg4={}",
+            _buffer, _size, _format, _arg1, _arg2, _arg3, _arg4
+        );
         panic!("msvcrt!swprintf_s not implemented");
     }
 
@@ -417,7 +465,11 @@ import_fn! {
         format: *const i8,
         argptr: *mut c_void,
     ) -> i32 {
-        trace_call!("msvcrt!_vsnprintf");
+        trace_call!(
+            "msvcrt!_vsnprintf",
+            "buffer={:p}, count={}, format={:p}, argptr={:p}",
+            buffer, count, format, argptr
+        );
         super::printf::vsnprintf_core(buffer, count, format, argptr as *const u64)
     }
 
@@ -427,7 +479,11 @@ import_fn! {
         _format: *const u16,
         _argptr: *mut c_void,
     ) -> i32 {
-        trace_call!("msvcrt!_vsnwprintf");
+        trace_call!(
+            "msvcrt!_vsnwprintf",
+            "buffer={:p}, count={}, format={:p}, argptr={:p}",
+            _buffer, _count, _format, _argptr
+        );
         panic!("msvcrt!_vsnwprintf not implemented");
     }
 
@@ -441,7 +497,11 @@ import_fn! {
         _arg3: u64,
         _arg4: u64,
     ) -> i32 {
-        trace_call!("msvcrt!_snwprintf_s");
+        trace_call!(
+            "msvcrt!_snwprintf_s",
+            "buffer={:p}, size_in_words={}, count={}, format={:p}, arg1={}, arg2={}, arg3={}, arg4={}",
+            _buffer, _size_in_words, _count, _format, _arg1, _arg2, _arg3, _arg4
+        );
         panic!("msvcrt!_snwprintf_s not implemented");
     }
 
@@ -468,7 +528,7 @@ import_fn! {
     }
 
     fn ftell(stream: *mut c_void) -> i64 {
-        trace_call!("msvcrt!ftell");
+        trace_call!("msvcrt!ftell", "stream={:p}", stream);
         libc::ftell(stream as *mut libc::FILE) as i64
     }
 
@@ -477,14 +537,18 @@ import_fn! {
         mode: *const u16,
         _shflag: i32,
     ) -> *mut c_void {
-        trace_call!("msvcrt!_wfsopen");
+        trace_call!(
+            "msvcrt!_wfsopen",
+            "filename={:p}, mode={:p}, shflag=0x{:x}",
+            filename, mode, _shflag
+        );
         let filename = wstr_to_string(filename);
         let mode = wstr_to_string(mode);
         libc::fopen(filename.as_ptr() as *const i8, mode.as_ptr() as *const i8) as *mut c_void
     }
 
     fn _fileno(stream: *mut c_void) -> i32 {
-        trace_call!("msvcrt!_fileno");
+        trace_call!("msvcrt!_fileno", "stream={:p}", stream);
         libc::fileno(stream as *mut libc::FILE)
     }
 
@@ -547,85 +611,85 @@ import_fn! {
     // ============ msvcrt - math ============
 
     fn acos(x: f64) -> f64 {
-        trace_call!("msvcrt!acos");
+        trace_call!("msvcrt!acos", "x={}", x);
         x.acos()
     }
     fn asin(x: f64) -> f64 {
-        trace_call!("msvcrt!asin");
+        trace_call!("msvcrt!asin", "x={}", x);
         x.asin()
     }
     fn atan(x: f64) -> f64 {
-        trace_call!("msvcrt!atan");
+        trace_call!("msvcrt!atan", "x={}", x);
         x.atan()
     }
     fn atan2(y: f64, x: f64) -> f64 {
-        trace_call!("msvcrt!atan2");
+        trace_call!("msvcrt!atan2", "y={}, x={}", y, x);
         y.atan2(x)
     }
     fn ceil(x: f64) -> f64 {
-        trace_call!("msvcrt!ceil");
+        trace_call!("msvcrt!ceil", "x={}", x);
         x.ceil()
     }
     fn cos(x: f64) -> f64 {
-        trace_call!("msvcrt!cos");
+        trace_call!("msvcrt!cos", "x={}", x);
         x.cos()
     }
     fn cosh(x: f64) -> f64 {
-        trace_call!("msvcrt!cosh");
+        trace_call!("msvcrt!cosh", "x={}", x);
         x.cosh()
     }
     fn exp(x: f64) -> f64 {
-        trace_call!("msvcrt!exp");
+        trace_call!("msvcrt!exp", "x={}", x);
         x.exp()
     }
     fn floor(x: f64) -> f64 {
-        trace_call!("msvcrt!floor");
+        trace_call!("msvcrt!floor", "x={}", x);
         x.floor()
     }
     fn floorf(x: f32) -> f32 {
-        trace_call!("msvcrt!floorf");
+        trace_call!("msvcrt!floorf", "x={}", x);
         x.floor()
     }
     fn fmod(x: f64, y: f64) -> f64 {
-        trace_call!("msvcrt!fmod");
+        trace_call!("msvcrt!fmod", "x={}, y={}", x, y);
         x % y
     }
     fn log(x: f64) -> f64 {
-        trace_call!("msvcrt!log");
+        trace_call!("msvcrt!log", "x={}", x);
         x.ln()
     }
     fn modf(x: f64, iptr: *mut f64) -> f64 {
-        trace_call!("msvcrt!modf");
+        trace_call!("msvcrt!modf", "x={}, iptr={:p}", x, iptr);
         *iptr = x.trunc();
         x.fract()
     }
     fn pow(x: f64, y: f64) -> f64 {
-        trace_call!("msvcrt!pow");
+        trace_call!("msvcrt!pow", "x={}, y={}", x, y);
         x.powf(y)
     }
     fn sin(x: f64) -> f64 {
-        trace_call!("msvcrt!sin");
+        trace_call!("msvcrt!sin", "x={}", x);
         x.sin()
     }
     fn sinh(x: f64) -> f64 {
-        trace_call!("msvcrt!sinh");
+        trace_call!("msvcrt!sinh", "x={}", x);
         x.sinh()
     }
     fn sqrt(x: f64) -> f64 {
-        trace_call!("msvcrt!sqrt");
+        trace_call!("msvcrt!sqrt", "x={}", x);
         x.sqrt()
     }
     fn tan(x: f64) -> f64 {
-        trace_call!("msvcrt!tan");
+        trace_call!("msvcrt!tan", "x={}", x);
         x.tan()
     }
     fn tanh(x: f64) -> f64 {
-        trace_call!("msvcrt!tanh");
+        trace_call!("msvcrt!tanh", "x={}", x);
         x.tanh()
     }
 
     fn _isnan(x: f64) -> i32 {
-        trace_call!("msvcrt!_isnan");
+        trace_call!("msvcrt!_isnan", "x={}", x);
         if x.is_nan() {
             1
         } else {
@@ -634,7 +698,7 @@ import_fn! {
     }
 
     fn _finite(x: f64) -> i32 {
-        trace_call!("msvcrt!_finite");
+        trace_call!("msvcrt!_finite", "x={}", x);
         if x.is_finite() {
             1
         } else {
@@ -643,7 +707,7 @@ import_fn! {
     }
 
     fn _fpclass(x: f64) -> i32 {
-        trace_call!("msvcrt!_fpclass");
+        trace_call!("msvcrt!_fpclass", "x={}", x);
         if x.is_nan() {
             0x0002
         } else if x.is_infinite() {
@@ -665,45 +729,45 @@ import_fn! {
     }
 
     fn _controlfp(_new: u32, _mask: u32) -> u32 {
-        trace_call!("msvcrt!_controlfp");
+        trace_call!("msvcrt!_controlfp", "new={}, mask=0x{:x}", _new, _mask);
         0
     }
 
     // ============ msvcrt - conversion ============
 
     fn atoi(s: *const i8) -> i32 {
-        trace_call!("msvcrt!atoi");
+        trace_call!("msvcrt!atoi", "s={:p}", s);
         libc::atoi(s)
     }
 
     fn atof(s: *const i8) -> f64 {
-        trace_call!("msvcrt!atof");
+        trace_call!("msvcrt!atof", "s={:p}", s);
         libc::atof(s)
     }
 
     fn _atoi64(s: *const i8) -> i64 {
-        trace_call!("msvcrt!_atoi64");
+        trace_call!("msvcrt!_atoi64", "s={:p}", s);
         libc::strtoll(s, std::ptr::null_mut(), 10)
     }
 
     fn strtod(s: *const i8, endptr: *mut *mut i8) -> f64 {
-        trace_call!("msvcrt!strtod");
+        trace_call!("msvcrt!strtod", "s={:p}, endptr={:p}", s, endptr);
         libc::strtod(s, endptr)
     }
 
     fn strtoul(s: *const i8, endptr: *mut *mut i8, base: i32) -> u64 {
-        trace_call!("msvcrt!strtoul");
+        trace_call!("msvcrt!strtoul", "s={:p}, endptr={:p}, base={}", s, endptr, base);
         libc::strtoul(s, endptr, base) as u64
     }
 
     fn wcstoul(s: *const u16, _endptr: *mut *mut u16, base: i32) -> u64 {
-        trace_call!("msvcrt!wcstoul");
+        trace_call!("msvcrt!wcstoul", "s={:p}, endptr={:p}, base={}", s, _endptr, base);
         let narrow = wstr_to_string(s);
         libc::strtoul(narrow.as_ptr() as *const i8, std::ptr::null_mut(), base) as u64
     }
 
     fn _strtoui64(s: *const i8, endptr: *mut *mut i8, base: i32) -> u64 {
-        trace_call!("msvcrt!_strtoui64");
+        trace_call!("msvcrt!_strtoui64", "s={:p}, endptr={:p}, base={}", s, endptr, base);
         libc::strtoull(s, endptr, base)
     }
 
@@ -738,22 +802,22 @@ import_fn! {
     }
 
     fn getenv(name: *const i8) -> *mut i8 {
-        trace_call!("msvcrt!getenv");
+        trace_call!("msvcrt!getenv", "name={:p}", name);
         libc::getenv(name)
     }
 
     fn _wgetenv(_name: *const u16) -> *mut u16 {
-        trace_call!("msvcrt!_wgetenv");
+        trace_call!("msvcrt!_wgetenv", "name={:p}", _name);
         panic!("msvcrt!_wgetenv not implemented");
     }
 
     fn setlocale(category: i32, locale: *const i8) -> *mut i8 {
-        trace_call!("msvcrt!setlocale");
+        trace_call!("msvcrt!setlocale", "category={}, locale={:p}", category, locale);
         libc::setlocale(category, locale)
     }
 
     fn _time64(timer: *mut i64) -> i64 {
-        trace_call!("msvcrt!_time64");
+        trace_call!("msvcrt!_time64", "timer={:p}", timer);
         let t = libc::time(std::ptr::null_mut());
         if !timer.is_null() {
             *timer = t;
@@ -769,7 +833,7 @@ import_fn! {
     // ============ msvcrt - CRT init ============
 
     fn _initterm(start: *const *const c_void, end: *const *const c_void) {
-        trace_call!("msvcrt!_initterm");
+        trace_call!("msvcrt!_initterm", "start={:p}, end={:p}", start, end);
         let mut p = start;
         while p < end {
             if !(*p).is_null() {
@@ -791,7 +855,11 @@ import_fn! {
     }
 
     fn _onexit(func: *const c_void) -> *const c_void {
-        trace_call!("msvcrt!_onexit");
+        trace_call!("msvcrt!_onexit", "func=0x{:x}", to_original_va(func as usize));
+        ONEXIT
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(func as usize);
         func
     }
 
@@ -800,40 +868,51 @@ import_fn! {
         _pbegin: *mut *const c_void,
         _pend: *mut *const c_void,
     ) -> *const c_void {
-        trace_call!("msvcrt!__dllonexit");
+        trace_call!("msvcrt!__dllonexit", "func=0x{:x}", to_original_va(func as usize));
+        // Kept here rather than in the DLL's own table, so unloading runs it
+        ONEXIT
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(func as usize);
         func
     }
 
     fn _lock(_locknum: i32) {
-        trace_call!("msvcrt!_lock");
+        trace_call!("msvcrt!_lock", "locknum={}", _locknum);
         // No-op: CRT lock for thread safety
     }
 
     fn _unlock(_locknum: i32) {
-        trace_call!("msvcrt!_unlock");
+        trace_call!("msvcrt!_unlock", "locknum={}", _locknum);
         // No-op: CRT unlock for thread safety
     }
 
     fn _callnewh(_size: usize) -> i32 {
-        trace_call!("msvcrt!_callnewh");
-        panic!("msvcrt!_callnewh not implemented");
+        trace_call!("msvcrt!_callnewh", "size={}", _size);
+        // No new handler installed, so operator new throws std::bad_alloc
+        0
     }
 
     // ============ msvcrt - exceptions ============
 
-    fn __C_specific_handler() {
-        trace_call!("msvcrt!__C_specific_handler");
-        panic!("msvcrt!__C_specific_handler not implemented");
+    fn __C_specific_handler(
+        record: *mut eh::EXCEPTION_RECORD,
+        establisher_frame: u64,
+        context: *mut ntdll::CONTEXT,
+        dispatcher: *mut eh::DISPATCHER_CONTEXT,
+    ) -> u32 {
+        trace_call!("msvcrt!__C_specific_handler", "frame=0x{:x}", establisher_frame);
+        eh::c_specific_handler(record, establisher_frame, context, dispatcher)
     }
 
-    fn __CxxFrameHandler3() {
-        trace_call!("msvcrt!__CxxFrameHandler3");
-        panic!("msvcrt!__CxxFrameHandler3 not implemented");
-    }
-
-    fn _CxxThrowException(_obj: *mut c_void, _info: *mut c_void) {
-        trace_call!("msvcrt!_CxxThrowException");
-        panic!("C++ exception thrown");
+    fn __CxxFrameHandler3(
+        record: *mut eh::EXCEPTION_RECORD,
+        establisher_frame: u64,
+        context: *mut ntdll::CONTEXT,
+        dispatcher: *mut eh::DISPATCHER_CONTEXT,
+    ) -> u32 {
+        trace_call!("msvcrt!__CxxFrameHandler3", "frame=0x{:x}", establisher_frame);
+        eh::cxx_frame_handler(record, establisher_frame, context, dispatcher)
     }
 
     fn terminate() {
@@ -854,7 +933,11 @@ import_fn! {
         _free: *const c_void,
         _flags: u16,
     ) -> *mut i8 {
-        trace_call!("msvcrt!__unDName");
+        trace_call!(
+            "msvcrt!__unDName",
+            "buffer={:p}, name={:p}, buflen={}, malloc={:p}, free={:p}, flags=0x{:x}",
+            buffer, name, buflen, _malloc, _free, _flags
+        );
         if buffer.is_null() {
             _strdup(name)
         } else {
@@ -864,7 +947,7 @@ import_fn! {
     }
 
     fn _XcptFilter(_code: u32, _info: *mut c_void) -> i32 {
-        trace_call!("msvcrt!_XcptFilter");
+        trace_call!("msvcrt!_XcptFilter", "code={}, info={:p}", _code, _info);
         panic!("msvcrt!_XcptFilter not implemented");
     }
 
@@ -875,7 +958,11 @@ import_fn! {
         relPath: *const u16,
         maxLength: usize,
     ) -> *mut u16 {
-        trace_call!("msvcrt!_wfullpath");
+        trace_call!(
+            "msvcrt!_wfullpath",
+            "abs_path={:p}, rel_path={:p}, max_length={}",
+            absPath, relPath, maxLength
+        );
         wcscpy_s(absPath, maxLength, relPath);
         absPath
     }
@@ -888,7 +975,11 @@ import_fn! {
         fname: *const u16,
         ext: *const u16,
     ) -> i32 {
-        trace_call!("msvcrt!_wmakepath_s");
+        trace_call!(
+            "msvcrt!_wmakepath_s",
+            "path={:p}, size={}, drive={:p}, dir={:p}, fname={:p}, ext={:p}",
+            path, size, _drive, dir, fname, ext
+        );
         *path = 0;
         if !dir.is_null() {
             wcscat_s(path, size, dir);
@@ -913,7 +1004,11 @@ import_fn! {
         ext: *mut u16,
         ext_size: usize,
     ) -> i32 {
-        trace_call!("msvcrt!_wsplitpath_s");
+        trace_call!(
+            "msvcrt!_wsplitpath_s",
+            "path={:p}, drive={:p}, drive_size={}, dir={:p}, dir_size={}, fname={:p}, fname_size={}, ext={:p}, ext_size={}",
+            _path, drive, drive_size, dir, dir_size, fname, fname_size, ext, ext_size
+        );
         if !drive.is_null() && drive_size > 0 {
             *drive = 0;
         }
@@ -936,7 +1031,7 @@ import_fn! {
 /// Win64 ABI: RCX=buffer, RDX=size, R8=format, R9=first_vararg, stack has rest
 #[unsafe(naked)]
 pub unsafe extern "win64" fn sprintf_s() -> i32 {
-    std::arch::naked_asm!(
+    super::thunk_asm!(
         // Store R9 (first vararg) into shadow space to make args contiguous
         "mov [rsp+0x20], r9",
         // R9 becomes pointer to varargs (va_list)
@@ -952,6 +1047,37 @@ unsafe extern "win64" fn sprintf_s_impl(
     format: *const i8,
     argptr: *const u64,
 ) -> i32 {
-    trace_call!("msvcrt!sprintf_s");
+    trace_call!(
+        "msvcrt!sprintf_s",
+        "buffer={:p}, size={}, format={:p}, argptr={:p}",
+        buffer, size, format, argptr
+    );
     super::printf::vsnprintf_core(buffer, size, format, argptr)
 }
+
+/// Captures the thrower's registers and raises a C++ exception.
+///
+/// Written by hand rather than with `import_fn!` because the unwinder needs
+/// the registers of the DLL code that called it.
+#[unsafe(naked)]
+pub unsafe extern "win64" fn _CxxThrowException(_obj: *mut c_void, _info: *mut c_void) -> ! {
+    std::arch::naked_asm!(
+        // Shadow space, then a 16-byte aligned context
+        "sub rsp, 0x508",
+        "mov [rsp + 0x30], rcx",
+        "mov [rsp + 0x38], rdx",
+        "lea rcx, [rsp + 0x30]",
+        "call {capture}",
+        "lea rax, [rsp + 0x510]",
+        "mov [rsp + 0x30 + 0x98], rax",
+        "mov rax, [rsp + 0x508]",
+        "mov [rsp + 0x30 + 0xF8], rax",
+        "mov rcx, [rsp + 0x30]",
+        "mov rdx, [rsp + 0x38]",
+        "lea r8, [rsp + 0x30]",
+        "call {throw}",
+        "ud2",
+        capture = sym ntdll::RtlCaptureContext,
+        throw = sym eh::cxx_throw,
+    )
+}
@@ -1,9 +1,77 @@
 use super::*;
 
-#[repr(C)]
+/// x64 register context, laid out exactly like the Windows `CONTEXT`
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct CONTEXT {
-    data: [u8; 1232], // Full x64 CONTEXT structure
+    pub P1Home: u64,
+    pub P2Home: u64,
+    pub P3Home: u64,
+    pub P4Home: u64,
+    pub P5Home: u64,
+    pub P6Home: u64,
+    pub ContextFlags: u32,
+    pub MxCsr: u32,
+    pub SegCs: u16,
+    pub SegDs: u16,
+    pub SegEs: u16,
+    pub SegFs: u16,
+    pub SegGs: u16,
+    pub SegSs: u16,
+    pub EFlags: u32,
+    pub Dr0: u64,
+    pub Dr1: u64,
+    pub Dr2: u64,
+    pub Dr3: u64,
+    pub Dr6: u64,
+    pub Dr7: u64,
+    // Integer registers, in unwind-code register number order
+    pub Rax: u64,
+    pub Rcx: u64,
+    pub Rdx: u64,
+    pub Rbx: u64,
+    pub Rsp: u64,
+    pub Rbp: u64,
+    pub Rsi: u64,
+    pub Rdi: u64,
+    pub R8: u64,
+    pub R9: u64,
+    pub R10: u64,
+    pub R11: u64,
+    pub R12: u64,
+    pub R13: u64,
+    pub R14: u64,
+    pub R15: u64,
+    pub Rip: u64,
+    // XMM_SAVE_AREA32, split around the XMM registers
+    pub FltSaveHeader: [u8; 0xA0],
+    pub Xmm: [u128; 16],
+    pub FltSaveReserved: [u8; 0x60],
+    pub VectorRegister: [u128; 26],
+    pub VectorControl: u64,
+    pub DebugControl: u64,
+    pub LastBranchToRip: u64,
+    pub LastBranchFromRip: u64,
+    pub LastExceptionToRip: u64,
+    pub LastExceptionFromRip: u64,
+}
+
+const _: () = assert!(std::mem::size_of::<CONTEXT>() == 0x4D0);
+const _: () = assert!(std::mem::offset_of!(CONTEXT, Rax) == 0x78);
+const _: () = assert!(std::mem::offset_of!(CONTEXT, Rip) == 0xF8);
+const _: () = assert!(std::mem::offset_of!(CONTEXT, Xmm) == 0x1A0);
+
+impl CONTEXT {
+    pub fn zeroed() -> Self {
+        unsafe { std::mem::zeroed() }
+    }
+
+    /// Integer register by unwind-code number (0 = RAX ... 15 = R15)
+    fn gpr(&mut self, reg: u8) -> &mut u64 {
+        debug_assert!(reg < 16);
+        unsafe { &mut *(&raw mut self.Rax).add(reg as usize) }
+    }
 }
 
 #[repr(C)]
@@ -13,46 +81,543 @@ pub struct RUNTIME_FUNCTION {
     pub UnwindData: u32,
 }
 
-import_fn! {
-    fn RtlCaptureContext(context: *mut CONTEXT) {
-        trace_call!("ntdll!RtlCaptureContext");
-        if !context.is_null() {
-            std::ptr::write_bytes(context, 0, 1);
+// UNWIND_INFO flags, also used as RtlVirtualUnwind handler types
+pub const UNW_FLAG_EHANDLER: u32 = 1;
+pub const UNW_FLAG_UHANDLER: u32 = 2;
+const UNW_FLAG_CHAININFO: u8 = 4;
+
+// Unwind operation codes
+const UWOP_PUSH_NONVOL: u8 = 0;
+const UWOP_ALLOC_LARGE: u8 = 1;
+const UWOP_ALLOC_SMALL: u8 = 2;
+const UWOP_SET_FPREG: u8 = 3;
+const UWOP_SAVE_NONVOL: u8 = 4;
+const UWOP_SAVE_NONVOL_FAR: u8 = 5;
+const UWOP_EPILOG: u8 = 6;
+const UWOP_SAVE_XMM128: u8 = 8;
+const UWOP_SAVE_XMM128_FAR: u8 = 9;
+const UWOP_PUSH_MACHFRAME: u8 = 10;
+
+/// Returns how many slots of the unwind code array an operation takes.
+fn code_slots(op: u8, op_info: u8) -> usize {
+    match op {
+        UWOP_ALLOC_LARGE if op_info == 0 => 2,
+        UWOP_ALLOC_LARGE => 3,
+        UWOP_SAVE_NONVOL | UWOP_SAVE_XMM128 | UWOP_EPILOG => 2,
+        UWOP_SAVE_NONVOL_FAR | UWOP_SAVE_XMM128_FAR => 3,
+        _ => 1,
+    }
+}
+
+/// Finds the `.pdata` entry covering `pc`, which must be a runtime address
+/// inside the loaded image.
+///
+/// Returns the entry and the image base, or `None` for addresses outside the
+/// image and for leaf functions.
+pub(crate) unsafe fn lookup_function_entry(pc: u64) -> Option<(*mut RUNTIME_FUNCTION, u64)> {
+    let image = find_image(pc as usize)?;
+    let base = image.map_base as u64;
+    let table_rva = image.exception_dir as u64;
+    let table_size = image.exception_dir_size;
+    if table_rva == 0 {
+        return None;
+    }
+
+    let rva = (pc - base) as u32;
+    let table = std::slice::from_raw_parts_mut(
+        (base + table_rva) as *mut RUNTIME_FUNCTION,
+        table_size / std::mem::size_of::<RUNTIME_FUNCTION>(),
+    );
+    let index = table.partition_point(|entry| entry.EndAddress <= rva);
+    let entry = table.get_mut(index)?;
+    (entry.BeginAddress <= rva).then_some((entry as *mut RUNTIME_FUNCTION, base))
+}
+
+/// Unwinds `context` out of the function described by `entry`, the way
+/// `RtlVirtualUnwind` does.
+///
+/// Returns the language handler if the function has one of `handler_type`
+/// and `pc` is past its prolog, with its handler data and the function's
+/// establisher frame. Epilogs are not recognized, which only matters for
+/// faults raised while a function is returning.
+pub(crate) unsafe fn virtual_unwind(
+    handler_type: u32,
+    image_base: u64,
+    pc: u64,
+    entry: *const RUNTIME_FUNCTION,
+    context: &mut CONTEXT,
+    handler_data: &mut *mut c_void,
+    establisher_frame: &mut u64,
+) -> Option<u64> {
+    let mut entry = entry;
+    let offset = pc - image_base - (*entry).BeginAddress as u64;
+    let mut handler = None;
+    let mut primary = true;
+
+    loop {
+        let info = (image_base + (*entry).UnwindData as u64) as *const u8;
+        let flags = *info >> 3;
+        let prolog_size = *info.add(1) as u64;
+        let count = *info.add(2) as usize;
+        let frame_reg = *info.add(3) & 0xF;
+        let frame_offset = (*info.add(3) >> 4) as u64 * 16;
+        let codes = info.add(4) as *const u16;
+        let in_prolog = primary && offset < prolog_size;
+
+        // A code only applies once the prolog has executed past it
+        let applies = |i: usize| !in_prolog || (*codes.add(i) & 0xFF) as u64 <= offset;
+
+        let mut frame_set = false;
+        let mut i = 0;
+        while i < count {
+            let code = *codes.add(i);
+            let op = (code >> 8) as u8 & 0xF;
+            frame_set |= frame_reg != 0 && op == UWOP_SET_FPREG && applies(i);
+            i += code_slots(op, (code >> 12) as u8);
+        }
+        let frame = if frame_set {
+            *context.gpr(frame_reg) - frame_offset
+        } else {
+            context.Rsp
+        };
+        if primary {
+            *establisher_frame = frame;
+        }
+
+        let mut i = 0;
+        while i < count {
+            let code = *codes.add(i);
+            let op = (code >> 8) as u8 & 0xF;
+            let op_info = (code >> 12) as u8;
+            let slot = |n: usize| *codes.add(i + n) as u64;
+            if !applies(i) {
+                i += code_slots(op, op_info);
+                continue;
+            }
+
+            match op {
+                UWOP_PUSH_NONVOL => {
+                    *context.gpr(op_info) = *(context.Rsp as *const u64);
+                    context.Rsp += 8;
+                }
+                UWOP_ALLOC_LARGE if op_info == 0 => context.Rsp += slot(1) * 8,
+                UWOP_ALLOC_LARGE => context.Rsp += slot(1) | (slot(2) << 16),
+                UWOP_ALLOC_SMALL => context.Rsp += op_info as u64 * 8 + 8,
+                UWOP_SET_FPREG => context.Rsp = *context.gpr(frame_reg) - frame_offset,
+                UWOP_SAVE_NONVOL => {
+                    *context.gpr(op_info) = *((frame + slot(1) * 8) as *const u64);
+                }
+                UWOP_SAVE_NONVOL_FAR => {
+                    let offset = slot(1) | (slot(2) << 16);
+                    *context.gpr(op_info) = *((frame + offset) as *const u64);
+                }
+                UWOP_SAVE_XMM128 => {
+                    let address = (frame + slot(1) * 16) as *const u128;
+                    context.Xmm[op_info as usize] = address.read_unaligned();
+                }
+                UWOP_SAVE_XMM128_FAR => {
+                    let offset = slot(1) | (slot(2) << 16);
+                    let address = (frame + offset) as *const u128;
+                    context.Xmm[op_info as usize] = address.read_unaligned();
+                }
+                UWOP_PUSH_MACHFRAME => {
+                    if op_info != 0 {
+                        context.Rsp += 8;
+                    }
+                    context.Rip = *(context.Rsp as *const u64);
+                    context.Rsp = *((context.Rsp + 24) as *const u64);
+                    // The machine frame holds the return state itself
+                    return handler;
+                }
+                _ => {}
+            }
+            i += code_slots(op, op_info);
+        }
+
+        if primary
+            && !in_prolog
+            && flags & UNW_FLAG_CHAININFO == 0
+            && flags as u32 & handler_type != 0
+        {
+            let tail = info.add(4 + count.next_multiple_of(2) * 2) as *const u32;
+            *handler_data = tail.add(1) as *mut c_void;
+            handler = Some(image_base + *tail as u64);
         }
+
+        if flags & UNW_FLAG_CHAININFO == 0 {
+            break;
+        }
+        // Chained entries describe the rest of the prolog, which has run
+        entry = info.add(4 + count.next_multiple_of(2) * 2) as *const RUNTIME_FUNCTION;
+        primary = false;
     }
 
+    debug_assert!(context.Rsp != 0, "unwound into a null stack");
+    context.Rip = *(context.Rsp as *const u64);
+    context.Rsp += 8;
+    handler
+}
+
+/// Returns true if `address` is inside a loaded image.
+pub(crate) fn in_image(address: u64) -> bool {
+    find_image(address as usize).is_some()
+}
+
+/// Fills `context` with the caller's registers, as if it had just returned
+/// from this call.
+#[unsafe(naked)]
+pub unsafe extern "win64" fn RtlCaptureContext(_context: *mut CONTEXT) {
+    std::arch::naked_asm!(
+        "mov [rcx + 0x78], rax",
+        "mov [rcx + 0x80], rcx",
+        "mov [rcx + 0x88], rdx",
+        "mov [rcx + 0x90], rbx",
+        "lea rax, [rsp + 8]",
+        "mov [rcx + 0x98], rax",
+        "mov [rcx + 0xA0], rbp",
+        "mov [rcx + 0xA8], rsi",
+        "mov [rcx + 0xB0], rdi",
+        "mov [rcx + 0xB8], r8",
+        "mov [rcx + 0xC0], r9",
+        "mov [rcx + 0xC8], r10",
+        "mov [rcx + 0xD0], r11",
+        "mov [rcx + 0xD8], r12",
+        "mov [rcx + 0xE0], r13",
+        "mov [rcx + 0xE8], r14",
+        "mov [rcx + 0xF0], r15",
+        "mov rax, [rsp]",
+        "mov [rcx + 0xF8], rax",
+        "movups [rcx + 0x1A0], xmm0",
+        "movups [rcx + 0x1B0], xmm1",
+        "movups [rcx + 0x1C0], xmm2",
+        "movups [rcx + 0x1D0], xmm3",
+        "movups [rcx + 0x1E0], xmm4",
+        "movups [rcx + 0x1F0], xmm5",
+        "movups [rcx + 0x200], xmm6",
+        "movups [rcx + 0x210], xmm7",
+        "movups [rcx + 0x220], xmm8",
+        "movups [rcx + 0x230], xmm9",
+        "movups [rcx + 0x240], xmm10",
+        "movups [rcx + 0x250], xmm11",
+        "movups [rcx + 0x260], xmm12",
+        "movups [rcx + 0x270], xmm13",
+        "movups [rcx + 0x280], xmm14",
+        "movups [rcx + 0x290], xmm15",
+        "stmxcsr [rcx + 0x34]",
+        "stmxcsr [rcx + 0x118]",
+        "pushfq",
+        "pop rax",
+        "mov [rcx + 0x44], eax",
+        // CONTEXT_AMD64 | CONTROL | INTEGER | FLOATING_POINT
+        "mov dword ptr [rcx + 0x30], 0x10000B",
+        "mov rax, [rcx + 0x78]",
+        "ret",
+    )
+}
+
+/// Loads every register from `context` and continues at `context.Rip`.
+#[unsafe(naked)]
+pub(crate) unsafe extern "win64" fn restore_context(_context: *const CONTEXT) -> ! {
+    std::arch::naked_asm!(
+        "movups xmm0, [rcx + 0x1A0]",
+        "movups xmm1, [rcx + 0x1B0]",
+        "movups xmm2, [rcx + 0x1C0]",
+        "movups xmm3, [rcx + 0x1D0]",
+        "movups xmm4, [rcx + 0x1E0]",
+        "movups xmm5, [rcx + 0x1F0]",
+        "movups xmm6, [rcx + 0x200]",
+        "movups xmm7, [rcx + 0x210]",
+        "movups xmm8, [rcx + 0x220]",
+        "movups xmm9, [rcx + 0x230]",
+        "movups xmm10, [rcx + 0x240]",
+        "movups xmm11, [rcx + 0x250]",
+        "movups xmm12, [rcx + 0x260]",
+        "movups xmm13, [rcx + 0x270]",
+        "movups xmm14, [rcx + 0x280]",
+        "movups xmm15, [rcx + 0x290]",
+        "mov rax, [rcx + 0x78]",
+        "mov rdx, [rcx + 0x88]",
+        "mov rbx, [rcx + 0x90]",
+        "mov rbp, [rcx + 0xA0]",
+        "mov rsi, [rcx + 0xA8]",
+        "mov rdi, [rcx + 0xB0]",
+        "mov r8, [rcx + 0xB8]",
+        "mov r9, [rcx + 0xC0]",
+        "mov r10, [rcx + 0xC8]",
+        "mov r11, [rcx + 0xD0]",
+        "mov r12, [rcx + 0xD8]",
+        "mov r13, [rcx + 0xE0]",
+        "mov r14, [rcx + 0xE8]",
+        "mov r15, [rcx + 0xF0]",
+        // The target frame is above us, so pushing its return address onto
+        // it cannot overwrite the context we are reading from
+        "mov rsp, [rcx + 0x98]",
+        "push qword ptr [rcx + 0xF8]",
+        "mov rcx, [rcx + 0x80]",
+        "ret",
+    )
+}
+
+/// Unwinds to `target_frame` and continues at `target_ip`, capturing the
+/// caller's registers first so the walk can start from them.
+#[unsafe(naked)]
+pub unsafe extern "win64" fn RtlUnwindEx(
+    _target_frame: *mut c_void,
+    _target_ip: *mut c_void,
+    _exception_record: *mut c_void,
+    _return_value: *mut c_void,
+    _context: *mut CONTEXT,
+    _history_table: *mut c_void,
+) {
+    std::arch::naked_asm!(
+        // Shadow space, the fifth argument, then a 16-byte aligned context
+        "sub rsp, 0x508",
+        "mov [rsp + 0x30], rcx",
+        "mov [rsp + 0x38], rdx",
+        "mov [rsp + 0x40], r8",
+        "mov [rsp + 0x48], r9",
+        "lea rcx, [rsp + 0x30]",
+        "call {capture}",
+        "lea rax, [rsp + 0x510]",
+        "mov [rsp + 0x30 + 0x98], rax",
+        "mov rax, [rsp + 0x508]",
+        "mov [rsp + 0x30 + 0xF8], rax",
+        "mov rcx, [rsp + 0x30]",
+        "mov rdx, [rsp + 0x38]",
+        "mov r8, [rsp + 0x40]",
+        "mov r9, [rsp + 0x48]",
+        "lea rax, [rsp + 0x30]",
+        "mov [rsp + 0x20], rax",
+        "call {unwind}",
+        "ud2",
+        capture = sym RtlCaptureContext,
+        unwind = sym eh::unwind_ex,
+    )
+}
+
+import_fn! {
     fn RtlLookupFunctionEntry(
-        _pc: u64,
-        _image_base: *mut u64,
+        pc: u64,
+        image_base: *mut u64,
         _history_table: *mut c_void,
     ) -> *mut RUNTIME_FUNCTION {
-        trace_call!("ntdll!RtlLookupFunctionEntry", "pc=0x{:x}", _pc);
-        panic!("ntdll!RtlLookupFunctionEntry not implemented");
+        trace_call!("ntdll!RtlLookupFunctionEntry", "pc=0x{:x}", pc);
+        match lookup_function_entry(pc) {
+            Some((entry, base)) => {
+                *image_base = base;
+                entry
+            }
+            None => std::ptr::null_mut(),
+        }
     }
 
     fn RtlVirtualUnwind(
-        _handler_type: u32,
-        _image_base: u64,
-        _control_pc: u64,
-        _function_entry: *mut RUNTIME_FUNCTION,
-        _context: *mut CONTEXT,
-        _handler_data: *mut *mut c_void,
-        _establisher_frame: *mut u64,
+        handler_type: u32,
+        image_base: u64,
+        control_pc: u64,
+        function_entry: *mut RUNTIME_FUNCTION,
+        context: *mut CONTEXT,
+        handler_data: *mut *mut c_void,
+        establisher_frame: *mut u64,
         _context_pointers: *mut c_void,
     ) -> *mut c_void {
-        trace_call!("ntdll!RtlVirtualUnwind");
-        panic!("ntdll!RtlVirtualUnwind not implemented");
+        trace_call!("ntdll!RtlVirtualUnwind", "pc=0x{:x}", control_pc);
+        let handler = virtual_unwind(
+            handler_type,
+            image_base,
+            control_pc,
+            function_entry,
+            &mut *context,
+            &mut *handler_data,
+            &mut *establisher_frame,
+        );
+        handler.unwrap_or(0) as *mut c_void
     }
+}
 
-    fn RtlUnwindEx(
-        _target_frame: *mut c_void,
-        _target_ip: *mut c_void,
-        _exception_record: *mut c_void,
-        _return_value: *mut c_void,
-        _context: *mut CONTEXT,
-        _history_table: *mut c_void,
-    ) {
-        trace_call!("ntdll!RtlUnwindEx");
-        std::process::abort();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(offset: u8, op: u8, op_info: u8) -> u16 {
+        offset as u16 | (op as u16) << 8 | (op_info as u16) << 12
+    }
+
+    /// Builds an UNWIND_INFO followed by `tail`, the handler or chained entry.
+    fn unwind_info(flags: u8, prolog_size: u8, frame: u8, codes: &[u16], tail: &[u32]) -> Vec<u32> {
+        let mut bytes = vec![1 | flags << 3, prolog_size, codes.len() as u8, frame];
+        for code in codes {
+            bytes.extend(code.to_le_bytes());
+        }
+        if codes.len() % 2 == 1 {
+            bytes.extend([0, 0]);
+        }
+        let mut words: Vec<u32> = bytes
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        words.extend(tail);
+        words
+    }
+
+    /// An image holding one RUNTIME_FUNCTION at RVA 0 for 0x1000..0x1100,
+    /// whose UNWIND_INFO follows it at RVA 12.
+    fn image(infos: &[Vec<u32>]) -> Vec<u32> {
+        let mut image = vec![0x1000, 0x1100, 12];
+        for info in infos {
+            image.extend(info);
+        }
+        image
+    }
+
+    /// Unwinds from `offset` into the image's function.
+    unsafe fn unwind(
+        image: &[u32],
+        offset: u64,
+        handler_type: u32,
+        context: &mut CONTEXT,
+    ) -> (Option<u64>, *mut c_void, u64) {
+        let base = image.as_ptr() as u64;
+        let mut handler_data = std::ptr::null_mut();
+        let mut establisher_frame = 0;
+        let handler = virtual_unwind(
+            handler_type,
+            base,
+            base + 0x1000 + offset,
+            image.as_ptr() as *const RUNTIME_FUNCTION,
+            context,
+            &mut handler_data,
+            &mut establisher_frame,
+        );
+        (handler, handler_data, establisher_frame)
+    }
+
+    #[test]
+    fn test_code_slots() {
+        assert_eq!(code_slots(UWOP_PUSH_NONVOL, 3), 1);
+        assert_eq!(code_slots(UWOP_ALLOC_SMALL, 15), 1);
+        assert_eq!(code_slots(UWOP_SET_FPREG, 0), 1);
+        assert_eq!(code_slots(UWOP_PUSH_MACHFRAME, 1), 1);
+        assert_eq!(code_slots(UWOP_ALLOC_LARGE, 0), 2);
+        assert_eq!(code_slots(UWOP_ALLOC_LARGE, 1), 3);
+        assert_eq!(code_slots(UWOP_SAVE_NONVOL, 6), 2);
+        assert_eq!(code_slots(UWOP_SAVE_XMM128, 6), 2);
+        assert_eq!(code_slots(UWOP_EPILOG, 0), 2);
+        assert_eq!(code_slots(UWOP_SAVE_NONVOL_FAR, 6), 3);
+        assert_eq!(code_slots(UWOP_SAVE_XMM128_FAR, 6), 3);
+    }
+
+    // push rbx; push rbp; sub rsp, 0x20; lea rbp, [rsp + 0x10];
+    // mov [rsp + 8], rsi
+    fn frame_pointer_function(flags: u8, tail: &[u32]) -> Vec<u32> {
+        let codes = [
+            code(16, UWOP_SAVE_NONVOL, 6),
+            1,
+            code(11, UWOP_SET_FPREG, 0),
+            code(6, UWOP_ALLOC_SMALL, 3),
+            code(2, UWOP_PUSH_NONVOL, 5),
+            code(1, UWOP_PUSH_NONVOL, 3),
+        ];
+        image(&[unwind_info(flags, 16, 5 | 1 << 4, &codes, tail)])
+    }
+
+    #[test]
+    fn test_virtual_unwind_frame_pointer() {
+        let image = frame_pointer_function(0, &[]);
+        let stack: [u64; 8] = [0, 0x5151, 0, 0, 0xB0B0, 0xB1B1, 0x4242, 0];
+        let frame = stack.as_ptr() as u64;
+
+        let mut context = CONTEXT::zeroed();
+        // Only the frame pointer locates the frame once the prolog has run
+        context.Rsp = frame - 0x40;
+        context.Rbp = frame + 0x10;
+        let (handler, _, establisher_frame) = unsafe { unwind(&image, 0x20, 0, &mut context) };
+        assert_eq!(handler, None);
+        assert_eq!(establisher_frame, frame);
+        assert_eq!(context.Rip, 0x4242);
+        assert_eq!(context.Rsp, frame + 7 * 8);
+        assert_eq!(context.Rbp, 0xB0B0);
+        assert_eq!(context.Rbx, 0xB1B1);
+        assert_eq!(context.Rsi, 0x5151);
+    }
+
+    #[test]
+    fn test_virtual_unwind_in_prolog() {
+        let image = frame_pointer_function(UNW_FLAG_EHANDLER as u8, &[0x2000, 7]);
+        // Stopped after both pushes: only they are undone
+        let stack: [u64; 3] = [0xB0B0, 0xB1B1, 0x4242];
+        let mut context = CONTEXT::zeroed();
+        context.Rsp = stack.as_ptr() as u64;
+        context.Rbp = 0x1234;
+        context.Rsi = 0x5678;
+        let (handler, _, _) = unsafe { unwind(&image, 2, UNW_FLAG_EHANDLER, &mut context) };
+        assert_eq!(
+            handler, None,
+            "no handler runs for a frame still in its prolog"
+        );
+        assert_eq!(context.Rip, 0x4242);
+        assert_eq!(context.Rsp, stack.as_ptr() as u64 + 24);
+        assert_eq!(context.Rbp, 0xB0B0);
+        assert_eq!(context.Rbx, 0xB1B1);
+        assert_eq!(context.Rsi, 0x5678);
+    }
+
+    #[test]
+    fn test_virtual_unwind_handler() {
+        let image = frame_pointer_function(UNW_FLAG_EHANDLER as u8, &[0x2000, 7]);
+        let stack: [u64; 8] = [0, 0, 0, 0, 0, 0, 0x4242, 0];
+        let base = image.as_ptr() as u64;
+        let mut context = CONTEXT::zeroed();
+        context.Rbp = stack.as_ptr() as u64 + 0x10;
+
+        let (handler, handler_data, _) =
+            unsafe { unwind(&image, 0x20, UNW_FLAG_EHANDLER, &mut context) };
+        assert_eq!(handler, Some(base + 0x2000));
+        assert_eq!(unsafe { *(handler_data as *const u32) }, 7);
+
+        // Unwinding looks for termination handlers only
+        let mut context = CONTEXT::zeroed();
+        context.Rbp = stack.as_ptr() as u64 + 0x10;
+        let (handler, _, _) = unsafe { unwind(&image, 0x20, UNW_FLAG_UHANDLER, &mut context) };
+        assert_eq!(handler, None);
+    }
+
+    #[test]
+    fn test_virtual_unwind_chained() {
+        // The primary entry allocates 0x80 bytes, then 0x10000 more; its
+        // chained entry pushed r12 before that
+        let primary_codes = [
+            code(20, UWOP_ALLOC_LARGE, 1),
+            0,
+            1,
+            code(9, UWOP_ALLOC_LARGE, 0),
+            0x80 / 8,
+        ];
+        let primary_len = unwind_info(0, 0, 0, &primary_codes, &[0; 3]).len() as u32;
+        let chained_rva = 12 + primary_len * 4;
+        let primary = unwind_info(
+            UNW_FLAG_CHAININFO | UNW_FLAG_EHANDLER as u8,
+            20,
+            0,
+            &primary_codes,
+            &[0x1000, 0x1100, chained_rva],
+        );
+        let chained = unwind_info(0, 2, 0, &[code(2, UWOP_PUSH_NONVOL, 12)], &[]);
+        let image = image(&[primary, chained]);
+
+        let mut stack = vec![0u64; (0x10080 + 24) / 8];
+        let top = stack.len();
+        stack[top - 3] = 0xC1C1;
+        stack[top - 2] = 0x4242;
+        let mut context = CONTEXT::zeroed();
+        context.Rsp = stack.as_ptr() as u64;
+        let (handler, _, establisher_frame) =
+            unsafe { unwind(&image, 0x40, UNW_FLAG_EHANDLER, &mut context) };
+        // Only unchained entries carry a handler
+        assert_eq!(handler, None);
+        assert_eq!(establisher_frame, stack.as_ptr() as u64);
+        assert_eq!(context.R12, 0xC1C1);
+        assert_eq!(context.Rip, 0x4242);
+        assert_eq!(context.Rsp, stack.as_ptr() as u64 + 0x10080 + 16);
     }
 }
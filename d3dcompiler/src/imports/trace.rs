@@ -0,0 +1,149 @@
+//! Import call tracing, enabled by the `trace-imports` feature
+//!
+//! Every import thunk passes the DLL's return address to `set_caller`
+//! before jumping to its implementation, and `trace_call!` turns it into an
+//! [`ImportCall`]. Calls are only formatted and recorded while a ring buffer
+//! or a hook is installed.
+
+use super::*;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+
+/// One call the DLL made into an import
+#[derive(Clone, Debug)]
+pub struct ImportCall {
+    /// Original (unrelocated) VA of the instruction after the call
+    pub caller: usize,
+    /// Import name, such as `kernel32!HeapAlloc`
+    pub name: &'static str,
+    /// Formatted arguments, empty for imports that take none. Pointers and
+    /// handles print as addresses and flags in hex. Only a few imports, such
+    /// as `CreateFileA`, print the string an argument points to.
+    pub args: String,
+}
+
+impl std::fmt::Display for ImportCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x} {}", self.caller, self.name)?;
+        if !self.args.is_empty() {
+            write!(f, " - {}", self.args)?;
+        }
+        Ok(())
+    }
+}
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static BUFFER: Mutex<VecDeque<ImportCall>> = Mutex::new(VecDeque::new());
+static HOOK: Mutex<Option<fn(&ImportCall)>> = Mutex::new(None);
+
+fn update_tracing() {
+    let hooked = HOOK.lock().unwrap_or_else(|e| e.into_inner()).is_some();
+    TRACING.store(
+        hooked || CAPACITY.load(Ordering::SeqCst) > 0,
+        Ordering::SeqCst,
+    );
+}
+
+pub fn set_import_trace_capacity(capacity: usize) {
+    CAPACITY.store(capacity, Ordering::SeqCst);
+    let mut buffer = BUFFER.lock().unwrap_or_else(|e| e.into_inner());
+    while buffer.len() > capacity {
+        buffer.pop_front();
+    }
+    drop(buffer);
+    update_tracing();
+}
+
+pub fn set_import_trace_hook(hook: Option<fn(&ImportCall)>) {
+    *HOOK.lock().unwrap_or_else(|e| e.into_inner()) = hook;
+    update_tracing();
+}
+
+pub fn take_import_trace() -> Vec<ImportCall> {
+    BUFFER
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .drain(..)
+        .collect()
+}
+
+thread_local! {
+    // Return address of the current import call, set by its thunk
+    static CALLER: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Called by every import thunk with the DLL's return address.
+///
+/// Kept in host TLS rather than the TIB so shims called on threads that
+/// never ran `setup_tib` don't write through a null GS base.
+pub(crate) extern "win64" fn set_caller(caller: usize) {
+    CALLER.with(|slot| slot.set(caller));
+}
+
+pub fn record(name: &'static str, args: impl FnOnce() -> String) {
+    if !TRACING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let call = ImportCall {
+        caller: to_original_va(CALLER.with(Cell::get)),
+        name,
+        args: args(),
+    };
+
+    let hook = *HOOK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(hook) = hook {
+        hook(&call);
+    }
+
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    if capacity > 0 {
+        let mut buffer = BUFFER.lock().unwrap_or_else(|e| e.into_inner());
+        if buffer.len() >= capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(call);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imports::kernel32::{
+        GetProcessHeap, HeapAlloc, HeapFree, IsProcessorFeaturePresent,
+    };
+
+    #[test]
+    fn test_trace_without_tib() {
+        set_import_trace_capacity(1024);
+        // A fresh thread has never run setup_tib, so its GS base is 0
+        std::thread::spawn(|| unsafe {
+            assert_eq!(IsProcessorFeaturePresent(10), 1);
+            let heap = GetProcessHeap();
+            let block = HeapAlloc(heap, 0x08, 4321);
+            assert!(!block.is_null());
+            assert_eq!(*(block as *const u8), 0);
+            assert_eq!(HeapFree(heap, 0, block), 1);
+        })
+        .join()
+        .unwrap();
+
+        // Other tests call shims concurrently, so look for these calls only
+        let calls = take_import_trace();
+        set_import_trace_capacity(0);
+        let traced = |name: &str, args: &str| {
+            calls
+                .iter()
+                .find(|call| call.name == name && call.args == args)
+                .unwrap_or_else(|| panic!("{name} {args} not traced"))
+                .caller
+        };
+        assert_ne!(
+            traced("kernel32!IsProcessorFeaturePresent", "feature=10"),
+            0
+        );
+        assert_ne!(traced("kernel32!HeapAlloc", "size=4321"), 0);
+    }
+}
@@ -0,0 +1,99 @@
+//! Trap stubs for imports the loader cannot resolve
+//!
+//! Every unresolved IAT slot gets its own small stub that loads a pointer to
+//! the import's name into `r10` and jumps to [`unresolved_import`]. A call
+//! through the slot then reports which import was missing and where it was
+//! called from, instead of jumping to a garbage address.
+
+use super::*;
+
+/// The DLL and function name of an unresolved import
+pub struct Trap {
+    pub dll: String,
+    pub name: String,
+}
+
+// mov r10, imm64 ; mov r11, imm64 ; jmp r11, padded with int3
+const STUB_SIZE: usize = 32;
+
+/// Builds one trap stub per entry of `traps` and returns their addresses.
+///
+/// The stubs and the names they report are never freed.
+pub fn build_traps(traps: Vec<Trap>) -> Result<Vec<usize>> {
+    if traps.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let traps: &'static [Trap] = Vec::leak(traps);
+    let size = traps.len() * STUB_SIZE;
+    let code = unsafe {
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(D3DCompilerError::LoadError(
+                "mmap failed for import trap stubs".into(),
+            ));
+        }
+        std::slice::from_raw_parts_mut(ptr as *mut u8, size)
+    };
+
+    let handler = unresolved_import as *const () as u64;
+    let mut addresses = Vec::with_capacity(traps.len());
+    for (trap, stub) in traps.iter().zip(code.chunks_exact_mut(STUB_SIZE)) {
+        stub.fill(0xCC);
+        stub[0..2].copy_from_slice(&[0x49, 0xBA]);
+        stub[2..10].copy_from_slice(&(trap as *const Trap as u64).to_le_bytes());
+        stub[10..12].copy_from_slice(&[0x49, 0xBB]);
+        stub[12..20].copy_from_slice(&handler.to_le_bytes());
+        stub[20..23].copy_from_slice(&[0x41, 0xFF, 0xE3]);
+        addresses.push(stub.as_ptr() as usize);
+    }
+
+    // Without execute permission a missing import would crash unreported
+    unsafe {
+        let ptr = code.as_mut_ptr() as *mut c_void;
+        if libc::mprotect(ptr, size, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+            let error = std::io::Error::last_os_error();
+            libc::munmap(ptr, size);
+            return Err(D3DCompilerError::LoadError(format!(
+                "mprotect failed for import trap stubs: {}",
+                error
+            )));
+        }
+    }
+    Ok(addresses)
+}
+
+/// Entered from a trap stub with the [`Trap`] in `r10` and the DLL's return
+/// address on top of the stack
+#[unsafe(naked)]
+unsafe extern "win64" fn unresolved_import() -> ! {
+    std::arch::naked_asm!(
+        "mov rcx, r10",
+        "mov rdx, [rsp]",
+        "and rsp, -16",
+        "sub rsp, 0x20",
+        "call {report}",
+        "ud2",
+        report = sym report_unresolved_import,
+    )
+}
+
+unsafe extern "win64" fn report_unresolved_import(trap: *const Trap, caller: usize) -> ! {
+    let trap = &*trap;
+    let dll = find_image(caller).map_or_else(|| DLL_NAME.to_string(), |image| image.name.clone());
+    eprintln!(
+        "[d3dcompiler] {} called unresolved import {}!{} from 0x{:x}",
+        dll,
+        trap.dll,
+        trap.name,
+        to_original_va(caller)
+    );
+    std::process::abort()
+}
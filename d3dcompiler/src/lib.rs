@@ -11,7 +11,12 @@
 #![allow(clippy::missing_transmute_annotations)]
 #![allow(unsafe_op_in_unsafe_fn)]
 
+#[cfg(feature = "crash-handler")]
+mod crash;
+mod dllmain;
 mod imports;
+mod stack;
+mod version;
 
 macro_rules! debug_log {
     ($($arg:tt)*) => {
@@ -37,19 +42,47 @@ macro_rules! debug_log_return {
 
 use d3dcompiler_proc::com_wrapper;
 use std::ffi::c_void;
-use std::sync::OnceLock;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum D3DCompilerError {
     #[error("Failed to load DLL: {0}")]
     LoadError(String),
-    #[error("Function not found: {0}")]
+    #[error("DLL does not export {0}")]
     FunctionNotFound(String),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("PE parse error: {0}")]
     ParseError(String),
+    #[error("Failed to read {}: {source}", path.display())]
+    ReadFailed {
+        path: std::path::PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Failed to map {size} bytes for the image: {source}")]
+    MapFailed {
+        size: usize,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{DLL_NAME} not found, tried: {}", join_paths(tried))]
+    DllNotFound { tried: Vec<std::path::PathBuf> },
+    #[error("{dll} imports functions that are not implemented: {}", imports.join(", "))]
+    MissingImports { dll: String, imports: Vec<String> },
+    #[error("{dll} failed to initialize: DllMain returned FALSE")]
+    AttachFailed { dll: String },
+}
+
+fn join_paths(paths: &[std::path::PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 pub type Result<T> = std::result::Result<T, D3DCompilerError>;
@@ -64,6 +97,7 @@ pub type LPVOID = *mut c_void;
 
 pub const S_OK: HRESULT = 0;
 pub const E_FAIL: HRESULT = 0x80004005u32 as i32;
+pub const E_NOTIMPL: HRESULT = 0x80004001u32 as i32;
 
 // D3D11 Shader Reflection descriptor types
 #[repr(C)]
@@ -354,11 +388,127 @@ static INCLUDE_WRAPPER_VTABLE: Win64IncludeVtbl = Win64IncludeVtbl {
     Close: include_close_thunk,
 };
 
-// Wrap a user's C ABI include in a win64 ABI wrapper for the DLL
-unsafe fn wrap_include(inner: *mut ID3DInclude) -> *mut Win64Include {
+// ============================================================================
+// D3D_COMPILE_STANDARD_FILE_INCLUDE (built-in file system include handler)
+// ============================================================================
+
+/// Sentinel `pInclude` value requesting the compiler's default file include handler
+pub const D3D_COMPILE_STANDARD_FILE_INCLUDE: *mut ID3DInclude = std::ptr::without_provenance_mut(1);
+
+// Default include handler used for D3D_COMPILE_STANDARD_FILE_INCLUDE. Includes are
+// resolved relative to the including file, starting from the source name's directory.
+#[repr(C)]
+struct StandardFileInclude {
+    vtable: *const Win64IncludeVtbl,
+    source_dir: std::path::PathBuf,
+    // Open buffers keyed by data pointer, with the path they were read from
+    open_files: std::collections::HashMap<usize, (Vec<u8>, std::path::PathBuf)>,
+}
+
+unsafe extern "win64" fn standard_include_open(
+    this: *mut Win64Include,
+    _include_type: u32,
+    filename: LPCSTR,
+    parent_data: *const c_void,
+    data_out: *mut *const c_void,
+    bytes_out: *mut UINT,
+) -> HRESULT {
+    debug_log!(
+        "[INCLUDE] StandardOpen(this={:?}, type={}, filename={:?})",
+        this,
+        _include_type,
+        filename
+    );
+    if filename.is_null() || data_out.is_null() || bytes_out.is_null() {
+        return E_FAIL;
+    }
+    let include = &mut *(this as *mut StandardFileInclude);
+
+    // Windows sources use backslash separators
+    let name = std::ffi::CStr::from_ptr(filename)
+        .to_string_lossy()
+        .replace('\\', "/");
+    let dir = include
+        .open_files
+        .get(&(parent_data as usize))
+        .and_then(|(_, path)| path.parent())
+        .unwrap_or(&include.source_dir);
+    let path = dir.join(name);
+
+    let Ok(mut data) = std::fs::read(&path) else {
+        return E_FAIL;
+    };
+    let Ok(len) = UINT::try_from(data.len()) else {
+        return E_FAIL;
+    };
+    // Force an allocation so every open buffer has a distinct address
+    data.reserve(1);
+    let ptr = data.as_ptr() as *const c_void;
+    include.open_files.insert(ptr as usize, (data, path));
+    *data_out = ptr;
+    *bytes_out = len;
+    S_OK
+}
+
+unsafe extern "win64" fn standard_include_close(
+    this: *mut Win64Include,
+    data: *const c_void,
+) -> HRESULT {
+    debug_log!("[INCLUDE] StandardClose(this={:?}, data={:?})", this, data);
+    let include = &mut *(this as *mut StandardFileInclude);
+    include.open_files.remove(&(data as usize));
+    S_OK
+}
+
+static STANDARD_INCLUDE_VTABLE: Win64IncludeVtbl = Win64IncludeVtbl {
+    Open: standard_include_open,
+    Close: standard_include_close,
+};
+
+// Directory containing a narrow source name (empty means the current directory)
+unsafe fn source_dir_from_name(name: LPCSTR) -> std::path::PathBuf {
+    if name.is_null() {
+        return std::path::PathBuf::new();
+    }
+    let name = std::ffi::CStr::from_ptr(name)
+        .to_string_lossy()
+        .replace('\\', "/");
+    std::path::Path::new(&name)
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default()
+}
+
+// Directory containing a wide file name (empty means the current directory)
+unsafe fn source_dir_from_wide_name(name: LPCWSTR) -> std::path::PathBuf {
+    if name.is_null() {
+        return std::path::PathBuf::new();
+    }
+    let len = (0..).take_while(|&i| *name.add(i) != 0).count();
+    let name = String::from_utf16_lossy(std::slice::from_raw_parts(name, len)).replace('\\', "/");
+    std::path::Path::new(&name)
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default()
+}
+
+// Wrap a user's C ABI include in a win64 ABI wrapper for the DLL. The standard
+// include sentinel is replaced with the built-in handler rooted at `source_dir`.
+unsafe fn wrap_include(
+    inner: *mut ID3DInclude,
+    source_dir: impl FnOnce() -> std::path::PathBuf,
+) -> *mut Win64Include {
     if inner.is_null() {
         return std::ptr::null_mut();
     }
+    if inner == D3D_COMPILE_STANDARD_FILE_INCLUDE {
+        let include = Box::new(StandardFileInclude {
+            vtable: &STANDARD_INCLUDE_VTABLE,
+            source_dir: source_dir(),
+            open_files: std::collections::HashMap::new(),
+        });
+        return Box::into_raw(include) as *mut Win64Include;
+    }
     let wrapper = Box::new(IncludeWrapper {
         vtable: &INCLUDE_WRAPPER_VTABLE,
         inner,
@@ -368,7 +518,12 @@ unsafe fn wrap_include(inner: *mut ID3DInclude) -> *mut Win64Include {
 
 // Free the include wrapper (call after DLL function returns)
 unsafe fn free_include_wrapper(wrapper: *mut Win64Include) {
-    if !wrapper.is_null() {
+    if wrapper.is_null() {
+        return;
+    }
+    if std::ptr::eq((*wrapper).vtable, &STANDARD_INCLUDE_VTABLE) {
+        drop(Box::from_raw(wrapper as *mut StandardFileInclude));
+    } else {
         drop(Box::from_raw(wrapper as *mut IncludeWrapper));
     }
 }
@@ -481,56 +636,717 @@ type PFN_D3DSetBlobPart = unsafe extern "win64" fn(
     ppNewShader: *mut *mut Win64Blob,
 ) -> HRESULT;
 
-// Global state for loaded DLL
-struct D3DCompilerState {
+/// A loaded copy of `d3dcompiler_47.dll`, or of an older version such as
+/// `d3dcompiler_43.dll`
+///
+/// The C exports of this crate forward to a default instance loaded on first
+/// use, see [`Compiler::global`]. Instances loaded explicitly can sit side by
+/// side with it. Dropping an instance leaves its image mapped, because blobs
+/// and reflection objects it created may outlive it; call
+/// [`unload`](Compiler::unload) to release it.
+pub struct Compiler {
     #[cfg(unix)]
     _mmap: *mut u8,
     #[cfg(unix)]
     _mmap_size: usize,
 
-    // Function pointers
+    /// Module name from the export directory, such as `D3DCOMPILER_47.dll`
+    name: String,
+    info: CompilerInfo,
+    load_report: LoadReport,
+
+    // Function pointers. Older versions lack the optional ones, and their
+    // methods then return E_NOTIMPL.
     d3d_compile: PFN_D3DCompile,
-    d3d_compile2: PFN_D3DCompile2,
-    d3d_compile_from_file: PFN_D3DCompileFromFile,
+    d3d_compile2: Option<PFN_D3DCompile2>,
+    d3d_compile_from_file: Option<PFN_D3DCompileFromFile>,
     d3d_preprocess: PFN_D3DPreprocess,
     d3d_disassemble: PFN_D3DDisassemble,
     d3d_create_blob: PFN_D3DCreateBlob,
     d3d_reflect: PFN_D3DReflect,
     d3d_strip_shader: PFN_D3DStripShader,
     d3d_get_blob_part: PFN_D3DGetBlobPart,
-    d3d_set_blob_part: PFN_D3DSetBlobPart,
+    d3d_set_blob_part: Option<PFN_D3DSetBlobPart>,
 }
 
-unsafe impl Send for D3DCompilerState {}
-unsafe impl Sync for D3DCompilerState {}
+unsafe impl Send for Compiler {}
+unsafe impl Sync for Compiler {}
+
+static DEFAULT: OnceLock<Result<Compiler>> = OnceLock::new();
 
-static STATE: OnceLock<Result<D3DCompilerState>> = OnceLock::new();
+#[cfg(feature = "embed-dll")]
+static EMBEDDED_DLL: &[u8] =
+    include_bytes_aligned::include_bytes_aligned!(8, "../../d3dcompiler_47.dll");
 
-// Expected hash for verification (update with actual hash)
 static DLL_NAME: &str = "d3dcompiler_47.dll";
 
-fn get_dll_path() -> std::path::PathBuf {
-    // Look for DLL next to executable, or in current directory
-    if let Ok(exe) = std::env::current_exe() {
-        let path = exe.with_file_name(DLL_NAME);
-        if path.exists() {
-            return path;
+// Set with set_dll_path, searched after D3DCOMPILER_DLL
+static DLL_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Sets the DLL the default compiler loads from.
+///
+/// Searched after the `D3DCOMPILER_DLL` environment variable and before the
+/// built-in locations, see [`dll_search_paths`]. Only has an effect without
+/// the `embed-dll` feature, and before the default compiler is first used.
+pub fn set_dll_path(path: impl Into<PathBuf>) {
+    *DLL_PATH.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.into());
+}
+
+/// Returns the paths the default compiler looks for the DLL at, in order.
+///
+/// These are `D3DCOMPILER_DLL`, the [`set_dll_path`] setting, the directory
+/// of the shared library or executable this crate is linked into, the
+/// executable's directory, the current directory, and finally `system32` in
+/// `$WINEPREFIX` and `~/.wine`. A directory stands for the DLL inside it.
+pub fn dll_search_paths() -> Vec<PathBuf> {
+    let env_path = std::env::var_os("D3DCOMPILER_DLL").map(PathBuf::from);
+    let configured = DLL_PATH.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    search_paths(env_path, configured)
+}
+
+// dll_search_paths with the D3DCOMPILER_DLL value and set_dll_path setting
+// passed in
+fn search_paths(env_path: Option<PathBuf>, configured: Option<PathBuf>) -> Vec<PathBuf> {
+    let in_dir = |path: PathBuf| {
+        if path.is_dir() {
+            path.join(DLL_NAME)
+        } else {
+            path
         }
+    };
+
+    let mut paths = Vec::new();
+    if let Some(path) = env_path.filter(|p| !p.as_os_str().is_empty()) {
+        paths.push(in_dir(path));
+    }
+    if let Some(path) = configured {
+        paths.push(in_dir(path));
     }
-    std::path::PathBuf::from(DLL_NAME)
+    if let Some(dir) = linked_object_path().as_deref().and_then(Path::parent) {
+        paths.push(dir.join(DLL_NAME));
+    }
+    if let Some(dir) = std::env::current_exe()
+        .ok()
+        .as_deref()
+        .and_then(Path::parent)
+    {
+        paths.push(dir.join(DLL_NAME));
+    }
+    paths.push(PathBuf::from(DLL_NAME));
+    let prefixes = [
+        std::env::var_os("WINEPREFIX").map(PathBuf::from),
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(".wine")),
+    ];
+    for prefix in prefixes.into_iter().flatten() {
+        paths.push(prefix.join("drive_c/windows/system32").join(DLL_NAME));
+    }
+
+    let mut unique = Vec::new();
+    for path in paths {
+        if !unique.contains(&path) {
+            unique.push(path);
+        }
+    }
+    unique
 }
 
-// Initialize the compiler - call this before using any functions
-unsafe fn init() -> &'static Result<D3DCompilerState> {
-    use std::sync::Mutex;
-    static INIT_ERROR: Mutex<Option<String>> = Mutex::new(None);
+// Path of the shared library or executable containing this code
+fn linked_object_path() -> Option<PathBuf> {
+    unsafe {
+        let mut info: libc::Dl_info = std::mem::zeroed();
+        if libc::dladdr(linked_object_path as *const c_void, &mut info) == 0
+            || info.dli_fname.is_null()
+        {
+            return None;
+        }
+        let name = std::ffi::CStr::from_ptr(info.dli_fname);
+        Some(PathBuf::from(std::ffi::OsStr::from_bytes(name.to_bytes())))
+    }
+}
+
+fn find_dll() -> Result<PathBuf> {
+    let tried = dll_search_paths();
+    match tried.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.clone()),
+        None => Err(D3DCompilerError::DllNotFound { tried }),
+    }
+}
 
+// Initialize the default compiler - call this before using any functions
+unsafe fn init() -> &'static Result<Compiler> {
     unsafe { linux_loader::setup_tib() }
 
-    STATE.get_or_init(linux_loader::load_dll)
+    DEFAULT.get_or_init(|| {
+        #[cfg(feature = "embed-dll")]
+        return Compiler::load_from_bytes(EMBEDDED_DLL);
+
+        #[cfg(not(feature = "embed-dll"))]
+        return Compiler::load_from_path(find_dll()?);
+    })
 }
 
-// Public API functions that forward to the loaded DLL and wrap returned blobs
+/// Prepares the calling thread for calls into the DLL.
+///
+/// Points the GS segment at a per-thread Windows thread information block.
+/// The exported functions do this on first use, so this only matters for
+/// worker threads that want to pay the cost up front.
+pub fn setup_thread() {
+    unsafe { linux_loader::setup_tib() }
+}
+
+/// Runs calls into the DLL on a helper thread with a `size`-byte stack when
+/// the calling thread has less than `size` bytes of stack left.
+///
+/// The compiler can recurse deeply on large shaders, past the 2 MB stacks of
+/// typical worker threads. Each calling thread gets its own helper, which
+/// exits with it, and waits while the helper makes the call. Pass `None` to
+/// always call on the caller's stack, the default.
+///
+/// # Safety
+///
+/// Include handlers and import trace hooks then run on the helper rather
+/// than the calling thread, even when they are not `Send`. Until the setting
+/// is turned off, every include handler passed to the DLL must be safe to
+/// call from another thread while its own thread waits, and must not depend
+/// on that thread's thread-locals.
+pub unsafe fn set_large_stack_calls(size: Option<usize>) {
+    stack::set_large_stack_calls(size)
+}
+
+/// Limits the bytes the DLL may hold through its allocation imports.
+///
+/// Covers `malloc`, `HeapAlloc`, `LocalAlloc` and `VirtualAlloc`. Counting
+/// restarts from zero on every call, and the limit is process-wide. When an
+/// allocation would go over `limit`, `on_exceeded` runs and the allocation
+/// then fails with NULL. Pass `None` to remove the limit.
+///
+/// The count covers every thread and every loaded instance together, and
+/// each tracked allocation goes through one global lock. It only measures a
+/// single compile in a process that runs nothing else, such as a worker
+/// helper; elsewhere, concurrent compiles are charged against one another.
+pub fn set_allocation_limit(limit: Option<usize>, on_exceeded: Option<fn()>) {
+    imports::set_allocation_limit(limit, on_exceeded)
+}
+
+/// Returns the SHA-1 of the loaded `d3dcompiler_47.dll`, loading it if needed.
+///
+/// Returns `None` if the DLL failed to load.
+pub fn dll_sha1() -> Option<[u8; 20]> {
+    Compiler::global().ok().map(Compiler::dll_sha1)
+}
+
+pub use version::{CompilerInfo, FileVersion, KNOWN_BUILDS, KnownBuild};
+
+/// Returns the identity of the default compiler's DLL, loading it if needed.
+pub fn compiler_info() -> std::result::Result<&'static CompilerInfo, &'static D3DCompilerError> {
+    Compiler::global().map(Compiler::info)
+}
+
+/// How an import of the DLL was resolved at load time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImportStatus {
+    /// Resolved to a working implementation
+    Implemented,
+    /// Resolved to a placeholder that panics when called
+    Stubbed,
+    /// Not resolved; calling it aborts with the import's name
+    Missing,
+}
+
+/// One entry of the DLL's import table
+#[derive(Clone, Debug)]
+pub struct ImportReport {
+    /// Lowercased name of the DLL the function is imported from
+    pub dll: String,
+    /// Name of the imported function
+    pub name: String,
+    /// How the import was resolved
+    pub status: ImportStatus,
+    /// RVA of the import's IAT slot
+    pub iat_rva: usize,
+}
+
+/// The imports of the loaded DLL and how each was resolved
+#[derive(Clone, Debug)]
+pub struct LoadReport {
+    /// Every named import, in import table order
+    pub imports: Vec<ImportReport>,
+}
+
+impl LoadReport {
+    /// Returns the imports with the given status
+    pub fn with_status(&self, status: ImportStatus) -> impl Iterator<Item = &ImportReport> {
+        self.imports
+            .iter()
+            .filter(move |import| import.status == status)
+    }
+}
+
+/// Returns how each import of the default compiler was resolved, loading it
+/// if needed.
+pub fn load_report() -> std::result::Result<&'static LoadReport, &'static D3DCompilerError> {
+    Compiler::global().map(Compiler::load_report)
+}
+
+#[cfg(feature = "trace-imports")]
+pub use imports::trace::ImportCall;
+
+/// Records the last `capacity` import calls the DLL makes, across all threads.
+///
+/// Pass 0 to stop recording. Shrinking the buffer drops the oldest calls.
+#[cfg(feature = "trace-imports")]
+pub fn set_import_trace_capacity(capacity: usize) {
+    imports::trace::set_import_trace_capacity(capacity)
+}
+
+/// Calls `hook` on the calling thread for every import call the DLL makes.
+///
+/// Runs whether or not the ring buffer is enabled. Pass `None` to remove it.
+#[cfg(feature = "trace-imports")]
+pub fn set_import_trace_hook(hook: Option<fn(&ImportCall)>) {
+    imports::trace::set_import_trace_hook(hook)
+}
+
+/// Removes and returns the recorded import calls, oldest first.
+#[cfg(feature = "trace-imports")]
+pub fn take_import_trace() -> Vec<ImportCall> {
+    imports::trace::take_import_trace()
+}
+
+fn read_dll(path: &std::path::Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|source| D3DCompilerError::ReadFailed {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Methods that call into a loaded DLL and wrap returned blobs. They take the
+// same arguments as the C exports of the same name.
+
+#[allow(clippy::too_many_arguments)]
+impl Compiler {
+    /// Loads the DLL at `path`.
+    pub fn load_from_path(path: impl AsRef<std::path::Path>) -> Result<Compiler> {
+        Self::load_from_bytes(&read_dll(path.as_ref())?)
+    }
+
+    /// Loads a DLL from its file contents.
+    pub fn load_from_bytes(dll: &[u8]) -> Result<Compiler> {
+        linux_loader::load_dll(dll, false)
+    }
+
+    /// Loads the DLL at `path`, refusing it if any import is missing.
+    ///
+    /// See [`Compiler::load_from_bytes_checked`].
+    pub fn load_from_path_checked(path: impl AsRef<std::path::Path>) -> Result<Compiler> {
+        Self::load_from_bytes_checked(&read_dll(path.as_ref())?)
+    }
+
+    /// Loads a DLL from its file contents, refusing it if any import is
+    /// missing.
+    ///
+    /// Fails with [`D3DCompilerError::MissingImports`] before DllMain runs,
+    /// so a DLL that [`check_imports`](Compiler::check_imports) would reject
+    /// never executes any of its code.
+    pub fn load_from_bytes_checked(dll: &[u8]) -> Result<Compiler> {
+        linux_loader::load_dll(dll, true)
+    }
+
+    /// Returns the default instance used by the C exports, loading it if needed.
+    ///
+    /// It comes from the embedded DLL with the `embed-dll` feature, and from
+    /// the first of [`dll_search_paths`] that exists otherwise. A failed load
+    /// is remembered and returned again.
+    pub fn global() -> std::result::Result<&'static Compiler, &'static D3DCompilerError> {
+        unsafe { init() }.as_ref()
+    }
+
+    /// Returns the SHA-1 of the DLL file this instance was loaded from.
+    pub fn dll_sha1(&self) -> [u8; 20] {
+        self.info.sha1
+    }
+
+    /// Returns the module name the DLL exports under, such as
+    /// `D3DCOMPILER_47.dll`.
+    pub fn dll_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the hash, file version and known build of this instance's DLL.
+    pub fn info(&self) -> &CompilerInfo {
+        &self.info
+    }
+
+    /// Unloads the DLL and releases its image.
+    ///
+    /// Sends `DLL_PROCESS_DETACH` to the TLS callbacks and DllMain, runs the
+    /// functions the DLL registered with `_onexit`, then unmaps the image.
+    /// The default instance lives in a static and is never unloaded.
+    ///
+    /// # Safety
+    ///
+    /// Nothing the DLL created may be used afterwards, including blobs and
+    /// reflection objects, and no other thread may be calling into it.
+    pub unsafe fn unload(self) {
+        linux_loader::setup_tib();
+        if let Some(image) = imports::find_image(self._mmap as usize) {
+            dllmain::process_detach(&image);
+            linux_loader::forget_image(&image);
+        }
+        libc::munmap(self._mmap as *mut c_void, self._mmap_size);
+    }
+
+    /// Returns how each import of this instance's DLL was resolved.
+    pub fn load_report(&self) -> &LoadReport {
+        &self.load_report
+    }
+
+    /// Fails with [`D3DCompilerError::MissingImports`] if any import of this
+    /// instance's DLL was left unresolved.
+    ///
+    /// A plain load succeeds either way, and calling a missing import aborts.
+    /// Older versions can import functions the shims don't cover yet, so
+    /// check before compiling with one, or load it with
+    /// [`load_from_path_checked`](Compiler::load_from_path_checked).
+    pub fn check_imports(&self) -> Result<()> {
+        let missing: Vec<String> = self
+            .load_report
+            .with_status(ImportStatus::Missing)
+            .map(|import| format!("{}!{}", import.dll, import.name))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(D3DCompilerError::MissingImports {
+            dll: self.name.clone(),
+            imports: missing,
+        })
+    }
+
+    pub unsafe fn D3DCompile(
+        &self,
+        pSrcData: *const c_void,
+        SrcDataSize: SIZE_T,
+        pSourceName: LPCSTR,
+        pDefines: *const D3D_SHADER_MACRO,
+        pInclude: *mut ID3DInclude,
+        pEntrypoint: LPCSTR,
+        pTarget: LPCSTR,
+        Flags1: UINT,
+        Flags2: UINT,
+        ppCode: *mut *mut ID3DBlob,
+        ppErrorMsgs: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DCompile");
+
+        // let src = slice::from_raw_parts(pSrcData.cast(), SrcDataSize);
+        // let src_str = String::from_utf8_lossy(&src);
+
+        // let name = std::ffi::CStr::from_ptr(pSourceName).to_string_lossy();
+        // let entry = std::ffi::CStr::from_ptr(pEntrypoint).to_string_lossy();
+
+        // eprintln!("COMPILING {name:?} {entry:?}");
+        // let path = format!("/tmp/shaders/{}.hlsl", name.replace('/', "_"));
+        // std::fs::write(&path, src).unwrap();
+        // println!("{} bytes => {path}", src.len());
+
+        let mut code: *mut Win64Blob = std::ptr::null_mut();
+        let mut errors: *mut Win64Blob = std::ptr::null_mut();
+        let wrapped_include = wrap_include(pInclude, || source_dir_from_name(pSourceName));
+        let result = stack::call(|| {
+            (self.d3d_compile)(
+                pSrcData,
+                SrcDataSize,
+                pSourceName,
+                pDefines,
+                wrapped_include,
+                pEntrypoint,
+                pTarget,
+                Flags1,
+                Flags2,
+                &mut code,
+                &mut errors,
+            )
+        });
+        free_include_wrapper(wrapped_include);
+        if !ppCode.is_null() {
+            *ppCode = wrap_blob(code);
+        }
+        if !ppErrorMsgs.is_null() {
+            *ppErrorMsgs = wrap_blob(errors);
+        }
+        // eprintln!("[EXPORT EXIT] D3DCompile = 0x{result:x} {ppCode:?}");
+        result
+    }
+
+    pub unsafe fn D3DCompile2(
+        &self,
+        pSrcData: *const c_void,
+        SrcDataSize: SIZE_T,
+        pSourceName: LPCSTR,
+        pDefines: *const D3D_SHADER_MACRO,
+        pInclude: *mut ID3DInclude,
+        pEntrypoint: LPCSTR,
+        pTarget: LPCSTR,
+        Flags1: UINT,
+        Flags2: UINT,
+        SecondaryDataFlags: UINT,
+        pSecondaryData: *const c_void,
+        SecondaryDataSize: SIZE_T,
+        ppCode: *mut *mut ID3DBlob,
+        ppErrorMsgs: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        let Some(d3d_compile2) = self.d3d_compile2 else {
+            return E_NOTIMPL;
+        };
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DCompile2");
+        let mut code: *mut Win64Blob = std::ptr::null_mut();
+        let mut errors: *mut Win64Blob = std::ptr::null_mut();
+        let wrapped_include = wrap_include(pInclude, || source_dir_from_name(pSourceName));
+        let result = stack::call(|| {
+            d3d_compile2(
+                pSrcData,
+                SrcDataSize,
+                pSourceName,
+                pDefines,
+                wrapped_include,
+                pEntrypoint,
+                pTarget,
+                Flags1,
+                Flags2,
+                SecondaryDataFlags,
+                pSecondaryData,
+                SecondaryDataSize,
+                &mut code,
+                &mut errors,
+            )
+        });
+        free_include_wrapper(wrapped_include);
+        if !ppCode.is_null() {
+            *ppCode = wrap_blob(code);
+        }
+        if !ppErrorMsgs.is_null() {
+            *ppErrorMsgs = wrap_blob(errors);
+        }
+        // eprintln!("[EXPORT EXIT] D3DCompile2 = 0x{result:x}");
+        result
+    }
+
+    pub unsafe fn D3DCompileFromFile(
+        &self,
+        pFileName: LPCWSTR,
+        pDefines: *const D3D_SHADER_MACRO,
+        pInclude: *mut ID3DInclude,
+        pEntrypoint: LPCSTR,
+        pTarget: LPCSTR,
+        Flags1: UINT,
+        Flags2: UINT,
+        ppCode: *mut *mut ID3DBlob,
+        ppErrorMsgs: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        let Some(d3d_compile_from_file) = self.d3d_compile_from_file else {
+            return E_NOTIMPL;
+        };
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DCompileFromFile");
+        let mut code: *mut Win64Blob = std::ptr::null_mut();
+        let mut errors: *mut Win64Blob = std::ptr::null_mut();
+        let wrapped_include = wrap_include(pInclude, || source_dir_from_wide_name(pFileName));
+        let result = stack::call(|| {
+            d3d_compile_from_file(
+                pFileName,
+                pDefines,
+                wrapped_include,
+                pEntrypoint,
+                pTarget,
+                Flags1,
+                Flags2,
+                &mut code,
+                &mut errors,
+            )
+        });
+        free_include_wrapper(wrapped_include);
+        if !ppCode.is_null() {
+            *ppCode = wrap_blob(code);
+        }
+        if !ppErrorMsgs.is_null() {
+            *ppErrorMsgs = wrap_blob(errors);
+        }
+        // eprintln!("[EXPORT EXIT] D3DCompileFromFile = 0x{result:x}");
+        result
+    }
+
+    pub unsafe fn D3DPreprocess(
+        &self,
+        pSrcData: *const c_void,
+        SrcDataSize: SIZE_T,
+        pSourceName: LPCSTR,
+        pDefines: *const D3D_SHADER_MACRO,
+        pInclude: *mut ID3DInclude,
+        ppCodeText: *mut *mut ID3DBlob,
+        ppErrorMsgs: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DPreprocess");
+        let mut code: *mut Win64Blob = std::ptr::null_mut();
+        let mut errors: *mut Win64Blob = std::ptr::null_mut();
+        let wrapped_include = wrap_include(pInclude, || source_dir_from_name(pSourceName));
+        let result = stack::call(|| {
+            (self.d3d_preprocess)(
+                pSrcData,
+                SrcDataSize,
+                pSourceName,
+                pDefines,
+                wrapped_include,
+                &mut code,
+                &mut errors,
+            )
+        });
+        free_include_wrapper(wrapped_include);
+        if !ppCodeText.is_null() {
+            *ppCodeText = wrap_blob(code);
+        }
+        if !ppErrorMsgs.is_null() {
+            *ppErrorMsgs = wrap_blob(errors);
+        }
+        // eprintln!("[EXPORT EXIT] D3DPreprocess = 0x{result:x}");
+        result
+    }
+
+    pub unsafe fn D3DDisassemble(
+        &self,
+        pSrcData: *const c_void,
+        SrcDataSize: SIZE_T,
+        Flags: UINT,
+        szComments: LPCSTR,
+        ppDisassembly: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DDisassemble");
+        let mut disasm: *mut Win64Blob = std::ptr::null_mut();
+        let result = stack::call(|| {
+            (self.d3d_disassemble)(pSrcData, SrcDataSize, Flags, szComments, &mut disasm)
+        });
+        if !ppDisassembly.is_null() {
+            *ppDisassembly = wrap_blob(disasm);
+        }
+        // eprintln!("[EXPORT EXIT] D3DDisassemble = 0x{result:x}");
+        result
+    }
+
+    pub unsafe fn D3DCreateBlob(&self, Size: SIZE_T, ppBlob: *mut *mut ID3DBlob) -> HRESULT {
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DCreateBlob");
+        let mut blob: *mut Win64Blob = std::ptr::null_mut();
+        let result = stack::call(|| (self.d3d_create_blob)(Size, &mut blob));
+        if !ppBlob.is_null() {
+            *ppBlob = wrap_blob(blob);
+        }
+        // eprintln!("[EXPORT ENTER] D3DCreateBlob = 0x{result:x}");
+        result
+    }
+
+    pub unsafe fn D3DReflect(
+        &self,
+        pSrcData: *const c_void,
+        SrcDataSize: SIZE_T,
+        pInterface: *const c_void,
+        ppReflector: *mut *mut c_void,
+    ) -> HRESULT {
+        linux_loader::setup_tib();
+        let mut reflector: *mut Win64Reflection = std::ptr::null_mut();
+        let result = stack::call(|| {
+            (self.d3d_reflect)(
+                pSrcData,
+                SrcDataSize,
+                pInterface,
+                &mut reflector as *mut _ as *mut *mut c_void,
+            )
+        });
+        if !ppReflector.is_null() {
+            *ppReflector = wrap_reflection(reflector) as *mut c_void;
+        }
+        result
+    }
+
+    pub unsafe fn D3DStripShader(
+        &self,
+        pShaderBytecode: *const c_void,
+        BytecodeLength: SIZE_T,
+        uStripFlags: UINT,
+        ppStrippedBlob: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DStripShader");
+        let mut blob: *mut Win64Blob = std::ptr::null_mut();
+        let result = stack::call(|| {
+            (self.d3d_strip_shader)(pShaderBytecode, BytecodeLength, uStripFlags, &mut blob)
+        });
+        if !ppStrippedBlob.is_null() {
+            *ppStrippedBlob = wrap_blob(blob);
+        }
+        // eprintln!("[EXPORT EXIT] D3DStripShader = 0x{result:x}");
+        result
+    }
+
+    pub unsafe fn D3DGetBlobPart(
+        &self,
+        pSrcData: *const c_void,
+        SrcDataSize: SIZE_T,
+        Part: u32,
+        Flags: UINT,
+        ppPart: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DGetBlobPart");
+        let mut blob: *mut Win64Blob = std::ptr::null_mut();
+        let result =
+            stack::call(|| (self.d3d_get_blob_part)(pSrcData, SrcDataSize, Part, Flags, &mut blob));
+        if !ppPart.is_null() {
+            *ppPart = wrap_blob(blob);
+        }
+        // eprintln!("[EXPORT EXIT] D3DGetBlobPart = 0x{result:x}");
+        result
+    }
+
+    pub unsafe fn D3DSetBlobPart(
+        &self,
+        pSrcData: *const c_void,
+        SrcDataSize: SIZE_T,
+        Part: u32,
+        Flags: UINT,
+        pPart: *const c_void,
+        PartSize: SIZE_T,
+        ppNewShader: *mut *mut ID3DBlob,
+    ) -> HRESULT {
+        let Some(d3d_set_blob_part) = self.d3d_set_blob_part else {
+            return E_NOTIMPL;
+        };
+        linux_loader::setup_tib();
+        // eprintln!("[EXPORT ENTER] D3DSetBlobPart");
+        let mut blob: *mut Win64Blob = std::ptr::null_mut();
+        let result = stack::call(|| {
+            d3d_set_blob_part(
+                pSrcData,
+                SrcDataSize,
+                Part,
+                Flags,
+                pPart,
+                PartSize,
+                &mut blob,
+            )
+        });
+        if !ppNewShader.is_null() {
+            *ppNewShader = wrap_blob(blob);
+        }
+        // eprintln!("[EXPORT EXIT] D3DSetBlobPart = 0x{result:x}");
+        result
+    }
+}
+
+// Public API functions that forward to the default compiler
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn D3DCompile(
@@ -546,47 +1362,22 @@ pub unsafe extern "C" fn D3DCompile(
     ppCode: *mut *mut ID3DBlob,
     ppErrorMsgs: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DCompile");
-
-    // let src = slice::from_raw_parts(pSrcData.cast(), SrcDataSize);
-    // let src_str = String::from_utf8_lossy(&src);
-
-    // let name = std::ffi::CStr::from_ptr(pSourceName).to_string_lossy();
-    // let entry = std::ffi::CStr::from_ptr(pEntrypoint).to_string_lossy();
-
-    // eprintln!("COMPILING {name:?} {entry:?}");
-    // let path = format!("/tmp/shaders/{}.hlsl", name.replace('/', "_"));
-    // std::fs::write(&path, src).unwrap();
-    // println!("{} bytes => {path}", src.len());
-
-    let mut code: *mut Win64Blob = std::ptr::null_mut();
-    let mut errors: *mut Win64Blob = std::ptr::null_mut();
-    let wrapped_include = wrap_include(pInclude);
-    let result = match init() {
-        Ok(s) => (s.d3d_compile)(
+    match init() {
+        Ok(compiler) => compiler.D3DCompile(
             pSrcData,
             SrcDataSize,
             pSourceName,
             pDefines,
-            wrapped_include,
+            pInclude,
             pEntrypoint,
             pTarget,
             Flags1,
             Flags2,
-            &mut code,
-            &mut errors,
+            ppCode,
+            ppErrorMsgs,
         ),
         Err(_) => E_FAIL,
-    };
-    free_include_wrapper(wrapped_include);
-    if !ppCode.is_null() {
-        *ppCode = wrap_blob(code);
     }
-    if !ppErrorMsgs.is_null() {
-        *ppErrorMsgs = wrap_blob(errors);
-    }
-    // eprintln!("[EXPORT EXIT] D3DCompile = 0x{result:x} {ppCode:?}");
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -606,17 +1397,13 @@ pub unsafe extern "C" fn D3DCompile2(
     ppCode: *mut *mut ID3DBlob,
     ppErrorMsgs: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DCompile2");
-    let mut code: *mut Win64Blob = std::ptr::null_mut();
-    let mut errors: *mut Win64Blob = std::ptr::null_mut();
-    let wrapped_include = wrap_include(pInclude);
-    let result = match init() {
-        Ok(s) => (s.d3d_compile2)(
+    match init() {
+        Ok(compiler) => compiler.D3DCompile2(
             pSrcData,
             SrcDataSize,
             pSourceName,
             pDefines,
-            wrapped_include,
+            pInclude,
             pEntrypoint,
             pTarget,
             Flags1,
@@ -624,20 +1411,11 @@ pub unsafe extern "C" fn D3DCompile2(
             SecondaryDataFlags,
             pSecondaryData,
             SecondaryDataSize,
-            &mut code,
-            &mut errors,
+            ppCode,
+            ppErrorMsgs,
         ),
         Err(_) => E_FAIL,
-    };
-    free_include_wrapper(wrapped_include);
-    if !ppCode.is_null() {
-        *ppCode = wrap_blob(code);
     }
-    if !ppErrorMsgs.is_null() {
-        *ppErrorMsgs = wrap_blob(errors);
-    }
-    // eprintln!("[EXPORT EXIT] D3DCompile2 = 0x{result:x}");
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -652,33 +1430,20 @@ pub unsafe extern "C" fn D3DCompileFromFile(
     ppCode: *mut *mut ID3DBlob,
     ppErrorMsgs: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DCompileFromFile");
-    let mut code: *mut Win64Blob = std::ptr::null_mut();
-    let mut errors: *mut Win64Blob = std::ptr::null_mut();
-    let wrapped_include = wrap_include(pInclude);
-    let result = match init() {
-        Ok(s) => (s.d3d_compile_from_file)(
+    match init() {
+        Ok(compiler) => compiler.D3DCompileFromFile(
             pFileName,
             pDefines,
-            wrapped_include,
+            pInclude,
             pEntrypoint,
             pTarget,
             Flags1,
             Flags2,
-            &mut code,
-            &mut errors,
+            ppCode,
+            ppErrorMsgs,
         ),
         Err(_) => E_FAIL,
-    };
-    free_include_wrapper(wrapped_include);
-    if !ppCode.is_null() {
-        *ppCode = wrap_blob(code);
     }
-    if !ppErrorMsgs.is_null() {
-        *ppErrorMsgs = wrap_blob(errors);
-    }
-    // eprintln!("[EXPORT EXIT] D3DCompileFromFile = 0x{result:x}");
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -691,31 +1456,18 @@ pub unsafe extern "C" fn D3DPreprocess(
     ppCodeText: *mut *mut ID3DBlob,
     ppErrorMsgs: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DPreprocess");
-    let mut code: *mut Win64Blob = std::ptr::null_mut();
-    let mut errors: *mut Win64Blob = std::ptr::null_mut();
-    let wrapped_include = wrap_include(pInclude);
-    let result = match init() {
-        Ok(s) => (s.d3d_preprocess)(
+    match init() {
+        Ok(compiler) => compiler.D3DPreprocess(
             pSrcData,
             SrcDataSize,
             pSourceName,
             pDefines,
-            wrapped_include,
-            &mut code,
-            &mut errors,
+            pInclude,
+            ppCodeText,
+            ppErrorMsgs,
         ),
         Err(_) => E_FAIL,
-    };
-    free_include_wrapper(wrapped_include);
-    if !ppCodeText.is_null() {
-        *ppCodeText = wrap_blob(code);
     }
-    if !ppErrorMsgs.is_null() {
-        *ppErrorMsgs = wrap_blob(errors);
-    }
-    // eprintln!("[EXPORT EXIT] D3DPreprocess = 0x{result:x}");
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -726,32 +1478,20 @@ pub unsafe extern "C" fn D3DDisassemble(
     szComments: LPCSTR,
     ppDisassembly: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DDisassemble");
-    let mut disasm: *mut Win64Blob = std::ptr::null_mut();
-    let result = match init() {
-        Ok(s) => (s.d3d_disassemble)(pSrcData, SrcDataSize, Flags, szComments, &mut disasm),
+    match init() {
+        Ok(compiler) => {
+            compiler.D3DDisassemble(pSrcData, SrcDataSize, Flags, szComments, ppDisassembly)
+        }
         Err(_) => E_FAIL,
-    };
-    if !ppDisassembly.is_null() {
-        *ppDisassembly = wrap_blob(disasm);
     }
-    // eprintln!("[EXPORT EXIT] D3DDisassemble = 0x{result:x}");
-    result
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn D3DCreateBlob(Size: SIZE_T, ppBlob: *mut *mut ID3DBlob) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DCreateBlob");
-    let mut blob: *mut Win64Blob = std::ptr::null_mut();
-    let result = match init() {
-        Ok(s) => (s.d3d_create_blob)(Size, &mut blob),
+    match init() {
+        Ok(compiler) => compiler.D3DCreateBlob(Size, ppBlob),
         Err(_) => E_FAIL,
-    };
-    if !ppBlob.is_null() {
-        *ppBlob = wrap_blob(blob);
     }
-    // eprintln!("[EXPORT ENTER] D3DCreateBlob = 0x{result:x}");
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -761,20 +1501,10 @@ pub unsafe extern "C" fn D3DReflect(
     pInterface: *const c_void,
     ppReflector: *mut *mut c_void,
 ) -> HRESULT {
-    let mut reflector: *mut Win64Reflection = std::ptr::null_mut();
-    let result = match init() {
-        Ok(s) => (s.d3d_reflect)(
-            pSrcData,
-            SrcDataSize,
-            pInterface,
-            &mut reflector as *mut _ as *mut *mut c_void,
-        ),
+    match init() {
+        Ok(compiler) => compiler.D3DReflect(pSrcData, SrcDataSize, pInterface, ppReflector),
         Err(_) => E_FAIL,
-    };
-    if !ppReflector.is_null() {
-        *ppReflector = wrap_reflection(reflector) as *mut c_void;
     }
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -784,17 +1514,12 @@ pub unsafe extern "C" fn D3DStripShader(
     uStripFlags: UINT,
     ppStrippedBlob: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DStripShader");
-    let mut blob: *mut Win64Blob = std::ptr::null_mut();
-    let result = match init() {
-        Ok(s) => (s.d3d_strip_shader)(pShaderBytecode, BytecodeLength, uStripFlags, &mut blob),
+    match init() {
+        Ok(compiler) => {
+            compiler.D3DStripShader(pShaderBytecode, BytecodeLength, uStripFlags, ppStrippedBlob)
+        }
         Err(_) => E_FAIL,
-    };
-    if !ppStrippedBlob.is_null() {
-        *ppStrippedBlob = wrap_blob(blob);
     }
-    // eprintln!("[EXPORT EXIT] D3DStripShader = 0x{result:x}");
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -805,17 +1530,10 @@ pub unsafe extern "C" fn D3DGetBlobPart(
     Flags: UINT,
     ppPart: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DGetBlobPart");
-    let mut blob: *mut Win64Blob = std::ptr::null_mut();
-    let result = match init() {
-        Ok(s) => (s.d3d_get_blob_part)(pSrcData, SrcDataSize, Part, Flags, &mut blob),
+    match init() {
+        Ok(compiler) => compiler.D3DGetBlobPart(pSrcData, SrcDataSize, Part, Flags, ppPart),
         Err(_) => E_FAIL,
-    };
-    if !ppPart.is_null() {
-        *ppPart = wrap_blob(blob);
     }
-    // eprintln!("[EXPORT EXIT] D3DGetBlobPart = 0x{result:x}");
-    result
 }
 
 #[unsafe(no_mangle)]
@@ -828,25 +1546,18 @@ pub unsafe extern "C" fn D3DSetBlobPart(
     PartSize: SIZE_T,
     ppNewShader: *mut *mut ID3DBlob,
 ) -> HRESULT {
-    // eprintln!("[EXPORT ENTER] D3DSetBlobPart");
-    let mut blob: *mut Win64Blob = std::ptr::null_mut();
-    let result = match init() {
-        Ok(s) => (s.d3d_set_blob_part)(
+    match init() {
+        Ok(compiler) => compiler.D3DSetBlobPart(
             pSrcData,
             SrcDataSize,
             Part,
             Flags,
             pPart,
             PartSize,
-            &mut blob,
+            ppNewShader,
         ),
         Err(_) => E_FAIL,
-    };
-    if !ppNewShader.is_null() {
-        *ppNewShader = wrap_blob(blob);
     }
-    // eprintln!("[EXPORT EXIT] D3DSetBlobPart = 0x{result:x}");
-    result
 }
 
 // Linux loader - manual PE loading with import hooking
@@ -854,8 +1565,8 @@ pub unsafe extern "C" fn D3DSetBlobPart(
 mod linux_loader {
     use super::*;
     use object::pe::{
-        IMAGE_REL_BASED_DIR64, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE,
-        ImageNtHeaders64,
+        IMAGE_DIRECTORY_ENTRY_EXCEPTION, IMAGE_DIRECTORY_ENTRY_TLS, IMAGE_REL_BASED_DIR64,
+        IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ, IMAGE_SCN_MEM_WRITE, ImageNtHeaders64,
     };
     use object::read::pe::{ImageOptionalHeader, ImageThunkData, PeFile64};
     use object::{LittleEndian as LE, Object, ObjectSection};
@@ -870,6 +1581,7 @@ mod linux_loader {
     //   0x20: FiberData / Version
     //   0x28: ArbitraryUserPointer
     //   0x30: Self (pointer to TEB itself - NT_TIB.Self)
+    //   0x58: ThreadLocalStoragePointer
     #[repr(C)]
     struct ThreadInformationBlock {
         exception_list: usize,         // 0x00
@@ -882,6 +1594,8 @@ mod linux_loader {
         environment_pointer: usize,    // 0x38
         process_id: usize,             // 0x40
         thread_id: usize,              // 0x48
+        active_rpc_handle: usize,      // 0x50
+        thread_local_storage: usize,   // 0x58 - per-image __declspec(thread) blocks
     }
 
     // Thread-local TIB - each thread gets its own
@@ -898,12 +1612,15 @@ mod linux_loader {
                 environment_pointer: 0,
                 process_id: 0,
                 thread_id: 0,
+                active_rpc_handle: 0,
+                thread_local_storage: 0,
             })
         };
         static TIB_INITIALIZED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
     }
 
-    // Set up GS register for Windows TIB access (once per thread)
+    // Set up GS register for Windows TIB access (once per thread), then
+    // attach the thread to images loaded since it last called in
     pub unsafe fn setup_tib() {
         TIB_INITIALIZED.with(|initialized| {
             if initialized.get() {
@@ -913,14 +1630,16 @@ mod linux_loader {
             TIB.with(|tib| {
                 let tib_ptr = tib.get();
 
-                // Get current stack info
-                let mut stack_var: usize = 0;
-                let stack_ptr = (&raw mut stack_var) as usize;
-
-                // Estimate stack bounds (stack grows down on x86-64)
-                // Use 8MB stack size estimate for maximum compatibility
-                let stack_base = (stack_ptr + 0x800000) & !0xFFF;
-                let stack_limit = (stack_ptr - 0x800000) & !0xFFF;
+                // Stack bounds (stack grows down on x86-64). If pthread
+                // can't tell, estimate 8MB either side of the current frame.
+                let (stack_base, stack_limit) = stack::current_bounds().unwrap_or_else(|| {
+                    let mut stack_var: usize = 0;
+                    let stack_ptr = (&raw mut stack_var) as usize;
+                    (
+                        (stack_ptr + 0x800000) & !0xFFF,
+                        stack_ptr.saturating_sub(0x800000) & !0xFFF,
+                    )
+                });
 
                 // Initialize TIB fields
                 (*tib_ptr).stack_base = stack_base;
@@ -928,138 +1647,35 @@ mod linux_loader {
                 (*tib_ptr).teb_self = tib_ptr as usize;
                 (*tib_ptr).process_id = std::process::id() as usize;
                 (*tib_ptr).thread_id = libc::syscall(libc::SYS_gettid) as usize;
+                (*tib_ptr).thread_local_storage = dllmain::tls_array() as usize;
 
                 // Set GS base to point to our TIB using arch_prctl
                 const ARCH_SET_GS: i32 = 0x1001;
                 libc::syscall(libc::SYS_arch_prctl, ARCH_SET_GS, tib_ptr as usize);
             });
 
+            // Lets the crash handler run when the DLL overflows this stack
+            #[cfg(feature = "crash-handler")]
+            crate::crash::install_alt_stack();
+
             initialized.set(true);
         });
+        dllmain::attach_thread();
     }
 
-    // // Global state for crash debugging
-    // static mut DLL_MAP_BASE: usize = 0;
-    // static mut DLL_MAP_SIZE: usize = 0;
-    // static mut DLL_IMAGE_BASE: usize = 0;
-
-    // unsafe extern "C" fn crash_handler(
-    //     sig: i32,
-    //     _info: *mut libc::siginfo_t,
-    //     context: *mut c_void,
-    // ) {
-    //     let uc = context as *mut libc::ucontext_t;
-    //     let rip = (*uc).uc_mcontext.gregs[libc::REG_RIP as usize] as u64;
-    //     let rsp = (*uc).uc_mcontext.gregs[libc::REG_RSP as usize] as u64;
-    //     let rax = (*uc).uc_mcontext.gregs[libc::REG_RAX as usize] as u64;
-    //     let rbx = (*uc).uc_mcontext.gregs[libc::REG_RBX as usize] as u64;
-    //     let rcx = (*uc).uc_mcontext.gregs[libc::REG_RCX as usize] as u64;
-    //     let rdx = (*uc).uc_mcontext.gregs[libc::REG_RDX as usize] as u64;
-    //     let rsi = (*uc).uc_mcontext.gregs[libc::REG_RSI as usize] as u64;
-    //     let rdi = (*uc).uc_mcontext.gregs[libc::REG_RDI as usize] as u64;
-    //     let r8 = (*uc).uc_mcontext.gregs[libc::REG_R8 as usize] as u64;
-    //     let r9 = (*uc).uc_mcontext.gregs[libc::REG_R9 as usize] as u64;
-
-    //     eprintln!("\n============================================================");
-    //     eprintln!("CRASH: Signal {} at RIP=0x{:016x}", sig, rip);
-    //     eprintln!("============================================================");
-
-    //     // Check if crash is in DLL
-    //     let map_base = DLL_MAP_BASE;
-    //     let map_size = DLL_MAP_SIZE;
-    //     let image_base = DLL_IMAGE_BASE;
-
-    //     if map_base != 0 && (rip as usize) >= map_base && (rip as usize) < map_base + map_size {
-    //         let dll_offset = rip as usize - map_base;
-    //         let dll_rva = dll_offset; // RVA from start of image
-    //         let original_va = image_base + dll_offset; // VA in original DLL
-
-    //         eprintln!("CRASH IN DLL:");
-    //         eprintln!("  Map base:        0x{:016x}", map_base);
-    //         eprintln!("  Crash RIP:       0x{:016x}", rip);
-    //         eprintln!("  DLL offset/RVA:  0x{:08x}", dll_rva);
-    //         eprintln!("  Original VA:     0x{:016x}", original_va);
-    //         eprintln!("");
-    //         eprintln!("To debug in IDA/Ghidra, go to address: 0x{:x}", original_va);
-    //         eprintln!(
-    //             "Or use RVA: 0x{:x} from image base 0x{:x}",
-    //             dll_rva, image_base
-    //         );
-    //     } else {
-    //         eprintln!(
-    //             "Crash outside DLL (map_base=0x{:x}, size=0x{:x})",
-    //             map_base, map_size
-    //         );
-    //     }
-
-    //     eprintln!("\nRegisters:");
-    //     eprintln!("  RAX=0x{:016x}  RBX=0x{:016x}", rax, rbx);
-    //     eprintln!("  RCX=0x{:016x}  RDX=0x{:016x}", rcx, rdx);
-    //     eprintln!("  RSI=0x{:016x}  RDI=0x{:016x}", rsi, rdi);
-    //     eprintln!("  R8 =0x{:016x}  R9 =0x{:016x}", r8, r9);
-    //     eprintln!("  RSP=0x{:016x}  RIP=0x{:016x}", rsp, rip);
-
-    //     // Dump stack
-    //     eprintln!("\nStack (top 16 qwords):");
-    //     let stack = rsp as *const u64;
-    //     for i in 0..16 {
-    //         let addr = stack.add(i);
-    //         let val = *addr;
-    //         let in_dll = (val as usize) >= map_base && (val as usize) < map_base + map_size;
-    //         if in_dll {
-    //             let rva = val as usize - map_base;
-    //             eprintln!(
-    //                 "  [RSP+0x{:02x}] 0x{:016x}  <- DLL RVA 0x{:x}",
-    //                 i * 8,
-    //                 val,
-    //                 rva
-    //             );
-    //         } else {
-    //             eprintln!("  [RSP+0x{:02x}] 0x{:016x}", i * 8, val);
-    //         }
-    //     }
-
-    //     // Dump bytes at RIP
-    //     eprintln!("\nCode at RIP:");
-    //     let code = rip as *const u8;
-    //     eprint!("  ");
-    //     for i in 0..32 {
-    //         eprint!("{:02x} ", *code.add(i));
-    //     }
-    //     eprintln!();
-
-    //     eprintln!("============================================================\n");
-
-    //     std::process::abort();
-    // }
-
-    // pub fn install_crash_handler() {
-    //     unsafe {
-    //         let mut sa: libc::sigaction = std::mem::zeroed();
-    //         sa.sa_sigaction = crash_handler as usize;
-    //         sa.sa_flags = libc::SA_SIGINFO;
-    //         libc::sigaction(libc::SIGSEGV, &sa, std::ptr::null_mut());
-    //         libc::sigaction(libc::SIGBUS, &sa, std::ptr::null_mut());
-    //         libc::sigaction(libc::SIGILL, &sa, std::ptr::null_mut());
-    //     }
-    // }
-
-    #[cfg(feature = "embed-dll")]
-    static EMBEDDED_DLL: &[u8] =
-        include_bytes_aligned::include_bytes_aligned!(8, "../../d3dcompiler_47.dll");
-
-    pub fn load_dll() -> Result<D3DCompilerState> {
-        #[cfg(feature = "embed-dll")]
-        let dll: &[u8] = EMBEDDED_DLL;
-
-        #[cfg(not(feature = "embed-dll"))]
-        let dll_vec = std::fs::read(get_dll_path())?;
-        #[cfg(not(feature = "embed-dll"))]
-        let dll: &[u8] = &dll_vec;
+    /// Lowest usable stack address of the calling thread, from its TIB
+    pub fn stack_limit() -> usize {
+        TIB.with(|tib| unsafe { (*tib.get()).stack_limit })
+    }
 
+    /// Maps, links and attaches a DLL. With `refuse_missing`, it fails
+    /// before anything in it runs if any import is left unresolved.
+    pub fn load_dll(dll: &[u8], refuse_missing: bool) -> Result<Compiler> {
         let obj_file =
             PeFile64::parse(dll).map_err(|e| D3DCompilerError::ParseError(e.to_string()))?;
 
+        let info = CompilerInfo::new(&obj_file, dll);
+
         let size = obj_file.nt_headers().optional_header.size_of_image() as usize;
         let header_size = obj_file.nt_headers().optional_header.size_of_headers() as usize;
         let image_base = obj_file.relative_address_base() as usize;
@@ -1075,25 +1691,22 @@ mod linux_loader {
                 0,
             );
             if ptr == libc::MAP_FAILED {
-                return Err(D3DCompilerError::LoadError("mmap failed".into()));
+                return Err(D3DCompilerError::MapFailed {
+                    size,
+                    source: std::io::Error::last_os_error(),
+                });
             }
             std::slice::from_raw_parts_mut(ptr as *mut u8, size)
         };
 
         let map_base = mmap.as_ptr();
+        // Nothing in the image has run yet, so a failed load can unmap it
+        let unmap = || unsafe {
+            libc::munmap(map_base as *mut c_void, size);
+        };
 
-        // Store globals for crash handler and import tracing
-        imports::DLL_MAP_BASE.store(map_base as usize, std::sync::atomic::Ordering::Relaxed);
-        imports::DLL_MAP_SIZE.store(size, std::sync::atomic::Ordering::Relaxed);
-        imports::DLL_IMAGE_BASE.store(image_base, std::sync::atomic::Ordering::Relaxed);
-
-        // // Install crash handler
-        // install_crash_handler();
-        // eprintln!("[d3dcompiler] Crash handler installed");
-        // eprintln!(
-        //     "[d3dcompiler] DLL mapped at 0x{:x}, size 0x{:x}, image base 0x{:x}",
-        //     map_base as usize, size, image_base
-        // );
+        #[cfg(feature = "crash-handler")]
+        crate::crash::install();
 
         // Copy header
         mmap[0..header_size].copy_from_slice(&dll[0..header_size]);
@@ -1142,7 +1755,21 @@ mod linux_loader {
             }
         }
 
+        // The module name from the export table
+        let name = obj_file
+            .export_table()
+            .ok()
+            .flatten()
+            .and_then(|table| table.name_from_pointer(table.directory().name.get(LE)).ok())
+            .map_or_else(
+                || DLL_NAME.to_string(),
+                |name| String::from_utf8_lossy(name).into(),
+            );
+
         // Fix up imports
+        let mut report = Vec::new();
+        let mut missing = Vec::new();
+        let mut traps = Vec::new();
         if let Ok(Some(import_table)) = obj_file.import_table()
             && let Ok(mut import_descs) = import_table.descriptors()
         {
@@ -1161,10 +1788,29 @@ mod linux_loader {
                     while let Ok(Some(thunk)) = thunks.next::<ImageNtHeaders64>() {
                         if let Ok((_hint, name)) = import_table.hint_name(thunk.address()) {
                             let name = String::from_utf8_lossy(name).to_string();
-                            let fn_addr = resolve_import(&dll_name, &name);
-                            if address + 8 <= mmap.len() {
-                                mmap[address..address + 8].copy_from_slice(&fn_addr.to_le_bytes());
-                            }
+                            let status = match resolve_import(&dll_name, &name) {
+                                Some((fn_addr, status)) => {
+                                    if address + 8 <= mmap.len() {
+                                        mmap[address..address + 8]
+                                            .copy_from_slice(&fn_addr.to_le_bytes());
+                                    }
+                                    status
+                                }
+                                None => {
+                                    missing.push(address);
+                                    traps.push(imports::trap::Trap {
+                                        dll: dll_name.clone(),
+                                        name: name.clone(),
+                                    });
+                                    ImportStatus::Missing
+                                }
+                            };
+                            report.push(ImportReport {
+                                dll: dll_name.clone(),
+                                name,
+                                status,
+                                iat_rva: address,
+                            });
                         }
                         address += 8;
                     }
@@ -1172,6 +1818,57 @@ mod linux_loader {
             }
         }
 
+        if refuse_missing && !traps.is_empty() {
+            unmap();
+            return Err(D3DCompilerError::MissingImports {
+                dll: name,
+                imports: traps
+                    .iter()
+                    .map(|trap| format!("{}!{}", trap.dll, trap.name))
+                    .collect(),
+            });
+        }
+
+        // Point unresolved imports at trap stubs that report them when called
+        let trap_addresses = imports::trap::build_traps(traps).inspect_err(|_| unmap())?;
+        for (address, trap) in missing.into_iter().zip(trap_addresses) {
+            if address + 8 <= mmap.len() {
+                mmap[address..address + 8].copy_from_slice(&trap.to_le_bytes());
+            }
+        }
+
+        // Read the TLS directory, whose addresses are relocated by now, and
+        // store the image's TLS index where its code expects it
+        let mut tls_callbacks = 0;
+        let mut tls = None;
+        if let Some(dir) = obj_file.data_directory(IMAGE_DIRECTORY_ENTRY_TLS) {
+            let rva = dir.virtual_address.get(LE) as usize;
+            let field = |offset: usize| {
+                mmap.get(rva + offset..rva + offset + 8).map_or(0, |bytes| {
+                    u64::from_le_bytes(bytes.try_into().unwrap()) as usize
+                })
+            };
+            let (start, end, index_address) = (field(0), field(8), field(16));
+            tls_callbacks = field(24);
+            let zero_fill = field(32) & 0xFFFF_FFFF;
+            if start != 0 || index_address != 0 {
+                let index = dllmain::alloc_tls_index().inspect_err(|_| unmap())?;
+                let offset = index_address.wrapping_sub(map_base as usize);
+                if let Some(slot) = offset
+                    .checked_add(4)
+                    .and_then(|end| mmap.get_mut(offset..end))
+                {
+                    slot.copy_from_slice(&(index as u32).to_le_bytes());
+                }
+                tls = Some(dllmain::TlsData {
+                    index,
+                    start,
+                    end: end.max(start),
+                    zero_fill,
+                });
+            }
+        }
+
         // Build export table
         let mut exports = HashMap::new();
         if let Ok(export_list) = obj_file.exports() {
@@ -1221,73 +1918,118 @@ mod linux_loader {
 
         // Get function pointers from exports
         let get_fn = |name: &str| -> Result<*const c_void> {
-            exports
-                .get(name)
-                .copied()
-                .ok_or_else(|| D3DCompilerError::FunctionNotFound(name.into()))
+            exports.get(name).copied().ok_or_else(|| {
+                unmap();
+                if let Some(tls) = &tls {
+                    dllmain::free_tls_index(tls.index);
+                }
+                D3DCompilerError::FunctionNotFound(name.into())
+            })
         };
-
-        // Set up TIB before calling into DLL
-        unsafe {
-            setup_tib();
-        }
-
-        // Call DllMain via PE entry point (DLL_PROCESS_ATTACH = 1)
-        let entry_rva = obj_file
-            .nt_headers()
-            .optional_header
-            .address_of_entry_point();
-        if entry_rva != 0 {
-            let entry_addr = map_base as usize + entry_rva as usize;
-            // eprintln!(
-            //     "[d3dcompiler] Calling DllMain at entry point 0x{:x} (RVA 0x{:x})...",
-            //     entry_addr, entry_rva
-            // );
-
-            // Call with win64 ABI: DllMain(hModule, DLL_PROCESS_ATTACH, lpReserved)
-            let _result = unsafe {
-                type DllMain = unsafe extern "win64" fn(
-                    hinst: *const (),
-                    fdw_reason: u32,
-                    lpv_reserved: *mut (),
-                ) -> bool;
-
-                let dll_main = std::mem::transmute::<usize, DllMain>(entry_addr);
-                dll_main(
-                    map_base.cast(),
-                    1,                    // DLL_PROCESS_ATTACH
-                    std::ptr::null_mut(), // NULL
-                )
-            };
-            // eprintln!("[d3dcompiler] DllMain returned: {}", result);
-        } else {
-            // eprintln!("[d3dcompiler] No entry point found (this is unusual for a DLL)");
-        }
-
-        // eprintln!("[d3dcompiler] Resolving D3D exports...");
-
-        unsafe {
-            let state = D3DCompilerState {
+        let compiler = unsafe {
+            Compiler {
+                name: name.clone(),
                 _mmap: mmap.as_mut_ptr(),
                 _mmap_size: size,
+                info,
+                load_report: LoadReport {
+                    imports: report.clone(),
+                },
                 d3d_compile: std::mem::transmute(get_fn("D3DCompile")?),
-                d3d_compile2: std::mem::transmute(get_fn("D3DCompile2")?),
-                d3d_compile_from_file: std::mem::transmute(get_fn("D3DCompileFromFile")?),
+                d3d_compile2: exports.get("D3DCompile2").map(|f| std::mem::transmute(*f)),
+                d3d_compile_from_file: exports
+                    .get("D3DCompileFromFile")
+                    .map(|f| std::mem::transmute(*f)),
                 d3d_preprocess: std::mem::transmute(get_fn("D3DPreprocess")?),
                 d3d_disassemble: std::mem::transmute(get_fn("D3DDisassemble")?),
                 d3d_create_blob: std::mem::transmute(get_fn("D3DCreateBlob")?),
                 d3d_reflect: std::mem::transmute(get_fn("D3DReflect")?),
                 d3d_strip_shader: std::mem::transmute(get_fn("D3DStripShader")?),
                 d3d_get_blob_part: std::mem::transmute(get_fn("D3DGetBlobPart")?),
-                d3d_set_blob_part: std::mem::transmute(get_fn("D3DSetBlobPart")?),
-            };
-            // eprintln!("[d3dcompiler] DLL loaded successfully!");
-            Ok(state)
+                d3d_set_blob_part: exports
+                    .get("D3DSetBlobPart")
+                    .map(|f| std::mem::transmute(*f)),
+            }
+        };
+
+        // Register the image for unwinding, tracing and crash reports
+        let (exception_dir, exception_dir_size) = obj_file
+            .data_directory(IMAGE_DIRECTORY_ENTRY_EXCEPTION)
+            .map_or((0, 0), |dir| {
+                (
+                    dir.virtual_address.get(LE) as usize,
+                    dir.size.get(LE) as usize,
+                )
+            });
+        let mut export_rvas: Vec<(usize, String)> = exports
+            .iter()
+            .map(|(name, address)| (*address as usize - map_base as usize, name.clone()))
+            .collect();
+        export_rvas.sort();
+        let mut import_slots: Vec<(usize, String)> = report
+            .into_iter()
+            .map(|import| (import.iat_rva, format!("{}!{}", import.dll, import.name)))
+            .collect();
+        import_slots.sort();
+        let entry_rva = obj_file
+            .nt_headers()
+            .optional_header
+            .address_of_entry_point() as usize;
+        let image = imports::register_image(imports::Image {
+            id: imports::next_image_id(),
+            name,
+            map_base: map_base as usize,
+            map_size: size,
+            image_base,
+            exception_dir,
+            exception_dir_size,
+            exports: export_rvas,
+            import_slots,
+            entry_point: if entry_rva != 0 {
+                map_base as usize + entry_rva
+            } else {
+                0
+            },
+            tls_callbacks,
+            tls,
+            attached: std::sync::atomic::AtomicBool::new(false),
+        });
+        #[cfg(feature = "crash-handler")]
+        unsafe {
+            crate::crash::add_image(&image);
+        }
+
+        // Set up TIB before calling into DLL
+        unsafe {
+            setup_tib();
+        }
+
+        // Run the TLS callbacks and DllMain with DLL_PROCESS_ATTACH. If it
+        // fails, the image has been detached again and is released here.
+        if !unsafe { dllmain::process_attach(&image) } {
+            forget_image(&image);
+            unmap();
+            return Err(D3DCompilerError::AttachFailed {
+                dll: compiler.name,
+            });
+        }
+
+        // eprintln!("[d3dcompiler] DLL loaded successfully!");
+        Ok(compiler)
+    }
+
+    /// Drops a detached image's registration, crash symbols and TLS index
+    pub fn forget_image(image: &imports::Image) {
+        imports::unregister_image(image.id);
+        #[cfg(feature = "crash-handler")]
+        crate::crash::remove_image(image.id);
+        if let Some(tls) = &image.tls {
+            dllmain::free_tls_index(tls.index);
         }
     }
 
     // Import resolver - resolves by DLL name and import name
-    fn resolve_import(dll: &str, name: &str) -> usize {
+    fn resolve_import(dll: &str, name: &str) -> Option<(usize, ImportStatus)> {
         // Log the import resolution
         // eprintln!("[d3dcompiler] Resolving {}!{}", dll, name);
 
@@ -1341,12 +2083,17 @@ mod linux_loader {
             }
             "ntdll" => resolve_ntdll(name),
             "rpcrt4" => resolve_rpcrt4(name),
-            _ => 0xDEADBEEF,
+            _ => None,
         }
     }
 
-    fn resolve_msvcrt(name: &str) -> usize {
-        match name {
+    // Shims with placeholder bodies that panic when called
+    fn stubbed(address: usize) -> Option<(usize, ImportStatus)> {
+        Some((address, ImportStatus::Stubbed))
+    }
+
+    fn resolve_msvcrt(name: &str) -> Option<(usize, ImportStatus)> {
+        let address = match name {
             // memory
             "malloc" => imports::msvcrt::malloc as *const () as usize,
             "free" => imports::msvcrt::free as *const () as usize,
@@ -1396,11 +2143,11 @@ mod linux_loader {
 
             // printf/scanf
             "sprintf_s" => imports::msvcrt::sprintf_s as *const () as usize,
-            "sscanf_s" => imports::msvcrt::sscanf_s as *const () as usize,
-            "swprintf_s" => imports::msvcrt::swprintf_s as *const () as usize,
+            "sscanf_s" => return stubbed(imports::msvcrt::sscanf_s as *const () as usize),
+            "swprintf_s" => return stubbed(imports::msvcrt::swprintf_s as *const () as usize),
             "_vsnprintf" => imports::msvcrt::_vsnprintf as *const () as usize,
-            "_vsnwprintf" => imports::msvcrt::_vsnwprintf as *const () as usize,
-            "_snwprintf_s" => imports::msvcrt::_snwprintf_s as *const () as usize,
+            "_vsnwprintf" => return stubbed(imports::msvcrt::_vsnwprintf as *const () as usize),
+            "_snwprintf_s" => return stubbed(imports::msvcrt::_snwprintf_s as *const () as usize),
 
             // file I/O
             "fclose" => imports::msvcrt::fclose as *const () as usize,
@@ -1457,7 +2204,7 @@ mod linux_loader {
             "qsort" => imports::msvcrt::qsort as *const () as usize,
             "bsearch" => imports::msvcrt::bsearch as *const () as usize,
             "getenv" => imports::msvcrt::getenv as *const () as usize,
-            "_wgetenv" => imports::msvcrt::_wgetenv as *const () as usize,
+            "_wgetenv" => return stubbed(imports::msvcrt::_wgetenv as *const () as usize),
             "setlocale" => imports::msvcrt::setlocale as *const () as usize,
             "_time64" => imports::msvcrt::_time64 as *const () as usize,
             "_errno" => imports::msvcrt::_errno as *const () as usize,
@@ -1477,20 +2224,21 @@ mod linux_loader {
             "__CxxFrameHandler3" => imports::msvcrt::__CxxFrameHandler3 as *const () as usize,
             "_CxxThrowException" => imports::msvcrt::_CxxThrowException as *const () as usize,
             "?terminate@@YAXXZ" => imports::msvcrt::terminate as *const () as usize,
-            "??1type_info@@UEAA@XZ" => imports::msvcrt::type_info_dtor as *const () as usize,
+            "??1type_info@@UEAA@XZ" => return stubbed(imports::msvcrt::type_info_dtor as *const () as usize),
             "__unDName" => imports::msvcrt::__unDName as *const () as usize,
-            "_XcptFilter" => imports::msvcrt::_XcptFilter as *const () as usize,
+            "_XcptFilter" => return stubbed(imports::msvcrt::_XcptFilter as *const () as usize),
 
             // path
             "_wfullpath" => imports::msvcrt::_wfullpath as *const () as usize,
             "_wmakepath_s" => imports::msvcrt::_wmakepath_s as *const () as usize,
             "_wsplitpath_s" => imports::msvcrt::_wsplitpath_s as *const () as usize,
-            _ => 0xDEADBEEF,
-        }
+            _ => return None,
+        };
+        Some((address, ImportStatus::Implemented))
     }
 
-    fn resolve_kernel32(name: &str) -> usize {
-        match name {
+    fn resolve_kernel32(name: &str) -> Option<(usize, ImportStatus)> {
+        let address = match name {
             // memory
             "VirtualAlloc" => imports::kernel32::VirtualAlloc as *const () as usize,
             "VirtualFree" => imports::kernel32::VirtualFree as *const () as usize,
@@ -1524,7 +2272,7 @@ mod linux_loader {
             "MapViewOfFileEx" => imports::kernel32::MapViewOfFileEx as *const () as usize,
             "UnmapViewOfFile" => imports::kernel32::UnmapViewOfFile as *const () as usize,
             "FlushViewOfFile" => imports::kernel32::FlushViewOfFile as *const () as usize,
-            "DeviceIoControl" => imports::kernel32::DeviceIoControl as *const () as usize,
+            "DeviceIoControl" => return stubbed(imports::kernel32::DeviceIoControl as *const () as usize),
 
             // sync
             "InitializeCriticalSection" => imports::kernel32::InitializeCriticalSection as *const () as usize,
@@ -1555,8 +2303,8 @@ mod linux_loader {
             "OutputDebugStringA" => imports::kernel32::OutputDebugStringA as *const () as usize,
             "DisableThreadLibraryCalls" => imports::kernel32::DisableThreadLibraryCalls as *const () as usize,
             "FreeLibrary" => imports::kernel32::FreeLibrary as *const () as usize,
-            "LoadLibraryExW" => imports::kernel32::LoadLibraryExW as *const () as usize,
-            "GetProcAddress" => imports::kernel32::GetProcAddress as *const () as usize,
+            "LoadLibraryExW" => return stubbed(imports::kernel32::LoadLibraryExW as *const () as usize),
+            "GetProcAddress" => return stubbed(imports::kernel32::GetProcAddress as *const () as usize),
             "GetModuleFileNameA" => imports::kernel32::GetModuleFileNameA as *const () as usize,
             "GetEnvironmentVariableA" => imports::kernel32::GetEnvironmentVariableA as *const () as usize,
             "ExpandEnvironmentStringsW" => imports::kernel32::ExpandEnvironmentStringsW as *const () as usize,
@@ -1565,18 +2313,19 @@ mod linux_loader {
             "LCMapStringW" => imports::kernel32::LCMapStringW as *const () as usize,
             "lstrcmpiA" => imports::kernel32::lstrcmpiA as *const () as usize,
             "TerminateProcess" => imports::kernel32::TerminateProcess as *const () as usize,
-            "UnhandledExceptionFilter" => imports::kernel32::UnhandledExceptionFilter as *const () as usize,
+            "UnhandledExceptionFilter" => return stubbed(imports::kernel32::UnhandledExceptionFilter as *const () as usize),
             "SetUnhandledExceptionFilter" => {
                 imports::kernel32::SetUnhandledExceptionFilter as *const () as usize
             }
             "IsDebuggerPresent" => imports::kernel32::IsDebuggerPresent as *const () as usize,
             "IsProcessorFeaturePresent" => imports::kernel32::IsProcessorFeaturePresent as *const () as usize,
-            _ => 0xDEADBEEF,
-        }
+            _ => return None,
+        };
+        Some((address, ImportStatus::Implemented))
     }
 
-    fn resolve_advapi32(name: &str) -> usize {
-        match name {
+    fn resolve_advapi32(name: &str) -> Option<(usize, ImportStatus)> {
+        let address = match name {
             // registry
             "RegOpenKeyExA" => imports::advapi32::RegOpenKeyExA as *const () as usize,
             "RegOpenKeyExW" => imports::advapi32::RegOpenKeyExW as *const () as usize,
@@ -1592,24 +2341,51 @@ mod linux_loader {
             "CryptDestroyHash" => imports::advapi32::CryptDestroyHash as *const () as usize,
             "CryptHashData" => imports::advapi32::CryptHashData as *const () as usize,
             "CryptGetHashParam" => imports::advapi32::CryptGetHashParam as *const () as usize,
-            _ => 0xDEADBEEF,
-        }
+            _ => return None,
+        };
+        Some((address, ImportStatus::Implemented))
     }
 
-    fn resolve_ntdll(name: &str) -> usize {
-        match name {
+    fn resolve_ntdll(name: &str) -> Option<(usize, ImportStatus)> {
+        let address = match name {
             "RtlCaptureContext" => imports::ntdll::RtlCaptureContext as *const () as usize,
             "RtlLookupFunctionEntry" => imports::ntdll::RtlLookupFunctionEntry as *const () as usize,
             "RtlVirtualUnwind" => imports::ntdll::RtlVirtualUnwind as *const () as usize,
             "RtlUnwindEx" => imports::ntdll::RtlUnwindEx as *const () as usize,
-            _ => 0xDEADBEEF,
-        }
+            _ => return None,
+        };
+        Some((address, ImportStatus::Implemented))
     }
 
-    fn resolve_rpcrt4(name: &str) -> usize {
-        match name {
+    fn resolve_rpcrt4(name: &str) -> Option<(usize, ImportStatus)> {
+        let address = match name {
             "UuidCreate" => imports::rpcrt4::UuidCreate as *const () as usize,
-            _ => 0xDEADBEEF,
-        }
+            _ => return None,
+        };
+        Some((address, ImportStatus::Implemented))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_paths_order() {
+        let configured = PathBuf::from("/opt/sdk/d3dcompiler_47.dll");
+        let paths = search_paths(None, Some(configured.clone()));
+        assert_eq!(paths[0], configured);
+        let cwd = paths
+            .iter()
+            .position(|p| p == Path::new(DLL_NAME))
+            .expect("current directory should be searched");
+        assert!(cwd > 0);
+
+        // The environment variable comes first; an empty one is ignored
+        let env_path = PathBuf::from("/env/d3dcompiler_47.dll");
+        let paths = search_paths(Some(env_path.clone()), Some(configured.clone()));
+        assert_eq!(paths[..2], [env_path, configured.clone()]);
+        let paths = search_paths(Some(PathBuf::new()), Some(configured.clone()));
+        assert_eq!(paths[0], configured);
     }
 }
@@ -0,0 +1,175 @@
+//! Stacks for calls into the DLL
+//!
+//! `setup_tib` fills the TEB's stack bounds from [`current_bounds`], so the
+//! DLL's stack probes see the thread's real stack. The compiler can recurse
+//! deeply on large shaders, more than fits in the 2 MB stacks of typical
+//! worker threads, so [`call`] can move a call onto a helper thread with a
+//! larger stack.
+
+use std::cell::RefCell;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+/// Returns the calling thread's stack as `(base, limit)`, its highest and
+/// lowest usable addresses.
+pub fn current_bounds() -> Option<(usize, usize)> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut addr = std::ptr::null_mut();
+        let mut size = 0;
+        let result = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        (result == 0).then_some((addr as usize + size, addr as usize))
+    }
+}
+
+// Size of the helper threads' stacks, and the least stack a call may start
+// with before it moves to one. 0 when calls always stay on the caller.
+static LARGE_STACK: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_large_stack_calls(size: Option<usize>) {
+    LARGE_STACK.store(size.unwrap_or(0), Ordering::SeqCst);
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A calling thread's helper, which exits when `jobs` is dropped with it
+struct Helper {
+    /// Process the helper thread runs in
+    pid: u32,
+    stack_size: usize,
+    jobs: Sender<Job>,
+    done: Receiver<std::thread::Result<()>>,
+}
+
+thread_local! {
+    static HELPER: RefCell<Option<Helper>> = const { RefCell::new(None) };
+}
+
+impl Helper {
+    fn spawn(stack_size: usize) -> Option<Helper> {
+        let (jobs, job_rx) = channel::<Job>();
+        let (done_tx, done) = channel();
+        std::thread::Builder::new()
+            .name("d3dcompiler-stack".into())
+            .stack_size(stack_size)
+            .spawn(move || {
+                for job in job_rx {
+                    unsafe { crate::linux_loader::setup_tib() }
+                    let result = std::panic::catch_unwind(AssertUnwindSafe(job));
+                    if done_tx.send(result).is_err() {
+                        break;
+                    }
+                }
+            })
+            .ok()?;
+        Some(Helper {
+            pid: std::process::id(),
+            stack_size,
+            jobs,
+            done,
+        })
+    }
+}
+
+/// Runs `f` on the calling thread, or on its helper if less stack is left
+/// than set with [`set_large_stack_calls`].
+///
+/// # Safety
+///
+/// `f` may run on another thread, so it must not depend on which thread it
+/// runs on. The caller waits for it, so it may borrow from the caller.
+pub unsafe fn call<R>(f: impl FnOnce() -> R) -> R {
+    let size = LARGE_STACK.load(Ordering::Relaxed);
+    if !needs_large_stack(remaining(), size) {
+        return f();
+    }
+
+    // Without a helper, as while the thread exits, stay on this stack
+    let ready = HELPER.try_with(|helper| {
+        let mut helper = helper.borrow_mut();
+        // A helper inherited across fork has no thread in this process, and
+        // its channels may have been in use when the parent forked
+        if helper
+            .as_ref()
+            .is_some_and(|helper| helper.pid != std::process::id())
+        {
+            std::mem::forget(helper.take());
+        }
+        if helper
+            .as_ref()
+            .is_none_or(|helper| helper.stack_size != size)
+        {
+            *helper = Helper::spawn(size);
+        }
+        helper.is_some()
+    });
+    if ready != Ok(true) {
+        return f();
+    }
+
+    let mut result = None;
+    let slot = &mut result;
+    let job: Box<dyn FnOnce() + '_> = Box::new(move || *slot = Some(f()));
+    // Waiting for the helper below keeps the borrows in `job` alive
+    let job = std::mem::transmute::<Box<dyn FnOnce() + '_>, Job>(job);
+    let outcome = HELPER.with(|helper| {
+        let helper = helper.borrow();
+        let helper = helper.as_ref()?;
+        helper.jobs.send(job).ok()?;
+        helper.done.recv().ok()
+    });
+    match outcome {
+        Some(Ok(())) => result.expect("large-stack call finished without a result"),
+        Some(Err(panic)) => std::panic::resume_unwind(panic),
+        None => panic!("large-stack thread exited during a call into the DLL"),
+    }
+}
+
+/// Returns true if a call with `remaining` bytes of stack left should move
+/// to a helper with a `size`-byte stack. A size of 0 keeps every call.
+fn needs_large_stack(remaining: usize, size: usize) -> bool {
+    size != 0 && remaining < size
+}
+
+/// Bytes left on the calling thread's stack, as recorded by `setup_tib`
+fn remaining() -> usize {
+    let marker = 0u8;
+    let sp = &marker as *const u8 as usize;
+    sp.saturating_sub(crate::linux_loader::stack_limit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_bounds() {
+        std::thread::Builder::new()
+            .stack_size(1 << 20)
+            .spawn(|| {
+                let (base, limit) = current_bounds().expect("pthread knows the thread's stack");
+                let marker = 0u8;
+                let sp = &marker as *const u8 as usize;
+                assert!(limit < sp && sp < base);
+                assert!(base - limit >= 1 << 20);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_needs_large_stack() {
+        assert!(!needs_large_stack(0, 0));
+        assert!(!needs_large_stack(1 << 20, 0));
+        assert!(needs_large_stack(256 << 10, 64 << 20));
+        assert!(needs_large_stack((64 << 20) - 1, 64 << 20));
+        assert!(!needs_large_stack(64 << 20, 64 << 20));
+        assert!(!needs_large_stack(128 << 20, 64 << 20));
+    }
+}
@@ -0,0 +1,287 @@
+//! Identifies which build of `d3dcompiler_47.dll` is loaded
+//!
+//! A build is told apart by the SHA-1 of its file, looked up in
+//! [`KNOWN_BUILDS`], and by the file version in its `VS_VERSIONINFO`
+//! resource. Other builds may call imports the shims don't implement.
+
+use object::LittleEndian as LE;
+use object::pe::RT_VERSION;
+use object::read::pe::{PeFile64, ResourceDirectoryEntryData};
+use sha1::Digest;
+
+/// A build of the DLL the shims have been tested against
+#[derive(Debug)]
+pub struct KnownBuild {
+    /// Lowercase hex SHA-1 of the DLL file
+    pub sha1: &'static str,
+    /// Where the build ships, such as an SDK or Windows release
+    pub source: &'static str,
+}
+
+/// Builds that load with no missing imports and pass the test suite.
+///
+/// Add an entry only after `d3dcrs doctor` and `cargo test` pass with that
+/// exact file.
+pub static KNOWN_BUILDS: &[KnownBuild] = &[];
+
+/// File version from the DLL's `VS_FIXEDFILEINFO`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub build: u16,
+    pub revision: u16,
+}
+
+impl std::fmt::Display for FileVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.build, self.revision
+        )
+    }
+}
+
+/// The identity of a loaded DLL
+#[derive(Clone, Debug)]
+pub struct CompilerInfo {
+    /// SHA-1 of the DLL file
+    pub sha1: [u8; 20],
+    /// File version, if the DLL has a version resource
+    pub file_version: Option<FileVersion>,
+    /// The matching entry of [`KNOWN_BUILDS`], if any
+    pub known_build: Option<&'static KnownBuild>,
+}
+
+impl CompilerInfo {
+    pub(crate) fn new(file: &PeFile64, dll: &[u8]) -> Self {
+        Self::with_builds(file, dll, KNOWN_BUILDS)
+    }
+
+    /// Identifies `dll` against `builds` instead of [`KNOWN_BUILDS`]
+    fn with_builds(file: &PeFile64, dll: &[u8], builds: &'static [KnownBuild]) -> Self {
+        let sha1: [u8; 20] = sha1::Sha1::digest(dll).into();
+        let hex = to_hex(&sha1);
+        CompilerInfo {
+            sha1,
+            file_version: file_version(file, dll),
+            known_build: builds.iter().find(|build| build.sha1 == hex),
+        }
+    }
+
+    /// Returns the SHA-1 as lowercase hex
+    pub fn sha1_hex(&self) -> String {
+        to_hex(&self.sha1)
+    }
+}
+
+impl std::fmt::Display for CompilerInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.file_version {
+            Some(version) => write!(f, "version {}", version)?,
+            None => write!(f, "unknown version")?,
+        }
+        write!(f, ", sha1 {}", self.sha1_hex())?;
+        match self.known_build {
+            Some(build) => write!(f, " ({})", build.source),
+            None => write!(f, " (unrecognized build)"),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Reads the file version from the first `RT_VERSION` resource
+fn file_version(file: &PeFile64, dll: &[u8]) -> Option<FileVersion> {
+    let sections = file.section_table();
+    let resources = file
+        .data_directories()
+        .resource_directory(dll, &sections)
+        .ok()??;
+    let root = resources.root().ok()?;
+    let entry = root
+        .entries
+        .iter()
+        .find(|entry| entry.name_or_id().id() == Some(RT_VERSION))?;
+
+    // Take the first name, then the first language
+    let mut data = entry.data(resources).ok()?;
+    let entry = loop {
+        match data {
+            ResourceDirectoryEntryData::Table(table) => {
+                data = table.entries.first()?.data(resources).ok()?;
+            }
+            ResourceDirectoryEntryData::Data(entry) => break entry,
+        }
+    };
+    let resource = sections.pe_data_at(dll, entry.offset_to_data.get(LE))?;
+    let resource = resource.get(..entry.size.get(LE) as usize)?;
+    parse_version_info(resource)
+}
+
+/// Parses the `VS_FIXEDFILEINFO` at the start of a `VS_VERSIONINFO` block
+fn parse_version_info(data: &[u8]) -> Option<FileVersion> {
+    let u16_at = |offset: usize| -> Option<u16> {
+        Some(u16::from_le_bytes(
+            data.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let u32_at = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    // wLength, wValueLength, wType, then the key as a NUL-terminated UTF-16
+    // string, padded to a 4-byte boundary
+    const KEY: &str = "VS_VERSION_INFO";
+    let value_length = u16_at(2)? as usize;
+    let key = KEY.encode_utf16().chain([0]).enumerate();
+    for (i, c) in key {
+        if u16_at(6 + i * 2)? != c {
+            return None;
+        }
+    }
+    let value = (6 + (KEY.len() + 1) * 2).next_multiple_of(4);
+
+    const SIGNATURE: u32 = 0xFEEF04BD;
+    if value_length < 52 || u32_at(value)? != SIGNATURE {
+        return None;
+    }
+    let ms = u32_at(value + 8)?;
+    let ls = u32_at(value + 12)?;
+    Some(FileVersion {
+        major: (ms >> 16) as u16,
+        minor: ms as u16,
+        build: (ls >> 16) as u16,
+        revision: ls as u16,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PE32+ DLL with headers only
+    fn minimal_dll() -> Vec<u8> {
+        let mut dll = vec![0u8; 0x200];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            dll[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(0, b"MZ");
+        put(0x3C, &0x40u32.to_le_bytes());
+        put(0x40, b"PE\0\0");
+        // IMAGE_FILE_HEADER: AMD64, no sections, a 64-bit optional header
+        put(0x44, &0x8664u16.to_le_bytes());
+        put(0x54, &0xF0u16.to_le_bytes());
+        put(0x56, &0x2022u16.to_le_bytes());
+        // IMAGE_OPTIONAL_HEADER64
+        put(0x58, &0x20Bu16.to_le_bytes());
+        put(0x58 + 24, &0x1_8000_0000u64.to_le_bytes());
+        put(0x58 + 32, &0x1000u32.to_le_bytes());
+        put(0x58 + 36, &0x200u32.to_le_bytes());
+        put(0x58 + 56, &0x1000u32.to_le_bytes());
+        put(0x58 + 60, &0x200u32.to_le_bytes());
+        put(0x58 + 108, &16u32.to_le_bytes());
+        dll
+    }
+
+    #[test]
+    fn test_compiler_info_known_build() {
+        let dll = minimal_dll();
+        let file = PeFile64::parse(dll.as_slice()).expect("minimal DLL should parse");
+        let sha1: [u8; 20] = sha1::Sha1::digest(&dll).into();
+        let builds: &'static [KnownBuild] = Box::leak(Box::new([
+            KnownBuild {
+                sha1: "0000000000000000000000000000000000000000",
+                source: "Other",
+            },
+            KnownBuild {
+                sha1: Box::leak(to_hex(&sha1).into_boxed_str()),
+                source: "Test build",
+            },
+        ]));
+
+        let info = CompilerInfo::with_builds(&file, &dll, builds);
+        assert_eq!(info.sha1, sha1);
+        assert_eq!(info.file_version, None);
+        assert_eq!(
+            info.known_build.map(|build| build.source),
+            Some("Test build")
+        );
+        assert!(info.to_string().ends_with("(Test build)"));
+
+        let info = CompilerInfo::with_builds(&file, &dll, &builds[..1]);
+        assert!(info.known_build.is_none());
+        assert!(info.to_string().ends_with("(unrecognized build)"));
+    }
+
+    /// A `VS_VERSIONINFO` block holding only its `VS_FIXEDFILEINFO`
+    fn version_info(signature: u32, ms: u32, ls: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&92u16.to_le_bytes());
+        data.extend_from_slice(&52u16.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        for c in "VS_VERSION_INFO\0".encode_utf16() {
+            data.extend_from_slice(&c.to_le_bytes());
+        }
+        // The key ends at offset 38; padding to 40 must be skipped
+        data.extend_from_slice(&[0xAA, 0xAA]);
+        data.extend_from_slice(&signature.to_le_bytes());
+        data.extend_from_slice(&0x0001_0000u32.to_le_bytes());
+        data.extend_from_slice(&ms.to_le_bytes());
+        data.extend_from_slice(&ls.to_le_bytes());
+        data.resize(92, 0);
+        data
+    }
+
+    #[test]
+    fn test_parse_version_info() {
+        let data = version_info(0xFEEF04BD, (10 << 16) | 1, (19041 << 16) | 685);
+        assert_eq!(
+            parse_version_info(&data),
+            Some(FileVersion {
+                major: 10,
+                minor: 1,
+                build: 19041,
+                revision: 685,
+            })
+        );
+        assert_eq!(
+            parse_version_info(&data).unwrap().to_string(),
+            "10.1.19041.685"
+        );
+
+        // Truncated before the version numbers
+        assert_eq!(parse_version_info(&data[..50]), None);
+    }
+
+    #[test]
+    fn test_parse_version_info_rejects_bad_signature() {
+        let data = version_info(0xFEEF04BE, 10 << 16, 19041 << 16);
+        assert_eq!(parse_version_info(&data), None);
+
+        // A different key is not a VS_VERSIONINFO block either
+        let mut data = version_info(0xFEEF04BD, 10 << 16, 19041 << 16);
+        data[6] = b'X';
+        assert_eq!(parse_version_info(&data), None);
+    }
+
+    #[test]
+    fn test_known_builds_are_lowercase_sha1() {
+        for build in KNOWN_BUILDS {
+            assert_eq!(build.sha1.len(), 40, "{}", build.source);
+            assert!(
+                build
+                    .sha1
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)),
+                "{}",
+                build.source
+            );
+        }
+    }
+}
@@ -398,6 +398,62 @@ float4 main() : SV_TARGET { return float4(0, 1, 0, 1); }
     }
 }
 
+#[test]
+fn test_compile_with_standard_file_include() {
+    // Nested include resolved relative to the including file, not the source dir
+    let dir = std::env::temp_dir().join(format!("d3dcompiler_std_include_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("inc")).unwrap();
+    std::fs::write(
+        dir.join("inc").join("color.hlsl"),
+        "#include \"value.hlsl\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("inc").join("value.hlsl"),
+        "#define COLOR float4(1, 0, 0, 1)\n",
+    )
+    .unwrap();
+
+    let shader = b"
+#include \"inc\\color.hlsl\"
+float4 main() : SV_TARGET { return COLOR; }
+\0";
+    let source_name = std::ffi::CString::new(dir.join("main.hlsl").to_str().unwrap()).unwrap();
+
+    unsafe {
+        let mut code: *mut ID3DBlob = ptr::null_mut();
+        let mut errors: *mut ID3DBlob = ptr::null_mut();
+
+        let result = D3DCompile(
+            shader.as_ptr() as *const _,
+            shader.len() - 1,
+            source_name.as_ptr(),
+            ptr::null(),
+            D3D_COMPILE_STANDARD_FILE_INCLUDE,
+            c"main".as_ptr(),
+            c"ps_5_0".as_ptr(),
+            0,
+            0,
+            &mut code,
+            &mut errors,
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        if result != S_OK {
+            let err_msg = get_error_message(errors);
+            release_blob(errors);
+            panic!("Shader with standard include failed: {}", err_msg);
+        }
+
+        let bytecode = get_blob_data(code);
+        assert!(!bytecode.is_empty());
+
+        release_blob(code);
+        release_blob(errors);
+    }
+}
+
 #[test]
 fn test_compile_with_optimization_levels() {
     let shader = b"
@@ -2181,3 +2237,63 @@ fn test_variable_get_buffer() {
         release_blob(errors);
     }
 }
+
+// ============================================================================
+// Compiler Instance Loading Tests
+// ============================================================================
+
+#[test]
+fn test_load_from_missing_path() {
+    let path = std::path::Path::new("does/not/exist/d3dcompiler_47.dll");
+    match Compiler::load_from_path(path) {
+        Err(D3DCompilerError::ReadFailed { path: failed, .. }) => assert_eq!(failed, path),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loading a missing file should fail"),
+    }
+}
+
+#[test]
+fn test_load_from_invalid_bytes() {
+    match Compiler::load_from_bytes(b"not a PE image") {
+        Err(D3DCompilerError::ParseError(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loading garbage should fail"),
+    }
+}
+
+#[test]
+fn test_compiler_info() {
+    let info = compiler_info().expect("Failed to load DLL");
+    assert_eq!(Some(info.sha1), dll_sha1());
+    assert!(info.file_version.is_some(), "DLL has no version resource");
+}
+
+#[test]
+fn test_unload_after_compiling_on_another_thread() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../d3dcompiler_47.dll");
+    for _ in 0..2 {
+        let compiler = Compiler::load_from_path(path).expect("Failed to load DLL");
+        // The worker attaches on its first call and detaches when it exits
+        std::thread::scope(|scope| {
+            scope.spawn(|| unsafe {
+                let mut code: *mut ID3DBlob = ptr::null_mut();
+                let result = compiler.D3DCompile(
+                    PIXEL_SHADER.as_ptr() as *const _,
+                    PIXEL_SHADER.len() - 1,
+                    c"pixel.hlsl".as_ptr(),
+                    ptr::null(),
+                    ptr::null_mut(),
+                    c"main".as_ptr(),
+                    c"ps_5_0".as_ptr(),
+                    0,
+                    0,
+                    &mut code,
+                    ptr::null_mut(),
+                );
+                assert_eq!(result, S_OK);
+                release_blob(code);
+            });
+        });
+        unsafe { compiler.unload() };
+    }
+}
@@ -0,0 +1,69 @@
+//! Exceptions the DLL throws and catches itself
+//!
+//! In its own test binary because it sets the process-wide allocation limit.
+//! Requires d3dcompiler_47.dll, like the other integration tests.
+
+use d3dcompiler::*;
+use std::ptr;
+
+const SHADER: &[u8] = b"
+cbuffer Constants : register(b0) {
+    float4x4 worldViewProj;
+};
+
+float4 main(float3 pos : POSITION) : SV_POSITION {
+    return mul(float4(pos, 1.0), worldViewProj);
+}
+";
+
+unsafe fn release_blob(blob: *mut ID3DBlob) {
+    if !blob.is_null() {
+        unsafe { ((*(*blob).vtable).Release)(blob) };
+    }
+}
+
+fn compile() -> HRESULT {
+    let mut code: *mut ID3DBlob = ptr::null_mut();
+    let mut errors: *mut ID3DBlob = ptr::null_mut();
+    unsafe {
+        let result = D3DCompile(
+            SHADER.as_ptr() as *const _,
+            SHADER.len(),
+            c"shader.hlsl".as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            c"main".as_ptr(),
+            c"vs_5_0".as_ptr(),
+            0,
+            0,
+            &mut code,
+            &mut errors,
+        );
+        release_blob(code);
+        release_blob(errors);
+        result
+    }
+}
+
+#[test]
+fn test_caught_internal_error_fails_compile() {
+    assert_eq!(compile(), S_OK, "Compilation should succeed");
+
+    // Running out of memory makes operator new throw std::bad_alloc, which
+    // the DLL catches at its API boundary
+    set_allocation_limit(Some(4096), None);
+    let result = compile();
+    set_allocation_limit(None, None);
+    assert!(
+        result < 0,
+        "Compiling out of memory should fail, got 0x{:08x}",
+        result
+    );
+
+    // The unwound compile left the DLL usable
+    assert_eq!(
+        compile(),
+        S_OK,
+        "Compilation should succeed after the error"
+    );
+}
@@ -0,0 +1,253 @@
+//! Parallel batch compilation
+//!
+//! Runs many compiles on a pool of worker threads sharing the loaded DLL.
+//!
+//! # Example
+//! ```no_run
+//! use d3dcrs::batch::{Job, compile_all};
+//! use d3dcrs::ShaderTarget;
+//!
+//! let jobs = vec![
+//!     Job::from_file("shaders/triangle.hlsl", "VSMain", ShaderTarget::VS_5_0).unwrap(),
+//!     Job::from_file("shaders/triangle.hlsl", "PSMain", ShaderTarget::PS_5_0).unwrap(),
+//! ];
+//!
+//! for result in compile_all(jobs) {
+//!     match result {
+//!         Ok(result) => println!("{} bytes", result.bytecode.len()),
+//!         Err(e) => eprintln!("{}", e),
+//!     }
+//! }
+//! ```
+
+use crate::{
+    Cache, CompileBuilder, CompileFlags, CompileResult, FileSystemInclude, IncludeHandler, Result,
+    ShaderTarget, WorkerPool,
+};
+use std::path::Path;
+use std::sync::{Mutex, mpsc};
+use std::time::Duration;
+
+/// A single compile for [`compile_all`]
+///
+/// Holds the same inputs as [`CompileBuilder`], but owns them so the job can
+/// move to a worker thread.
+pub struct Job<'a> {
+    source: Vec<u8>,
+    source_name: Option<String>,
+    entry_point: String,
+    target: ShaderTarget,
+    defines: Vec<(String, String)>,
+    flags1: CompileFlags,
+    flags2: u32,
+    include: Option<Box<dyn IncludeHandler + Send + 'a>>,
+    cache: Option<&'a Cache>,
+    pool: Option<&'a WorkerPool>,
+    timeout: Option<Duration>,
+    memory_limit: Option<usize>,
+}
+
+impl<'a> Job<'a> {
+    /// Creates a job compiling `entry_point` from in-memory source.
+    pub fn new<S: Into<Vec<u8>>>(source: S, entry_point: &str, target: ShaderTarget) -> Self {
+        Job {
+            source: source.into(),
+            source_name: None,
+            entry_point: entry_point.to_string(),
+            target,
+            defines: Vec::new(),
+            flags1: CompileFlags::empty(),
+            flags2: 0,
+            include: None,
+            cache: None,
+            pool: None,
+            timeout: None,
+            memory_limit: None,
+        }
+    }
+
+    /// Creates a job reading its source from `path`.
+    ///
+    /// The path becomes the source name, and `#include "..."` resolves
+    /// relative to it.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        entry_point: &str,
+        target: ShaderTarget,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read(path)?;
+        Ok(Self::new(source, entry_point, target)
+            .source_name(&path.to_string_lossy())
+            .include(FileSystemInclude::new()))
+    }
+
+    /// Sets the source file name (used in error messages).
+    pub fn source_name(mut self, name: &str) -> Self {
+        self.source_name = Some(name.to_string());
+        self
+    }
+
+    /// Adds a preprocessor define.
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets compilation flags.
+    pub fn flags(mut self, flags: CompileFlags) -> Self {
+        self.flags1 = flags;
+        self
+    }
+
+    /// Sets secondary flags (Flags2 parameter).
+    pub fn flags2(mut self, flags: u32) -> Self {
+        self.flags2 = flags;
+        self
+    }
+
+    /// Sets an include handler used to resolve `#include` directives.
+    pub fn include<H: IncludeHandler + Send + 'a>(mut self, handler: H) -> Self {
+        self.include = Some(Box::new(handler));
+        self
+    }
+
+    /// Looks the compile up in `cache` before compiling.
+    pub fn cache(mut self, cache: &'a Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Runs the compile in one of `pool`'s helper processes.
+    pub fn isolated(mut self, pool: &'a WorkerPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Fails the job with [`Error::Timeout`](crate::Error::Timeout) if it
+    /// runs longer than `limit`; see [`CompileBuilder::timeout`].
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.timeout = Some(limit);
+        self
+    }
+
+    /// Fails the job with
+    /// [`Error::MemoryLimitExceeded`](crate::Error::MemoryLimitExceeded) if
+    /// the compiler allocates more than `bytes`; see
+    /// [`CompileBuilder::memory_limit`].
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    fn compile(self) -> Result<CompileResult> {
+        let mut builder = CompileBuilder::from_bytes(&self.source, &self.entry_point, self.target)
+            .flags(self.flags1)
+            .flags2(self.flags2);
+        if let Some(name) = &self.source_name {
+            builder = builder.source_name(name);
+        }
+        for (name, value) in &self.defines {
+            builder = builder.define(name, value);
+        }
+        if let Some(include) = self.include {
+            builder = builder.include(include);
+        }
+        if let Some(cache) = self.cache {
+            builder = builder.cache(cache);
+        }
+        if let Some(pool) = self.pool {
+            builder = builder.isolated(pool);
+        }
+        if let Some(limit) = self.timeout {
+            builder = builder.timeout(limit);
+        }
+        if let Some(bytes) = self.memory_limit {
+            builder = builder.memory_limit(bytes);
+        }
+        builder.compile()
+    }
+}
+
+/// Compiles every job using one worker per available CPU.
+///
+/// Results are returned in the same order as `jobs`.
+pub fn compile_all(jobs: Vec<Job<'_>>) -> Vec<Result<CompileResult>> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    compile_all_with_threads(jobs, threads)
+}
+
+/// Compiles every job using at most `threads` workers.
+///
+/// Results are returned in the same order as `jobs`.
+pub fn compile_all_with_threads(jobs: Vec<Job<'_>>, threads: usize) -> Vec<Result<CompileResult>> {
+    let count = jobs.len();
+    let threads = threads.clamp(1, count.max(1));
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let (sender, receiver) = mpsc::channel();
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let queue = &queue;
+            scope.spawn(move || {
+                d3dcompiler::setup_thread();
+                loop {
+                    // Release the lock before compiling so other workers can dequeue
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let Some((index, job)) = next else {
+                        break;
+                    };
+                    if sender.send((index, job.compile())).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    drop(sender);
+
+    let mut results: Vec<Option<Result<CompileResult>>> = (0..count).map(|_| None).collect();
+    for (index, result) in receiver {
+        results[index] = Some(result);
+    }
+    results
+        .into_iter()
+        .map(|result| result.expect("every job produces a result"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn test_compile_all_empty() {
+        assert!(compile_all(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_compile_all_order() {
+        let jobs = (0..8)
+            .map(|i| {
+                let source = if i == 5 {
+                    "float4 main() : SV_TARGET { return undefined_variable; }".to_string()
+                } else {
+                    format!("float4 main() : SV_TARGET {{ return {}; }}", i)
+                };
+                Job::new(source, "main", ShaderTarget::PS_5_0)
+            })
+            .collect();
+
+        let results = compile_all_with_threads(jobs, 3);
+        assert_eq!(results.len(), 8);
+        for (i, result) in results.iter().enumerate() {
+            if i == 5 {
+                assert!(matches!(result, Err(Error::Compilation { .. })));
+            } else {
+                assert!(result.is_ok(), "job {} failed: {:?}", i, result);
+            }
+        }
+    }
+}
@@ -50,6 +50,13 @@ impl Blob {
         }
     }
 
+    /// Creates a new blob holding a copy of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut blob = Self::new(data.len())?;
+        blob.as_bytes_mut().copy_from_slice(data);
+        Ok(blob)
+    }
+
     /// Returns the blob data as a byte slice.
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
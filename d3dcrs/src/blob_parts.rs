@@ -1,7 +1,8 @@
 //! Blob part extraction and modification API
 
+use crate::compile::compiler_or_default;
 use crate::{Blob, Error, HResult, Result};
-use d3dcompiler::{D3DGetBlobPart, D3DSetBlobPart, ID3DBlob, S_OK};
+use d3dcompiler::{Compiler, ID3DBlob, S_OK};
 use std::ptr;
 
 /// Blob part types for D3DGetBlobPart/D3DSetBlobPart
@@ -36,6 +37,108 @@ pub enum BlobPart {
     DebugName = 12,
 }
 
+/// Builder for reading or replacing one part of compiled shader bytecode
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::{compile, BlobPart, BlobPartBuilder, ShaderTarget};
+///
+/// let bytecode = compile(
+///     "float4 main(float4 pos : SV_POSITION) : SV_TARGET { return pos; }",
+///     "main",
+///     ShaderTarget::PS_5_0
+/// ).unwrap();
+///
+/// let input_sig = BlobPartBuilder::new(&bytecode, BlobPart::InputSignature)
+///     .get()
+///     .unwrap();
+/// ```
+pub struct BlobPartBuilder<'a> {
+    bytecode: &'a [u8],
+    part: BlobPart,
+    flags: u32,
+    compiler: Option<&'a Compiler>,
+}
+
+impl<'a> BlobPartBuilder<'a> {
+    /// Creates a new builder for `part` of `bytecode`.
+    pub fn new(bytecode: &'a [u8], part: BlobPart) -> Self {
+        BlobPartBuilder {
+            bytecode,
+            part,
+            flags: 0,
+            compiler: None,
+        }
+    }
+
+    /// Sets the flags passed to D3DGetBlobPart/D3DSetBlobPart.
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Uses `compiler` instead of the default instance.
+    pub fn compiler(mut self, compiler: &'a Compiler) -> Self {
+        self.compiler = Some(compiler);
+        self
+    }
+
+    /// Extracts the part.
+    pub fn get(self) -> Result<Blob> {
+        let compiler = compiler_or_default(self.compiler)?;
+        unsafe {
+            let mut blob: *mut ID3DBlob = ptr::null_mut();
+
+            let result = compiler.D3DGetBlobPart(
+                self.bytecode.as_ptr() as *const _,
+                self.bytecode.len(),
+                self.part as u32,
+                self.flags,
+                &mut blob,
+            );
+
+            if result != S_OK {
+                return Err(Error::GetBlobPart {
+                    hresult: HResult(result),
+                });
+            }
+
+            Blob::from_raw(blob).ok_or(Error::GetBlobPart {
+                hresult: HResult(result),
+            })
+        }
+    }
+
+    /// Returns a copy of the bytecode with the part replaced by `data`, or
+    /// inserted if it was absent.
+    pub fn set(self, data: &[u8]) -> Result<Blob> {
+        let compiler = compiler_or_default(self.compiler)?;
+        unsafe {
+            let mut blob: *mut ID3DBlob = ptr::null_mut();
+
+            let result = compiler.D3DSetBlobPart(
+                self.bytecode.as_ptr() as *const _,
+                self.bytecode.len(),
+                self.part as u32,
+                self.flags,
+                data.as_ptr() as *const _,
+                data.len(),
+                &mut blob,
+            );
+
+            if result != S_OK {
+                return Err(Error::SetBlobPart {
+                    hresult: HResult(result),
+                });
+            }
+
+            Blob::from_raw(blob).ok_or(Error::SetBlobPart {
+                hresult: HResult(result),
+            })
+        }
+    }
+}
+
 /// Extracts a specific part from compiled shader bytecode.
 ///
 /// # Example
@@ -59,27 +162,7 @@ pub fn get_blob_part(bytecode: &[u8], part: BlobPart) -> Result<Blob> {
 
 /// Extracts a specific part from compiled shader bytecode with flags.
 pub fn get_blob_part_with_flags(bytecode: &[u8], part: BlobPart, flags: u32) -> Result<Blob> {
-    unsafe {
-        let mut blob: *mut ID3DBlob = ptr::null_mut();
-
-        let result = D3DGetBlobPart(
-            bytecode.as_ptr() as *const _,
-            bytecode.len(),
-            part as u32,
-            flags,
-            &mut blob,
-        );
-
-        if result != S_OK {
-            return Err(Error::GetBlobPart {
-                hresult: HResult(result),
-            });
-        }
-
-        Blob::from_raw(blob).ok_or(Error::GetBlobPart {
-            hresult: HResult(result),
-        })
-    }
+    BlobPartBuilder::new(bytecode, part).flags(flags).get()
 }
 
 /// Replaces or inserts a part in compiled shader bytecode.
@@ -112,29 +195,7 @@ pub fn set_blob_part_with_flags(
     flags: u32,
     data: &[u8],
 ) -> Result<Blob> {
-    unsafe {
-        let mut blob: *mut ID3DBlob = ptr::null_mut();
-
-        let result = D3DSetBlobPart(
-            bytecode.as_ptr() as *const _,
-            bytecode.len(),
-            part as u32,
-            flags,
-            data.as_ptr() as *const _,
-            data.len(),
-            &mut blob,
-        );
-
-        if result != S_OK {
-            return Err(Error::SetBlobPart {
-                hresult: HResult(result),
-            });
-        }
-
-        Blob::from_raw(blob).ok_or(Error::SetBlobPart {
-            hresult: HResult(result),
-        })
-    }
+    BlobPartBuilder::new(bytecode, part).flags(flags).set(data)
 }
 
 /// Extracts the input signature from compiled bytecode.
@@ -0,0 +1,436 @@
+//! Content-addressed compile cache
+//!
+//! Compiles through a [`Cache`] are keyed on the preprocessed source, entry
+//! point, target, flags, defines, secondary data and the hash of the loaded
+//! DLL, so identical inputs reuse an earlier result regardless of which files
+//! they were included from. Debug compiles also key on the original source
+//! and its name, which the debug info records.
+
+use crate::{Error, Result};
+use sha1::{Digest, Sha1};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Bumped whenever the key inputs or entry format change.
+const CACHE_VERSION: &[u8] = b"d3dcrs-cache-2";
+
+/// Magic at the start of every entry file.
+const ENTRY_MAGIC: &[u8; 4] = b"DXCE";
+
+/// On-disk compile cache with size-based eviction
+///
+/// Entries are evicted least recently used first once the directory grows
+/// beyond [`max_size`](Self::with_max_size). A cache can be shared between
+/// threads and processes.
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::{Cache, CompileBuilder, ShaderTarget};
+///
+/// let cache = Cache::new("/tmp/shader-cache").unwrap();
+///
+/// let result = CompileBuilder::new(
+///     "float4 main() : SV_TARGET { return float4(1,0,0,1); }",
+///     "main",
+///     ShaderTarget::PS_5_0,
+/// )
+/// .cache(&cache)
+/// .compile()
+/// .unwrap();
+///
+/// println!("{:?}", cache.stats().unwrap());
+/// ```
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+    /// Total size of all entries, computed on first insert
+    size: Mutex<Option<u64>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Cache usage statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of stored entries
+    pub entries: u64,
+    /// Total size of stored entries in bytes
+    pub size: u64,
+    /// Lookups answered from the cache by this `Cache` instance
+    pub hits: u64,
+    /// Lookups that had to compile with this `Cache` instance
+    pub misses: u64,
+}
+
+/// Result of pruning the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruneStats {
+    /// Number of entries removed
+    pub removed_entries: u64,
+    /// Bytes freed
+    pub removed_size: u64,
+}
+
+/// A stored compile result
+pub(crate) struct CacheEntry {
+    pub(crate) bytecode: Vec<u8>,
+    pub(crate) warnings: Option<String>,
+}
+
+/// Hash identifying a compile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CacheKey([u8; 20]);
+
+impl CacheKey {
+    fn to_hex(self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Builds a [`CacheKey`] from length-prefixed fields
+pub(crate) struct KeyHasher(Sha1);
+
+impl KeyHasher {
+    pub(crate) fn new() -> Self {
+        let mut hasher = KeyHasher(Sha1::new());
+        hasher.field(CACHE_VERSION);
+        hasher
+    }
+
+    /// Adds a field; the length prefix keeps adjacent fields from running together.
+    pub(crate) fn field(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+        self
+    }
+
+    pub(crate) fn finish(self) -> CacheKey {
+        CacheKey(self.0.finalize().into())
+    }
+}
+
+struct EntryFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl Cache {
+    /// Default maximum cache size (1 GiB).
+    pub const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+    /// Opens a cache in `dir`, creating the directory if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Cache {
+            dir,
+            max_size: Self::DEFAULT_MAX_SIZE,
+            size: Mutex::new(None),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Opens the cache in [`default_dir`](Self::default_dir).
+    pub fn open_default() -> Result<Self> {
+        Self::new(Self::default_dir())
+    }
+
+    /// Returns the default cache directory.
+    ///
+    /// `$D3DCRS_CACHE_DIR` if set, otherwise `d3dcrs` under `$XDG_CACHE_HOME`
+    /// or `~/.cache`.
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("D3DCRS_CACHE_DIR") {
+            return PathBuf::from(dir);
+        }
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(std::env::temp_dir);
+        base.join("d3dcrs")
+    }
+
+    /// Sets the size above which old entries are evicted (default: 1 GiB).
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Returns the cache directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the maximum cache size in bytes.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Returns entry counts and sizes on disk, and this instance's hit rate.
+    pub fn stats(&self) -> Result<CacheStats> {
+        let entries = self.entries()?;
+        Ok(CacheStats {
+            entries: entries.len() as u64,
+            size: entries.iter().map(|e| e.size).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Removes every entry.
+    pub fn clear(&self) -> Result<PruneStats> {
+        self.prune_to(0)
+    }
+
+    /// Evicts least recently used entries until the cache fits in `max_size`.
+    pub fn prune(&self) -> Result<PruneStats> {
+        self.prune_to(self.max_size)
+    }
+
+    /// Evicts least recently used entries until the cache fits in `target` bytes.
+    pub fn prune_to(&self, target: u64) -> Result<PruneStats> {
+        let mut size = self.size.lock().unwrap_or_else(|e| e.into_inner());
+        let (remaining, stats) = self.evict(target)?;
+        *size = Some(remaining);
+        Ok(stats)
+    }
+
+    /// Looks up a stored result, marking it as recently used.
+    pub(crate) fn get(&self, key: CacheKey) -> Option<CacheEntry> {
+        let path = self.entry_path(key);
+        let entry = fs::read(&path).ok().and_then(|data| decode_entry(&data));
+        match &entry {
+            Some(_) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                // Eviction order follows mtime, so refresh it on use
+                if let Ok(file) = File::options().append(true).open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        entry
+    }
+
+    /// Stores a result, evicting old entries if the cache grows too large.
+    pub(crate) fn put(&self, key: CacheKey, bytecode: &[u8], warnings: Option<&str>) -> Result<()> {
+        let path = self.entry_path(key);
+        let parent = path.parent().expect("entry paths have a parent");
+        fs::create_dir_all(parent)?;
+
+        // Write to a temporary file and rename so readers never see a partial entry
+        static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let temp = parent.join(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let data = encode_entry(bytecode, warnings);
+        let mut replaced = 0;
+        let written = File::create(&temp)
+            .and_then(|mut file| file.write_all(&data))
+            .and_then(|_| {
+                // An entry being overwritten only adds the difference
+                replaced = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+                fs::rename(&temp, &path)
+            });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(Error::Io(e));
+        }
+
+        let mut size = self.size.lock().unwrap_or_else(|e| e.into_inner());
+        let current = match *size {
+            Some(current) => (current + data.len() as u64).saturating_sub(replaced),
+            None => self.entries()?.iter().map(|e| e.size).sum(),
+        };
+        *size = Some(current);
+
+        if current > self.max_size {
+            // Prune below the limit so the next few inserts don't rescan
+            let (remaining, _) = self.evict(self.max_size / 10 * 9)?;
+            *size = Some(remaining);
+        }
+        Ok(())
+    }
+
+    fn entry_path(&self, key: CacheKey) -> PathBuf {
+        let hex = key.to_hex();
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Lists entry files in the two-level `ab/cdef...` layout.
+    fn entries(&self) -> Result<Vec<EntryFile>> {
+        let mut entries = Vec::new();
+        for bucket in fs::read_dir(&self.dir)? {
+            let bucket = bucket?;
+            if !is_bucket_name(&bucket.file_name().to_string_lossy())
+                || !bucket.file_type()?.is_dir()
+            {
+                continue;
+            }
+            for file in fs::read_dir(bucket.path())? {
+                let file = file?;
+                let metadata = file.metadata()?;
+                if file.file_name().to_string_lossy().starts_with('.') || !metadata.is_file() {
+                    continue;
+                }
+                entries.push(EntryFile {
+                    path: file.path(),
+                    size: metadata.len(),
+                    modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Removes oldest entries until at most `target` bytes remain, returning
+    /// the remaining size.
+    fn evict(&self, target: u64) -> Result<(u64, PruneStats)> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|e| e.modified);
+
+        let mut remaining: u64 = entries.iter().map(|e| e.size).sum();
+        let mut stats = PruneStats {
+            removed_entries: 0,
+            removed_size: 0,
+        };
+        for entry in entries {
+            if remaining <= target {
+                break;
+            }
+            match fs::remove_file(&entry.path) {
+                Ok(()) => {
+                    stats.removed_entries += 1;
+                    stats.removed_size += entry.size;
+                }
+                // Another process evicted it first
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::Io(e)),
+            }
+            remaining -= entry.size;
+        }
+        Ok((remaining, stats))
+    }
+}
+
+fn is_bucket_name(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Entry layout: magic, u32 warnings length, warnings, bytecode.
+fn encode_entry(bytecode: &[u8], warnings: Option<&str>) -> Vec<u8> {
+    let warnings = warnings.unwrap_or_default().as_bytes();
+    let mut data = Vec::with_capacity(8 + warnings.len() + bytecode.len());
+    data.extend_from_slice(ENTRY_MAGIC);
+    data.extend_from_slice(&(warnings.len() as u32).to_le_bytes());
+    data.extend_from_slice(warnings);
+    data.extend_from_slice(bytecode);
+    data
+}
+
+fn decode_entry(data: &[u8]) -> Option<CacheEntry> {
+    let rest = data.strip_prefix(ENTRY_MAGIC)?;
+    let (len, rest) = rest.split_first_chunk::<4>()?;
+    let len = u32::from_le_bytes(*len) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (warnings, bytecode) = rest.split_at(len);
+    Some(CacheEntry {
+        bytecode: bytecode.to_vec(),
+        warnings: (!warnings.is_empty()).then(|| String::from_utf8_lossy(warnings).into_owned()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cache(name: &str) -> Cache {
+        let dir =
+            std::env::temp_dir().join(format!("d3dcrs-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Cache::new(dir).unwrap()
+    }
+
+    fn key(n: u8) -> CacheKey {
+        let mut hasher = KeyHasher::new();
+        hasher.field(&[n]);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_key_fields_are_delimited() {
+        let mut a = KeyHasher::new();
+        a.field(b"ab").field(b"c");
+        let mut b = KeyHasher::new();
+        b.field(b"a").field(b"bc");
+        assert_ne!(a.finish(), b.finish());
+    }
+
+    #[test]
+    fn test_put_get() {
+        let cache = temp_cache("put-get");
+        assert!(cache.get(key(1)).is_none());
+
+        cache
+            .put(key(1), b"DXBC1234", Some("warning X3206"))
+            .unwrap();
+        let entry = cache.get(key(1)).unwrap();
+        assert_eq!(entry.bytecode, b"DXBC1234");
+        assert_eq!(entry.warnings.as_deref(), Some("warning X3206"));
+
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+
+        cache.clear().unwrap();
+        assert_eq!(cache.stats().unwrap().entries, 0);
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_overwrite_keeps_size() {
+        let cache = temp_cache("overwrite");
+        cache.put(key(1), &[1; 100], None).unwrap();
+        cache.put(key(1), &[2; 40], None).unwrap();
+
+        let tracked = cache.size.lock().unwrap().unwrap();
+        assert_eq!(tracked, cache.stats().unwrap().size);
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = temp_cache("evict").with_max_size(250);
+        for n in 0..4 {
+            cache.put(key(n), &[n; 100], None).unwrap();
+            // Keep modification times distinct so eviction order is stable
+            let path = cache.entry_path(key(n));
+            let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000 + n as u64);
+            File::options()
+                .append(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        }
+
+        // Each entry is 108 bytes, so at most two fit
+        let stats = cache.stats().unwrap();
+        assert!(stats.size <= 250);
+        assert!(cache.get(key(3)).is_some());
+        assert!(cache.get(key(0)).is_none());
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
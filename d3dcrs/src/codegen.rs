@@ -0,0 +1,236 @@
+//! Source code generation for embedding compiled shaders
+//!
+//! Renders bytecode as C/C++ headers in the format written by `fxc /Fh`, or as
+//! Rust source defining a `pub static` byte array.
+
+use crate::{Blob, DisassembleBuilder, DisassembleFlags, Error, Result};
+use std::fmt::Write;
+
+/// Builder for C/C++ headers containing shader bytecode
+///
+/// The output matches `fxc /Fh`: an optional `#if 0` block holding the
+/// disassembly, followed by a `const BYTE name[]` array.
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::{compile, codegen::HeaderBuilder, ShaderTarget};
+///
+/// let bytecode = compile(
+///     "float4 main() : SV_TARGET { return float4(1,0,0,1); }",
+///     "main",
+///     ShaderTarget::PS_5_0
+/// ).unwrap();
+///
+/// let header = HeaderBuilder::new(&bytecode, "g_main")
+///     .with_disassembly()
+///     .render()
+///     .unwrap();
+///
+/// std::fs::write("shader.h", header).unwrap();
+/// ```
+pub struct HeaderBuilder<'a> {
+    bytecode: &'a [u8],
+    var_name: String,
+    disassembly: Option<DisassembleFlags>,
+    listing: Option<String>,
+}
+
+impl<'a> HeaderBuilder<'a> {
+    /// Creates a new header builder for the given bytecode and variable name.
+    pub fn new(bytecode: &'a [u8], var_name: &str) -> Self {
+        HeaderBuilder {
+            bytecode,
+            var_name: var_name.to_string(),
+            disassembly: None,
+            listing: None,
+        }
+    }
+
+    /// Creates a new header builder from a Blob.
+    pub fn from_blob(blob: &'a Blob, var_name: &str) -> Self {
+        Self::new(blob.as_bytes(), var_name)
+    }
+
+    /// Includes the disassembly of the bytecode as a comment.
+    pub fn with_disassembly(self) -> Self {
+        self.disassembly_flags(DisassembleFlags::empty())
+    }
+
+    /// Includes the disassembly of the bytecode, produced with the given flags.
+    pub fn disassembly_flags(mut self, flags: DisassembleFlags) -> Self {
+        self.disassembly = Some(flags);
+        self
+    }
+
+    /// Includes an already produced listing instead of disassembling again.
+    pub fn listing(mut self, listing: &str) -> Self {
+        self.listing = Some(listing.to_string());
+        self
+    }
+
+    /// Renders the header.
+    pub fn render(self) -> Result<String> {
+        validate_identifier(&self.var_name)?;
+
+        let listing = match (self.listing, self.disassembly) {
+            (Some(listing), _) => Some(listing),
+            (None, Some(flags)) => Some(
+                DisassembleBuilder::new(self.bytecode)
+                    .flags(flags)
+                    .disassemble()?
+                    .to_string_lossy(),
+            ),
+            (None, None) => None,
+        };
+
+        let mut out = String::new();
+        if let Some(listing) = listing {
+            out.push_str("#if 0\n");
+            out.push_str(listing.trim_end_matches(['\0', '\n', '\r']));
+            out.push_str("\n#endif\n\n");
+        }
+
+        writeln!(out, "const BYTE {}[] =\n{{", self.var_name).unwrap();
+        // fxc leaves a space before each line break
+        write_rows(&mut out, self.bytecode, 6, ", \n", |b| format!("{:>3}", b));
+        out.push_str("};\n");
+        Ok(out)
+    }
+}
+
+/// Renders bytecode as a C/C++ header without a disassembly comment.
+///
+/// Shorthand for `HeaderBuilder::new(bytecode, var_name).render()`.
+pub fn c_header(bytecode: &[u8], var_name: &str) -> Result<String> {
+    HeaderBuilder::new(bytecode, var_name).render()
+}
+
+/// Renders bytecode as Rust source defining `pub static NAME: [u8; N]`.
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::{compile, codegen, ShaderTarget};
+///
+/// let bytecode = compile(
+///     "float4 main() : SV_TARGET { return float4(1,0,0,1); }",
+///     "main",
+///     ShaderTarget::PS_5_0
+/// ).unwrap();
+///
+/// let module = codegen::rust_module(&bytecode, "PIXEL_SHADER").unwrap();
+/// std::fs::write("shader.rs", module).unwrap();
+/// ```
+pub fn rust_module(bytecode: &[u8], var_name: &str) -> Result<String> {
+    validate_identifier(var_name)?;
+
+    let mut out = String::new();
+    writeln!(out, "#[rustfmt::skip]").unwrap();
+    writeln!(out, "pub static {}: [u8; {}] = [", var_name, bytecode.len()).unwrap();
+    write_rows(&mut out, bytecode, 12, ",\n", |b| format!("0x{:02x}", b));
+    out.push_str("];\n");
+    Ok(out)
+}
+
+/// Writes `bytes` as comma-separated rows of `per_row` values, indented by
+/// four spaces, ending every row but the last with `row_end`.
+fn write_rows(
+    out: &mut String,
+    bytes: &[u8],
+    per_row: usize,
+    row_end: &str,
+    fmt: impl Fn(u8) -> String,
+) {
+    let rows = bytes.len().div_ceil(per_row);
+    for (i, row) in bytes.chunks(per_row).enumerate() {
+        let values: Vec<String> = row.iter().map(|&b| fmt(b)).collect();
+        out.push_str("    ");
+        out.push_str(&values.join(", "));
+        out.push_str(if i + 1 < rows { row_end } else { "\n" });
+    }
+}
+
+// Keywords, including reserved ones, that can't name a variable in Rust or
+// in the C and C++ that include generated headers
+const RUST_KEYWORDS: &str = "\
+    Self abstract as async await become box break const continue crate do dyn else enum \
+    extern false final fn for gen if impl in let loop macro match mod move mut override priv \
+    pub ref return self static struct super trait true try type typeof unsafe unsized use \
+    virtual where while yield";
+const C_KEYWORDS: &str = "\
+    auto bool break case catch char class const constexpr continue default delete do double \
+    else enum explicit extern false float for friend goto if inline int long namespace new \
+    nullptr operator private protected public register restrict return short signed sizeof \
+    static struct switch template this throw true try typedef typename union unsigned using \
+    virtual void volatile while";
+
+/// Checks that `name` is usable as both a C and a Rust identifier.
+fn validate_identifier(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name != "_"
+        && !RUST_KEYWORDS
+            .split_whitespace()
+            .chain(C_KEYWORDS.split_whitespace())
+            .any(|keyword| keyword == name);
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidParameter(format!(
+            "'{}' is not a valid variable name",
+            name
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_c_header() {
+        let bytes: Vec<u8> = (0..8).map(|i| i * 30).collect();
+        let header = c_header(&bytes, "g_main").unwrap();
+        assert_eq!(
+            header,
+            "const BYTE g_main[] =\n{\n      0,  30,  60,  90, 120, 150, \n    180, 210\n};\n"
+        );
+    }
+
+    #[test]
+    fn test_c_header_with_listing() {
+        let header = HeaderBuilder::new(&[1, 2], "g_ps")
+            .listing("//\n// Generated by Microsoft (R) HLSL Shader Compiler\nps_5_0\n")
+            .render()
+            .unwrap();
+        assert!(header.starts_with("#if 0\n//\n"));
+        assert!(header.contains("ps_5_0\n#endif\n\nconst BYTE g_ps[] =\n{\n      1,   2\n};\n"));
+    }
+
+    #[test]
+    fn test_rust_module() {
+        let module = rust_module(&[0xde, 0xad, 0xbe, 0xef], "SHADER").unwrap();
+        assert_eq!(
+            module,
+            "#[rustfmt::skip]\npub static SHADER: [u8; 4] = [\n    0xde, 0xad, 0xbe, 0xef\n];\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_var_name() {
+        assert!(c_header(&[0], "1abc").is_err());
+        assert!(rust_module(&[0], "my-shader").is_err());
+        assert!(rust_module(&[0], "").is_err());
+
+        // Keywords would not compile as a variable name
+        for keyword in ["fn", "type", "match", "Self", "gen", "int", "class"] {
+            assert!(rust_module(&[0], keyword).is_err(), "{}", keyword);
+            assert!(c_header(&[0], keyword).is_err(), "{}", keyword);
+        }
+        assert!(rust_module(&[0], "r#type").is_err());
+        assert!(rust_module(&[0], "SHADER_TYPE").is_ok());
+    }
+}
@@ -1,9 +1,17 @@
 //! Shader compilation API
 
-use crate::{Blob, CompileFlags, Error, HResult, Result, ShaderTarget};
-use d3dcompiler::{D3D_SHADER_MACRO, D3DCompile, ID3DBlob, ID3DInclude, S_OK};
+use crate::cache::KeyHasher;
+use crate::include::IncludeBridge;
+use crate::worker::CompileJob;
+use crate::{
+    Blob, Cache, CompileFlags, CompilerVersion, Error, HResult, IncludeHandler, PreprocessBuilder,
+    Result, ShaderTarget, WorkerPool, compiler_for_version,
+};
+use d3dcompiler::{Compiler, D3D_SHADER_MACRO, ID3DBlob, ID3DInclude, S_OK};
 use std::ffi::CString;
+use std::path::PathBuf;
 use std::ptr;
+use std::time::Duration;
 
 /// A preprocessor macro definition
 #[derive(Debug, Clone)]
@@ -40,6 +48,11 @@ pub struct CompileResult {
     pub bytecode: Blob,
     /// Any warning messages from the compiler (if present)
     pub warnings: Option<String>,
+    /// Files opened through the include handler set with `include`
+    ///
+    /// Listed in the order they were first opened, without duplicates. Files
+    /// that have no on-disk path are listed by their include name.
+    pub dependencies: Vec<PathBuf>,
 }
 
 /// Builder for shader compilation with fluent API
@@ -65,8 +78,16 @@ pub struct CompileBuilder<'a> {
     target: ShaderTarget,
     defines: Vec<Define>,
     include: Option<*mut ID3DInclude>,
+    handler: Option<Box<dyn IncludeHandler + 'a>>,
     flags1: CompileFlags,
     flags2: u32,
+    secondary_data: Option<(u32, &'a [u8])>,
+    cache: Option<&'a Cache>,
+    pool: Option<&'a WorkerPool>,
+    timeout: Option<Duration>,
+    memory_limit: Option<usize>,
+    compiler: Option<&'a Compiler>,
+    version: Option<CompilerVersion>,
 }
 
 impl<'a> CompileBuilder<'a> {
@@ -84,8 +105,16 @@ impl<'a> CompileBuilder<'a> {
             target,
             defines: Vec::new(),
             include: None,
+            handler: None,
             flags1: CompileFlags::empty(),
             flags2: 0,
+            secondary_data: None,
+            cache: None,
+            pool: None,
+            timeout: None,
+            memory_limit: None,
+            compiler: None,
+            version: None,
         }
     }
 
@@ -98,8 +127,16 @@ impl<'a> CompileBuilder<'a> {
             target,
             defines: Vec::new(),
             include: None,
+            handler: None,
             flags1: CompileFlags::empty(),
             flags2: 0,
+            secondary_data: None,
+            cache: None,
+            pool: None,
+            timeout: None,
+            memory_limit: None,
+            compiler: None,
+            version: None,
         }
     }
 
@@ -190,9 +227,21 @@ impl<'a> CompileBuilder<'a> {
     ///
     /// # Safety
     /// The include handler must remain valid for the duration of compilation.
-    /// Use `IncludeWrapper` from the include module for a safe interface.
+    /// Use [`include`](Self::include) for a safe interface.
     pub unsafe fn include_handler(mut self, include: *mut ID3DInclude) -> Self {
         self.include = Some(include);
+        self.handler = None;
+        self
+    }
+
+    /// Sets an include handler used to resolve `#include` directives.
+    ///
+    /// Replaces any handler set with [`include_handler`](Self::include_handler).
+    /// If the handler fails to open a file, compilation fails with
+    /// [`Error::Include`] naming that file.
+    pub fn include<H: IncludeHandler + 'a>(mut self, handler: H) -> Self {
+        self.handler = Some(Box::new(handler));
+        self.include = None;
         self
     }
 
@@ -202,10 +251,236 @@ impl<'a> CompileBuilder<'a> {
         self
     }
 
+    /// Sets secondary data passed to `D3DCompile2` along with its flags.
+    pub fn secondary_data(mut self, data: &'a [u8], flags: u32) -> Self {
+        self.secondary_data = Some((flags, data));
+        self
+    }
+
+    /// Looks the compile up in `cache` before compiling, and stores new results.
+    ///
+    /// The source is preprocessed on every call to compute the cache key, so
+    /// include handlers still run and `dependencies` is always filled in. On a
+    /// miss the original source is compiled, so the stored bytecode matches
+    /// an uncached compile. An isolated or limited compile preprocesses in a
+    /// helper process as well.
+    pub fn cache(mut self, cache: &'a Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Compiles with `compiler` instead of the default instance.
+    ///
+    /// Cannot be combined with an isolated compile, since helper processes
+    /// always use the default instance.
+    pub fn compiler(mut self, compiler: &'a Compiler) -> Self {
+        self.compiler = Some(compiler);
+        self
+    }
+
+    /// Compiles with the DLL loaded for `version`, see
+    /// [`load_compiler_version`](crate::load_compiler_version).
+    ///
+    /// Takes precedence over [`compiler`](Self::compiler). Like it, cannot be
+    /// combined with an isolated compile.
+    pub fn compiler_version(mut self, version: CompilerVersion) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Runs the compile in one of `pool`'s helper processes.
+    ///
+    /// A crash in the compiler then fails with [`Error::CompilerCrashed`]
+    /// instead of taking down this process. Include handlers set with
+    /// [`include`](Self::include) still run here; raw handlers set with
+    /// [`include_handler`](Self::include_handler) are rejected.
+    pub fn isolated(mut self, pool: &'a WorkerPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Fails with [`Error::Timeout`] if compiling takes longer than `limit`.
+    ///
+    /// The compiler cannot be interrupted in-process, so this runs the compile
+    /// in a helper process: the pool set with [`isolated`](Self::isolated), or
+    /// a shared pool with one helper per CPU.
+    pub fn timeout(mut self, limit: Duration) -> Self {
+        self.timeout = Some(limit);
+        self
+    }
+
+    /// Fails with [`Error::MemoryLimitExceeded`] if the compiler tries to hold
+    /// more than `bytes` at once.
+    ///
+    /// Like [`timeout`](Self::timeout), this runs the compile in a helper
+    /// process.
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
     /// Compiles the shader.
     ///
     /// Returns the compiled bytecode and any warning messages.
-    pub fn compile(self) -> Result<CompileResult> {
+    pub fn compile(mut self) -> Result<CompileResult> {
+        if let Some(version) = self.version {
+            self.compiler = Some(compiler_for_version(version)?);
+        }
+        match self.cache.take() {
+            Some(cache) => self.compile_cached(cache),
+            None => self.compile_uncached(),
+        }
+    }
+
+    fn compile_cached(mut self, cache: &Cache) -> Result<CompileResult> {
+        // Preprocessing only computes the key; a miss compiles the original
+        // source, so debug info refers to the real files and lines. An
+        // isolated or limited compile preprocesses in a helper too.
+        let preprocessed = match self.isolation_pool()? {
+            Some(pool) => {
+                let job = self.isolated_job(true)?;
+                pool.preprocess(job, self.handler.as_deref_mut())?
+            }
+            None => {
+                let mut preprocess = PreprocessBuilder::from_bytes(self.source);
+                if let Some(compiler) = self.compiler {
+                    preprocess = preprocess.compiler(compiler);
+                }
+                if let Some(name) = &self.source_name {
+                    preprocess = preprocess.source_name(&name.to_string_lossy());
+                }
+                for define in &self.defines {
+                    preprocess = preprocess.with_define(define.clone());
+                }
+                if let Some(handler) = self.handler.as_deref_mut() {
+                    preprocess = preprocess.include(handler);
+                } else if let Some(include) = self.include {
+                    preprocess = unsafe { preprocess.include_handler(include) };
+                }
+
+                // Report preprocessing failures the same way an uncached
+                // compile would
+                preprocess.preprocess().map_err(|e| match e {
+                    Error::Preprocessing { hresult, message } => {
+                        Error::Compilation { hresult, message }
+                    }
+                    other => other,
+                })?
+            }
+        };
+
+        let mut key = KeyHasher::new();
+        key.field(preprocessed.source.as_bytes())
+            .field(self.entry_point.as_bytes())
+            .field(self.target.to_string().as_bytes())
+            .field(&self.flags1.bits().to_le_bytes())
+            .field(&self.flags2.to_le_bytes())
+            .field(&match self.compiler {
+                Some(compiler) => compiler.dll_sha1(),
+                None => d3dcompiler::dll_sha1().unwrap_or_default(),
+            });
+        for define in &self.defines {
+            key.field(define.name.as_bytes())
+                .field(define.value.as_bytes());
+        }
+        if let Some((flags, data)) = self.secondary_data {
+            key.field(&flags.to_le_bytes()).field(data);
+        }
+        // Debug info records the file name and the text as written, comments
+        // included, which preprocessing drops
+        if self.flags1.contains(CompileFlags::DEBUG) {
+            key.field(self.source).field(
+                self.source_name
+                    .as_ref()
+                    .map_or(&[][..], |name| name.as_bytes()),
+            );
+        }
+        let key = key.finish();
+
+        if let Some(entry) = cache.get(key) {
+            return Ok(CompileResult {
+                bytecode: Blob::from_bytes(&entry.bytecode)?,
+                warnings: entry.warnings,
+                dependencies: preprocessed.dependencies,
+            });
+        }
+
+        let result = self.compile_uncached()?;
+
+        // The cache is only an optimization, so failing to store is not an error
+        let _ = cache.put(key, result.bytecode.as_bytes(), result.warnings.as_deref());
+
+        Ok(CompileResult {
+            bytecode: result.bytecode,
+            warnings: result.warnings,
+            dependencies: preprocessed.dependencies,
+        })
+    }
+
+    /// Returns the pool the compile runs in, if it is isolated or limited.
+    fn isolation_pool(&self) -> Result<Option<&'a WorkerPool>> {
+        if let Some(pool) = self.pool {
+            return Ok(Some(pool));
+        }
+        if self.timeout.is_some() || self.memory_limit.is_some() {
+            return WorkerPool::shared().map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Builds the job a helper runs for this compile, or only its
+    /// preprocessing step if `preprocess_only` is set.
+    fn isolated_job(&self, preprocess_only: bool) -> Result<CompileJob> {
+        if self.include.is_some() {
+            return Err(Error::InvalidParameter(
+                "raw include handlers cannot be used with an isolated compile".to_string(),
+            ));
+        }
+        if self.compiler.is_some() {
+            return Err(Error::InvalidParameter(
+                "a compiler instance cannot be used with an isolated compile".to_string(),
+            ));
+        }
+
+        Ok(CompileJob {
+            source: self.source.to_vec(),
+            source_name: self
+                .source_name
+                .as_ref()
+                .map(|name| name.to_string_lossy().into_owned()),
+            entry_point: self.entry_point.to_string_lossy().into_owned(),
+            target: self.target,
+            defines: self
+                .defines
+                .iter()
+                .map(|d| {
+                    (
+                        d.name.to_string_lossy().into_owned(),
+                        d.value.to_string_lossy().into_owned(),
+                    )
+                })
+                .collect(),
+            flags1: self.flags1,
+            flags2: self.flags2,
+            secondary_data: self
+                .secondary_data
+                .map(|(flags, data)| (flags, data.to_vec())),
+            has_include: self.handler.is_some(),
+            preprocess_only,
+            timeout: self.timeout,
+            memory_limit: self.memory_limit,
+        })
+    }
+
+    fn compile_uncached(self) -> Result<CompileResult> {
+        if let Some(pool) = self.isolation_pool()? {
+            let job = self.isolated_job(false)?;
+            let mut handler = self.handler;
+            return pool.compile(job, handler.as_deref_mut());
+        }
+
+        let compiler = compiler_or_default(self.compiler)?;
+
         // Build defines array (null-terminated)
         let mut defines_raw: Vec<D3D_SHADER_MACRO> = self
             .defines
@@ -222,30 +497,68 @@ impl<'a> CompileBuilder<'a> {
 
         let target_cstr = self.target.as_cstring();
 
+        let root = self
+            .source_name
+            .as_ref()
+            .and_then(|name| name.to_str().ok())
+            .map(PathBuf::from);
+        let mut bridge = self
+            .handler
+            .map(|handler| IncludeBridge::new(handler, root));
+        let include = match bridge.as_mut() {
+            Some(bridge) => bridge.as_raw(),
+            None => self.include.unwrap_or(ptr::null_mut()),
+        };
+
         unsafe {
             let mut code: *mut ID3DBlob = ptr::null_mut();
             let mut errors: *mut ID3DBlob = ptr::null_mut();
 
-            let result = D3DCompile(
-                self.source.as_ptr() as *const _,
-                self.source.len(),
-                self.source_name
-                    .as_ref()
-                    .map(|s| s.as_ptr())
-                    .unwrap_or(ptr::null()),
-                defines_raw.as_ptr(),
-                self.include.unwrap_or(ptr::null_mut()),
-                self.entry_point.as_ptr(),
-                target_cstr.as_ptr(),
-                self.flags1.bits(),
-                self.flags2,
-                &mut code,
-                &mut errors,
-            );
+            let source_name = self
+                .source_name
+                .as_ref()
+                .map(|s| s.as_ptr())
+                .unwrap_or(ptr::null());
+
+            let result = match self.secondary_data {
+                Some((secondary_flags, secondary_data)) => compiler.D3DCompile2(
+                    self.source.as_ptr() as *const _,
+                    self.source.len(),
+                    source_name,
+                    defines_raw.as_ptr(),
+                    include,
+                    self.entry_point.as_ptr(),
+                    target_cstr.as_ptr(),
+                    self.flags1.bits(),
+                    self.flags2,
+                    secondary_flags,
+                    secondary_data.as_ptr() as *const _,
+                    secondary_data.len(),
+                    &mut code,
+                    &mut errors,
+                ),
+                None => compiler.D3DCompile(
+                    self.source.as_ptr() as *const _,
+                    self.source.len(),
+                    source_name,
+                    defines_raw.as_ptr(),
+                    include,
+                    self.entry_point.as_ptr(),
+                    target_cstr.as_ptr(),
+                    self.flags1.bits(),
+                    self.flags2,
+                    &mut code,
+                    &mut errors,
+                ),
+            };
 
             let error_blob = Blob::from_raw(errors);
 
             if result != S_OK {
+                if let Some(failure) = bridge.as_mut().and_then(|b| b.take_failure()) {
+                    return Err(failure);
+                }
+
                 let message = error_blob
                     .as_ref()
                     .map(|b| b.to_string_lossy())
@@ -268,11 +581,28 @@ impl<'a> CompileBuilder<'a> {
                 .map(|b| b.to_string_lossy())
                 .filter(|s| !s.is_empty());
 
-            Ok(CompileResult { bytecode, warnings })
+            let dependencies = bridge
+                .as_mut()
+                .map(|b| b.take_dependencies())
+                .unwrap_or_default();
+
+            Ok(CompileResult {
+                bytecode,
+                warnings,
+                dependencies,
+            })
         }
     }
 }
 
+/// Returns `compiler`, or the default instance if none was set.
+pub(crate) fn compiler_or_default(compiler: Option<&Compiler>) -> Result<&Compiler> {
+    match compiler {
+        Some(compiler) => Ok(compiler),
+        None => Compiler::global().map_err(|e| Error::Load(e.to_string())),
+    }
+}
+
 /// Convenience function for simple shader compilation.
 ///
 /// # Example
@@ -325,6 +655,175 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_compile_with_include() {
+        let source = r#"
+            #include "color.hlsl"
+            float4 main() : SV_TARGET { return COLOR; }
+        "#;
+
+        let includes =
+            crate::MemoryInclude::new().with_file("color.hlsl", b"#define COLOR float4(1,0,0,1)");
+        let result = CompileBuilder::new(source, "main", ShaderTarget::PS_5_0)
+            .include(includes)
+            .compile();
+
+        let result = result.expect("Compilation should succeed");
+        assert_eq!(result.dependencies, vec![PathBuf::from("color.hlsl")]);
+    }
+
+    #[test]
+    fn test_compile_include_not_found() {
+        let source = r#"
+            #include "missing.hlsl"
+            float4 main() : SV_TARGET { return 0; }
+        "#;
+
+        let result = CompileBuilder::new(source, "main", ShaderTarget::PS_5_0)
+            .include(crate::MemoryInclude::new())
+            .compile();
+
+        match result {
+            Err(Error::Include { filename, .. }) => assert_eq!(filename, "missing.hlsl"),
+            other => panic!("Expected include error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compile_with_cache() {
+        let dir = std::env::temp_dir().join(format!("d3dcrs-compile-cache-{}", std::process::id()));
+        let cache = Cache::new(&dir).unwrap();
+        let source = r#"
+            #include "color.hlsl"
+            float4 main() : SV_TARGET { return COLOR; }
+        "#;
+        let includes = || {
+            crate::MemoryInclude::new().with_file("color.hlsl", b"#define COLOR float4(1,0,0,1)")
+        };
+
+        let first = CompileBuilder::new(source, "main", ShaderTarget::PS_5_0)
+            .include(includes())
+            .cache(&cache)
+            .compile()
+            .expect("Compilation should succeed");
+        let second = CompileBuilder::new(source, "main", ShaderTarget::PS_5_0)
+            .include(includes())
+            .cache(&cache)
+            .compile()
+            .expect("Cached compilation should succeed");
+
+        assert_eq!(first.bytecode.as_bytes(), second.bytecode.as_bytes());
+        assert_eq!(second.dependencies, vec![PathBuf::from("color.hlsl")]);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compile_with_cache_matches_uncached_debug() {
+        let dir =
+            std::env::temp_dir().join(format!("d3dcrs-compile-cache-debug-{}", std::process::id()));
+        let cache = Cache::new(&dir).unwrap();
+        let source = r#"
+            // Kept in the debug info but not in the preprocessed source
+            #include "color.hlsl"
+            float4 main() : SV_TARGET { return COLOR; }
+        "#;
+        let compile = |cache: Option<&Cache>| {
+            let mut builder = CompileBuilder::new(source, "main", ShaderTarget::PS_5_0)
+                .source_name("shaders/debug.hlsl")
+                .include(
+                    crate::MemoryInclude::new()
+                        .with_file("color.hlsl", b"#define COLOR float4(1,0,0,1)"),
+                )
+                .flags(CompileFlags::DEBUG | CompileFlags::SKIP_OPTIMIZATION);
+            if let Some(cache) = cache {
+                builder = builder.cache(cache);
+            }
+            builder.compile().expect("Compilation should succeed")
+        };
+
+        let uncached = compile(None);
+        let miss = compile(Some(&cache));
+        let hit = compile(Some(&cache));
+
+        assert_eq!(miss.bytecode.as_bytes(), uncached.bytecode.as_bytes());
+        assert_eq!(hit.bytecode.as_bytes(), uncached.bytecode.as_bytes());
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compile_with_cache_matches_uncached_warnings() {
+        let dir = std::env::temp_dir().join(format!(
+            "d3dcrs-compile-cache-warnings-{}",
+            std::process::id()
+        ));
+        let cache = Cache::new(&dir).unwrap();
+        // The redefinition warns while preprocessing, so it must appear once
+        let source = r#"
+            #define COLOR float4(0,1,0,1)
+            #define COLOR float4(1,0,0,1)
+            float4 main() : SV_TARGET { return COLOR; }
+        "#;
+        let compile = |cache: Option<&Cache>| {
+            let mut builder = CompileBuilder::new(source, "main", ShaderTarget::PS_5_0);
+            if let Some(cache) = cache {
+                builder = builder.cache(cache);
+            }
+            builder.compile().expect("Compilation should succeed")
+        };
+
+        let uncached = compile(None);
+        let miss = compile(Some(&cache));
+        let hit = compile(Some(&cache));
+
+        let warnings = uncached.warnings.as_deref().expect("Should warn");
+        assert!(warnings.contains("COLOR"), "{warnings}");
+        assert_eq!(miss.warnings, uncached.warnings);
+        assert_eq!(hit.warnings, uncached.warnings);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compile_with_cache_isolated() {
+        let dir = std::env::temp_dir().join(format!(
+            "d3dcrs-compile-cache-isolated-{}",
+            std::process::id()
+        ));
+        let cache = Cache::new(&dir).unwrap();
+        let pool = WorkerPool::new(1).unwrap();
+        let source = r#"
+            #include "color.hlsl"
+            float4 main() : SV_TARGET { return COLOR; }
+        "#;
+        let compile = |source: &str| {
+            CompileBuilder::new(source, "main", ShaderTarget::PS_5_0)
+                .include(
+                    crate::MemoryInclude::new()
+                        .with_file("color.hlsl", b"#define COLOR float4(1,0,0,1)"),
+                )
+                .cache(&cache)
+                .isolated(&pool)
+                .compile()
+        };
+
+        let miss = compile(source).expect("Compilation should succeed");
+        let hit = compile(source).expect("Cached compilation should succeed");
+        assert_eq!(miss.bytecode.as_bytes(), hit.bytecode.as_bytes());
+        assert_eq!(hit.dependencies, vec![PathBuf::from("color.hlsl")]);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        // A preprocessing failure in the helper reads like a compile error
+        match compile("#error stop\n") {
+            Err(Error::Compilation { message, .. }) => assert!(message.contains("stop")),
+            other => panic!("Expected compilation error, got {:?}", other),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
     #[test]
     fn test_compile_error() {
         let bad_source = "float4 main() : SV_TARGET { return undefined_variable; }";
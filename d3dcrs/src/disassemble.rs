@@ -1,7 +1,8 @@
 //! Shader disassembly API
 
+use crate::compile::compiler_or_default;
 use crate::{Blob, DisassembleFlags, Error, HResult, Result};
-use d3dcompiler::{D3DDisassemble, ID3DBlob, S_OK};
+use d3dcompiler::{Compiler, ID3DBlob, S_OK};
 use std::ffi::CString;
 use std::ptr;
 
@@ -29,6 +30,7 @@ pub struct DisassembleBuilder<'a> {
     bytecode: &'a [u8],
     flags: DisassembleFlags,
     comment: Option<CString>,
+    compiler: Option<&'a Compiler>,
 }
 
 impl<'a> DisassembleBuilder<'a> {
@@ -38,6 +40,7 @@ impl<'a> DisassembleBuilder<'a> {
             bytecode,
             flags: DisassembleFlags::empty(),
             comment: None,
+            compiler: None,
         }
     }
 
@@ -79,12 +82,19 @@ impl<'a> DisassembleBuilder<'a> {
         self
     }
 
+    /// Disassembles with `compiler` instead of the default instance.
+    pub fn compiler(mut self, compiler: &'a Compiler) -> Self {
+        self.compiler = Some(compiler);
+        self
+    }
+
     /// Disassembles the bytecode.
     pub fn disassemble(self) -> Result<Blob> {
+        let compiler = compiler_or_default(self.compiler)?;
         unsafe {
             let mut disasm: *mut ID3DBlob = ptr::null_mut();
 
-            let result = D3DDisassemble(
+            let result = compiler.D3DDisassemble(
                 self.bytecode.as_ptr() as *const _,
                 self.bytecode.len(),
                 self.flags.bits(),
@@ -1,6 +1,7 @@
 //! Error types for d3dcrs operations
 
 use std::fmt;
+use std::time::Duration;
 use thiserror::Error;
 
 /// HRESULT error codes from Windows/D3D APIs
@@ -127,9 +128,76 @@ pub enum Error {
     #[error("Include file not found: {0}")]
     IncludeNotFound(String),
 
+    /// An include handler failed to provide a file
+    #[error("Failed to include '{filename}': {source}")]
+    Include {
+        /// The filename from the #include directive
+        filename: String,
+        /// The error returned by the include handler
+        #[source]
+        source: Box<Error>,
+    },
+
     /// IO error during include resolution
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+
+    /// An isolated compiler process crashed, aborted or hung
+    ///
+    /// Only returned for compiles run on a [`WorkerPool`](crate::WorkerPool).
+    #[error("Compiler process crashed{}", crash_details(*signal, stderr))]
+    CompilerCrashed {
+        /// The signal that killed the process, if it did not exit on its own
+        signal: Option<i32>,
+        /// What the process wrote to stderr during the failed compile
+        stderr: String,
+    },
+
+    /// A compile ran longer than the limit set with
+    /// [`CompileBuilder::timeout`](crate::CompileBuilder::timeout)
+    #[error("Compilation timed out after {limit:?}")]
+    Timeout {
+        /// The time limit that was exceeded
+        limit: Duration,
+    },
+
+    /// A compile tried to allocate more than the limit set with
+    /// [`CompileBuilder::memory_limit`](crate::CompileBuilder::memory_limit)
+    #[error("Compilation exceeded the memory limit of {limit} bytes")]
+    MemoryLimitExceeded {
+        /// The memory limit in bytes
+        limit: usize,
+    },
+
+    /// The default compiler failed to load
+    #[error("Failed to load the compiler: {0}")]
+    Load(String),
+
+    /// A compiler version's DLL imports functions that are not implemented
+    #[error("{dll} imports functions that are not implemented: {}", imports.join(", "))]
+    MissingImports {
+        /// Module name of the DLL
+        dll: String,
+        /// The missing imports, as `dll!name`
+        imports: Vec<String>,
+    },
+
+    /// A compile server rejected a request or sent a malformed reply
+    #[error("Compile server error: {0}")]
+    Server(String),
+}
+
+fn crash_details(signal: Option<i32>, stderr: &str) -> String {
+    let mut details = match signal {
+        Some(signal) => format!(" (signal {})", signal),
+        None => String::new(),
+    };
+    let stderr = stderr.trim();
+    if !stderr.is_empty() {
+        details.push_str(": ");
+        details.push_str(stderr);
+    }
+    details
 }
 
 /// Result type for d3dcrs operations
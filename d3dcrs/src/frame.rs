@@ -0,0 +1,199 @@
+//! Length-prefixed framing shared by the compile server and worker pool
+//!
+//! Also provides a minimal field codec for messages that do not need JSON.
+
+use std::io::{self, Read, Write};
+
+/// Largest frame either side will accept
+pub const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+/// Writes one length-prefixed frame.
+pub fn write_frame<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|&len| len as usize <= MAX_FRAME_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Reads one length-prefixed frame.
+///
+/// Returns `None` if the stream ends cleanly before the length prefix.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds limit", len),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+/// Appends little-endian fields to a message buffer.
+#[derive(Default)]
+pub(crate) struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) -> &mut Self {
+        self.buf.push(value);
+        self
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(crate) fn i32(&mut self, value: i32) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) -> &mut Self {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    pub(crate) fn str(&mut self, value: &str) -> &mut Self {
+        self.bytes(value.as_bytes())
+    }
+
+    pub(crate) fn opt_str(&mut self, value: Option<&str>) -> &mut Self {
+        match value {
+            Some(value) => self.u8(1).str(value),
+            None => self.u8(0),
+        }
+    }
+
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// Reads fields written by [`Encoder`], failing on truncated input.
+pub(crate) struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Decoder { data }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated message",
+            ));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn str(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    pub(crate) fn opt_str(&mut self) -> io::Result<Option<String>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => self.str().map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        assert_eq!(&buf[..4], &5u32.to_le_bytes());
+
+        let mut reader = buf.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"hello");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"");
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_frame_truncated() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        buf.truncate(6);
+        assert!(read_frame(&mut buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let message = Encoder::new()
+            .u8(7)
+            .u32(42)
+            .i32(-5)
+            .u64(1 << 40)
+            .bytes(b"\x00\x01")
+            .str("main")
+            .opt_str(None)
+            .opt_str(Some("a.hlsl"))
+            .finish();
+
+        let mut decoder = Decoder::new(&message);
+        assert_eq!(decoder.u8().unwrap(), 7);
+        assert_eq!(decoder.u32().unwrap(), 42);
+        assert_eq!(decoder.i32().unwrap(), -5);
+        assert_eq!(decoder.u64().unwrap(), 1 << 40);
+        assert_eq!(decoder.bytes().unwrap(), b"\x00\x01");
+        assert_eq!(decoder.str().unwrap(), "main");
+        assert_eq!(decoder.opt_str().unwrap(), None);
+        assert_eq!(decoder.opt_str().unwrap().as_deref(), Some("a.hlsl"));
+        assert!(decoder.u8().is_err());
+    }
+}
@@ -1,7 +1,11 @@
 //! Include handler trait for custom #include resolution
 
 use crate::{Error, Result};
-use std::path::PathBuf;
+use d3dcompiler::{E_FAIL, HRESULT, ID3DInclude, ID3DIncludeVtbl, LPCSTR, S_OK, UINT};
+use std::collections::HashMap;
+use std::ffi::{CStr, c_void};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::{Path, PathBuf};
 
 /// Include type (local or system)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -52,21 +56,79 @@ pub trait IncludeHandler {
     /// # Returns
     /// The file contents as a byte vector, or an error if the file cannot be found/read.
     fn open(&mut self, include_type: IncludeType, filename: &str) -> Result<Vec<u8>>;
+
+    /// Opens an include file relative to the file that contains the directive.
+    ///
+    /// `parent` is the on-disk path of the including file when it is known: the
+    /// builder's source name for the top-level file, or the path a previous call
+    /// returned for nested includes. The default implementation ignores it and
+    /// forwards to [`open`](Self::open).
+    fn open_from(
+        &mut self,
+        include_type: IncludeType,
+        filename: &str,
+        parent: Option<&Path>,
+    ) -> Result<IncludeFile> {
+        let _ = parent;
+        self.open(include_type, filename)
+            .map(IncludeFile::in_memory)
+    }
+}
+
+/// An include file returned by [`IncludeHandler::open_from`]
+#[derive(Debug, Clone)]
+pub struct IncludeFile {
+    /// The file contents
+    pub contents: Vec<u8>,
+    /// Where the file was found on disk, used to resolve its own includes
+    pub path: Option<PathBuf>,
+}
+
+impl IncludeFile {
+    /// Creates an include file that was not read from disk.
+    pub fn in_memory(contents: Vec<u8>) -> Self {
+        IncludeFile {
+            contents,
+            path: None,
+        }
+    }
+
+    /// Reads an include file from disk, remembering its path.
+    pub fn read<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let contents = std::fs::read(&path)?;
+        Ok(IncludeFile {
+            contents,
+            path: Some(path),
+        })
+    }
 }
 
 /// File system include handler that resolves includes from specified directories.
 ///
+/// Follows FXC's lookup rules:
+/// * `#include "file"` searches the including file's directory first, then the
+///   search paths.
+/// * `#include <file>` searches the system paths only.
+///
+/// Filenames under a virtual mount (e.g. `/Engine/Private/Common.ush`) are
+/// mapped to the mount's directory first. Other absolute filenames are opened
+/// directly.
+///
 /// # Example
 /// ```no_run
 /// use d3dcrs::FileSystemInclude;
 ///
 /// let include = FileSystemInclude::new()
 ///     .with_path("shaders/include")
-///     .with_path("/usr/local/share/hlsl");
+///     .with_system_path("/usr/local/share/hlsl")
+///     .with_virtual_path("/Engine/Private", "engine/shaders/private");
 /// ```
 #[derive(Debug, Clone, Default)]
 pub struct FileSystemInclude {
     search_paths: Vec<PathBuf>,
+    system_paths: Vec<PathBuf>,
+    virtual_paths: Vec<(String, PathBuf)>,
 }
 
 impl FileSystemInclude {
@@ -74,6 +136,8 @@ impl FileSystemInclude {
     pub fn new() -> Self {
         FileSystemInclude {
             search_paths: Vec::new(),
+            system_paths: Vec::new(),
+            virtual_paths: Vec::new(),
         }
     }
 
@@ -101,27 +165,121 @@ impl FileSystemInclude {
     pub fn search_paths(&self) -> &[PathBuf] {
         &self.search_paths
     }
-}
 
-impl IncludeHandler for FileSystemInclude {
-    fn open(&mut self, _include_type: IncludeType, filename: &str) -> Result<Vec<u8>> {
-        // Try each search path
-        for search_path in &self.search_paths {
-            let path = search_path.join(filename);
-            if path.exists() {
-                return std::fs::read(&path).map_err(Into::into);
-            }
+    /// Adds a system search path used for `#include <...>` (builder pattern).
+    pub fn with_system_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.system_paths.push(path.into());
+        self
+    }
+
+    /// Adds a system search path used for `#include <...>`.
+    pub fn add_system_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.system_paths.push(path.into());
+    }
+
+    /// Returns the system search paths.
+    pub fn system_paths(&self) -> &[PathBuf] {
+        &self.system_paths
+    }
+
+    /// Maps a virtual include prefix to a directory (builder pattern).
+    ///
+    /// `#include "/Engine/Private/Common.ush"` with the mount `/Engine/Private`
+    /// reads `Common.ush` from `dir`.
+    pub fn with_virtual_path<P: Into<PathBuf>>(mut self, mount: &str, dir: P) -> Self {
+        self.add_virtual_path(mount, dir);
+        self
+    }
+
+    /// Maps a virtual include prefix to a directory.
+    ///
+    /// The mount `/` maps every absolute include into `dir`. An empty mount
+    /// never matches.
+    pub fn add_virtual_path<P: Into<PathBuf>>(&mut self, mount: &str, dir: P) {
+        let trimmed = mount.trim_end_matches('/');
+        let mount = if trimmed.is_empty() && !mount.is_empty() {
+            "/"
+        } else {
+            trimmed
+        };
+        self.virtual_paths.push((mount.to_string(), dir.into()));
+    }
+
+    /// Returns the virtual mounts and the directories they map to.
+    pub fn virtual_paths(&self) -> &[(String, PathBuf)] {
+        &self.virtual_paths
+    }
+
+    /// Maps a filename under a virtual mount to its on-disk path, preferring the
+    /// longest matching mount.
+    fn resolve_virtual(&self, filename: &str) -> Option<PathBuf> {
+        self.virtual_paths
+            .iter()
+            .filter(|(mount, _)| !mount.is_empty())
+            .filter_map(|(mount, dir)| {
+                let rest = filename.strip_prefix(mount.as_str())?;
+                let rest = if rest.is_empty() || mount == "/" {
+                    rest
+                } else {
+                    rest.strip_prefix('/')?
+                };
+                Some((mount.len(), dir.join(rest)))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, path)| path)
+    }
+
+    /// Resolves an include to the path it would be read from.
+    pub fn resolve(
+        &self,
+        include_type: IncludeType,
+        filename: &str,
+        parent: Option<&Path>,
+    ) -> Option<PathBuf> {
+        if let Some(path) = self.resolve_virtual(filename) {
+            return path.is_file().then_some(path);
         }
 
-        // If no search paths, try the filename directly
-        if self.search_paths.is_empty() {
-            let path = PathBuf::from(filename);
-            if path.exists() {
-                return std::fs::read(&path).map_err(Into::into);
-            }
+        let name = Path::new(filename);
+        if name.is_absolute() {
+            return name.is_file().then(|| name.to_path_buf());
         }
 
-        Err(Error::IncludeNotFound(filename.to_string()))
+        let candidates: Vec<PathBuf> = match include_type {
+            IncludeType::Local => {
+                let parent_dir = parent.map(|p| p.parent().unwrap_or(Path::new("")));
+                let mut dirs: Vec<&Path> = parent_dir.into_iter().collect();
+                dirs.extend(self.search_paths.iter().map(PathBuf::as_path));
+
+                // Without any context, fall back to the working directory
+                if dirs.is_empty() {
+                    dirs.push(Path::new(""));
+                }
+                dirs.into_iter().map(|dir| dir.join(name)).collect()
+            }
+            IncludeType::System => self.system_paths.iter().map(|dir| dir.join(name)).collect(),
+        };
+
+        candidates.into_iter().find(|path| path.is_file())
+    }
+}
+
+impl IncludeHandler for FileSystemInclude {
+    fn open(&mut self, include_type: IncludeType, filename: &str) -> Result<Vec<u8>> {
+        self.open_from(include_type, filename, None)
+            .map(|file| file.contents)
+    }
+
+    fn open_from(
+        &mut self,
+        include_type: IncludeType,
+        filename: &str,
+        parent: Option<&Path>,
+    ) -> Result<IncludeFile> {
+        let path = self
+            .resolve(include_type, filename, parent)
+            .ok_or_else(|| Error::IncludeNotFound(filename.to_string()))?;
+        IncludeFile::read(path)
     }
 }
 
@@ -173,6 +331,183 @@ impl IncludeHandler for MemoryInclude {
     }
 }
 
+impl<T: IncludeHandler + ?Sized> IncludeHandler for &mut T {
+    fn open(&mut self, include_type: IncludeType, filename: &str) -> Result<Vec<u8>> {
+        (**self).open(include_type, filename)
+    }
+
+    fn open_from(
+        &mut self,
+        include_type: IncludeType,
+        filename: &str,
+        parent: Option<&Path>,
+    ) -> Result<IncludeFile> {
+        (**self).open_from(include_type, filename, parent)
+    }
+}
+
+impl<T: IncludeHandler + ?Sized> IncludeHandler for Box<T> {
+    fn open(&mut self, include_type: IncludeType, filename: &str) -> Result<Vec<u8>> {
+        (**self).open(include_type, filename)
+    }
+
+    fn open_from(
+        &mut self,
+        include_type: IncludeType,
+        filename: &str,
+        parent: Option<&Path>,
+    ) -> Result<IncludeFile> {
+        (**self).open_from(include_type, filename, parent)
+    }
+}
+
+/// Adapts an [`IncludeHandler`] to the compiler's C ABI `ID3DInclude` interface.
+///
+/// The `base` field must stay first so a pointer to the bridge can be handed to
+/// the compiler as an `ID3DInclude`. Buffers returned from `Open` are owned by
+/// the bridge until the compiler calls `Close` on them (or the bridge is dropped),
+/// together with the on-disk path they came from. The compiler passes the
+/// including file's buffer back as `pParentData`, which is how nested includes
+/// find their parent's path.
+#[repr(C)]
+pub(crate) struct IncludeBridge<'a> {
+    base: ID3DInclude,
+    handler: Box<dyn IncludeHandler + 'a>,
+    root: Option<PathBuf>,
+    buffers: HashMap<usize, IncludeFile>,
+    dependencies: Vec<PathBuf>,
+    failure: Option<(String, Error)>,
+}
+
+static INCLUDE_BRIDGE_VTABLE: ID3DIncludeVtbl = ID3DIncludeVtbl {
+    Open: include_bridge_open,
+    Close: include_bridge_close,
+};
+
+impl<'a> IncludeBridge<'a> {
+    /// Creates a boxed bridge so its address stays stable while the compiler holds it.
+    ///
+    /// `root` is the path of the top-level source, used as the parent of its includes.
+    pub(crate) fn new(handler: Box<dyn IncludeHandler + 'a>, root: Option<PathBuf>) -> Box<Self> {
+        Box::new(IncludeBridge {
+            base: ID3DInclude {
+                vtable: &INCLUDE_BRIDGE_VTABLE,
+            },
+            handler,
+            root,
+            buffers: HashMap::new(),
+            dependencies: Vec::new(),
+            failure: None,
+        })
+    }
+
+    /// Returns the pointer to pass as `pInclude`.
+    pub(crate) fn as_raw(&mut self) -> *mut ID3DInclude {
+        self as *mut Self as *mut ID3DInclude
+    }
+
+    /// Takes the first handler error recorded during compilation, if any.
+    pub(crate) fn take_failure(&mut self) -> Option<Error> {
+        self.failure
+            .take()
+            .map(|(filename, source)| Error::Include {
+                filename,
+                source: Box::new(source),
+            })
+    }
+
+    /// Takes the files opened so far, in first-opened order without duplicates.
+    ///
+    /// Files without an on-disk path are reported by their include name.
+    pub(crate) fn take_dependencies(&mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.dependencies)
+    }
+
+    fn record_dependency(&mut self, name: &str, path: Option<&Path>) {
+        let dependency = path.map_or_else(|| PathBuf::from(name), Path::to_path_buf);
+        if !self.dependencies.contains(&dependency) {
+            self.dependencies.push(dependency);
+        }
+    }
+
+    fn record_failure(&mut self, filename: String, error: Error) {
+        if self.failure.is_none() {
+            self.failure = Some((filename, error));
+        }
+    }
+}
+
+unsafe extern "C" fn include_bridge_open(
+    this: *mut ID3DInclude,
+    include_type: u32,
+    filename: LPCSTR,
+    parent_data: *const c_void,
+    data_out: *mut *const c_void,
+    bytes_out: *mut UINT,
+) -> HRESULT {
+    if this.is_null() || filename.is_null() || data_out.is_null() || bytes_out.is_null() {
+        return E_FAIL;
+    }
+
+    let bridge = unsafe { &mut *(this as *mut IncludeBridge) };
+    let name = unsafe { CStr::from_ptr(filename) }
+        .to_string_lossy()
+        .into_owned();
+
+    let parent = if parent_data.is_null() {
+        bridge.root.clone()
+    } else {
+        bridge
+            .buffers
+            .get(&(parent_data as usize))
+            .and_then(|file| file.path.clone())
+    };
+
+    // Unwinding across the compiler would abort, so handler panics become failures
+    let opened = catch_unwind(AssertUnwindSafe(|| {
+        bridge
+            .handler
+            .open_from(IncludeType::from(include_type), &name, parent.as_deref())
+    }))
+    .unwrap_or_else(|_| Err(Error::InvalidParameter("include handler panicked".into())));
+
+    match opened {
+        Ok(mut file) => {
+            let Ok(len) = UINT::try_from(file.contents.len()) else {
+                bridge.record_failure(
+                    name,
+                    Error::InvalidParameter("include file larger than 4 GiB".into()),
+                );
+                return E_FAIL;
+            };
+            bridge.record_dependency(&name, file.path.as_deref());
+
+            // Force an allocation so every open buffer has a distinct address
+            file.contents.reserve(1);
+            let ptr = file.contents.as_ptr() as *const c_void;
+            bridge.buffers.insert(ptr as usize, file);
+            unsafe {
+                *data_out = ptr;
+                *bytes_out = len;
+            }
+            S_OK
+        }
+        Err(e) => {
+            bridge.record_failure(name, e);
+            E_FAIL
+        }
+    }
+}
+
+unsafe extern "C" fn include_bridge_close(this: *mut ID3DInclude, data: *const c_void) -> HRESULT {
+    if this.is_null() {
+        return E_FAIL;
+    }
+    let bridge = unsafe { &mut *(this as *mut IncludeBridge) };
+    bridge.buffers.remove(&(data as usize));
+    S_OK
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -195,4 +530,135 @@ mod tests {
         assert_eq!(IncludeType::from(1), IncludeType::System);
         assert_eq!(IncludeType::from(99), IncludeType::System);
     }
+
+    #[test]
+    fn test_include_bridge_open_close() {
+        let handler = MemoryInclude::new().with_file("common.hlsl", b"#define ONE 1");
+        let mut bridge = IncludeBridge::new(Box::new(handler), None);
+        let raw = bridge.as_raw();
+
+        let mut data: *const c_void = std::ptr::null();
+        let mut bytes: UINT = 0;
+        unsafe {
+            let vtable = &*(*raw).vtable;
+            let hr = (vtable.Open)(
+                raw,
+                0,
+                c"common.hlsl".as_ptr(),
+                std::ptr::null(),
+                &mut data,
+                &mut bytes,
+            );
+            assert_eq!(hr, S_OK);
+            assert_eq!(
+                std::slice::from_raw_parts(data as *const u8, bytes as usize),
+                b"#define ONE 1"
+            );
+            assert_eq!((vtable.Close)(raw, data), S_OK);
+
+            let hr = (vtable.Open)(
+                raw,
+                0,
+                c"missing.hlsl".as_ptr(),
+                std::ptr::null(),
+                &mut data,
+                &mut bytes,
+            );
+            assert_eq!(hr, E_FAIL);
+        }
+
+        assert!(bridge.buffers.is_empty());
+        assert_eq!(
+            bridge.take_dependencies(),
+            vec![PathBuf::from("common.hlsl")]
+        );
+        match bridge.take_failure() {
+            Some(Error::Include { filename, .. }) => assert_eq!(filename, "missing.hlsl"),
+            other => panic!("expected include failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_system_include_lookup_order() {
+        let root = std::env::temp_dir().join(format!("d3dcrs_include_{}", std::process::id()));
+        let shaders = root.join("shaders");
+        let common = root.join("common");
+        let system = root.join("system");
+        for dir in [&shaders, &common, &system] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(shaders.join("local.hlsl"), "local").unwrap();
+        std::fs::write(common.join("local.hlsl"), "common").unwrap();
+        std::fs::write(common.join("shared.hlsl"), "shared").unwrap();
+        std::fs::write(system.join("sys.hlsl"), "system").unwrap();
+
+        let handler = FileSystemInclude::new()
+            .with_path(&common)
+            .with_system_path(&system);
+        let parent = shaders.join("main.hlsl");
+
+        // Quoted includes prefer the including file's directory, then search paths
+        let found = handler.resolve(IncludeType::Local, "local.hlsl", Some(&parent));
+        assert_eq!(found, Some(shaders.join("local.hlsl")));
+        let found = handler.resolve(IncludeType::Local, "shared.hlsl", Some(&parent));
+        assert_eq!(found, Some(common.join("shared.hlsl")));
+        assert_eq!(
+            handler.resolve(IncludeType::Local, "sys.hlsl", Some(&parent)),
+            None
+        );
+
+        // Angle-bracket includes only look at system paths
+        let found = handler.resolve(IncludeType::System, "sys.hlsl", Some(&parent));
+        assert_eq!(found, Some(system.join("sys.hlsl")));
+        assert_eq!(
+            handler.resolve(IncludeType::System, "local.hlsl", Some(&parent)),
+            None
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_file_system_include_virtual_paths() {
+        let root = std::env::temp_dir().join(format!("d3dcrs_virtual_{}", std::process::id()));
+        let engine = root.join("engine");
+        let private = root.join("private");
+        std::fs::create_dir_all(&engine).unwrap();
+        std::fs::create_dir_all(&private).unwrap();
+        std::fs::write(engine.join("Common.ush"), "engine").unwrap();
+        std::fs::write(private.join("Common.ush"), "private").unwrap();
+
+        let handler = FileSystemInclude::new()
+            .with_virtual_path("/Engine/", &engine)
+            .with_virtual_path("/Engine/Private", &private);
+
+        // The longest matching mount wins
+        let found = handler.resolve(IncludeType::Local, "/Engine/Private/Common.ush", None);
+        assert_eq!(found, Some(private.join("Common.ush")));
+        let found = handler.resolve(IncludeType::System, "/Engine/Common.ush", None);
+        assert_eq!(found, Some(engine.join("Common.ush")));
+
+        // Mounts only match whole path components
+        assert_eq!(
+            handler.resolve(IncludeType::Local, "/EngineX/Common.ush", None),
+            None
+        );
+
+        // An empty mount matches nothing, `/` is the root of every path
+        let handler = FileSystemInclude::new().with_virtual_path("", &engine);
+        assert_eq!(
+            handler.resolve(IncludeType::Local, "Common.ush", None),
+            None
+        );
+        let handler = FileSystemInclude::new()
+            .with_virtual_path("/", &root)
+            .with_virtual_path("/Engine", &engine);
+        let found = handler.resolve(IncludeType::Local, "/private/Common.ush", None);
+        assert_eq!(found, Some(private.join("Common.ush")));
+        let found = handler.resolve(IncludeType::Local, "/Engine/Common.ush", None);
+        assert_eq!(found, Some(engine.join("Common.ush")));
+        assert_eq!(handler.virtual_paths()[0].0, "/");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
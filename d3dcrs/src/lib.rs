@@ -24,29 +24,52 @@
 //! println!("Instructions: {}", desc.instruction_count);
 //! ```
 
+pub mod batch;
 mod blob;
 mod blob_parts;
+mod cache;
+pub mod codegen;
 mod compile;
 mod disassemble;
 mod error;
 mod flags;
+mod frame;
 mod include;
+mod permutation;
 mod preprocess;
 pub mod reflect;
+#[cfg(feature = "server")]
+pub mod server;
 mod strip;
 mod target;
+mod versions;
+mod worker;
 
 pub use blob::Blob;
 pub use blob_parts::{
-    BlobPart, get_blob_part, get_debug_info, get_input_signature, get_output_signature,
-    get_private_data, set_blob_part,
+    BlobPart, BlobPartBuilder, get_blob_part, get_debug_info, get_input_signature,
+    get_output_signature, get_private_data, set_blob_part,
 };
+pub use cache::{Cache, CacheStats, PruneStats};
 pub use compile::{CompileBuilder, CompileResult, Define, compile};
 pub use disassemble::{DisassembleBuilder, disassemble};
 pub use error::{Error, HResult, Result};
 pub use flags::{CompileFlags, DisassembleFlags, StripFlags};
-pub use include::{FileSystemInclude, IncludeHandler, IncludeType, MemoryInclude};
+pub use include::{FileSystemInclude, IncludeFile, IncludeHandler, IncludeType, MemoryInclude};
+pub use permutation::{Permutation, PermutationBuilder, PermutationSet};
 pub use preprocess::{PreprocessBuilder, PreprocessResult, preprocess};
 pub use reflect::ShaderReflection;
-pub use strip::{strip_debug_info, strip_reflection_data, strip_shader};
+pub use strip::{StripBuilder, strip_debug_info, strip_reflection_data, strip_shader};
 pub use target::{ShaderModel, ShaderTarget, ShaderType};
+pub use versions::{CompilerVersion, compiler_for_version, load_compiler_version};
+pub use worker::WorkerPool;
+
+pub use d3dcompiler::{
+    Compiler, CompilerInfo, D3DCompilerError, FileVersion, ImportReport, ImportStatus,
+    KNOWN_BUILDS, KnownBuild, LoadReport, compiler_info, dll_search_paths, load_report,
+    set_dll_path, set_large_stack_calls,
+};
+#[cfg(feature = "trace-imports")]
+pub use d3dcompiler::{
+    ImportCall, set_import_trace_capacity, set_import_trace_hook, take_import_trace,
+};
@@ -0,0 +1,327 @@
+//! Define-permutation expansion
+//!
+//! Compiles a shader once for every combination of a set of define axes and
+//! folds permutations that produce identical bytecode onto one blob.
+
+use crate::batch::{self, Job};
+use crate::{Blob, Cache, CompileFlags, IncludeHandler, Result, ShaderTarget};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fmt;
+
+/// One compiled combination of axis values
+#[derive(Debug)]
+pub struct Permutation {
+    /// The define set for this combination, in axis order
+    ///
+    /// Flag axes that are off are left out.
+    pub defines: Vec<(String, String)>,
+    /// Index into [`PermutationSet::blobs`], or the compile error
+    pub blob: Result<usize>,
+    /// Any warning messages from the compiler
+    pub warnings: Option<String>,
+}
+
+impl Permutation {
+    /// Returns the permutation key, e.g. `QUALITY=1,USE_FOG=1`.
+    pub fn key(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Permutation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.defines.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of compiling every permutation
+#[derive(Debug)]
+pub struct PermutationSet {
+    /// Unique bytecode blobs
+    pub blobs: Vec<Blob>,
+    /// Every combination, in axis order with the last axis varying fastest
+    pub permutations: Vec<Permutation>,
+}
+
+impl PermutationSet {
+    /// Returns the permutation with the given key, if any.
+    pub fn get(&self, key: &str) -> Option<&Permutation> {
+        self.permutations.iter().find(|p| p.key() == key)
+    }
+
+    /// Returns the number of permutations that failed to compile.
+    pub fn failed(&self) -> usize {
+        self.permutations.iter().filter(|p| p.blob.is_err()).count()
+    }
+}
+
+type IncludeFactory<'a> = Box<dyn Fn() -> Box<dyn IncludeHandler + Send + 'a> + 'a>;
+
+/// Builder for permutation compiles
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::{PermutationBuilder, ShaderTarget};
+///
+/// let source = r#"
+///     float4 main() : SV_TARGET {
+///     #ifdef USE_FOG
+///         return QUALITY * 0.5;
+///     #else
+///         return 1;
+///     #endif
+///     }
+/// "#;
+///
+/// let set = PermutationBuilder::new(source, "main", ShaderTarget::PS_5_0)
+///     .axis("QUALITY", ["0", "1", "2"])
+///     .flag("USE_FOG")
+///     .compile();
+///
+/// // Without USE_FOG, QUALITY is unused, so those three share one blob
+/// for permutation in &set.permutations {
+///     println!("{} -> {:?}", permutation, permutation.blob);
+/// }
+/// ```
+pub struct PermutationBuilder<'a> {
+    source: &'a [u8],
+    source_name: Option<String>,
+    entry_point: String,
+    target: ShaderTarget,
+    axes: Vec<(String, Vec<Option<String>>)>,
+    defines: Vec<(String, String)>,
+    flags1: CompileFlags,
+    include: Option<IncludeFactory<'a>>,
+    cache: Option<&'a Cache>,
+    threads: Option<usize>,
+}
+
+impl<'a> PermutationBuilder<'a> {
+    /// Creates a new permutation builder with the required parameters.
+    pub fn new(source: &'a str, entry_point: &str, target: ShaderTarget) -> Self {
+        Self::from_bytes(source.as_bytes(), entry_point, target)
+    }
+
+    /// Creates a permutation builder from raw bytes.
+    pub fn from_bytes(source: &'a [u8], entry_point: &str, target: ShaderTarget) -> Self {
+        PermutationBuilder {
+            source,
+            source_name: None,
+            entry_point: entry_point.to_string(),
+            target,
+            axes: Vec::new(),
+            defines: Vec::new(),
+            flags1: CompileFlags::empty(),
+            include: None,
+            cache: None,
+            threads: None,
+        }
+    }
+
+    /// Sets the source file name (used in error messages).
+    pub fn source_name(mut self, name: &str) -> Self {
+        self.source_name = Some(name.to_string());
+        self
+    }
+
+    /// Adds an axis defining `name` to each of `values` in turn.
+    pub fn axis<I, S>(mut self, name: &str, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let values = values.into_iter().map(|v| Some(v.into())).collect();
+        self.axes.push((name.to_string(), values));
+        self
+    }
+
+    /// Adds an axis that either defines `name` as `1` or leaves it undefined.
+    pub fn flag(mut self, name: &str) -> Self {
+        self.axes
+            .push((name.to_string(), vec![Some("1".to_string()), None]));
+        self
+    }
+
+    /// Adds a define shared by every permutation.
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets compilation flags.
+    pub fn flags(mut self, flags: CompileFlags) -> Self {
+        self.flags1 = flags;
+        self
+    }
+
+    /// Sets an include handler; each permutation gets its own clone.
+    pub fn include<H: IncludeHandler + Clone + Send + 'a>(mut self, handler: H) -> Self {
+        self.include = Some(Box::new(move || Box::new(handler.clone())));
+        self
+    }
+
+    /// Looks each permutation up in `cache` before compiling.
+    pub fn cache(mut self, cache: &'a Cache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Sets the number of worker threads (default: one per CPU).
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Returns every combination of axis values, last axis varying fastest.
+    fn combinations(&self) -> Vec<Vec<(String, String)>> {
+        let mut combinations = vec![Vec::new()];
+        for (name, values) in &self.axes {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|prefix: Vec<(String, String)>| {
+                    values.iter().map(move |value| {
+                        let mut defines = prefix.clone();
+                        if let Some(value) = value {
+                            defines.push((name.clone(), value.clone()));
+                        }
+                        defines
+                    })
+                })
+                .collect();
+        }
+        combinations
+    }
+
+    /// Compiles every permutation.
+    ///
+    /// Failures are recorded per permutation rather than stopping the run.
+    pub fn compile(self) -> PermutationSet {
+        let combinations = self.combinations();
+
+        let jobs = combinations
+            .iter()
+            .map(|defines| {
+                let mut job =
+                    Job::new(self.source, &self.entry_point, self.target).flags(self.flags1);
+                if let Some(name) = &self.source_name {
+                    job = job.source_name(name);
+                }
+                for (name, value) in self.defines.iter().chain(defines) {
+                    job = job.define(name, value);
+                }
+                if let Some(include) = &self.include {
+                    job = job.include(include());
+                }
+                if let Some(cache) = self.cache {
+                    job = job.cache(cache);
+                }
+                job
+            })
+            .collect();
+
+        let results = match self.threads {
+            Some(threads) => batch::compile_all_with_threads(jobs, threads),
+            None => batch::compile_all(jobs),
+        };
+
+        let mut blobs = Vec::new();
+        let mut seen: HashMap<[u8; 20], usize> = HashMap::new();
+        let permutations = combinations
+            .into_iter()
+            .zip(results)
+            .map(|(defines, result)| match result {
+                Ok(result) => {
+                    let hash: [u8; 20] = Sha1::digest(result.bytecode.as_bytes()).into();
+                    let index = *seen.entry(hash).or_insert_with(|| {
+                        blobs.push(result.bytecode);
+                        blobs.len() - 1
+                    });
+                    Permutation {
+                        defines,
+                        blob: Ok(index),
+                        warnings: result.warnings,
+                    }
+                }
+                Err(e) => Permutation {
+                    defines,
+                    blob: Err(e),
+                    warnings: None,
+                },
+            })
+            .collect();
+
+        PermutationSet {
+            blobs,
+            permutations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combinations() {
+        let builder = PermutationBuilder::new("", "main", ShaderTarget::PS_5_0)
+            .axis("QUALITY", ["0", "1", "2"])
+            .flag("USE_FOG");
+        let keys: Vec<String> = builder
+            .combinations()
+            .into_iter()
+            .map(|defines| {
+                Permutation {
+                    defines,
+                    blob: Ok(0),
+                    warnings: None,
+                }
+                .key()
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "QUALITY=0,USE_FOG=1",
+                "QUALITY=0",
+                "QUALITY=1,USE_FOG=1",
+                "QUALITY=1",
+                "QUALITY=2,USE_FOG=1",
+                "QUALITY=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_permutation_dedupe() {
+        let source = r#"
+            float4 main() : SV_TARGET {
+            #ifdef USE_FOG
+                return QUALITY * 0.5;
+            #else
+                return 1;
+            #endif
+            }
+        "#;
+
+        let set = PermutationBuilder::new(source, "main", ShaderTarget::PS_5_0)
+            .axis("QUALITY", ["0", "1", "2"])
+            .flag("USE_FOG")
+            .compile();
+
+        assert_eq!(set.failed(), 0);
+        assert_eq!(set.permutations.len(), 6);
+        assert_eq!(set.blobs.len(), 4);
+        let unfogged = set.get("QUALITY=0").unwrap().blob.as_ref().unwrap();
+        assert_eq!(
+            set.get("QUALITY=2").unwrap().blob.as_ref().unwrap(),
+            unfogged
+        );
+    }
+}
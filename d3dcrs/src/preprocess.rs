@@ -1,9 +1,11 @@
 //! HLSL preprocessing API
 
-use crate::compile::Define;
-use crate::{Blob, Error, HResult, Result};
-use d3dcompiler::{D3D_SHADER_MACRO, D3DPreprocess, ID3DBlob, ID3DInclude, S_OK};
+use crate::compile::{Define, compiler_or_default};
+use crate::include::IncludeBridge;
+use crate::{Blob, Error, HResult, IncludeHandler, Result};
+use d3dcompiler::{Compiler, D3D_SHADER_MACRO, ID3DBlob, ID3DInclude, S_OK};
 use std::ffi::CString;
+use std::path::PathBuf;
 use std::ptr;
 
 /// Result of successful preprocessing
@@ -13,6 +15,11 @@ pub struct PreprocessResult {
     pub source: Blob,
     /// Any warning messages
     pub warnings: Option<String>,
+    /// Files opened through the include handler set with `include`
+    ///
+    /// Listed in the order they were first opened, without duplicates. Files
+    /// that have no on-disk path are listed by their include name.
+    pub dependencies: Vec<PathBuf>,
 }
 
 /// Builder for HLSL preprocessing
@@ -39,6 +46,8 @@ pub struct PreprocessBuilder<'a> {
     source_name: Option<CString>,
     defines: Vec<Define>,
     include: Option<*mut ID3DInclude>,
+    handler: Option<Box<dyn IncludeHandler + 'a>>,
+    compiler: Option<&'a Compiler>,
 }
 
 impl<'a> PreprocessBuilder<'a> {
@@ -49,6 +58,8 @@ impl<'a> PreprocessBuilder<'a> {
             source_name: None,
             defines: Vec::new(),
             include: None,
+            handler: None,
+            compiler: None,
         }
     }
 
@@ -59,9 +70,17 @@ impl<'a> PreprocessBuilder<'a> {
             source_name: None,
             defines: Vec::new(),
             include: None,
+            handler: None,
+            compiler: None,
         }
     }
 
+    /// Preprocesses with `compiler` instead of the default instance.
+    pub fn compiler(mut self, compiler: &'a Compiler) -> Self {
+        self.compiler = Some(compiler);
+        self
+    }
+
     /// Sets the source file name (used in error messages).
     pub fn source_name(mut self, name: &str) -> Self {
         self.source_name = Some(CString::new(name).expect("Source name contains null byte"));
@@ -101,13 +120,28 @@ impl<'a> PreprocessBuilder<'a> {
     ///
     /// # Safety
     /// The include handler must remain valid for the duration of preprocessing.
+    /// Use [`include`](Self::include) for a safe interface.
     pub unsafe fn include_handler(mut self, include: *mut ID3DInclude) -> Self {
         self.include = Some(include);
+        self.handler = None;
+        self
+    }
+
+    /// Sets an include handler used to resolve `#include` directives.
+    ///
+    /// Replaces any handler set with [`include_handler`](Self::include_handler).
+    /// If the handler fails to open a file, preprocessing fails with
+    /// [`Error::Include`] naming that file.
+    pub fn include<H: IncludeHandler + 'a>(mut self, handler: H) -> Self {
+        self.handler = Some(Box::new(handler));
+        self.include = None;
         self
     }
 
     /// Preprocesses the source.
     pub fn preprocess(self) -> Result<PreprocessResult> {
+        let compiler = compiler_or_default(self.compiler)?;
+
         // Build defines array (null-terminated)
         let mut defines_raw: Vec<D3D_SHADER_MACRO> = self
             .defines
@@ -122,11 +156,24 @@ impl<'a> PreprocessBuilder<'a> {
             Definition: ptr::null(),
         });
 
+        let root = self
+            .source_name
+            .as_ref()
+            .and_then(|name| name.to_str().ok())
+            .map(PathBuf::from);
+        let mut bridge = self
+            .handler
+            .map(|handler| IncludeBridge::new(handler, root));
+        let include = match bridge.as_mut() {
+            Some(bridge) => bridge.as_raw(),
+            None => self.include.unwrap_or(ptr::null_mut()),
+        };
+
         unsafe {
             let mut code: *mut ID3DBlob = ptr::null_mut();
             let mut errors: *mut ID3DBlob = ptr::null_mut();
 
-            let result = D3DPreprocess(
+            let result = compiler.D3DPreprocess(
                 self.source.as_ptr() as *const _,
                 self.source.len(),
                 self.source_name
@@ -134,7 +181,7 @@ impl<'a> PreprocessBuilder<'a> {
                     .map(|s| s.as_ptr())
                     .unwrap_or(ptr::null()),
                 defines_raw.as_ptr(),
-                self.include.unwrap_or(ptr::null_mut()),
+                include,
                 &mut code,
                 &mut errors,
             );
@@ -142,6 +189,10 @@ impl<'a> PreprocessBuilder<'a> {
             let error_blob = Blob::from_raw(errors);
 
             if result != S_OK {
+                if let Some(failure) = bridge.as_mut().and_then(|b| b.take_failure()) {
+                    return Err(failure);
+                }
+
                 let message = error_blob
                     .as_ref()
                     .map(|b| b.to_string_lossy())
@@ -164,7 +215,16 @@ impl<'a> PreprocessBuilder<'a> {
                 .map(|b| b.to_string_lossy())
                 .filter(|s| !s.is_empty());
 
-            Ok(PreprocessResult { source, warnings })
+            let dependencies = bridge
+                .as_mut()
+                .map(|b| b.take_dependencies())
+                .unwrap_or_default();
+
+            Ok(PreprocessResult {
+                source,
+                warnings,
+                dependencies,
+            })
         }
     }
 }
@@ -5,6 +5,7 @@ use std::ffi::{CStr, CString};
 
 /// Resource type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u32)]
 pub enum ResourceType {
     /// Constant buffer (cbuffer)
@@ -55,6 +56,7 @@ impl From<u32> for ResourceType {
 
 /// Resource return type (for textures)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u32)]
 pub enum ResourceReturnType {
     /// Unorm
@@ -93,6 +95,7 @@ impl From<u32> for ResourceReturnType {
 
 /// Resource dimension
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u32)]
 pub enum ResourceDimension {
     /// Unknown
@@ -143,6 +146,7 @@ impl From<u32> for ResourceDimension {
 
 /// Resource binding information
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 pub struct ResourceBinding {
     /// Resource name
     pub name: String,
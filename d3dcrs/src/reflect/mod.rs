@@ -45,8 +45,9 @@ pub use signature::{ComponentType, SignatureParameter, SystemValueType};
 pub use types::{ShaderTypeClass, ShaderVariableType, TypeInfo};
 pub use variable::Variable;
 
+use crate::compile::compiler_or_default;
 use crate::{Error, HResult, Result};
-use d3dcompiler::{D3D11_SHADER_DESC, D3DReflect, ID3D11ShaderReflection, S_OK};
+use d3dcompiler::{Compiler, D3D11_SHADER_DESC, ID3D11ShaderReflection, S_OK};
 use std::ffi::CStr;
 use std::ptr;
 
@@ -57,6 +58,7 @@ const IID_ID3D11SHADERREFLECTION: [u8; 16] = [
 
 /// High-level shader description
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 pub struct ShaderDesc {
     /// Shader version (encoded as type and model)
     pub version: u32,
@@ -142,9 +144,23 @@ pub struct ShaderReflection {
 impl ShaderReflection {
     /// Creates a shader reflection from compiled bytecode.
     pub fn new(bytecode: &[u8]) -> Result<Self> {
+        Self::reflect(None, bytecode)
+    }
+
+    /// Creates a shader reflection with `compiler` instead of the default
+    /// instance.
+    ///
+    /// The reflection calls back into `compiler`'s DLL, so it must not be
+    /// used after the instance is unloaded.
+    pub fn with_compiler(compiler: &Compiler, bytecode: &[u8]) -> Result<Self> {
+        Self::reflect(Some(compiler), bytecode)
+    }
+
+    fn reflect(compiler: Option<&Compiler>, bytecode: &[u8]) -> Result<Self> {
+        let compiler = compiler_or_default(compiler)?;
         unsafe {
             let mut reflector: *mut std::ffi::c_void = ptr::null_mut();
-            let result = D3DReflect(
+            let result = compiler.D3DReflect(
                 bytecode.as_ptr() as *const _,
                 bytecode.len(),
                 IID_ID3D11SHADERREFLECTION.as_ptr() as *const _,
@@ -5,6 +5,7 @@ use std::ffi::CStr;
 
 /// System value semantic type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u32)]
 pub enum SystemValueType {
     /// Undefined (user-defined semantic)
@@ -85,6 +86,7 @@ impl From<u32> for SystemValueType {
 
 /// Component type for shader parameters
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u32)]
 pub enum ComponentType {
     /// Unknown type
@@ -110,6 +112,7 @@ impl From<u32> for ComponentType {
 
 /// Shader input/output signature parameter
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
 pub struct SignatureParameter {
     /// Semantic name (e.g., "POSITION", "TEXCOORD")
     pub semantic_name: String,
@@ -0,0 +1,732 @@
+//! Local compile server over a Unix socket
+//!
+//! Loading and relocating the DLL dominates the cost of a short-lived
+//! compile process. [`Server`] keeps one compiler loaded and answers
+//! compile, preprocess, disassemble and reflect requests; [`Client`] talks to
+//! it without loading the DLL itself.
+//!
+//! # Protocol
+//!
+//! Every frame is a little-endian `u32` byte length followed by that many
+//! bytes. A message is a JSON header frame followed by one binary payload
+//! frame, which may be empty. Requests carry the shader source or bytecode in
+//! the payload; replies carry the bytecode, preprocessed source or
+//! disassembly. A connection may send any number of requests in turn.
+//!
+//! Requests can name include files by absolute path, so the socket is only
+//! accessible to the server's user, and requests from any other user are
+//! refused.
+//!
+//! # Example
+//! ```no_run
+//! use d3dcrs::server::{Client, CompileOptions};
+//! use d3dcrs::ShaderTarget;
+//!
+//! let mut client = Client::connect("/tmp/d3dcrs.sock").unwrap();
+//! let source = b"float4 main() : SV_TARGET { return 1; }";
+//! let output = client
+//!     .compile(source, "main", ShaderTarget::PS_5_0, &CompileOptions::new())
+//!     .unwrap();
+//! println!("{} bytes", output.data.len());
+//! ```
+
+pub use crate::frame::{MAX_FRAME_SIZE, read_frame, write_frame};
+
+use crate::reflect::{ResourceBinding, ShaderDesc, SignatureParameter};
+use crate::{
+    CompileBuilder, CompileFlags, Compiler, DisassembleBuilder, DisassembleFlags, Error,
+    FileSystemInclude, HResult, PreprocessBuilder, Result, ShaderReflection, ShaderTarget,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Writes a JSON header frame followed by a payload frame.
+fn write_message<W: Write, T: Serialize>(writer: &mut W, header: &T, payload: &[u8]) -> Result<()> {
+    let header = serde_json::to_vec(header).map_err(|e| Error::Server(e.to_string()))?;
+    write_frame(writer, &header)?;
+    write_frame(writer, payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a JSON header frame and its payload frame.
+fn read_message<R: Read, T: for<'de> Deserialize<'de>>(
+    reader: &mut R,
+) -> Result<Option<(T, Vec<u8>)>> {
+    let Some(header) = read_frame(reader)? else {
+        return Ok(None);
+    };
+    let header = serde_json::from_slice(&header)
+        .map_err(|e| Error::Server(format!("malformed header: {}", e)))?;
+    let payload = read_frame(reader)?
+        .ok_or_else(|| Error::Server("connection closed before payload".to_string()))?;
+    Ok(Some((header, payload)))
+}
+
+/// Compile and preprocess options sent with a request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompileOptions {
+    /// Source file name, used in messages and to resolve `#include "..."`
+    pub source_name: Option<String>,
+    /// Preprocessor defines
+    pub defines: Vec<(String, String)>,
+    /// Compilation flags (ignored when preprocessing)
+    pub flags: u32,
+    /// Secondary flags (ignored when preprocessing)
+    pub flags2: u32,
+    /// Include search directories, as seen by the server
+    pub include_paths: Vec<PathBuf>,
+}
+
+impl CompileOptions {
+    /// Creates empty options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the source file name.
+    pub fn source_name(mut self, name: &str) -> Self {
+        self.source_name = Some(name.to_string());
+        self
+    }
+
+    /// Adds a preprocessor define.
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets compilation flags.
+    pub fn flags(mut self, flags: CompileFlags) -> Self {
+        self.flags = flags.bits();
+        self
+    }
+
+    /// Sets secondary flags (Flags2 parameter).
+    pub fn flags2(mut self, flags: u32) -> Self {
+        self.flags2 = flags;
+        self
+    }
+
+    /// Adds an include search directory.
+    pub fn include_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Makes file paths absolute so the server resolves them like the caller.
+    fn absolute(&self) -> Self {
+        let mut options = self.clone();
+        if let Some(name) = &options.source_name
+            && Path::new(name).is_file()
+            && let Ok(path) = std::path::absolute(name)
+        {
+            options.source_name = Some(path.to_string_lossy().into_owned());
+        }
+        for path in &mut options.include_paths {
+            if let Ok(absolute) = std::path::absolute(&*path) {
+                *path = absolute;
+            }
+        }
+        options
+    }
+
+    fn include(&self) -> FileSystemInclude {
+        let mut include = FileSystemInclude::new();
+        for path in &self.include_paths {
+            include.add_path(path);
+        }
+        include
+    }
+}
+
+/// Request header
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// Payload: source
+    Compile {
+        entry: String,
+        target: String,
+        #[serde(flatten)]
+        options: CompileOptions,
+    },
+    /// Payload: source
+    Preprocess {
+        #[serde(flatten)]
+        options: CompileOptions,
+    },
+    /// Payload: bytecode
+    Disassemble {
+        flags: u32,
+    },
+    /// Payload: bytecode
+    Reflect,
+    Ping,
+    Shutdown,
+}
+
+/// Reflection data returned by [`Client::reflect`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reflection {
+    /// Shader description
+    pub desc: ShaderDesc,
+    /// Input signature
+    pub inputs: Vec<SignatureParameter>,
+    /// Output signature
+    pub outputs: Vec<SignatureParameter>,
+    /// Bound resources
+    pub bindings: Vec<ResourceBinding>,
+    /// Compute shader thread group size
+    pub thread_group_size: (u32, u32, u32),
+}
+
+impl Reflection {
+    fn new(bytecode: &[u8]) -> Result<Self> {
+        let reflection = ShaderReflection::new(bytecode)?;
+        Ok(Reflection {
+            desc: reflection.desc()?,
+            inputs: reflection.input_parameters().collect(),
+            outputs: reflection.output_parameters().collect(),
+            bindings: reflection.resource_bindings().collect(),
+            thread_group_size: reflection.thread_group_size(),
+        })
+    }
+}
+
+/// Kind of error reported by the server
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    Compilation,
+    Preprocessing,
+    Disassembly,
+    Reflection,
+    InvalidParameter,
+    Other,
+}
+
+/// Response header
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response {
+    Ok {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        warnings: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        dependencies: Vec<PathBuf>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reflection: Option<Box<Reflection>>,
+    },
+    Error {
+        kind: ErrorKind,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hresult: Option<i32>,
+        message: String,
+    },
+}
+
+impl Response {
+    fn ok() -> Self {
+        Response::Ok {
+            warnings: None,
+            dependencies: Vec::new(),
+            reflection: None,
+        }
+    }
+
+    fn from_error(error: &Error) -> Self {
+        let (kind, hresult, message) = match error {
+            Error::Compilation { hresult, message } => {
+                (ErrorKind::Compilation, Some(hresult.0), message.clone())
+            }
+            Error::Preprocessing { hresult, message } => {
+                (ErrorKind::Preprocessing, Some(hresult.0), message.clone())
+            }
+            Error::Disassembly { hresult } => {
+                (ErrorKind::Disassembly, Some(hresult.0), error.to_string())
+            }
+            Error::Reflection { hresult } => {
+                (ErrorKind::Reflection, Some(hresult.0), error.to_string())
+            }
+            Error::InvalidParameter(message) => {
+                (ErrorKind::InvalidParameter, None, message.clone())
+            }
+            _ => (ErrorKind::Other, None, error.to_string()),
+        };
+        Response::Error {
+            kind,
+            hresult,
+            message,
+        }
+    }
+
+    fn into_error(kind: ErrorKind, hresult: Option<i32>, message: String) -> Error {
+        let hresult = HResult(hresult.unwrap_or(HResult::E_FAIL.0));
+        match kind {
+            ErrorKind::Compilation => Error::Compilation { hresult, message },
+            ErrorKind::Preprocessing => Error::Preprocessing { hresult, message },
+            ErrorKind::Disassembly => Error::Disassembly { hresult },
+            ErrorKind::Reflection => Error::Reflection { hresult },
+            ErrorKind::InvalidParameter => Error::InvalidParameter(message),
+            ErrorKind::Other => Error::Server(message),
+        }
+    }
+}
+
+/// Output of a compile, preprocess or disassemble request
+#[derive(Debug)]
+pub struct Output {
+    /// Bytecode, preprocessed source or disassembly text
+    pub data: Vec<u8>,
+    /// Any warning messages from the compiler
+    pub warnings: Option<String>,
+    /// Files opened through the include search paths
+    pub dependencies: Vec<PathBuf>,
+}
+
+/// Client connection to a [`Server`]
+///
+/// The client never loads the DLL, so it is cheap to create per process.
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: BufWriter<UnixStream>,
+}
+
+impl Client {
+    /// Connects to the server listening on `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    fn request(&mut self, request: &Request, payload: &[u8]) -> Result<(Response, Vec<u8>)> {
+        write_message(&mut self.writer, request, payload)?;
+        let (response, payload) = read_message(&mut self.reader)?
+            .ok_or_else(|| Error::Server("connection closed by server".to_string()))?;
+        match response {
+            Response::Error {
+                kind,
+                hresult,
+                message,
+            } => Err(Response::into_error(kind, hresult, message)),
+            ok => Ok((ok, payload)),
+        }
+    }
+
+    fn output(&mut self, request: &Request, payload: &[u8]) -> Result<Output> {
+        match self.request(request, payload)? {
+            (
+                Response::Ok {
+                    warnings,
+                    dependencies,
+                    ..
+                },
+                data,
+            ) => Ok(Output {
+                data,
+                warnings,
+                dependencies,
+            }),
+            _ => unreachable!("errors are returned by request"),
+        }
+    }
+
+    /// Compiles `source` on the server.
+    ///
+    /// Relative paths in `options` are resolved against this process's
+    /// working directory before sending.
+    pub fn compile(
+        &mut self,
+        source: &[u8],
+        entry_point: &str,
+        target: ShaderTarget,
+        options: &CompileOptions,
+    ) -> Result<Output> {
+        let request = Request::Compile {
+            entry: entry_point.to_string(),
+            target: target.to_string(),
+            options: options.absolute(),
+        };
+        self.output(&request, source)
+    }
+
+    /// Preprocesses `source` on the server.
+    pub fn preprocess(&mut self, source: &[u8], options: &CompileOptions) -> Result<Output> {
+        let request = Request::Preprocess {
+            options: options.absolute(),
+        };
+        self.output(&request, source)
+    }
+
+    /// Disassembles `bytecode` on the server.
+    pub fn disassemble(&mut self, bytecode: &[u8], flags: DisassembleFlags) -> Result<String> {
+        let request = Request::Disassemble {
+            flags: flags.bits(),
+        };
+        let output = self.output(&request, bytecode)?;
+        Ok(String::from_utf8_lossy(&output.data).into_owned())
+    }
+
+    /// Reflects `bytecode` on the server.
+    pub fn reflect(&mut self, bytecode: &[u8]) -> Result<Reflection> {
+        match self.request(&Request::Reflect, bytecode)? {
+            (
+                Response::Ok {
+                    reflection: Some(reflection),
+                    ..
+                },
+                _,
+            ) => Ok(*reflection),
+            _ => Err(Error::Server(
+                "reply is missing reflection data".to_string(),
+            )),
+        }
+    }
+
+    /// Checks that the server is responding.
+    pub fn ping(&mut self) -> Result<()> {
+        self.request(&Request::Ping, &[]).map(|_| ())
+    }
+
+    /// Asks the server to stop accepting connections and exit.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.request(&Request::Shutdown, &[]).map(|_| ())
+    }
+}
+
+/// Compile server listening on a Unix socket
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::server::Server;
+///
+/// let server = Server::bind("/tmp/d3dcrs.sock").unwrap();
+/// server.run().unwrap();
+/// ```
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+    shutdown: Arc<AtomicBool>,
+    slots: Arc<ConnectionSlots>,
+}
+
+impl Server {
+    /// Binds to `path`.
+    ///
+    /// A stale socket left by a server that is no longer running is replaced.
+    /// Fails if another server is still accepting connections on `path`. The
+    /// socket is made accessible to the current user only.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let listener = match bind_owner_only(path) {
+            Ok(listener) => listener,
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(Error::Server(format!(
+                        "another server is listening on {}",
+                        path.display()
+                    )));
+                }
+                std::fs::remove_file(path)?;
+                bind_owner_only(path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Server {
+            listener,
+            path: path.to_path_buf(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            slots: Arc::new(ConnectionSlots::default()),
+        })
+    }
+
+    /// Returns the socket path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves connections until a client sends a shutdown request.
+    ///
+    /// Loads the DLL up front, then handles each connection on its own
+    /// thread, up to [`MAX_CONNECTIONS`] at once. Connections from other
+    /// users are closed before anything is read from them.
+    pub fn run(&self) -> Result<()> {
+        // Load the DLL now so the first request does not pay for it
+        Compiler::global().map_err(|e| Error::Load(e.to_string()))?;
+
+        loop {
+            let slot = ConnectionSlots::acquire(&self.slots);
+            let stream = self.listener.accept().map(|(stream, _)| stream);
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("d3dcrs server: accept failed: {}", e);
+                    continue;
+                }
+            };
+            // Requests read files as the server's user, so only that user
+            // may send them, even if the socket's mode was changed
+            match peer_uid(&stream) {
+                Ok(uid) if uid == unsafe { libc::geteuid() } => {}
+                Ok(uid) => {
+                    eprintln!("d3dcrs server: refused connection from uid {}", uid);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("d3dcrs server: cannot identify peer: {}", e);
+                    continue;
+                }
+            }
+            let shutdown = Arc::clone(&self.shutdown);
+            let path = self.path.clone();
+            std::thread::spawn(move || {
+                let _slot = slot;
+                d3dcompiler::setup_thread();
+                if let Err(e) = serve_connection(stream, &shutdown, &path) {
+                    eprintln!("d3dcrs server: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve_connection(stream: UnixStream, shutdown: &AtomicBool, path: &Path) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some((request, payload)) = read_message::<_, Request>(&mut reader)? {
+        let stop = matches!(request, Request::Shutdown);
+        let (response, output) = match handle(request, &payload) {
+            Ok(reply) => reply,
+            Err(e) => (Response::from_error(&e), Vec::new()),
+        };
+        write_message(&mut writer, &response, &output)?;
+
+        if stop {
+            shutdown.store(true, Ordering::SeqCst);
+            // Wake the accept loop so it sees the flag
+            let _ = UnixStream::connect(path);
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Most connections a server handles at once
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// Counts connection threads, so a flood of clients cannot start unbounded
+/// threads
+#[derive(Default)]
+struct ConnectionSlots {
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A connection thread's slot, given back when dropped
+struct ConnectionSlot(Arc<ConnectionSlots>);
+
+impl ConnectionSlots {
+    /// Waits until fewer than [`MAX_CONNECTIONS`] slots are taken.
+    fn acquire(slots: &Arc<Self>) -> ConnectionSlot {
+        let mut used = slots.used.lock().unwrap_or_else(|e| e.into_inner());
+        while *used >= MAX_CONNECTIONS {
+            used = slots.freed.wait(used).unwrap_or_else(|e| e.into_inner());
+        }
+        *used += 1;
+        ConnectionSlot(Arc::clone(slots))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.0.used.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Binds `path` with a umask that leaves the socket to the current user.
+///
+/// Changing the mode after binding would leave a window in which anyone can
+/// connect. The umask is process-wide, so files other threads create while
+/// this runs are owner-only too.
+fn bind_owner_only(path: &Path) -> io::Result<UnixListener> {
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    listener
+}
+
+/// Returns the effective uid of the process on the other end of `stream`.
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+fn handle(request: Request, payload: &[u8]) -> Result<(Response, Vec<u8>)> {
+    match request {
+        Request::Compile {
+            entry,
+            target,
+            options,
+        } => {
+            let target: ShaderTarget = target.parse()?;
+            let mut builder = CompileBuilder::from_bytes(payload, &entry, target)
+                .flags(CompileFlags::from_bits_retain(options.flags))
+                .flags2(options.flags2)
+                .include(options.include());
+            if let Some(name) = &options.source_name {
+                builder = builder.source_name(name);
+            }
+            for (name, value) in &options.defines {
+                builder = builder.define(name, value);
+            }
+            let result = builder.compile()?;
+            let response = Response::Ok {
+                warnings: result.warnings,
+                dependencies: result.dependencies,
+                reflection: None,
+            };
+            Ok((response, result.bytecode.as_bytes().to_vec()))
+        }
+        Request::Preprocess { options } => {
+            let mut builder = PreprocessBuilder::from_bytes(payload).include(options.include());
+            if let Some(name) = &options.source_name {
+                builder = builder.source_name(name);
+            }
+            for (name, value) in &options.defines {
+                builder = builder.define(name, value);
+            }
+            let result = builder.preprocess()?;
+            let response = Response::Ok {
+                warnings: result.warnings,
+                dependencies: result.dependencies,
+                reflection: None,
+            };
+            Ok((response, result.source.as_bytes().to_vec()))
+        }
+        Request::Disassemble { flags } => {
+            let text = DisassembleBuilder::new(payload)
+                .flags(DisassembleFlags::from_bits_retain(flags))
+                .disassemble()?;
+            Ok((Response::ok(), text.as_bytes().to_vec()))
+        }
+        Request::Reflect => {
+            let response = Response::Ok {
+                warnings: None,
+                dependencies: Vec::new(),
+                reflection: Some(Box::new(Reflection::new(payload)?)),
+            };
+            Ok((response, Vec::new()))
+        }
+        Request::Ping | Request::Shutdown => Ok((Response::ok(), Vec::new())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_header() {
+        let request = Request::Compile {
+            entry: "main".to_string(),
+            target: "ps_5_0".to_string(),
+            options: CompileOptions::new().define("A", "1"),
+        };
+        let mut buf = Vec::new();
+        write_message(
+            &mut buf,
+            &request,
+            b"float4 main() : SV_TARGET { return 1; }",
+        )
+        .unwrap();
+
+        let json = String::from_utf8(read_frame(&mut buf.as_slice()).unwrap().unwrap()).unwrap();
+        assert!(json.contains(r#""op":"compile""#), "{}", json);
+
+        let (request, payload) = read_message::<_, Request>(&mut buf.as_slice())
+            .unwrap()
+            .unwrap();
+        assert!(matches!(request, Request::Compile { ref entry, .. } if entry == "main"));
+        assert!(payload.starts_with(b"float4"));
+    }
+
+    #[test]
+    fn test_error_roundtrip() {
+        let error = Error::Compilation {
+            hresult: HResult::E_FAIL,
+            message: "error X3004: undeclared identifier".to_string(),
+        };
+        let json = serde_json::to_string(&Response::from_error(&error)).unwrap();
+        let Response::Error {
+            kind,
+            hresult,
+            message,
+        } = serde_json::from_str(&json).unwrap()
+        else {
+            panic!("expected error response: {}", json);
+        };
+        assert!(matches!(
+            Response::into_error(kind, hresult, message),
+            Error::Compilation { hresult: HResult::E_FAIL, ref message } if message.contains("X3004")
+        ));
+    }
+
+    #[test]
+    fn test_peer_uid() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let uid = unsafe { libc::geteuid() };
+        assert_eq!(peer_uid(&a).unwrap(), uid);
+        assert_eq!(peer_uid(&b).unwrap(), uid);
+    }
+
+    #[test]
+    fn test_bind_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("d3dcrs-server-mode-{}.sock", std::process::id()));
+        let server = Server::bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(server);
+        assert!(!path.exists());
+    }
+}
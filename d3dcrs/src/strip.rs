@@ -1,9 +1,74 @@
 //! Shader stripping API
 
+use crate::compile::compiler_or_default;
 use crate::{Blob, Error, HResult, Result, StripFlags};
-use d3dcompiler::{D3DStripShader, ID3DBlob, S_OK};
+use d3dcompiler::{Compiler, ID3DBlob, S_OK};
 use std::ptr;
 
+/// Builder for shader stripping
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::{compile, ShaderTarget, StripBuilder, StripFlags};
+///
+/// let bytecode = compile(
+///     "float4 main() : SV_TARGET { return float4(1,0,0,1); }",
+///     "main",
+///     ShaderTarget::PS_5_0
+/// ).unwrap();
+///
+/// let stripped = StripBuilder::new(&bytecode, StripFlags::DEBUG_INFO)
+///     .strip()
+///     .unwrap();
+/// ```
+pub struct StripBuilder<'a> {
+    bytecode: &'a [u8],
+    flags: StripFlags,
+    compiler: Option<&'a Compiler>,
+}
+
+impl<'a> StripBuilder<'a> {
+    /// Creates a new strip builder that removes the parts in `flags`.
+    pub fn new(bytecode: &'a [u8], flags: StripFlags) -> Self {
+        StripBuilder {
+            bytecode,
+            flags,
+            compiler: None,
+        }
+    }
+
+    /// Strips with `compiler` instead of the default instance.
+    pub fn compiler(mut self, compiler: &'a Compiler) -> Self {
+        self.compiler = Some(compiler);
+        self
+    }
+
+    /// Strips the bytecode.
+    pub fn strip(self) -> Result<Blob> {
+        let compiler = compiler_or_default(self.compiler)?;
+        unsafe {
+            let mut stripped: *mut ID3DBlob = ptr::null_mut();
+
+            let result = compiler.D3DStripShader(
+                self.bytecode.as_ptr() as *const _,
+                self.bytecode.len(),
+                self.flags.bits(),
+                &mut stripped,
+            );
+
+            if result != S_OK {
+                return Err(Error::StripShader {
+                    hresult: HResult(result),
+                });
+            }
+
+            Blob::from_raw(stripped).ok_or(Error::StripShader {
+                hresult: HResult(result),
+            })
+        }
+    }
+}
+
 /// Strips specified data from a compiled shader.
 ///
 /// This can be used to remove debug info, reflection data, or other
@@ -30,26 +95,7 @@ use std::ptr;
 ///     bytecode.bytecode.len(), stripped.len());
 /// ```
 pub fn strip_shader(bytecode: &[u8], flags: StripFlags) -> Result<Blob> {
-    unsafe {
-        let mut stripped: *mut ID3DBlob = ptr::null_mut();
-
-        let result = D3DStripShader(
-            bytecode.as_ptr() as *const _,
-            bytecode.len(),
-            flags.bits(),
-            &mut stripped,
-        );
-
-        if result != S_OK {
-            return Err(Error::StripShader {
-                hresult: HResult(result),
-            });
-        }
-
-        Blob::from_raw(stripped).ok_or(Error::StripShader {
-            hresult: HResult(result),
-        })
-    }
+    StripBuilder::new(bytecode, flags).strip()
 }
 
 /// Strips debug info from a compiled shader.
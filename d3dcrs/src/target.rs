@@ -1,7 +1,9 @@
 //! Shader target types (shader type + shader model)
 
+use crate::Error;
 use std::ffi::CString;
 use std::fmt;
+use std::str::FromStr;
 
 /// Shader type (vertex, pixel, compute, etc.)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -34,6 +36,25 @@ impl ShaderType {
     }
 }
 
+impl FromStr for ShaderType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vs" => Ok(ShaderType::Vertex),
+            "ps" => Ok(ShaderType::Pixel),
+            "gs" => Ok(ShaderType::Geometry),
+            "hs" => Ok(ShaderType::Hull),
+            "ds" => Ok(ShaderType::Domain),
+            "cs" => Ok(ShaderType::Compute),
+            _ => Err(Error::InvalidParameter(format!(
+                "unknown shader type '{}'",
+                s
+            ))),
+        }
+    }
+}
+
 impl fmt::Display for ShaderType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(self.prefix())
@@ -112,6 +133,31 @@ impl ShaderModel {
     }
 }
 
+impl FromStr for ShaderModel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4_0" => Ok(ShaderModel::SM4_0),
+            "4_1" => Ok(ShaderModel::SM4_1),
+            "5_0" => Ok(ShaderModel::SM5_0),
+            "5_1" => Ok(ShaderModel::SM5_1),
+            "6_0" => Ok(ShaderModel::SM6_0),
+            "6_1" => Ok(ShaderModel::SM6_1),
+            "6_2" => Ok(ShaderModel::SM6_2),
+            "6_3" => Ok(ShaderModel::SM6_3),
+            "6_4" => Ok(ShaderModel::SM6_4),
+            "6_5" => Ok(ShaderModel::SM6_5),
+            "6_6" => Ok(ShaderModel::SM6_6),
+            "6_7" => Ok(ShaderModel::SM6_7),
+            _ => Err(Error::InvalidParameter(format!(
+                "unknown shader model '{}'",
+                s
+            ))),
+        }
+    }
+}
+
 impl fmt::Display for ShaderModel {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(self.suffix())
@@ -182,6 +228,18 @@ impl fmt::Display for ShaderTarget {
     }
 }
 
+impl FromStr for ShaderTarget {
+    type Err = Error;
+
+    /// Parses a profile string such as `ps_5_0`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (shader_type, model) = s
+            .split_once('_')
+            .ok_or_else(|| Error::InvalidParameter(format!("invalid shader target '{}'", s)))?;
+        Ok(ShaderTarget::new(shader_type.parse()?, model.parse()?))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -201,4 +259,19 @@ mod tests {
         assert_eq!(ShaderModel::SM5_1.major(), 5);
         assert_eq!(ShaderModel::SM5_1.minor(), 1);
     }
+
+    #[test]
+    fn test_target_from_str() {
+        assert_eq!(
+            "ps_5_0".parse::<ShaderTarget>().unwrap(),
+            ShaderTarget::PS_5_0
+        );
+        assert_eq!(
+            "cs_4_1".parse::<ShaderTarget>().unwrap(),
+            ShaderTarget::CS_4_1
+        );
+        assert!("ps5_0".parse::<ShaderTarget>().is_err());
+        assert!("xs_5_0".parse::<ShaderTarget>().is_err());
+        assert!("ps_9_9".parse::<ShaderTarget>().is_err());
+    }
 }
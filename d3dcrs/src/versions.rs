@@ -0,0 +1,130 @@
+//! Side-by-side compiler versions
+//!
+//! Content that needs output parity with an older compiler can compile with
+//! `d3dcompiler_43.dll` or `d3dcompiler_46.dll` while everything else uses
+//! `d3dcompiler_47.dll`. Each version is a separate [`Compiler`] with its own
+//! mapped image, import fixups and export table.
+
+use crate::{Error, Result};
+use d3dcompiler::{Compiler, D3DCompilerError};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A version of the d3dcompiler DLL
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CompilerVersion {
+    /// `d3dcompiler_43.dll`, from the June 2010 DirectX SDK
+    V43,
+    /// `d3dcompiler_46.dll`, from the Windows 8 SDK
+    V46,
+    /// `d3dcompiler_47.dll`, the default compiler
+    V47,
+}
+
+impl CompilerVersion {
+    /// Returns the DLL's file name
+    pub fn dll_name(self) -> &'static str {
+        match self {
+            CompilerVersion::V43 => "d3dcompiler_43.dll",
+            CompilerVersion::V46 => "d3dcompiler_46.dll",
+            CompilerVersion::V47 => "d3dcompiler_47.dll",
+        }
+    }
+
+    /// Returns whether a DLL's export name is this version's, ignoring case
+    fn matches(self, dll_name: &str) -> bool {
+        dll_name.eq_ignore_ascii_case(self.dll_name())
+    }
+}
+
+// Compilers are leaked, since their images stay mapped for the life of the
+// process anyway
+static COMPILERS: Mutex<Vec<(CompilerVersion, &'static Compiler)>> = Mutex::new(Vec::new());
+
+/// Loads the DLL at `path` as `version`, for compiles that select it with
+/// [`CompileBuilder::compiler_version`](crate::CompileBuilder::compiler_version).
+///
+/// Fails with [`Error::MissingImports`] if the DLL imports functions the
+/// shims don't implement yet, rather than aborting when one is called, and
+/// with [`Error::Load`] if the DLL is a different version. Loading a version
+/// again replaces it for later compiles.
+pub fn load_compiler_version(
+    version: CompilerVersion,
+    path: impl AsRef<Path>,
+) -> Result<&'static Compiler> {
+    // Missing imports are refused before DllMain runs
+    let compiler = Compiler::load_from_path_checked(path).map_err(load_error)?;
+    if !version.matches(compiler.dll_name()) {
+        let e = Error::Load(format!(
+            "expected {}, but the DLL exports as {}",
+            version.dll_name(),
+            compiler.dll_name()
+        ));
+        // Nothing has used the instance yet, and no other thread can reach it
+        unsafe { compiler.unload() };
+        return Err(e);
+    }
+    let compiler: &'static Compiler = Box::leak(Box::new(compiler));
+
+    let mut compilers = COMPILERS.lock().unwrap_or_else(|e| e.into_inner());
+    compilers.retain(|(loaded, _)| *loaded != version);
+    compilers.push((version, compiler));
+    Ok(compiler)
+}
+
+/// Returns the compiler loaded for `version`.
+///
+/// [`CompilerVersion::V47`] falls back to the default compiler when no DLL
+/// was loaded for it; other versions must be loaded first.
+pub fn compiler_for_version(version: CompilerVersion) -> Result<&'static Compiler> {
+    let loaded = COMPILERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|(loaded, _)| *loaded == version)
+        .map(|(_, compiler)| *compiler);
+    match (loaded, version) {
+        (Some(compiler), _) => Ok(compiler),
+        (None, CompilerVersion::V47) => Compiler::global().map_err(|e| Error::Load(e.to_string())),
+        (None, _) => Err(Error::Load(format!(
+            "{} has not been loaded, see load_compiler_version",
+            version.dll_name()
+        ))),
+    }
+}
+
+fn load_error(error: D3DCompilerError) -> Error {
+    match error {
+        D3DCompilerError::MissingImports { dll, imports } => Error::MissingImports { dll, imports },
+        error => Error::Load(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_not_loaded() {
+        match compiler_for_version(CompilerVersion::V43) {
+            Err(Error::Load(message)) => assert!(message.contains("d3dcompiler_43.dll")),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("an unloaded version should not resolve"),
+        }
+    }
+
+    #[test]
+    fn test_version_matches_dll_name() {
+        assert!(CompilerVersion::V47.matches("D3DCOMPILER_47.dll"));
+        assert!(CompilerVersion::V43.matches("d3dcompiler_43.dll"));
+        assert!(!CompilerVersion::V43.matches("D3DCOMPILER_47.dll"));
+        assert!(!CompilerVersion::V46.matches(""));
+    }
+
+    #[test]
+    fn test_load_version_from_missing_path() {
+        let result = load_compiler_version(CompilerVersion::V46, "does/not/exist.dll");
+        assert!(matches!(result, Err(Error::Load(_))));
+        assert!(compiler_for_version(CompilerVersion::V46).is_err());
+    }
+}
@@ -0,0 +1,1153 @@
+//! Crash-isolated compiles in forked helper processes
+//!
+//! Some compiler paths still end in a stub that panics or aborts, which takes
+//! the whole host process down. A [`WorkerPool`] runs compiles in forked
+//! children instead, so a crash only fails the compile that caused it.
+//!
+//! Helpers are not forked from the pool's process, which may have other
+//! threads holding locks. A fork server is forked once, after the DLL is
+//! loaded, and forks every helper from its single thread, so helpers start
+//! with the relocated image already in memory. The fork server is itself a
+//! copy of the pool's process, so helpers only start with no lock held if
+//! [`WorkerPool::start_fork_server`] ran while that process had one thread.
+//! Include handlers keep running in the calling process: the child asks for
+//! each file over its socket.
+
+use crate::frame::{Decoder, Encoder, read_frame, write_frame};
+use crate::{
+    Blob, CompileBuilder, CompileFlags, CompileResult, Compiler, Error, HResult, IncludeFile,
+    IncludeHandler, IncludeType, PreprocessBuilder, PreprocessResult, Result, ShaderTarget,
+};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Parent to child: compile a job
+const MSG_JOB: u8 = 1;
+/// Parent to child: result of an include request
+const MSG_INCLUDE_REPLY: u8 = 2;
+/// Child to parent: open an include file
+const MSG_INCLUDE_OPEN: u8 = 3;
+/// Child to parent: the job finished
+const MSG_DONE: u8 = 4;
+
+/// Parent to fork server: fork a helper on the two attached descriptors
+const SERVER_SPAWN: u8 = 1;
+/// Parent to fork server: reap a helper and reply with its wait status
+const SERVER_WAIT: u8 = 2;
+
+/// Longest wait for the fork server to start or answer a request
+const SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most stderr kept from a child, from the end of its output
+const MAX_STDERR: usize = 64 * 1024;
+
+/// Exit status of a child that went over its job's memory limit
+const EXIT_MEMORY_LIMIT: i32 = 77;
+
+/// Error kinds a child can report
+const ERR_COMPILATION: u8 = 0;
+const ERR_INCLUDE: u8 = 1;
+const ERR_INVALID_PARAMETER: u8 = 2;
+const ERR_CREATE_BLOB: u8 = 3;
+const ERR_OTHER: u8 = 4;
+
+/// Pool of forked helper processes that run compiles
+///
+/// Use it with [`CompileBuilder::isolated`]. A helper that crashes, aborts or
+/// outlives the pool's timeout turns that compile into
+/// [`Error::CompilerCrashed`] and is replaced before the next compile.
+///
+/// Helpers are forked by a fork server, which is itself forked from this
+/// process the first time a pool starts. Call
+/// [`start_fork_server`](Self::start_fork_server) at the top of `main`,
+/// before other threads exist, so it starts from a clean copy of the process.
+/// Otherwise another thread may hold a lock at that moment, and helpers that
+/// need it hang until the [`timeout`](Self::timeout).
+///
+/// # Example
+/// ```no_run
+/// use d3dcrs::{CompileBuilder, ShaderTarget, WorkerPool};
+///
+/// let pool = WorkerPool::new(4).unwrap();
+/// let result = CompileBuilder::new(
+///     "float4 main() : SV_TARGET { return 1; }",
+///     "main",
+///     ShaderTarget::PS_5_0,
+/// )
+/// .isolated(&pool)
+/// .compile();
+/// ```
+pub struct WorkerPool {
+    state: Mutex<PoolState>,
+    available: Condvar,
+    size: usize,
+    timeout: Option<Duration>,
+}
+
+struct PoolState {
+    idle: Vec<Worker>,
+    live: usize,
+    restarts: usize,
+}
+
+impl WorkerPool {
+    /// Time limit for one compile on a pool that did not set one
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// Loads the DLL and forks the fork server, if neither happened yet.
+    ///
+    /// Pools start it on first use; calling this while the process has a
+    /// single thread makes sure no helper starts with a lock held.
+    pub fn start_fork_server() -> Result<()> {
+        Compiler::global().map_err(|e| Error::Load(e.to_string()))?;
+        let mut server = ForkServer::lock();
+        if server.is_none() {
+            *server = Some(ForkServer::start()?);
+        }
+        Ok(())
+    }
+
+    /// Loads the DLL and forks `size` helper processes.
+    pub fn new(size: usize) -> Result<Self> {
+        Self::start_fork_server()?;
+
+        let size = size.max(1);
+        let idle = (0..size)
+            .map(|_| Worker::spawn())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(WorkerPool {
+            state: Mutex::new(PoolState {
+                idle,
+                live: size,
+                restarts: 0,
+            }),
+            available: Condvar::new(),
+            size,
+            timeout: None,
+        })
+    }
+
+    /// Returns the pool used for limited compiles that did not pick one.
+    ///
+    /// Started on first use with one helper per CPU.
+    pub(crate) fn shared() -> Result<&'static WorkerPool> {
+        static SHARED: OnceLock<std::result::Result<WorkerPool, String>> = OnceLock::new();
+        SHARED
+            .get_or_init(|| {
+                let size = std::thread::available_parallelism().map_or(1, |n| n.get());
+                WorkerPool::new(size).map_err(|e| e.to_string())
+            })
+            .as_ref()
+            .map_err(|e| {
+                Error::InvalidParameter(format!("failed to start helper processes: {}", e))
+            })
+    }
+
+    /// Kills a helper that takes longer than `timeout` on one compile.
+    ///
+    /// Without one, and without a limit set on the compile, a helper gets
+    /// [`DEFAULT_TIMEOUT`](Self::DEFAULT_TIMEOUT), so one that hangs still
+    /// fails its compile.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the number of helper processes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns how many helpers have been replaced after a crash.
+    pub fn restarts(&self) -> usize {
+        self.lock().restarts
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn acquire(&self) -> Result<Worker> {
+        let mut state = self.lock();
+        loop {
+            if let Some(worker) = state.idle.pop() {
+                return Ok(worker);
+            }
+            if state.live < self.size {
+                // Reserve the slot and fork without holding the lock
+                state.live += 1;
+                drop(state);
+                return Worker::spawn().inspect_err(|_| self.vacate());
+            }
+            state = self
+                .available
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn release(&self, worker: Option<Worker>) {
+        let worker = match worker {
+            Some(worker) => worker,
+            None => {
+                // The dead helper's slot stays reserved for its replacement,
+                // which is forked without holding the lock
+                self.lock().restarts += 1;
+                match Worker::spawn() {
+                    Ok(worker) => worker,
+                    Err(_) => return self.vacate(),
+                }
+            }
+        };
+        self.lock().idle.push(worker);
+        self.available.notify_one();
+    }
+
+    /// Gives up a reserved slot whose helper could not be started.
+    fn vacate(&self) {
+        self.lock().live -= 1;
+        self.available.notify_one();
+    }
+
+    /// Runs `job` on a helper, answering its include requests with `handler`.
+    pub(crate) fn compile(
+        &self,
+        job: CompileJob,
+        handler: Option<&mut (dyn IncludeHandler + '_)>,
+    ) -> Result<CompileResult> {
+        let mut worker = self.acquire()?;
+        let start = Instant::now();
+        let deadline = start
+            + match (self.timeout, job.timeout) {
+                (Some(a), Some(b)) => a.min(b),
+                (a, b) => a.or(b).unwrap_or(Self::DEFAULT_TIMEOUT),
+            };
+        match worker.run(&job, handler, deadline) {
+            Ok(result) => {
+                self.release(Some(worker));
+                result
+            }
+            Err(Failure::Io(e)) => {
+                // The helper may be mid-message, so it cannot be reused
+                worker.kill();
+                self.release(None);
+                Err(e.into())
+            }
+            Err(Failure::Crashed { timed_out }) => {
+                let (status, stderr) = worker.reap(timed_out);
+                self.release(None);
+
+                let job_timed_out = timed_out
+                    && job
+                        .timeout
+                        .is_some_and(|limit| self.timeout.is_none_or(|pool| limit <= pool));
+                Err(match job.timeout {
+                    Some(limit) if job_timed_out => Error::Timeout { limit },
+                    _ => match job.memory_limit {
+                        Some(limit)
+                            if libc::WIFEXITED(status)
+                                && libc::WEXITSTATUS(status) == EXIT_MEMORY_LIMIT =>
+                        {
+                            Error::MemoryLimitExceeded { limit }
+                        }
+                        _ => Error::CompilerCrashed {
+                            signal: libc::WIFSIGNALED(status).then(|| libc::WTERMSIG(status)),
+                            stderr,
+                        },
+                    },
+                })
+            }
+        }
+    }
+
+    /// Runs the preprocessing step of `job` on a helper, so computing a
+    /// cache key is as isolated as the compile itself.
+    pub(crate) fn preprocess(
+        &self,
+        job: CompileJob,
+        handler: Option<&mut (dyn IncludeHandler + '_)>,
+    ) -> Result<PreprocessResult> {
+        debug_assert!(job.preprocess_only);
+        // The helper returns the preprocessed text in place of bytecode
+        self.compile(job, handler).map(|result| PreprocessResult {
+            source: result.bytecode,
+            warnings: result.warnings,
+            dependencies: result.dependencies,
+        })
+    }
+}
+
+/// An owned compile request sent to a helper
+pub(crate) struct CompileJob {
+    pub(crate) source: Vec<u8>,
+    pub(crate) source_name: Option<String>,
+    pub(crate) entry_point: String,
+    pub(crate) target: ShaderTarget,
+    pub(crate) defines: Vec<(String, String)>,
+    pub(crate) flags1: CompileFlags,
+    pub(crate) flags2: u32,
+    pub(crate) secondary_data: Option<(u32, Vec<u8>)>,
+    pub(crate) has_include: bool,
+    /// Only preprocess, returning the preprocessed source as the bytecode
+    pub(crate) preprocess_only: bool,
+    /// Enforced by the parent, so not sent to the helper
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) memory_limit: Option<usize>,
+}
+
+impl CompileJob {
+    fn encode(&self) -> Vec<u8> {
+        let mut message = Encoder::new();
+        message
+            .u8(MSG_JOB)
+            .bytes(&self.source)
+            .opt_str(self.source_name.as_deref())
+            .str(&self.entry_point)
+            .str(&self.target.to_string())
+            .u32(self.defines.len() as u32);
+        for (name, value) in &self.defines {
+            message.str(name).str(value);
+        }
+        message.u32(self.flags1.bits()).u32(self.flags2);
+        match &self.secondary_data {
+            Some((flags, data)) => message.u8(1).u32(*flags).bytes(data),
+            None => message.u8(0),
+        };
+        message
+            .u8(self.has_include as u8)
+            .u8(self.preprocess_only as u8)
+            .u64(self.memory_limit.unwrap_or(0) as u64)
+            .finish()
+    }
+
+    fn decode(decoder: &mut Decoder) -> io::Result<Self> {
+        let invalid = |e: Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let source = decoder.bytes()?.to_vec();
+        let source_name = decoder.opt_str()?;
+        let entry_point = decoder.str()?;
+        let target = decoder.str()?.parse().map_err(invalid)?;
+        let defines = (0..decoder.u32()?)
+            .map(|_| Ok((decoder.str()?, decoder.str()?)))
+            .collect::<io::Result<_>>()?;
+        let flags1 = CompileFlags::from_bits_retain(decoder.u32()?);
+        let flags2 = decoder.u32()?;
+        let secondary_data = match decoder.u8()? {
+            0 => None,
+            _ => Some((decoder.u32()?, decoder.bytes()?.to_vec())),
+        };
+        let has_include = decoder.u8()? != 0;
+        let preprocess_only = decoder.u8()? != 0;
+        let memory_limit = Some(decoder.u64()? as usize).filter(|&limit| limit != 0);
+        Ok(CompileJob {
+            source,
+            source_name,
+            entry_point,
+            target,
+            defines,
+            flags1,
+            flags2,
+            secondary_data,
+            has_include,
+            preprocess_only,
+            timeout: None,
+            memory_limit,
+        })
+    }
+}
+
+/// Why a job did not produce a result
+enum Failure {
+    /// The helper died, or was killed after the deadline
+    Crashed { timed_out: bool },
+    /// The pipe to a live helper failed
+    Io(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                Failure::Crashed { timed_out: true }
+            }
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset => Failure::Crashed { timed_out: false },
+            _ => Failure::Io(e),
+        }
+    }
+}
+
+/// One forked helper process
+struct Worker {
+    pid: libc::pid_t,
+    reader: BufReader<UnixStream>,
+    writer: BufWriter<UnixStream>,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    stderr_thread: Option<JoinHandle<()>>,
+    reaped: bool,
+}
+
+impl Worker {
+    fn spawn() -> Result<Self> {
+        let (parent, child) = UnixStream::pair()?;
+        let (stderr_read, stderr_write) = pipe()?;
+        let pid = ForkServer::request(
+            SERVER_SPAWN,
+            0,
+            &[child.as_raw_fd(), stderr_write.as_raw_fd()],
+        )?;
+        drop(child);
+        drop(stderr_write);
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&stderr);
+        let stderr_thread = std::thread::Builder::new()
+            .name("d3dcrs-worker-stderr".to_string())
+            .spawn(move || drain_stderr(stderr_read, &sink))?;
+
+        Ok(Worker {
+            pid: pid as libc::pid_t,
+            reader: BufReader::new(parent.try_clone()?),
+            writer: BufWriter::new(parent),
+            stderr,
+            stderr_thread: Some(stderr_thread),
+            reaped: false,
+        })
+    }
+
+    fn run(
+        &mut self,
+        job: &CompileJob,
+        mut handler: Option<&mut (dyn IncludeHandler + '_)>,
+        deadline: Instant,
+    ) -> std::result::Result<Result<CompileResult>, Failure> {
+        self.stderr
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        write_frame(&mut self.writer, &job.encode())?;
+        self.writer.flush()?;
+
+        let mut include_failure = None;
+        loop {
+            let timeout = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|left| !left.is_zero())
+                .ok_or(Failure::Crashed { timed_out: true })?;
+            self.reader.get_ref().set_read_timeout(Some(timeout))?;
+
+            let message =
+                read_frame(&mut self.reader)?.ok_or(Failure::Crashed { timed_out: false })?;
+            let mut decoder = Decoder::new(&message);
+            match decoder.u8()? {
+                MSG_INCLUDE_OPEN => {
+                    let include_type = IncludeType::from(decoder.u32()?);
+                    let filename = decoder.str()?;
+                    let parent = decoder.opt_str()?.map(PathBuf::from);
+
+                    let opened = match handler.as_mut() {
+                        Some(handler) => {
+                            handler.open_from(include_type, &filename, parent.as_deref())
+                        }
+                        None => Err(Error::IncludeNotFound(filename.clone())),
+                    };
+                    let mut reply = Encoder::new();
+                    reply.u8(MSG_INCLUDE_REPLY);
+                    match opened {
+                        Ok(file) => {
+                            let path = file.path.as_deref().map(Path::to_string_lossy);
+                            reply.u8(1).bytes(&file.contents).opt_str(path.as_deref());
+                        }
+                        Err(e) => {
+                            reply.u8(0).str(&e.to_string());
+                            include_failure.get_or_insert(e);
+                        }
+                    }
+                    write_frame(&mut self.writer, &reply.finish())?;
+                    self.writer.flush()?;
+                }
+                MSG_DONE => return Ok(decode_result(&mut decoder, include_failure)?),
+                tag => {
+                    return Err(Failure::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected message {} from compiler process", tag),
+                    )));
+                }
+            }
+        }
+    }
+
+    fn kill(&mut self) {
+        if !self.reaped {
+            unsafe {
+                libc::kill(self.pid, libc::SIGKILL);
+            }
+            self.wait();
+        }
+    }
+
+    /// Reaps the helper and returns its raw wait status.
+    ///
+    /// The helper is the fork server's child, so the server reaps it. The
+    /// status is 0 if the server cannot tell.
+    fn wait(&mut self) -> libc::c_int {
+        self.reaped = true;
+        ForkServer::request(SERVER_WAIT, self.pid, &[]).map_or(0, |status| status as libc::c_int)
+    }
+
+    /// Reaps a failed helper, killing it first if it is still running.
+    ///
+    /// Returns its wait status and what it wrote to stderr.
+    fn reap(&mut self, kill: bool) -> (libc::c_int, String) {
+        if kill {
+            unsafe {
+                libc::kill(self.pid, libc::SIGKILL);
+            }
+        }
+        let status = self.wait();
+
+        // The pipe closes once the helper is gone, so this collects all of its output
+        if let Some(thread) = self.stderr_thread.take() {
+            let _ = thread.join();
+        }
+        let stderr = std::mem::take(&mut *self.stderr.lock().unwrap_or_else(|e| e.into_inner()));
+        (status, String::from_utf8_lossy(&stderr).into_owned())
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// The process that forks helpers
+///
+/// It has a single thread, and only forks and reaps helpers, so every helper
+/// starts from the same clean state however busy the pool's process is.
+struct ForkServer {
+    pid: libc::pid_t,
+    control: UnixStream,
+}
+
+static FORK_SERVER: Mutex<Option<ForkServer>> = Mutex::new(None);
+
+impl ForkServer {
+    fn lock() -> std::sync::MutexGuard<'static, Option<ForkServer>> {
+        FORK_SERVER.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start() -> Result<Self> {
+        let (parent, child) = UnixStream::pair()?;
+        let pid = match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error().into()),
+            0 => {
+                drop(parent);
+                fork_server_main(child)
+            }
+            pid => pid,
+        };
+        drop(child);
+
+        let mut server = ForkServer {
+            pid,
+            control: parent,
+        };
+        let mut ready = [0u8; 1];
+        let started = server
+            .control
+            .set_read_timeout(Some(SERVER_TIMEOUT))
+            .and_then(|()| server.control.read_exact(&mut ready));
+        if let Err(e) = started {
+            server.kill();
+            return Err(Error::CompilerCrashed {
+                signal: None,
+                stderr: format!("fork server did not start: {}", e),
+            });
+        }
+        Ok(server)
+    }
+
+    /// Sends `op` with `arg` and `fds`, starting the server if it is not
+    /// running, and returns its reply.
+    fn request(op: u8, arg: libc::pid_t, fds: &[RawFd]) -> Result<i64> {
+        let mut server = Self::lock();
+        if server.is_none() {
+            *server = Some(ForkServer::start()?);
+        }
+        let running = server.as_mut().unwrap();
+
+        let mut message = [0u8; 5];
+        message[0] = op;
+        message[1..].copy_from_slice(&arg.to_le_bytes());
+        let mut reply = [0u8; 8];
+        let sent = send_with_fds(&running.control, &message, fds)
+            .and_then(|()| running.control.read_exact(&mut reply));
+        if let Err(e) = sent {
+            // Start a new server for the next request
+            running.kill();
+            *server = None;
+            return Err(e.into());
+        }
+
+        let reply = i64::from_le_bytes(reply);
+        if reply < 0 {
+            return Err(io::Error::from_raw_os_error(-reply as i32).into());
+        }
+        Ok(reply)
+    }
+
+    fn kill(&mut self) {
+        unsafe {
+            libc::kill(self.pid, libc::SIGKILL);
+            while libc::waitpid(self.pid, std::ptr::null_mut(), 0) == -1
+                && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted
+            {}
+        }
+    }
+}
+
+/// Runs the fork server until the pool's process closes the control socket.
+fn fork_server_main(control: UnixStream) -> ! {
+    // Keep only stdio and the control socket, so helpers do not hold the
+    // parent's files and sockets open
+    let control_fd = 3;
+    unsafe {
+        if libc::dup2(control.as_raw_fd(), control_fd) == -1 {
+            libc::_exit(1);
+        }
+        close_fds_from(control_fd + 1);
+        // Helpers are reaped with waitpid, which an ignored SIGCHLD breaks
+        libc::signal(libc::SIGCHLD, libc::SIG_DFL);
+    }
+    std::mem::forget(control);
+    let mut control = unsafe { UnixStream::from_raw_fd(control_fd) };
+    if control.write_all(&[1]).is_err() {
+        unsafe { libc::_exit(0) }
+    }
+
+    loop {
+        let mut message = [0u8; 5];
+        let fds = match recv_with_fds(&control, &mut message) {
+            Ok(fds) => fds,
+            Err(_) => unsafe { libc::_exit(0) },
+        };
+        let arg = libc::pid_t::from_le_bytes(message[1..].try_into().unwrap());
+        let reply: i64 = match (message[0], <[OwnedFd; 2]>::try_from(fds)) {
+            (SERVER_SPAWN, Ok([socket, stderr])) => match unsafe { libc::fork() } {
+                -1 => {
+                    -(io::Error::last_os_error()
+                        .raw_os_error()
+                        .unwrap_or(libc::EIO) as i64)
+                }
+                0 => unsafe {
+                    libc::close(control_fd);
+                    libc::dup2(stderr.as_raw_fd(), libc::STDERR_FILENO);
+                    drop(stderr);
+                    child_main(UnixStream::from(socket))
+                },
+                pid => pid as i64,
+            },
+            (SERVER_WAIT, _) => {
+                let mut status = 0;
+                loop {
+                    if unsafe { libc::waitpid(arg, &mut status, 0) } != -1 {
+                        break status as i64;
+                    }
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        break -(error.raw_os_error().unwrap_or(libc::EIO) as i64);
+                    }
+                }
+            }
+            _ => -(libc::EINVAL as i64),
+        };
+        if control.write_all(&reply.to_le_bytes()).is_err() {
+            unsafe { libc::_exit(0) }
+        }
+    }
+}
+
+/// Closes every descriptor from `first` up.
+unsafe fn close_fds_from(first: RawFd) {
+    unsafe {
+        if libc::syscall(
+            libc::SYS_close_range,
+            first as libc::c_uint,
+            libc::c_uint::MAX,
+            0,
+        ) == 0
+        {
+            return;
+        }
+        // Kernels before 5.9
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        let max = if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0 {
+            limit.rlim_cur.min(1 << 16) as RawFd
+        } else {
+            1024
+        };
+        for fd in first..max {
+            libc::close(fd);
+        }
+    }
+}
+
+/// Sends all of `bytes` with `fds` attached.
+fn send_with_fds(socket: &UnixStream, bytes: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fd_bytes = std::mem::size_of_val(fds);
+    // u64 keeps the buffer aligned for cmsghdr
+    let mut control = vec![0u64; unsafe { libc::CMSG_SPACE(fd_bytes as u32) } as usize / 8 + 1];
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        unsafe {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(fd_bytes as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fd_bytes as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+        }
+    }
+
+    loop {
+        let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if sent == bytes.len() as isize {
+            return Ok(());
+        }
+        let error = match sent {
+            -1 => io::Error::last_os_error(),
+            _ => io::Error::from(io::ErrorKind::WriteZero),
+        };
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Receives exactly `bytes.len()` bytes and the descriptors attached to them.
+fn recv_with_fds(socket: &UnixStream, bytes: &mut [u8]) -> io::Result<Vec<OwnedFd>> {
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_mut_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let received = loop {
+        let received =
+            unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if received != -1 {
+            break received as usize;
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    };
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    match received {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        n if n == bytes.len() => Ok(fds),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "short message from the pool's process",
+        )),
+    }
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds: [RawFd; 2] = [-1; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1]))) }
+}
+
+/// Keeps the tail of a helper's stderr until the pipe closes.
+fn drain_stderr(pipe: OwnedFd, sink: &Mutex<Vec<u8>>) {
+    let mut pipe = std::fs::File::from(pipe);
+    let mut chunk = [0u8; 4096];
+    loop {
+        match pipe.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+                sink.extend_from_slice(&chunk[..n]);
+                if sink.len() > MAX_STDERR {
+                    let excess = sink.len() - MAX_STDERR;
+                    sink.drain(..excess);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+}
+
+fn decode_result(
+    decoder: &mut Decoder,
+    include_failure: Option<Error>,
+) -> io::Result<Result<CompileResult>> {
+    if decoder.u8()? != 0 {
+        let bytecode = decoder.bytes()?;
+        let warnings = decoder.opt_str()?;
+        let dependencies = (0..decoder.u32()?)
+            .map(|_| decoder.str().map(PathBuf::from))
+            .collect::<io::Result<_>>()?;
+        return Ok(Blob::from_bytes(bytecode).map(|bytecode| CompileResult {
+            bytecode,
+            warnings,
+            dependencies,
+        }));
+    }
+
+    let kind = decoder.u8()?;
+    let hresult = HResult(decoder.i32()?);
+    let message = decoder.str()?;
+    Ok(Err(match kind {
+        ERR_COMPILATION => Error::Compilation { hresult, message },
+        ERR_INCLUDE => Error::Include {
+            filename: decoder.str()?,
+            // The handler ran here, so its own error is better than the relayed text
+            source: Box::new(include_failure.unwrap_or(Error::InvalidParameter(message))),
+        },
+        ERR_INVALID_PARAMETER => Error::InvalidParameter(message),
+        ERR_CREATE_BLOB => Error::CreateBlob { hresult },
+        _ => Error::Io(io::Error::other(message)),
+    }))
+}
+
+fn encode_result(result: &Result<CompileResult>) -> Vec<u8> {
+    let mut message = Encoder::new();
+    message.u8(MSG_DONE);
+    match result {
+        Ok(result) => {
+            message
+                .u8(1)
+                .bytes(result.bytecode.as_bytes())
+                .opt_str(result.warnings.as_deref())
+                .u32(result.dependencies.len() as u32);
+            for dependency in &result.dependencies {
+                message.str(&dependency.to_string_lossy());
+            }
+        }
+        Err(e) => {
+            let (kind, hresult, filename) = match e {
+                Error::Compilation { hresult, .. } => (ERR_COMPILATION, *hresult, None),
+                Error::Include { filename, .. } => {
+                    (ERR_INCLUDE, HResult::E_FAIL, Some(filename.as_str()))
+                }
+                Error::InvalidParameter(_) => (ERR_INVALID_PARAMETER, HResult::E_FAIL, None),
+                Error::CreateBlob { hresult } => (ERR_CREATE_BLOB, *hresult, None),
+                _ => (ERR_OTHER, HResult::E_FAIL, None),
+            };
+            let message_text = match e {
+                Error::Compilation { message, .. } => message.clone(),
+                Error::InvalidParameter(message) => message.clone(),
+                Error::Include { source, .. } => source.to_string(),
+                other => other.to_string(),
+            };
+            message.u8(0).u8(kind).i32(hresult.0).str(&message_text);
+            if let Some(filename) = filename {
+                message.str(filename);
+            }
+        }
+    }
+    message.finish()
+}
+
+/// Include handler that asks the parent process for each file
+struct RelayInclude<'a> {
+    reader: &'a mut BufReader<UnixStream>,
+    writer: &'a mut BufWriter<UnixStream>,
+}
+
+impl RelayInclude<'_> {
+    fn request(
+        &mut self,
+        include_type: IncludeType,
+        filename: &str,
+        parent: Option<&Path>,
+    ) -> io::Result<Option<IncludeFile>> {
+        let parent = parent.map(Path::to_string_lossy);
+        let request = Encoder::new()
+            .u8(MSG_INCLUDE_OPEN)
+            .u32(include_type as u32)
+            .str(filename)
+            .opt_str(parent.as_deref())
+            .finish();
+        write_frame(self.writer, &request)?;
+        self.writer.flush()?;
+
+        let reply = read_frame(self.reader)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let mut decoder = Decoder::new(&reply);
+        if decoder.u8()? != MSG_INCLUDE_REPLY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected include reply",
+            ));
+        }
+        if decoder.u8()? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(IncludeFile {
+            contents: decoder.bytes()?.to_vec(),
+            path: decoder.opt_str()?.map(PathBuf::from),
+        }))
+    }
+}
+
+impl IncludeHandler for RelayInclude<'_> {
+    fn open(&mut self, include_type: IncludeType, filename: &str) -> Result<Vec<u8>> {
+        self.open_from(include_type, filename, None)
+            .map(|file| file.contents)
+    }
+
+    fn open_from(
+        &mut self,
+        include_type: IncludeType,
+        filename: &str,
+        parent: Option<&Path>,
+    ) -> Result<IncludeFile> {
+        match self.request(include_type, filename, parent) {
+            Ok(Some(file)) => Ok(file),
+            // The parent keeps the real error and reports it for this job
+            Ok(None) => Err(Error::IncludeNotFound(filename.to_string())),
+            Err(_) => unsafe { libc::_exit(1) },
+        }
+    }
+}
+
+/// Ends a helper whose compile went over its memory limit.
+///
+/// Exiting right away is simpler than trusting the compiler to recover from
+/// a failed allocation, and the parent maps the status back to an error.
+fn exit_over_memory_limit() {
+    unsafe { libc::_exit(EXIT_MEMORY_LIMIT) }
+}
+
+fn compile_job(job: &CompileJob, include: Option<RelayInclude>) -> Result<CompileResult> {
+    let mut builder = CompileBuilder::from_bytes(&job.source, &job.entry_point, job.target)
+        .flags(job.flags1)
+        .flags2(job.flags2);
+    if let Some(name) = &job.source_name {
+        builder = builder.source_name(name);
+    }
+    for (name, value) in &job.defines {
+        builder = builder.define(name, value);
+    }
+    if let Some((flags, data)) = &job.secondary_data {
+        builder = builder.secondary_data(data, *flags);
+    }
+    if let Some(include) = include {
+        builder = builder.include(include);
+    }
+    builder.compile()
+}
+
+fn preprocess_job(job: &CompileJob, include: Option<RelayInclude>) -> Result<CompileResult> {
+    let mut preprocess = PreprocessBuilder::from_bytes(&job.source);
+    if let Some(name) = &job.source_name {
+        preprocess = preprocess.source_name(name);
+    }
+    for (name, value) in &job.defines {
+        preprocess = preprocess.define(name, value);
+    }
+    if let Some(include) = include {
+        preprocess = preprocess.include(include);
+    }
+    match preprocess.preprocess() {
+        Ok(result) => Ok(CompileResult {
+            bytecode: result.source,
+            warnings: result.warnings,
+            dependencies: result.dependencies,
+        }),
+        // Reported the way an uncached compile reports it
+        Err(Error::Preprocessing { hresult, message }) => {
+            Err(Error::Compilation { hresult, message })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Serves jobs until the parent closes the socket. Never returns.
+fn child_main(stream: UnixStream) -> ! {
+    d3dcompiler::setup_thread();
+
+    let code = std::panic::catch_unwind(move || -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        while let Some(message) = read_frame(&mut reader)? {
+            let mut decoder = Decoder::new(&message);
+            if decoder.u8()? != MSG_JOB {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "expected job"));
+            }
+            let job = CompileJob::decode(&mut decoder)?;
+
+            let include = job.has_include.then_some(RelayInclude {
+                reader: &mut reader,
+                writer: &mut writer,
+            });
+            d3dcompiler::set_allocation_limit(job.memory_limit, Some(exit_over_memory_limit));
+            let result = if job.preprocess_only {
+                preprocess_job(&job, include)
+            } else {
+                compile_job(&job, include)
+            };
+            d3dcompiler::set_allocation_limit(None, None);
+
+            write_frame(&mut writer, &encode_result(&result))?;
+            writer.flush()?;
+        }
+        Ok(())
+    });
+
+    // Skip the parent's atexit handlers and destructors
+    let code = match code {
+        Ok(Ok(())) => 0,
+        Ok(Err(_)) => 1,
+        Err(_) => 101,
+    };
+    unsafe { libc::_exit(code) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_roundtrip() {
+        let job = CompileJob {
+            source: b"float4 main() : SV_TARGET { return 1; }".to_vec(),
+            source_name: Some("a.hlsl".to_string()),
+            entry_point: "main".to_string(),
+            target: ShaderTarget::PS_5_0,
+            defines: vec![("A".to_string(), "1".to_string())],
+            flags1: CompileFlags::DEBUG,
+            flags2: 3,
+            secondary_data: Some((1, vec![9, 9])),
+            has_include: true,
+            preprocess_only: true,
+            timeout: None,
+            memory_limit: Some(1 << 30),
+        };
+        let message = job.encode();
+        let mut decoder = Decoder::new(&message);
+        assert_eq!(decoder.u8().unwrap(), MSG_JOB);
+        let decoded = CompileJob::decode(&mut decoder).unwrap();
+        assert_eq!(decoded.source, job.source);
+        assert_eq!(decoded.source_name.as_deref(), Some("a.hlsl"));
+        assert_eq!(decoded.target, ShaderTarget::PS_5_0);
+        assert_eq!(decoded.defines, job.defines);
+        assert_eq!(decoded.flags1, CompileFlags::DEBUG);
+        assert_eq!(decoded.secondary_data, Some((1, vec![9, 9])));
+        assert!(decoded.has_include);
+        assert!(decoded.preprocess_only);
+        assert_eq!(decoded.memory_limit, Some(1 << 30));
+    }
+
+    #[test]
+    fn test_error_roundtrip() {
+        let error = Err(Error::Compilation {
+            hresult: HResult::E_FAIL,
+            message: "error X3004: undeclared identifier".to_string(),
+        });
+        let message = encode_result(&error);
+        let mut decoder = Decoder::new(&message);
+        assert_eq!(decoder.u8().unwrap(), MSG_DONE);
+        let decoded = decode_result(&mut decoder, None).unwrap();
+        assert!(matches!(
+            decoded,
+            Err(Error::Compilation { ref message, .. }) if message.contains("X3004")
+        ));
+
+        let error = Err(Error::Include {
+            filename: "missing.hlsl".to_string(),
+            source: Box::new(Error::IncludeNotFound("missing.hlsl".to_string())),
+        });
+        let message = encode_result(&error);
+        let mut decoder = Decoder::new(&message);
+        decoder.u8().unwrap();
+        let decoded = decode_result(&mut decoder, Some(Error::InvalidParameter("no".into())));
+        assert!(matches!(
+            decoded.unwrap(),
+            Err(Error::Include { ref filename, ref source })
+                if filename == "missing.hlsl" && matches!(**source, Error::InvalidParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_send_with_fds() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (read, write) = pipe().unwrap();
+        send_with_fds(&a, &[SERVER_SPAWN, 7, 0, 0, 0], &[write.as_raw_fd()]).unwrap();
+        drop(write);
+
+        let mut message = [0u8; 5];
+        let fds = recv_with_fds(&b, &mut message).unwrap();
+        assert_eq!(message, [SERVER_SPAWN, 7, 0, 0, 0]);
+        assert_eq!(fds.len(), 1);
+
+        // The received descriptor is the pipe's write end
+        let mut write = std::fs::File::from(fds.into_iter().next().unwrap());
+        write.write_all(b"ok").unwrap();
+        drop(write);
+        let mut text = String::new();
+        std::fs::File::from(read).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "ok");
+
+        drop(a);
+        assert_eq!(
+            recv_with_fds(&b, &mut message).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_fork_server_spawn_and_reap() {
+        // Helpers wait for a job, so this one is still running when killed
+        let mut worker = Worker::spawn().unwrap();
+        assert!(worker.pid > 0);
+        let (status, _) = worker.reap(true);
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::WTERMSIG(status), libc::SIGKILL);
+    }
+}
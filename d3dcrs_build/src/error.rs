@@ -0,0 +1,37 @@
+//! Error types for d3dcrs_build
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Result type alias using d3dcrs_build's Error type
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error type for build script shader compilation
+#[derive(Error, Debug)]
+pub enum Error {
+    /// `OUT_DIR` is not set and no output directory was given
+    #[error("OUT_DIR is not set; call out_dir() when not running from a build script")]
+    OutDirNotSet,
+
+    /// Reading a source or writing an output failed
+    #[error("Failed to access {}: {source}", path.display())]
+    Io {
+        /// The file being read or written
+        path: PathBuf,
+        /// The underlying I/O error
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// A shader failed to compile
+    #[error("Failed to compile {entry} in {}: {source}", path.display())]
+    Compile {
+        /// The shader source file
+        path: PathBuf,
+        /// The entry point being compiled
+        entry: String,
+        /// The compiler error
+        #[source]
+        source: d3dcrs::Error,
+    },
+}
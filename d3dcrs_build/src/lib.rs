@@ -0,0 +1,319 @@
+//! Compile HLSL shaders from Cargo build scripts
+//!
+//! Compiles a list of entry points into `OUT_DIR`, telling Cargo to rerun the
+//! build script when a shader or any file it includes changes, and forwarding
+//! compiler warnings as `cargo:warning` lines.
+//!
+//! # Example
+//!
+//! ```no_run
+//! // build.rs
+//! use d3dcrs_build::{Build, ShaderTarget};
+//!
+//! fn main() {
+//!     Build::new()
+//!         .include_path("shaders/include")
+//!         .shader("shaders/triangle.hlsl", "VSMain", ShaderTarget::VS_5_0)
+//!         .shader("shaders/triangle.hlsl", "PSMain", ShaderTarget::PS_5_0)
+//!         .compile();
+//! }
+//! ```
+//!
+//! The bytecode can then be embedded with
+//! `include_bytes!(concat!(env!("OUT_DIR"), "/triangle_VSMain.dxbc"))`.
+
+mod error;
+
+pub use d3dcrs::{CompileFlags, ShaderTarget};
+pub use error::{Error, Result};
+
+use d3dcrs::{CompileBuilder, FileSystemInclude};
+use std::path::{Path, PathBuf};
+
+/// A single entry point to compile
+#[derive(Debug, Clone)]
+pub struct Shader {
+    path: PathBuf,
+    entry: String,
+    target: ShaderTarget,
+    name: Option<String>,
+    defines: Vec<(String, String)>,
+    flags: Option<CompileFlags>,
+}
+
+impl Shader {
+    /// Creates a shader compiling `entry` from the file at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P, entry: &str, target: ShaderTarget) -> Self {
+        Shader {
+            path: path.into(),
+            entry: entry.to_string(),
+            target,
+            name: None,
+            defines: Vec::new(),
+            flags: None,
+        }
+    }
+
+    /// Sets the output file name, without extension (default: `<stem>_<entry>`).
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Adds a preprocessor define for this shader only.
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Overrides the build-wide compile flags for this shader.
+    pub fn flags(mut self, flags: CompileFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Returns the output file name, without extension.
+    pub fn output_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            let stem = self
+                .path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy())
+                .unwrap_or_default();
+            format!("{}_{}", stem, self.entry)
+        })
+    }
+}
+
+/// A shader written to the output directory
+#[derive(Debug, Clone)]
+pub struct CompiledShader {
+    /// The output file name, without extension
+    pub name: String,
+    /// Path of the written `.dxbc` file
+    pub path: PathBuf,
+    /// Files opened through `#include` while compiling
+    pub dependencies: Vec<PathBuf>,
+}
+
+/// Build script shader compiler
+///
+/// Paths are relative to the build script's working directory, which Cargo
+/// sets to the package root.
+#[derive(Debug, Clone)]
+pub struct Build {
+    shaders: Vec<Shader>,
+    include: FileSystemInclude,
+    defines: Vec<(String, String)>,
+    flags: Option<CompileFlags>,
+    out_dir: Option<PathBuf>,
+    cargo_metadata: bool,
+}
+
+impl Default for Build {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Build {
+    /// Creates an empty build.
+    pub fn new() -> Self {
+        Build {
+            shaders: Vec::new(),
+            include: FileSystemInclude::new(),
+            defines: Vec::new(),
+            flags: None,
+            out_dir: None,
+            cargo_metadata: true,
+        }
+    }
+
+    /// Adds an entry point to compile.
+    pub fn shader<P: Into<PathBuf>>(self, path: P, entry: &str, target: ShaderTarget) -> Self {
+        self.with_shader(Shader::new(path, entry, target))
+    }
+
+    /// Adds a configured shader.
+    pub fn with_shader(mut self, shader: Shader) -> Self {
+        self.shaders.push(shader);
+        self
+    }
+
+    /// Adds a search path for `#include "..."`.
+    pub fn include_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.include.add_path(path);
+        self
+    }
+
+    /// Adds a search path for `#include <...>`.
+    pub fn system_include_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.include.add_system_path(path);
+        self
+    }
+
+    /// Maps a virtual include prefix to a directory.
+    pub fn virtual_include_path<P: Into<PathBuf>>(mut self, mount: &str, dir: P) -> Self {
+        self.include.add_virtual_path(mount, dir);
+        self
+    }
+
+    /// Replaces the include handler built up by the `*include_path` methods.
+    pub fn include(mut self, include: FileSystemInclude) -> Self {
+        self.include = include;
+        self
+    }
+
+    /// Adds a preprocessor define for every shader.
+    pub fn define(mut self, name: &str, value: &str) -> Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets compile flags for every shader.
+    pub fn flags(mut self, flags: CompileFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Sets the output directory (default: `OUT_DIR`).
+    pub fn out_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.out_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets whether to print `cargo:` directives (default: true).
+    pub fn cargo_metadata(mut self, enabled: bool) -> Self {
+        self.cargo_metadata = enabled;
+        self
+    }
+
+    /// Compiles every shader, panicking with the compiler output on failure.
+    ///
+    /// This is the usual entry point from `build.rs`, where a panic fails the
+    /// build and shows the message.
+    pub fn compile(self) -> Vec<CompiledShader> {
+        match self.try_compile() {
+            Ok(compiled) => compiled,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Compiles every shader, stopping at the first error.
+    pub fn try_compile(self) -> Result<Vec<CompiledShader>> {
+        let out_dir = match &self.out_dir {
+            Some(dir) => dir.clone(),
+            None => std::env::var_os("OUT_DIR")
+                .map(PathBuf::from)
+                .ok_or(Error::OutDirNotSet)?,
+        };
+
+        self.shaders
+            .iter()
+            .map(|shader| self.compile_shader(shader, &out_dir))
+            .collect()
+    }
+
+    fn compile_shader(&self, shader: &Shader, out_dir: &Path) -> Result<CompiledShader> {
+        self.rerun_if_changed(&shader.path);
+
+        let source = std::fs::read(&shader.path).map_err(|source| Error::Io {
+            path: shader.path.clone(),
+            source,
+        })?;
+
+        let mut builder = CompileBuilder::from_bytes(&source, &shader.entry, shader.target)
+            .source_name(&shader.path.to_string_lossy())
+            .include(self.include.clone());
+        for (name, value) in self.defines.iter().chain(&shader.defines) {
+            builder = builder.define(name, value);
+        }
+        if let Some(flags) = shader.flags.or(self.flags) {
+            builder = builder.flags(flags);
+        }
+
+        let result = builder.compile().map_err(|source| Error::Compile {
+            path: shader.path.clone(),
+            entry: shader.entry.clone(),
+            source,
+        })?;
+
+        // Cargo always reruns a failed build script, so includes only need
+        // reporting once the compile succeeds
+        for dependency in &result.dependencies {
+            self.rerun_if_changed(dependency);
+        }
+        if let Some(warnings) = &result.warnings
+            && self.cargo_metadata
+        {
+            for line in warning_lines(warnings) {
+                println!("cargo:warning={}", line);
+            }
+        }
+
+        let name = shader.output_name();
+        let path = out_dir.join(format!("{}.dxbc", name));
+        std::fs::write(&path, result.bytecode.as_bytes()).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+
+        Ok(CompiledShader {
+            name,
+            path,
+            dependencies: result.dependencies,
+        })
+    }
+
+    fn rerun_if_changed(&self, path: &Path) {
+        if self.cargo_metadata {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+}
+
+/// Splits compiler output into the non-empty lines Cargo shows as warnings.
+fn warning_lines(warnings: &str) -> impl Iterator<Item = &str> {
+    warnings
+        .lines()
+        .map(|line| line.trim_end_matches(['\0', '\r']))
+        .filter(|line| !line.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_name() {
+        let shader = Shader::new("shaders/triangle.hlsl", "VSMain", ShaderTarget::VS_5_0);
+        assert_eq!(shader.output_name(), "triangle_VSMain");
+        assert_eq!(shader.name("tri_vs").output_name(), "tri_vs");
+    }
+
+    #[test]
+    fn test_warning_lines() {
+        let warnings = "a.hlsl(3,5): warning X3206: implicit truncation\r\n\n\
+                        a.hlsl(4,1): warning X3557: loop only executes once\n\0";
+        let lines: Vec<&str> = warning_lines(warnings).collect();
+        assert_eq!(
+            lines,
+            [
+                "a.hlsl(3,5): warning X3206: implicit truncation",
+                "a.hlsl(4,1): warning X3557: loop only executes once",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_source() {
+        let dir = std::env::temp_dir();
+        let err = Build::new()
+            .out_dir(&dir)
+            .cargo_metadata(false)
+            .shader("does/not/exist.hlsl", "main", ShaderTarget::PS_5_0)
+            .try_compile()
+            .unwrap_err();
+        assert!(matches!(err, Error::Io { .. }));
+    }
+}
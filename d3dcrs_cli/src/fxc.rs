@@ -0,0 +1,499 @@
+//! fxc.exe-compatible command-line front end
+//!
+//! Accepts the option syntax of the Windows `fxc.exe` (both `/X` and `-X`
+//! forms, with values either attached or in the next argument) so existing
+//! build scripts can run unchanged.
+
+use d3dcrs::codegen::HeaderBuilder;
+use d3dcrs::{
+    CompileBuilder, CompileFlags, DisassembleBuilder, DisassembleFlags, Error, FileSystemInclude,
+    PreprocessBuilder, ShaderTarget, StripFlags, strip_shader,
+};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const LOGO: &str = "Microsoft (R) Direct3D Shader Compiler 10.1 (d3dcrs)\n\
+                    Copyright (C) 2013 Microsoft. All rights reserved.\n";
+
+const USAGE: &str = "\
+Usage: fxc <options> <file>
+
+   /?, /help           print this message
+
+   /T <profile>        target profile
+   /E <name>           entrypoint name
+   /I <include>        additional include path
+   /Vi                 accepted for compatibility; has no effect
+
+   /Od                 disable optimizations
+   /Op                 disable preshaders
+   /O{0,1,2,3}         optimization level; 1 is default
+   /WX                 treat warnings as errors
+   /Vd                 disable validation
+   /Zi                 enable debugging information
+   /Zpr                pack matrices in row-major order
+   /Zpc                pack matrices in column-major order
+
+   /Gpp                force partial precision
+   /Gfa                avoid flow control constructs
+   /Gfp                prefer flow control constructs
+   /Ges                enable strict mode
+   /Gec                enable backwards compatibility mode
+   /Gis                force IEEE strictness
+   /all_resources_bound enable aggressive flattening in SM5.1+
+
+   /Fo <file>          output object file
+   /Fc <file>          output assembly code listing file
+   /Fe <file>          output warnings and errors to a specific file
+   /Fh <file>          output header file containing object code
+   /Vn <name>          use <name> as variable name in header file
+   /P <file>           preprocess to file (must be used alone)
+
+   /Cc                 output color coded assembly listings
+   /Ni                 output instruction numbers in assembly listings
+   /No                 output instruction byte offset in assembly listings
+   /Lx                 output hexadecimal literals
+
+   /Qstrip_reflect     strip reflection data from 4_0+ shader bytecode
+   /Qstrip_debug       strip debug information from 4_0+ shader bytecode
+   /Qstrip_priv        strip private data from 4_0+ shader bytecode
+   /Qstrip_rootsignature strip root signature from shader bytecode
+
+   /D <id>=<text>      define macro
+   /nologo             suppress copyright message
+
+   <option> = -<option> (alternate form)
+";
+
+/// Options that take no value, with the compile flags they set.
+const FLAG_OPTIONS: &[(&str, CompileFlags)] = &[
+    ("Zi", CompileFlags::DEBUG),
+    ("Vd", CompileFlags::SKIP_VALIDATION),
+    ("Od", CompileFlags::SKIP_OPTIMIZATION),
+    ("Zpr", CompileFlags::PACK_MATRIX_ROW_MAJOR),
+    ("Zpc", CompileFlags::PACK_MATRIX_COLUMN_MAJOR),
+    ("Gpp", CompileFlags::PARTIAL_PRECISION),
+    ("Op", CompileFlags::NO_PRESHADER),
+    ("Gfa", CompileFlags::AVOID_FLOW_CONTROL),
+    ("Gfp", CompileFlags::PREFER_FLOW_CONTROL),
+    ("Ges", CompileFlags::ENABLE_STRICTNESS),
+    ("Gec", CompileFlags::ENABLE_BACKWARDS_COMPATIBILITY),
+    ("Gis", CompileFlags::IEEE_STRICTNESS),
+    ("WX", CompileFlags::WARNINGS_ARE_ERRORS),
+    ("res_may_alias", CompileFlags::RESOURCES_MAY_ALIAS),
+    (
+        "enable_unbounded_descriptor_tables",
+        CompileFlags::ENABLE_UNBOUNDED_DESCRIPTOR_TABLES,
+    ),
+    ("all_resources_bound", CompileFlags::ALL_RESOURCES_BOUND),
+];
+
+/// Options that take no value, with the strip flags they set.
+const STRIP_OPTIONS: &[(&str, StripFlags)] = &[
+    ("Qstrip_reflect", StripFlags::REFLECTION_DATA),
+    ("Qstrip_debug", StripFlags::DEBUG_INFO),
+    ("Qstrip_priv", StripFlags::PRIVATE_DATA),
+    ("Qstrip_rootsignature", StripFlags::ROOT_SIGNATURE),
+];
+
+/// Options that take no value, with the disassembly flags they set.
+const LISTING_OPTIONS: &[(&str, DisassembleFlags)] = &[
+    ("Cc", DisassembleFlags::ENABLE_COLOR_CODE),
+    ("Ni", DisassembleFlags::ENABLE_INSTRUCTION_NUMBERING),
+    ("No", DisassembleFlags::ENABLE_INSTRUCTION_OFFSET),
+    ("Lx", DisassembleFlags::PRINT_HEX_LITERALS),
+];
+
+/// Options that take a value, either attached (`/Tps_5_0`) or as the next argument.
+const VALUE_OPTIONS: &[&str] = &["T", "E", "I", "D", "Fo", "Fc", "Fe", "Fh", "Vn", "P"];
+
+/// Options that are accepted for compatibility but have no effect.
+const IGNORED_OPTIONS: &[&str] = &["Vi", "Gch"];
+
+#[derive(Default)]
+struct FxcOptions {
+    input: Option<PathBuf>,
+    target: Option<String>,
+    entry: Option<String>,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+    flags: CompileFlags,
+    optimization_level: Option<u32>,
+    strip: StripFlags,
+    listing_flags: DisassembleFlags,
+    object_file: Option<PathBuf>,
+    listing_file: Option<PathBuf>,
+    error_file: Option<PathBuf>,
+    header_file: Option<PathBuf>,
+    variable_name: Option<String>,
+    preprocess_file: Option<PathBuf>,
+    nologo: bool,
+    help: bool,
+}
+
+/// Splits `/D NAME=VALUE` into its parts; a bare name is defined as `1`.
+fn parse_define(s: &str) -> (String, String) {
+    s.split_once('=')
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .unwrap_or_else(|| (s.to_string(), "1".to_string()))
+}
+
+impl FxcOptions {
+    fn set_value(&mut self, name: &str, value: String) {
+        match name {
+            "T" => self.target = Some(value),
+            "E" => self.entry = Some(value),
+            "I" => self.include_dirs.push(PathBuf::from(value)),
+            "D" => self.defines.push(parse_define(&value)),
+            "Fo" => self.object_file = Some(PathBuf::from(value)),
+            "Fc" => self.listing_file = Some(PathBuf::from(value)),
+            "Fe" => self.error_file = Some(PathBuf::from(value)),
+            "Fh" => self.header_file = Some(PathBuf::from(value)),
+            "Vn" => self.variable_name = Some(value),
+            "P" => self.preprocess_file = Some(PathBuf::from(value)),
+            _ => unreachable!("unhandled value option {}", name),
+        }
+    }
+
+    /// Applies a value-less option, returning false if it is not recognised.
+    fn set_switch(&mut self, name: &str) -> bool {
+        if let Some((_, flags)) = FLAG_OPTIONS.iter().find(|(n, _)| *n == name) {
+            self.flags |= *flags;
+        } else if let Some((_, flags)) = STRIP_OPTIONS.iter().find(|(n, _)| *n == name) {
+            self.strip |= *flags;
+        } else if let Some((_, flags)) = LISTING_OPTIONS.iter().find(|(n, _)| *n == name) {
+            self.listing_flags |= *flags;
+        } else if let Some(level @ 0..=3) = name
+            .strip_prefix('O')
+            .and_then(|level| level.parse::<u32>().ok())
+        {
+            self.optimization_level = Some(level);
+        } else {
+            match name {
+                "nologo" => self.nologo = true,
+                "?" | "help" => self.help = true,
+                _ if IGNORED_OPTIONS.contains(&name) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<FxcOptions, String> {
+    let mut options = FxcOptions::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let option = arg
+            .strip_prefix('-')
+            .or_else(|| arg.strip_prefix('/'))
+            .filter(|_| arg.len() > 1);
+
+        // On Linux an absolute input path also starts with '/'
+        let option = match option {
+            Some(_) if arg.starts_with('/') && Path::new(&arg).exists() => None,
+            other => other,
+        };
+
+        let Some(option) = option else {
+            if options.input.replace(PathBuf::from(&arg)).is_some() {
+                return Err(format!("error: multiple input files specified ('{}')", arg));
+            }
+            continue;
+        };
+
+        if options.set_switch(option) {
+            continue;
+        }
+
+        if VALUE_OPTIONS.contains(&option) {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("error: missing argument for option '{}'", arg))?;
+            options.set_value(option, value);
+            continue;
+        }
+
+        // Attached value, preferring the longest option name (`/Fo` over `/F`)
+        let attached = VALUE_OPTIONS
+            .iter()
+            .filter(|name| option.starts_with(**name))
+            .max_by_key(|name| name.len());
+        match attached {
+            Some(name) => options.set_value(name, option[name.len()..].to_string()),
+            None => return Err(format!("Unknown or invalid option '{}'", arg)),
+        }
+    }
+
+    Ok(options)
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+/// Reports compiler diagnostics to stderr, and also writes them to the `/Fe`
+/// file if given.
+fn report_diagnostics(options: &FxcOptions, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    if let Some(path) = &options.error_file
+        && let Err(e) = write_file(path, text.as_bytes())
+    {
+        eprintln!("{}", e);
+    }
+    eprint!("{}", text);
+    if !text.ends_with('\n') {
+        eprintln!();
+    }
+}
+
+fn include_handler(options: &FxcOptions) -> FileSystemInclude {
+    let mut handler = FileSystemInclude::new();
+    for dir in &options.include_dirs {
+        handler.add_path(dir);
+        handler.add_system_path(dir);
+    }
+    handler
+}
+
+fn run_preprocess(options: &FxcOptions, input: &Path, source: &str, output: &Path) -> ExitCode {
+    let mut builder = PreprocessBuilder::new(source)
+        .source_name(&input.to_string_lossy())
+        .include(include_handler(options));
+    for (name, value) in &options.defines {
+        builder = builder.define(name, value);
+    }
+
+    match builder.preprocess() {
+        Ok(result) => {
+            report_diagnostics(options, result.warnings.as_deref().unwrap_or_default());
+            if let Err(e) = write_file(output, result.source.as_bytes()) {
+                eprintln!("{}", e);
+                return ExitCode::FAILURE;
+            }
+            println!("preprocessed file save succeeded; see {}", output.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            report_diagnostics(options, &diagnostic_text(&e));
+            println!("preprocessing failed; no output produced");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Extracts the compiler's own message where there is one.
+fn diagnostic_text(error: &Error) -> String {
+    match error {
+        Error::Compilation { message, .. } | Error::Preprocessing { message, .. } => {
+            message.clone()
+        }
+        other => format!("error: {}", other),
+    }
+}
+
+fn run(options: FxcOptions) -> Result<ExitCode, String> {
+    if !options.nologo {
+        println!("{}", LOGO);
+    }
+
+    if options.help {
+        print!("{}", USAGE);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let Some(input) = options.input.clone() else {
+        print!("{}", USAGE);
+        return Err("error: no input file specified".to_string());
+    };
+
+    let source = std::fs::read_to_string(&input)
+        .map_err(|e| format!("error: failed to open file: {}: {}", input.display(), e))?;
+
+    if let Some(output) = options.preprocess_file.clone() {
+        return Ok(run_preprocess(&options, &input, &source, &output));
+    }
+
+    let target: ShaderTarget = options
+        .target
+        .as_deref()
+        .ok_or("error: profile must be specified (/T)")?
+        .parse()
+        .map_err(|e| format!("error: {}", e))?;
+    let entry = options.entry.clone().unwrap_or_else(|| "main".to_string());
+
+    let mut flags = options.flags;
+    if let Some(level) = options.optimization_level {
+        flags = flags.with_optimization_level(level);
+    }
+
+    let mut builder = CompileBuilder::new(&source, &entry, target)
+        .source_name(&input.to_string_lossy())
+        .include(include_handler(&options))
+        .flags(flags);
+    for (name, value) in &options.defines {
+        builder = builder.define(name, value);
+    }
+
+    let result = match builder.compile() {
+        Ok(result) => result,
+        Err(e) => {
+            report_diagnostics(&options, &diagnostic_text(&e));
+            println!("compilation failed; no code produced");
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+    report_diagnostics(&options, result.warnings.as_deref().unwrap_or_default());
+
+    let object = if options.strip.is_empty() {
+        result.bytecode
+    } else {
+        strip_shader(&result.bytecode, options.strip).map_err(|e| format!("error: {}", e))?
+    };
+
+    let wants_listing = options.listing_file.is_some()
+        || options.header_file.is_some()
+        || (options.object_file.is_none() && options.header_file.is_none());
+    let listing = if wants_listing {
+        let listing = DisassembleBuilder::new(&object)
+            .flags(options.listing_flags)
+            .disassemble()
+            .map_err(|e| format!("error: {}", e))?;
+        Some(listing.to_string_lossy())
+    } else {
+        None
+    };
+
+    if let Some(path) = &options.object_file {
+        write_file(path, &object)?;
+        println!("compilation object save succeeded; see {}", path.display());
+    }
+
+    if let Some(path) = &options.header_file {
+        let variable_name = options
+            .variable_name
+            .clone()
+            .unwrap_or_else(|| format!("g_{}", entry));
+        let mut header = HeaderBuilder::new(&object, &variable_name);
+        if let Some(listing) = &listing {
+            header = header.listing(listing);
+        }
+        let header = header.render().map_err(|e| format!("error: {}", e))?;
+        write_file(path, header.as_bytes())?;
+        println!("compilation header save succeeded; see {}", path.display());
+    }
+
+    if let Some(path) = &options.listing_file {
+        write_file(path, listing.as_deref().unwrap_or_default().as_bytes())?;
+        println!("compilation code save succeeded; see {}", path.display());
+    } else if options.object_file.is_none() && options.header_file.is_none() {
+        // Like fxc, print the listing when no output file is requested
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(listing.as_deref().unwrap_or_default().as_bytes());
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(options) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a command line split on spaces
+    fn parse(args: &str) -> Result<FxcOptions, String> {
+        parse_args(args.split(' ').map(str::to_string).collect())
+    }
+
+    #[test]
+    fn test_parse_switches() {
+        let options = parse("/Zi -Od /O3 -nologo /Qstrip_debug /Ni").unwrap();
+        assert!(options.flags.contains(CompileFlags::DEBUG));
+        assert!(options.flags.contains(CompileFlags::SKIP_OPTIMIZATION));
+        assert_eq!(options.optimization_level, Some(3));
+        assert!(options.nologo);
+        assert_eq!(options.strip, StripFlags::DEBUG_INFO);
+        assert_eq!(
+            options.listing_flags,
+            DisassembleFlags::ENABLE_INSTRUCTION_NUMBERING
+        );
+
+        // Accepted but ignored
+        assert!(parse("/Gch -Vi").is_ok());
+    }
+
+    #[test]
+    fn test_parse_attached_values() {
+        let options = parse("/Tps_5_0 -Emain_ps /DNAME=V -DFLAG /Foout.dxbc").unwrap();
+        assert_eq!(options.target.as_deref(), Some("ps_5_0"));
+        assert_eq!(options.entry.as_deref(), Some("main_ps"));
+        assert_eq!(
+            options.defines,
+            vec![
+                ("NAME".to_string(), "V".to_string()),
+                ("FLAG".to_string(), "1".to_string()),
+            ]
+        );
+        assert_eq!(options.object_file, Some(PathBuf::from("out.dxbc")));
+    }
+
+    #[test]
+    fn test_parse_separate_values() {
+        let options =
+            parse("/T vs_5_0 -E main /I include -D A=2 /Fh shader.h /Vn g_Shader shader.hlsl")
+                .unwrap();
+        assert_eq!(options.target.as_deref(), Some("vs_5_0"));
+        assert_eq!(options.entry.as_deref(), Some("main"));
+        assert_eq!(options.include_dirs, vec![PathBuf::from("include")]);
+        assert_eq!(options.defines, vec![("A".to_string(), "2".to_string())]);
+        assert_eq!(options.header_file, Some(PathBuf::from("shader.h")));
+        assert_eq!(options.variable_name.as_deref(), Some("g_Shader"));
+        assert_eq!(options.input, Some(PathBuf::from("shader.hlsl")));
+    }
+
+    #[test]
+    fn test_parse_absolute_input_path() {
+        let input = std::env::temp_dir().join(format!("d3dcrs-fxc-{}.hlsl", std::process::id()));
+        std::fs::write(&input, "").unwrap();
+        let options = parse(&format!("/Tps_5_0 {}", input.display()));
+        std::fs::remove_file(&input).unwrap();
+        assert_eq!(options.unwrap().input, Some(input));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("/Tps_5_0 /E").err().as_deref(),
+            Some("error: missing argument for option '/E'")
+        );
+        assert_eq!(
+            parse("-Xfoo").err().as_deref(),
+            Some("Unknown or invalid option '-Xfoo'")
+        );
+        assert_eq!(
+            parse("a.hlsl b.hlsl").err().as_deref(),
+            Some("error: multiple input files specified ('b.hlsl')")
+        );
+    }
+}
@@ -1,48 +1,43 @@
 //! D3DCompiler CLI tool using safe Rust API
 
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use d3dcrs::batch::{self, Job};
+use d3dcrs::codegen::{self, HeaderBuilder};
+use d3dcrs::server::{Client, Server};
 use d3dcrs::{
-    BlobPart, CompileBuilder, CompileFlags, DisassembleBuilder, DisassembleFlags,
-    PreprocessBuilder, ShaderReflection, ShaderTarget, StripFlags, get_blob_part, get_debug_info,
-    get_input_signature, get_output_signature, set_blob_part, strip_shader,
+    BlobPart, Cache, CompileBuilder, CompileFlags, DisassembleBuilder, DisassembleFlags,
+    FileSystemInclude, ImportStatus, PermutationBuilder, PreprocessBuilder, ShaderReflection,
+    ShaderTarget, StripFlags, WorkerPool, get_blob_part, get_debug_info, get_input_signature,
+    get_output_signature, set_blob_part, strip_shader,
 };
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "d3dcrs")]
 #[command(about = "D3DCompiler command-line tool", long_about = None)]
+#[command(arg_required_else_help = true)]
 struct Cli {
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
+
+    /// Print the version of d3dcrs and of the loaded d3dcompiler_47.dll
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// Print every call the DLL makes into an import to stderr
+    #[cfg(feature = "trace-imports")]
+    #[arg(long, global = true)]
+    trace_imports: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Compile HLSL shader to bytecode
-    Compile {
-        /// Input HLSL file
-        input: PathBuf,
-
-        /// Entry point function name
-        #[arg(short, long)]
-        entry: String,
-
-        /// Shader target (e.g., vs_5_0, ps_5_0)
-        #[arg(short, long, value_enum)]
-        target: Target,
-
-        /// Output file (default: <input>.dxbc)
-        #[arg(short, long)]
-        output: Option<PathBuf>,
-
-        /// Optimization level 0-3
-        #[arg(short = 'O', long, default_value = "1", value_parser = clap::value_parser!(u8).range(0..=3))]
-        optimize: u8,
-
-        /// Preprocessor defines (NAME=VALUE or NAME)
-        #[arg(short = 'D', long = "define", value_name = "NAME=VALUE")]
-        defines: Vec<String>,
-    },
+    Compile(CompileArgs),
 
     /// Disassemble shader bytecode
     #[command(alias = "disassemble")]
@@ -100,6 +95,9 @@ enum Commands {
         /// Preprocessor defines (NAME=VALUE or NAME)
         #[arg(short = 'D', long = "define", value_name = "NAME=VALUE")]
         defines: Vec<String>,
+
+        #[command(flatten)]
+        includes: IncludeArgs,
     },
 
     /// Show shader reflection info
@@ -157,6 +155,305 @@ enum Commands {
         #[arg(short, long)]
         data: PathBuf,
     },
+
+    /// Compile many shaders in parallel from a TOML manifest
+    ///
+    /// The manifest holds `[[shader]]` tables with `source`, `entry`, `target`
+    /// and optional `defines`, `optimize` and `output` keys. Top-level
+    /// `include`, `defines` and `optimize` keys apply to every shader. Paths
+    /// are relative to the manifest.
+    Batch {
+        /// Manifest file
+        manifest: PathBuf,
+
+        /// Number of worker threads (default: number of CPUs)
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Reuse results from the compile cache
+        #[arg(long)]
+        cache: bool,
+
+        /// Cache directory (implies --cache)
+        #[arg(long, value_name = "DIR")]
+        cache_dir: Option<PathBuf>,
+
+        /// Compile in helper processes so a compiler crash only fails its shader
+        #[arg(long)]
+        isolate: bool,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+
+    /// Compile every combination of define axes, sharing identical bytecode
+    Permute(PermuteArgs),
+
+    /// Inspect or trim the compile cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+
+        /// Cache directory (default: $D3DCRS_CACHE_DIR or ~/.cache/d3dcrs)
+        #[arg(long, global = true, value_name = "DIR")]
+        cache_dir: Option<PathBuf>,
+    },
+
+    /// Keep the compiler loaded and serve requests on a Unix socket
+    Serve {
+        /// Socket path to listen on
+        #[arg(long, value_name = "PATH")]
+        socket: PathBuf,
+
+        /// Ask the server already listening on the socket to exit
+        #[arg(long)]
+        stop: bool,
+    },
+
+    /// List the DLL's imports and whether each is implemented, stubbed or missing
+    Doctor,
+}
+
+/// Batch manifest read by `d3dcrs batch`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// Include search directories for every shader
+    #[serde(default)]
+    include: Vec<PathBuf>,
+    /// Defines for every shader (NAME=VALUE or NAME)
+    #[serde(default)]
+    defines: Vec<String>,
+    /// Default optimization level 0-3
+    optimize: Option<u8>,
+    #[serde(default, rename = "shader")]
+    shaders: Vec<ManifestShader>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestShader {
+    source: PathBuf,
+    entry: String,
+    target: String,
+    #[serde(default)]
+    defines: Vec<String>,
+    optimize: Option<u8>,
+    /// Output file (default: <source dir>/<stem>_<entry>.dxbc)
+    output: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Show the number and total size of cached entries
+    Stats,
+
+    /// Remove every cached entry
+    Clear,
+
+    /// Evict least recently used entries down to a size limit
+    Prune {
+        /// Size limit, e.g. 512M or 2G (default: 1G)
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
+}
+
+#[derive(Args)]
+struct CompileArgs {
+    /// Input HLSL file
+    input: PathBuf,
+
+    /// Entry point function name
+    #[arg(short, long)]
+    entry: String,
+
+    /// Shader target (e.g., vs_5_0, ps_5_0)
+    #[arg(short, long, value_enum)]
+    target: Target,
+
+    /// Output file (default: <input>.dxbc, <input>.h or <input>.rs)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value = "binary")]
+    emit: Emit,
+
+    /// Variable name for --emit header|rust (default: g_<entry> or <ENTRY>)
+    #[arg(long, value_name = "NAME")]
+    var_name: Option<String>,
+
+    /// Omit the disassembly comment from --emit header
+    #[arg(long)]
+    no_listing: bool,
+
+    /// Optimization level 0-3
+    #[arg(short = 'O', long, default_value = "1", value_parser = clap::value_parser!(u8).range(0..=3))]
+    optimize: u8,
+
+    /// Preprocessor defines (NAME=VALUE or NAME)
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE")]
+    defines: Vec<String>,
+
+    #[command(flatten)]
+    includes: IncludeArgs,
+
+    /// Write a Make-style depfile listing included files (default: <output>.d)
+    #[arg(long = "MD")]
+    depfile: bool,
+
+    /// Depfile path (implies -MD)
+    #[arg(long = "MF", value_name = "FILE")]
+    depfile_path: Option<PathBuf>,
+
+    /// Reuse results from the compile cache
+    #[arg(long)]
+    cache: bool,
+
+    /// Cache directory (implies --cache)
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+}
+
+#[derive(Args)]
+struct PermuteArgs {
+    /// Input HLSL file
+    input: PathBuf,
+
+    /// Entry point function name
+    #[arg(short, long)]
+    entry: String,
+
+    /// Shader target (e.g., vs_5_0, ps_5_0)
+    #[arg(short, long, value_enum)]
+    target: Target,
+
+    /// Define axis: NAME=V1,V2,... or NAME to toggle it on and off (repeatable)
+    #[arg(
+        short = 'a',
+        long = "axis",
+        value_name = "NAME=VALUES",
+        required = true
+    )]
+    axes: Vec<String>,
+
+    /// Directory for the unique blobs (default: next to the input)
+    #[arg(short, long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Output manifest (default: <output dir>/<stem>.permutations.toml)
+    #[arg(short, long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Optimization level 0-3
+    #[arg(short = 'O', long, default_value = "1", value_parser = clap::value_parser!(u8).range(0..=3))]
+    optimize: u8,
+
+    /// Preprocessor defines shared by every permutation (NAME=VALUE or NAME)
+    #[arg(short = 'D', long = "define", value_name = "NAME=VALUE")]
+    defines: Vec<String>,
+
+    #[command(flatten)]
+    includes: IncludeArgs,
+
+    /// Number of worker threads (default: number of CPUs)
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Reuse results from the compile cache
+    #[arg(long)]
+    cache: bool,
+
+    /// Cache directory (implies --cache)
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+}
+
+/// Output manifest written by `d3dcrs permute`
+#[derive(Serialize)]
+struct PermutationManifest {
+    source: PathBuf,
+    entry: String,
+    target: String,
+    /// Unique blob files, relative to the manifest
+    blobs: Vec<String>,
+    #[serde(rename = "permutation")]
+    permutations: Vec<PermutationRecord>,
+}
+
+#[derive(Serialize)]
+struct PermutationRecord {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blob: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Args)]
+struct IncludeArgs {
+    /// Include search directory for #include "..." (repeatable)
+    #[arg(short = 'I', long = "include", value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+
+    /// System include directory for #include <...> (repeatable)
+    #[arg(long = "system-include", value_name = "DIR")]
+    system_include_dirs: Vec<PathBuf>,
+
+    /// Map a virtual include path to a directory, e.g. /Engine/Private=Shaders/Private
+    #[arg(long = "include-virtual", value_name = "MOUNT=DIR")]
+    virtual_dirs: Vec<String>,
+}
+
+#[derive(Args)]
+struct LimitArgs {
+    /// Fail a compile that runs longer than this many seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
+    /// Fail a compile that allocates more than this, e.g. 512M or 2G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    memory_limit: Option<u64>,
+}
+
+impl IncludeArgs {
+    /// Builds the include handler described by the options.
+    fn handler(&self) -> Result<FileSystemInclude, String> {
+        let mut handler = FileSystemInclude::new();
+        for dir in &self.include_dirs {
+            handler.add_path(dir);
+        }
+        for dir in &self.system_include_dirs {
+            handler.add_system_path(dir);
+        }
+        for mapping in &self.virtual_dirs {
+            let (mount, dir) = mapping
+                .split_once('=')
+                .filter(|(mount, dir)| !mount.is_empty() && !dir.is_empty())
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid --include-virtual '{}', expected MOUNT=DIR",
+                        mapping
+                    )
+                })?;
+            handler.add_virtual_path(mount, dir);
+        }
+        Ok(handler)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// Raw DXBC bytecode
+    Binary,
+    /// C/C++ header with a `const BYTE` array, like fxc /Fh
+    Header,
+    /// Rust source with a `pub static` byte array
+    Rust,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
@@ -261,6 +558,16 @@ fn parse_define(s: &str) -> (String, String) {
         .unwrap_or_else(|| (s.to_string(), "1".to_string()))
 }
 
+fn optimization_flags(level: u8) -> CompileFlags {
+    match level {
+        0 => CompileFlags::OPTIMIZATION_LEVEL0 | CompileFlags::SKIP_OPTIMIZATION,
+        1 => CompileFlags::OPTIMIZATION_LEVEL1,
+        2 => CompileFlags::OPTIMIZATION_LEVEL2,
+        3 => CompileFlags::OPTIMIZATION_LEVEL3,
+        _ => unreachable!(),
+    }
+}
+
 fn mask_to_string(mask: u8) -> String {
     let mut s = String::new();
     if mask & 1 != 0 {
@@ -281,29 +588,144 @@ fn mask_to_string(mask: u8) -> String {
     s
 }
 
-fn compile_shader(
-    input: PathBuf,
-    entry: String,
-    target: Target,
-    output: Option<PathBuf>,
-    optimize: u8,
-    defines: Vec<String>,
-) -> Result<(), String> {
-    let output = output.unwrap_or_else(|| input.with_extension("dxbc"));
+/// Maps GCC-style `-MD`/`-MF` onto the long options clap understands.
+///
+/// Only tokens in option position are rewritten, so the value of an option
+/// such as `-D` and everything after `--` pass through unchanged.
+fn normalize_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let takes_value = value_options(&Cli::command());
+    let mut normalized = Vec::new();
+    let mut args = args.into_iter();
+    normalized.extend(args.next());
+    let mut is_value = false;
+    let mut rest = false;
+    for arg in args {
+        if rest || is_value {
+            is_value = false;
+            normalized.push(arg);
+            continue;
+        }
+        let arg = match arg.to_str() {
+            Some("--") => {
+                rest = true;
+                arg
+            }
+            Some("-MD") => "--MD".into(),
+            Some("-MF") => {
+                is_value = true;
+                "--MF".into()
+            }
+            Some(option) => {
+                is_value = takes_value.contains(option);
+                arg
+            }
+            None => arg,
+        };
+        normalized.push(arg);
+    }
+    normalized
+}
+
+/// Collects the spellings of every option of `command` and its subcommands
+/// that takes its value as the next argument.
+fn value_options(command: &clap::Command) -> std::collections::HashSet<String> {
+    let mut options = std::collections::HashSet::new();
+    for arg in command.get_arguments() {
+        if arg.is_positional() || !arg.get_action().takes_values() {
+            continue;
+        }
+        options.extend(
+            arg.get_short_and_visible_aliases()
+                .into_iter()
+                .flatten()
+                .map(|c| format!("-{}", c)),
+        );
+        options.extend(
+            arg.get_long_and_visible_aliases()
+                .into_iter()
+                .flatten()
+                .map(|l| format!("--{}", l)),
+        );
+    }
+    for subcommand in command.get_subcommands() {
+        options.extend(value_options(subcommand));
+    }
+    options
+}
+
+/// Escapes a path for use in a Make rule.
+fn escape_make_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        match c {
+            ' ' | '#' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders a Make/Ninja depfile rule: `target: dep1 dep2 ...`
+fn render_depfile(target: &Path, dependencies: &[PathBuf]) -> String {
+    let mut rule = format!("{}:", escape_make_path(target));
+    for dependency in dependencies {
+        rule.push_str(" \\\n  ");
+        rule.push_str(&escape_make_path(dependency));
+    }
+    rule.push('\n');
+    rule
+}
+
+fn compile_shader(args: CompileArgs) -> Result<(), String> {
+    let CompileArgs {
+        input,
+        entry,
+        target,
+        output,
+        emit,
+        var_name,
+        no_listing,
+        optimize,
+        defines,
+        includes,
+        depfile,
+        depfile_path,
+        cache,
+        cache_dir,
+        limits,
+    } = args;
+
+    let output = output.unwrap_or_else(|| {
+        input.with_extension(match emit {
+            Emit::Binary => "dxbc",
+            Emit::Header => "h",
+            Emit::Rust => "rs",
+        })
+    });
+    let depfile = depfile_path.or_else(|| {
+        depfile.then(|| {
+            let mut path = output.clone().into_os_string();
+            path.push(".d");
+            PathBuf::from(path)
+        })
+    });
 
     let source = std::fs::read_to_string(&input)
         .map_err(|e| format!("Failed to read {}: {}", input.display(), e))?;
 
-    let flags = match optimize {
-        0 => CompileFlags::OPTIMIZATION_LEVEL0 | CompileFlags::SKIP_OPTIMIZATION,
-        1 => CompileFlags::OPTIMIZATION_LEVEL1,
-        2 => CompileFlags::OPTIMIZATION_LEVEL2,
-        3 => CompileFlags::OPTIMIZATION_LEVEL3,
-        _ => unreachable!(),
-    };
+    let flags = optimization_flags(optimize);
+
+    let cache = (cache || cache_dir.is_some())
+        .then(|| open_cache(cache_dir))
+        .transpose()?;
 
     let mut builder = CompileBuilder::new(&source, &entry, target.into())
         .source_name(&input.to_string_lossy())
+        .include(includes.handler()?)
         .flags(flags);
 
     for def in &defines {
@@ -311,10 +733,37 @@ fn compile_shader(
         builder = builder.define(&name, &value);
     }
 
+    if let Some(cache) = &cache {
+        builder = builder.cache(cache);
+    }
+    if let Some(limit) = limits.timeout {
+        builder = builder.timeout(limit);
+    }
+    if let Some(bytes) = limits.memory_limit {
+        builder = builder.memory_limit(bytes as usize);
+    }
+
     let result = builder.compile().map_err(|e| format!("{}", e))?;
 
     let bytecode = result.bytecode.as_bytes();
-    std::fs::write(&output, bytecode)
+    let contents = match emit {
+        Emit::Binary => bytecode.to_vec(),
+        Emit::Header => {
+            let var_name = var_name.unwrap_or_else(|| format!("g_{}", entry));
+            let mut header = HeaderBuilder::new(bytecode, &var_name);
+            if !no_listing {
+                header = header.with_disassembly();
+            }
+            header.render().map_err(|e| format!("{}", e))?.into_bytes()
+        }
+        Emit::Rust => {
+            let var_name = var_name.unwrap_or_else(|| entry.to_uppercase());
+            codegen::rust_module(bytecode, &var_name)
+                .map_err(|e| format!("{}", e))?
+                .into_bytes()
+        }
+    };
+    std::fs::write(&output, contents)
         .map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
 
     eprintln!(
@@ -324,6 +773,13 @@ fn compile_shader(
         bytecode.len()
     );
 
+    if let Some(depfile) = depfile {
+        let mut dependencies = vec![input.clone()];
+        dependencies.extend(result.dependencies.iter().cloned());
+        std::fs::write(&depfile, render_depfile(&output, &dependencies))
+            .map_err(|e| format!("Failed to write {}: {}", depfile.display(), e))?;
+    }
+
     if let Some(warnings) = result.warnings {
         eprintln!("Warnings:\n{}", warnings);
     }
@@ -361,11 +817,14 @@ fn preprocess_shader(
     input: PathBuf,
     output: Option<PathBuf>,
     defines: Vec<String>,
+    includes: IncludeArgs,
 ) -> Result<(), String> {
     let source = std::fs::read_to_string(&input)
         .map_err(|e| format!("Failed to read {}: {}", input.display(), e))?;
 
-    let mut builder = PreprocessBuilder::new(&source).source_name(&input.to_string_lossy());
+    let mut builder = PreprocessBuilder::new(&source)
+        .source_name(&input.to_string_lossy())
+        .include(includes.handler()?);
 
     for def in &defines {
         let (name, value) = parse_define(def);
@@ -656,18 +1115,406 @@ fn inject_private_data(input: PathBuf, output: PathBuf, data: PathBuf) -> Result
     Ok(())
 }
 
+fn batch_compile(
+    manifest_path: PathBuf,
+    jobs: Option<usize>,
+    cache: bool,
+    cache_dir: Option<PathBuf>,
+    isolate: bool,
+    limits: LimitArgs,
+) -> Result<(), String> {
+    // Helpers must come from a copy of this process made before the batch
+    // starts its threads
+    if isolate || limits.timeout.is_some() || limits.memory_limit.is_some() {
+        WorkerPool::start_fork_server()
+            .map_err(|e| format!("Failed to start helper processes: {}", e))?;
+    }
+
+    let text = std::fs::read_to_string(&manifest_path)
+        .map_err(|e| format!("Failed to read {}: {}", manifest_path.display(), e))?;
+    let manifest: Manifest = toml::from_str(&text)
+        .map_err(|e| format!("Failed to parse {}: {}", manifest_path.display(), e))?;
+    let base = manifest_path.parent().unwrap_or(Path::new(""));
+
+    let cache = (cache || cache_dir.is_some())
+        .then(|| open_cache(cache_dir))
+        .transpose()?;
+
+    let pool = isolate
+        .then(|| {
+            let size =
+                jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            WorkerPool::new(size).map_err(|e| format!("Failed to start helper processes: {}", e))
+        })
+        .transpose()?;
+
+    let mut includes = FileSystemInclude::new();
+    for dir in &manifest.include {
+        includes.add_path(base.join(dir));
+    }
+
+    let mut batch = Vec::with_capacity(manifest.shaders.len());
+    let mut outputs = Vec::with_capacity(manifest.shaders.len());
+    for shader in &manifest.shaders {
+        let source_path = base.join(&shader.source);
+        let source = std::fs::read(&source_path)
+            .map_err(|e| format!("Failed to read {}: {}", source_path.display(), e))?;
+        let target: ShaderTarget = shader
+            .target
+            .parse()
+            .map_err(|e| format!("{}: {}", source_path.display(), e))?;
+        let optimize = shader.optimize.or(manifest.optimize).unwrap_or(1);
+        if optimize > 3 {
+            return Err(format!(
+                "{}: optimize must be 0-3, got {}",
+                source_path.display(),
+                optimize
+            ));
+        }
+
+        let mut job = Job::new(source, &shader.entry, target)
+            .source_name(&source_path.to_string_lossy())
+            .include(includes.clone())
+            .flags(optimization_flags(optimize));
+        for def in manifest.defines.iter().chain(&shader.defines) {
+            let (name, value) = parse_define(def);
+            job = job.define(&name, &value);
+        }
+        if let Some(cache) = &cache {
+            job = job.cache(cache);
+        }
+        if let Some(pool) = &pool {
+            job = job.isolated(pool);
+        }
+        if let Some(limit) = limits.timeout {
+            job = job.timeout(limit);
+        }
+        if let Some(bytes) = limits.memory_limit {
+            job = job.memory_limit(bytes as usize);
+        }
+        batch.push(job);
+
+        outputs.push(match &shader.output {
+            Some(output) => base.join(output),
+            None => {
+                let stem = source_path
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy();
+                source_path.with_file_name(format!("{}_{}.dxbc", stem, shader.entry))
+            }
+        });
+    }
+
+    let total = batch.len();
+    let results = match jobs {
+        Some(jobs) => batch::compile_all_with_threads(batch, jobs),
+        None => batch::compile_all(batch),
+    };
+
+    let mut failed = 0;
+    for ((shader, output), result) in manifest.shaders.iter().zip(&outputs).zip(results) {
+        let label = format!(
+            "{}:{} ({})",
+            shader.source.display(),
+            shader.entry,
+            shader.target
+        );
+        let result = result.map_err(|e| format!("{}", e)).and_then(|result| {
+            std::fs::write(output, result.bytecode.as_bytes())
+                .map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+            Ok(result)
+        });
+        match result {
+            Ok(result) => {
+                eprintln!(
+                    "Compiled {} -> {} ({} bytes)",
+                    label,
+                    output.display(),
+                    result.bytecode.len()
+                );
+                if let Some(warnings) = result.warnings {
+                    eprintln!("Warnings:\n{}", warnings);
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Error: {}: {}", label, e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} shaders failed", failed, total));
+    }
+    Ok(())
+}
+
+fn permute_shader(args: PermuteArgs) -> Result<(), String> {
+    let PermuteArgs {
+        input,
+        entry,
+        target,
+        axes,
+        output_dir,
+        manifest,
+        optimize,
+        defines,
+        includes,
+        jobs,
+        cache,
+        cache_dir,
+    } = args;
+
+    let output_dir =
+        output_dir.unwrap_or_else(|| input.parent().unwrap_or(Path::new("")).to_path_buf());
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let manifest_path =
+        manifest.unwrap_or_else(|| output_dir.join(format!("{}.permutations.toml", stem)));
+
+    let source = std::fs::read_to_string(&input)
+        .map_err(|e| format!("Failed to read {}: {}", input.display(), e))?;
+    let cache = (cache || cache_dir.is_some())
+        .then(|| open_cache(cache_dir))
+        .transpose()?;
+
+    let mut builder = PermutationBuilder::new(&source, &entry, target.into())
+        .source_name(&input.to_string_lossy())
+        .include(includes.handler()?)
+        .flags(optimization_flags(optimize));
+    for axis in &axes {
+        builder = match axis.split_once('=') {
+            Some((name, values)) => builder.axis(name, values.split(',')),
+            None => builder.flag(axis),
+        };
+    }
+    for def in &defines {
+        let (name, value) = parse_define(def);
+        builder = builder.define(&name, &value);
+    }
+    if let Some(cache) = &cache {
+        builder = builder.cache(cache);
+    }
+    if let Some(jobs) = jobs {
+        builder = builder.threads(jobs);
+    }
+
+    let set = builder.compile();
+
+    std::fs::create_dir_all(&output_dir)
+        .map_err(|e| format!("Failed to create {}: {}", output_dir.display(), e))?;
+    let mut blob_names = Vec::with_capacity(set.blobs.len());
+    for (index, blob) in set.blobs.iter().enumerate() {
+        let name = format!("{}_{}.dxbc", stem, index);
+        let path = output_dir.join(&name);
+        std::fs::write(&path, blob.as_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        blob_names.push(name);
+    }
+
+    let mut records = Vec::with_capacity(set.permutations.len());
+    for permutation in &set.permutations {
+        if let Some(warnings) = &permutation.warnings {
+            eprintln!("Warnings ({}):\n{}", permutation, warnings);
+        }
+        let (blob, error) = match &permutation.blob {
+            Ok(index) => (Some(*index), None),
+            Err(e) => {
+                eprintln!("Error ({}): {}", permutation, e);
+                (None, Some(e.to_string()))
+            }
+        };
+        records.push(PermutationRecord {
+            key: permutation.key(),
+            blob,
+            error,
+        });
+    }
+
+    let manifest = PermutationManifest {
+        source: input.clone(),
+        entry,
+        target: ShaderTarget::from(target).to_string(),
+        blobs: blob_names,
+        permutations: records,
+    };
+    let text = toml::to_string(&manifest).map_err(|e| format!("{}", e))?;
+    std::fs::write(&manifest_path, text)
+        .map_err(|e| format!("Failed to write {}: {}", manifest_path.display(), e))?;
+
+    eprintln!(
+        "Compiled {} permutations of {} -> {} unique blobs ({})",
+        set.permutations.len(),
+        input.display(),
+        set.blobs.len(),
+        manifest_path.display()
+    );
+
+    match set.failed() {
+        0 => Ok(()),
+        failed => Err(format!(
+            "{} of {} permutations failed",
+            failed,
+            set.permutations.len()
+        )),
+    }
+}
+
+fn open_cache(dir: Option<PathBuf>) -> Result<Cache, String> {
+    let dir = dir.unwrap_or_else(Cache::default_dir);
+    Cache::new(&dir).map_err(|e| format!("Failed to open cache {}: {}", dir.display(), e))
+}
+
+/// Parses a byte count with an optional K, M or G suffix.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size '{}', expected e.g. 512M or 2G", s))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|limit| !limit.is_zero())
+        .ok_or_else(|| format!("Invalid duration '{}', expected seconds e.g. 30 or 2.5", s))
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{} bytes", b),
+    }
+}
+
+fn cache_cmd(command: CacheCommand, cache_dir: Option<PathBuf>) -> Result<(), String> {
+    let cache = open_cache(cache_dir)?;
+
+    match command {
+        CacheCommand::Stats => {
+            let stats = cache.stats().map_err(|e| format!("{}", e))?;
+            println!("Directory: {}", cache.dir().display());
+            println!("Entries:   {}", stats.entries);
+            println!("Size:      {}", format_size(stats.size));
+        }
+        CacheCommand::Clear => {
+            let stats = cache.clear().map_err(|e| format!("{}", e))?;
+            eprintln!(
+                "Removed {} entries ({})",
+                stats.removed_entries,
+                format_size(stats.removed_size)
+            );
+        }
+        CacheCommand::Prune { max_size } => {
+            let cache = cache.with_max_size(max_size.unwrap_or(Cache::DEFAULT_MAX_SIZE));
+            let stats = cache.prune().map_err(|e| format!("{}", e))?;
+            eprintln!(
+                "Removed {} entries ({})",
+                stats.removed_entries,
+                format_size(stats.removed_size)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn serve(socket: PathBuf, stop: bool) -> Result<(), String> {
+    if stop {
+        let client = Client::connect(&socket)
+            .map_err(|e| format!("Failed to connect to {}: {}", socket.display(), e))?;
+        client.shutdown().map_err(|e| format!("{}", e))?;
+        eprintln!("Server on {} stopped", socket.display());
+        return Ok(());
+    }
+
+    let server =
+        Server::bind(&socket).map_err(|e| format!("Failed to bind {}: {}", socket.display(), e))?;
+    eprintln!("Listening on {}", socket.display());
+    server.run().map_err(|e| format!("{}", e))
+}
+
+fn doctor() -> Result<(), String> {
+    let report = d3dcrs::load_report().map_err(|e| format!("{}", e))?;
+
+    let mut dll = None;
+    for import in &report.imports {
+        if dll != Some(&import.dll) {
+            println!("{}", import.dll);
+            dll = Some(&import.dll);
+        }
+        let status = match import.status {
+            ImportStatus::Implemented => "implemented",
+            ImportStatus::Stubbed => "stubbed",
+            ImportStatus::Missing => "missing",
+        };
+        println!("  {:<12} {}", status, import.name);
+    }
+
+    let count = |status| report.with_status(status).count();
+    let missing = count(ImportStatus::Missing);
+    println!(
+        "{} imports: {} implemented, {} stubbed, {} missing",
+        report.imports.len(),
+        count(ImportStatus::Implemented),
+        count(ImportStatus::Stubbed),
+        missing
+    );
+    if missing > 0 {
+        return Err(format!("{} imports are missing", missing));
+    }
+    Ok(())
+}
+
+fn version() -> Result<(), String> {
+    println!("d3dcrs {}", env!("CARGO_PKG_VERSION"));
+    let info = d3dcrs::compiler_info().map_err(|e| format!("{}", e))?;
+    match info.file_version {
+        Some(version) => println!("d3dcompiler_47.dll {}", version),
+        None => println!("d3dcompiler_47.dll (no version resource)"),
+    }
+    println!("sha1 {}", info.sha1_hex());
+    match info.known_build {
+        Some(build) => println!("known build: {}", build.source),
+        None => println!("unrecognized build"),
+    }
+    Ok(())
+}
+
 fn main() {
-    let cli = Cli::parse();
+    let cli = Cli::parse_from(normalize_args(std::env::args_os()));
+    #[cfg(feature = "trace-imports")]
+    if cli.trace_imports {
+        d3dcrs::set_import_trace_hook(Some(|call| eprintln!("[import] {}", call)));
+    }
 
     let result = match cli.command {
-        Commands::Compile {
-            input,
-            entry,
-            target,
-            output,
-            optimize,
-            defines,
-        } => compile_shader(input, entry, target, output, optimize, defines),
+        _ if cli.version => version(),
+        Some(command) => run(command),
+        None => Cli::command()
+            .error(ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit(),
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(command: Commands) -> Result<(), String> {
+    match command {
+        Commands::Compile(args) => compile_shader(args),
         Commands::Disasm {
             input,
             output,
@@ -711,7 +1558,8 @@ fn main() {
             input,
             output,
             defines,
-        } => preprocess_shader(input, output, defines),
+            includes,
+        } => preprocess_shader(input, output, defines, includes),
         Commands::Reflect { input } => reflect_shader(input),
         Commands::Strip {
             input,
@@ -730,10 +1578,42 @@ fn main() {
             output,
             data,
         } => inject_private_data(input, output, data),
-    };
+        Commands::Batch {
+            manifest,
+            jobs,
+            cache,
+            cache_dir,
+            isolate,
+            limits,
+        } => batch_compile(manifest, jobs, cache, cache_dir, isolate, limits),
+        Commands::Permute(args) => permute_shader(args),
+        Commands::Cache { command, cache_dir } => cache_cmd(command, cache_dir),
+        Commands::Serve { socket, stop } => serve(socket, stop),
+        Commands::Doctor => doctor(),
+    }
+}
 
-    if let Err(e) = result {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(args: &[&str]) -> Vec<String> {
+        normalize_args(args.iter().map(OsString::from))
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_normalize_args() {
+        assert_eq!(
+            normalize(&["d3dcrs", "compile", "a.hlsl", "-MD", "-MF", "-MD"]),
+            ["d3dcrs", "compile", "a.hlsl", "--MD", "--MF", "-MD"]
+        );
+        // Option values and arguments after `--` are left alone
+        assert_eq!(
+            normalize(&["d3dcrs", "compile", "-D", "-MF", "--", "-MD"]),
+            ["d3dcrs", "compile", "-D", "-MF", "--", "-MD"]
+        );
     }
 }
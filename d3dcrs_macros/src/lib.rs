@@ -0,0 +1,217 @@
+//! Compile-time HLSL compilation
+//!
+//! `include_hlsl!` compiles a shader while the calling crate is built and
+//! expands to its DXBC bytecode.
+
+use d3dcrs::{CompileBuilder, FileSystemInclude, ShaderTarget};
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use std::path::PathBuf;
+use syn::{
+    Ident, LitByteStr, LitInt, LitStr, Result, Token, bracketed,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+};
+
+/// The `include_hlsl!` arguments
+struct IncludeHlsl {
+    path: LitStr,
+    entry: Option<LitStr>,
+    target: LitStr,
+    defines: Vec<LitStr>,
+    include_paths: Vec<LitStr>,
+    optimization_level: Option<LitInt>,
+}
+
+impl Parse for IncludeHlsl {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let path: LitStr = input.parse()?;
+        let mut entry = None;
+        let mut target = None;
+        let mut defines = Vec::new();
+        let mut include_paths = Vec::new();
+        let mut optimization_level = None;
+
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match key.to_string().as_str() {
+                "entry" => entry = Some(input.parse()?),
+                "target" => target = Some(input.parse()?),
+                "defines" => defines = parse_str_list(input)?,
+                "include_paths" => include_paths = parse_str_list(input)?,
+                "optimization_level" => optimization_level = Some(input.parse()?),
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "expected one of `entry`, `target`, `defines`, `include_paths`, \
+                         `optimization_level`",
+                    ));
+                }
+            }
+        }
+
+        let target = target
+            .ok_or_else(|| syn::Error::new(path.span(), "missing `target = \"..\"` argument"))?;
+
+        Ok(IncludeHlsl {
+            path,
+            entry,
+            target,
+            defines,
+            include_paths,
+            optimization_level,
+        })
+    }
+}
+
+/// Parses `["a", "b", ...]`.
+fn parse_str_list(input: ParseStream) -> Result<Vec<LitStr>> {
+    let content;
+    bracketed!(content in input);
+    let items = Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?;
+    Ok(items.into_iter().collect())
+}
+
+/// Resolves a path relative to the calling crate's manifest directory.
+fn manifest_path(path: &str) -> PathBuf {
+    let root = std::env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    root.join(path)
+}
+
+/// Makes rustc rebuild the caller when `path` changes.
+fn track_file(path: &std::path::Path) -> TokenStream2 {
+    let path = path.to_string_lossy();
+    quote! { const _: &[u8] = ::core::include_bytes!(#path); }
+}
+
+fn expand(args: IncludeHlsl) -> Result<TokenStream2> {
+    let path = manifest_path(&args.path.value());
+    let source = std::fs::read(&path).map_err(|e| {
+        syn::Error::new(
+            args.path.span(),
+            format!("failed to read {}: {}", path.display(), e),
+        )
+    })?;
+
+    let target: ShaderTarget = args
+        .target
+        .value()
+        .parse()
+        .map_err(|e| syn::Error::new(args.target.span(), e))?;
+    let entry = args
+        .entry
+        .as_ref()
+        .map(LitStr::value)
+        .unwrap_or_else(|| "main".to_string());
+
+    let mut include = FileSystemInclude::new();
+    for dir in &args.include_paths {
+        include.add_path(manifest_path(&dir.value()));
+    }
+
+    let mut builder = CompileBuilder::from_bytes(&source, &entry, target)
+        .source_name(&path.to_string_lossy())
+        .include(include);
+    for define in &args.defines {
+        let define = define.value();
+        let (name, value) = define.split_once('=').unwrap_or((&define, "1"));
+        builder = builder.define(name, value);
+    }
+    if let Some(level) = &args.optimization_level {
+        builder = builder.optimization_level(level.base10_parse()?);
+    }
+
+    let tracked_source = track_file(&path);
+    let result = match builder.compile() {
+        Ok(result) => result,
+        Err(e) => {
+            // Keep tracking the source so fixing it triggers a rebuild
+            let error = syn::Error::new(Span::call_site(), e).to_compile_error();
+            return Ok(quote! {{ #tracked_source #error; &[] as &'static [u8] }});
+        }
+    };
+
+    let tracked_includes = result.dependencies.iter().map(|dep| track_file(dep));
+    let bytecode = LitByteStr::new(result.bytecode.as_bytes(), Span::call_site());
+
+    Ok(quote! {{
+        #tracked_source
+        #(#tracked_includes)*
+        #bytecode as &'static [u8]
+    }})
+}
+
+/// Compiles an HLSL file at build time and expands to its bytecode as a
+/// `&'static [u8]`.
+///
+/// Paths are relative to the calling crate's `CARGO_MANIFEST_DIR`. The source
+/// and every file it includes are tracked, so editing them rebuilds the
+/// caller. Compile errors are reported as `compile_error!` at the call site.
+///
+/// Arguments after the path:
+/// * `target` - shader profile, e.g. `"ps_5_0"` (required)
+/// * `entry` - entry point name (default: `"main"`)
+/// * `defines` - `["NAME", "NAME=VALUE", ...]`
+/// * `include_paths` - search directories for `#include "..."`
+/// * `optimization_level` - 0 to 3
+///
+/// # Example
+/// ```ignore
+/// use d3dcrs_macros::include_hlsl;
+///
+/// static BLIT_PS: &[u8] = include_hlsl!(
+///     "shaders/blit.hlsl",
+///     entry = "main",
+///     target = "ps_5_0",
+///     defines = ["GAMMA=2.2", "USE_SRGB"],
+/// );
+/// ```
+#[proc_macro]
+pub fn include_hlsl(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as IncludeHlsl);
+    expand(args)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args: IncludeHlsl = syn::parse_str(
+            r#""shaders/blit.hlsl", entry = "PSMain", target = "ps_5_0",
+               defines = ["A=1", "B"], include_paths = ["shaders/inc"], optimization_level = 3,"#,
+        )
+        .unwrap();
+        assert_eq!(args.path.value(), "shaders/blit.hlsl");
+        assert_eq!(args.entry.unwrap().value(), "PSMain");
+        assert_eq!(args.target.value(), "ps_5_0");
+        assert_eq!(args.defines.len(), 2);
+        assert_eq!(args.include_paths[0].value(), "shaders/inc");
+        assert_eq!(
+            args.optimization_level
+                .unwrap()
+                .base10_parse::<u32>()
+                .unwrap(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(syn::parse_str::<IncludeHlsl>(r#""a.hlsl", entry = "main""#).is_err());
+        assert!(syn::parse_str::<IncludeHlsl>(r#""a.hlsl", target = "ps_5_0", foo = 1"#).is_err());
+    }
+}